@@ -0,0 +1,89 @@
+//! Windowed RRQ throughput over loopback with and without batched UDP
+//! I/O (`sendmmsg`/`recvmmsg`, Linux only).
+//!
+//! Small blocks in large windows make per-datagram syscalls the
+//! bottleneck, which is what batching removes.
+//!
+//! ```text
+//! cargo bench --bench batched_io
+//! cargo bench --bench batched_io -- --blksize 1024 --windowsize 32 --rounds 5
+//! ```
+
+mod common;
+
+use std::net::SocketAddr;
+use std::time::Instant;
+
+use common::{Server, download, wait_ready};
+
+const PORT: u16 = 16_989;
+const IMAGE_SIZE: usize = 32 * 1024 * 1024;
+
+struct Options {
+    blksize: usize,
+    windowsize: u16,
+    rounds: usize,
+}
+
+fn parse_args() -> Options {
+    let mut options = Options {
+        blksize: 512,
+        windowsize: 64,
+        rounds: 3,
+    };
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().and_then(|v| v.parse().ok()).unwrap();
+        match arg.as_str() {
+            "--blksize" => options.blksize = value(),
+            "--windowsize" => options.windowsize = value() as u16,
+            "--rounds" => options.rounds = value(),
+            // `cargo bench` passes `--bench`.
+            _ => {}
+        }
+    }
+    options
+}
+
+#[tokio::main]
+async fn main() {
+    let options = parse_args();
+    let dir = tempfile::tempdir().unwrap();
+    std::fs::write(dir.path().join("image.bin"), vec![0xa5; IMAGE_SIZE]).unwrap();
+    std::fs::write(dir.path().join("probe"), b"ready").unwrap();
+    let server: SocketAddr = ([127, 0, 0, 1], PORT).into();
+    let window = options.windowsize.to_string();
+
+    println!(
+        "RRQ of {} MiB, blksize {}, windowsize {}, best of {}",
+        IMAGE_SIZE >> 20,
+        options.blksize,
+        options.windowsize,
+        options.rounds
+    );
+    println!("mode                      MiB/s   packets/s");
+    for (mode, batching) in [
+        ("one syscall per packet", &["--no-batched-io"][..]),
+        ("sendmmsg/recvmmsg", &[]),
+    ] {
+        let args = [&["--max-window-size", window.as_str()][..], batching].concat();
+        let _server = Server::start(PORT, dir.path(), &args);
+        wait_ready(server, "probe").await;
+
+        let mut best = f64::INFINITY;
+        for _ in 0..options.rounds {
+            let started = Instant::now();
+            let n = download(server, "image.bin", options.blksize, options.windowsize)
+                .await
+                .expect("download failed");
+            assert_eq!(n, IMAGE_SIZE);
+            best = best.min(started.elapsed().as_secs_f64());
+        }
+        let packets = (IMAGE_SIZE / options.blksize + 1) as f64;
+        println!(
+            "{mode:<22} {:>8.1} {:>11.0}",
+            IMAGE_SIZE as f64 / best / (1024.0 * 1024.0),
+            packets / best
+        );
+    }
+}
@@ -0,0 +1,125 @@
+//! Helpers shared by the benchmarks: start the release binary and
+//! download from it with a minimal TFTP client.
+
+use std::net::SocketAddr;
+use std::path::Path;
+use std::process::{Child, Command, Stdio};
+use std::time::Duration;
+
+use tokio::net::UdpSocket;
+use tokio::time::timeout;
+
+/// The server under test, listening on 127.0.0.1:`port`; killed on drop.
+pub struct Server(Child);
+
+impl Server {
+    pub fn start(port: u16, dir: &Path, args: &[&str]) -> Self {
+        let child = Command::new(env!("CARGO_BIN_EXE_tftp-rs"))
+            .args(["--headless", "-p", &port.to_string(), "-d"])
+            .arg(dir)
+            .args(args)
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()
+            .expect("failed to start tftp-rs");
+        Server(child)
+    }
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        let _ = self.0.kill();
+        let _ = self.0.wait();
+    }
+}
+
+fn rrq(filename: &str, blksize: usize, windowsize: u16) -> Vec<u8> {
+    let mut buf = 1u16.to_be_bytes().to_vec();
+    let (blksize, windowsize) = (blksize.to_string(), windowsize.to_string());
+    let mut fields = vec![filename, "octet", "blksize", &blksize];
+    if windowsize != "1" {
+        fields.extend(["windowsize", &windowsize]);
+    }
+    for field in fields {
+        buf.extend_from_slice(field.as_bytes());
+        buf.push(0);
+    }
+    buf
+}
+
+fn ack(block: u16) -> Vec<u8> {
+    let mut buf = 4u16.to_be_bytes().to_vec();
+    buf.extend_from_slice(&block.to_be_bytes());
+    buf
+}
+
+/// Download `filename`, ACKing every `windowsize` blocks (RFC 7440).
+/// Returns the number of bytes received.
+pub async fn download(
+    server: SocketAddr,
+    filename: &str,
+    blksize: usize,
+    windowsize: u16,
+) -> std::io::Result<usize> {
+    let sock = UdpSocket::bind("127.0.0.1:0").await?;
+    let mut buf = vec![0u8; 4 + blksize];
+    let mut last_sent = rrq(filename, blksize, windowsize);
+    let mut dest = server;
+    let mut expected: u16 = 1;
+    let mut in_window = 0;
+    let mut received = 0;
+    let mut retries = 0;
+    sock.send_to(&last_sent, dest).await?;
+    loop {
+        let (n, from) = match timeout(Duration::from_secs(1), sock.recv_from(&mut buf)).await {
+            Ok(r) => r?,
+            Err(_) if retries < 10 => {
+                retries += 1;
+                sock.send_to(&last_sent, dest).await?;
+                continue;
+            }
+            Err(_) => return Err(std::io::ErrorKind::TimedOut.into()),
+        };
+        dest = from;
+        match u16::from_be_bytes([buf[0], buf[1]]) {
+            // OACK
+            6 => {
+                last_sent = ack(0);
+                sock.send_to(&last_sent, dest).await?;
+            }
+            // DATA
+            3 if u16::from_be_bytes([buf[2], buf[3]]) == expected => {
+                received += n - 4;
+                in_window += 1;
+                let last = n - 4 < blksize;
+                if last || in_window == windowsize {
+                    last_sent = ack(expected);
+                    sock.send_to(&last_sent, dest).await?;
+                    in_window = 0;
+                }
+                if last {
+                    return Ok(received);
+                }
+                expected = expected.wrapping_add(1);
+            }
+            // Out of order: acknowledge what arrived in sequence.
+            3 => {
+                last_sent = ack(expected.wrapping_sub(1));
+                sock.send_to(&last_sent, dest).await?;
+                in_window = 0;
+            }
+            _ => return Err(std::io::ErrorKind::InvalidData.into()),
+        }
+    }
+}
+
+/// Wait until the server answers requests for `filename`.
+pub async fn wait_ready(server: SocketAddr, filename: &str) {
+    for _ in 0..50 {
+        if download(server, filename, 512, 1).await.is_ok() {
+            return;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    panic!("tftp-rs did not come up on {server}");
+}
@@ -0,0 +1,285 @@
+//! Per-block cost of the RRQ/WRQ data path, and proof that it does not
+//! allocate once warmed up.
+//!
+//! The protocol module is compiled in directly, and blocks are built and
+//! parsed the way the server does it: DATA packets assembled in place in
+//! a fixed set of window buffers, payloads borrowed from the receive
+//! buffer and ACKs serialized on the stack. The owned `Packet` round trip
+//! the server used before is measured alongside for comparison. Before
+//! timing anything, a counting allocator checks that steady-state blocks
+//! allocate nothing and the bench fails if they do.
+//!
+//! ```text
+//! cargo bench --bench data_path
+//! cargo bench --bench data_path -- rrq_window
+//! ```
+
+// Its unit tests are compiled out here, leaving their imports unused.
+#[allow(dead_code, unused_imports)]
+#[path = "../src/tftp_protocol.rs"]
+mod tftp_protocol;
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::collections::VecDeque;
+use std::hint::black_box;
+use std::io::{Read, Write};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use criterion::{Criterion, Throughput, criterion_group};
+
+use tftp_protocol::{DataBuf, NetasciiDecoder, NetasciiEncoder, Packet, PacketRef, ack_bytes};
+
+const IMAGE_SIZE: usize = 1024 * 1024;
+const WINDOWSIZE: usize = 16;
+
+// ---------------------------------------------------------------------------
+// Allocation counting
+// ---------------------------------------------------------------------------
+
+struct Counting;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+// SAFETY: defers to the system allocator; only counts calls.
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        unsafe { System.alloc(layout) }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        unsafe { System.dealloc(ptr, layout) }
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        unsafe { System.realloc(ptr, layout, new_size) }
+    }
+}
+
+#[global_allocator]
+static GLOBAL: Counting = Counting;
+
+/// Allocations made by `f`.
+fn allocations(f: impl FnOnce()) -> usize {
+    let before = ALLOCATIONS.load(Ordering::Relaxed);
+    f();
+    ALLOCATIONS.load(Ordering::Relaxed) - before
+}
+
+// ---------------------------------------------------------------------------
+// Data paths
+// ---------------------------------------------------------------------------
+
+/// Text-heavy image, so netascii has line endings to expand.
+fn image() -> Vec<u8> {
+    b"LABEL linux\r\n  KERNEL vmlinuz\n  APPEND initrd=initrd.img\n\0\xff"
+        .iter()
+        .copied()
+        .cycle()
+        .take(IMAGE_SIZE)
+        .collect()
+}
+
+/// An in-memory file that starts over at its end.
+struct Source<'a> {
+    image: &'a [u8],
+    pos: usize,
+}
+
+impl Source<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> usize {
+        if self.pos == self.image.len() {
+            self.pos = 0;
+        }
+        let n = (&self.image[self.pos..]).read(buf).unwrap();
+        self.pos += n;
+        n
+    }
+}
+
+/// The sending side of a windowed RRQ.
+struct RrqPath<'a> {
+    source: Source<'a>,
+    blksize: usize,
+    encoder: Option<NetasciiEncoder>,
+    raw: Vec<u8>,
+    window: VecDeque<DataBuf>,
+    spare: Vec<DataBuf>,
+    block_num: u16,
+}
+
+impl<'a> RrqPath<'a> {
+    fn new(image: &'a [u8], blksize: usize, netascii: bool) -> Self {
+        Self {
+            source: Source { image, pos: 0 },
+            blksize,
+            encoder: netascii.then(NetasciiEncoder::new),
+            raw: vec![0; if netascii { blksize } else { 0 }],
+            window: VecDeque::with_capacity(WINDOWSIZE),
+            spare: (0..WINDOWSIZE).map(|_| DataBuf::new(blksize)).collect(),
+            block_num: 1,
+        }
+    }
+
+    /// Fill a window, "send" it and release it as if fully acknowledged.
+    fn window(&mut self) {
+        let blksize = self.blksize;
+        while let Some(mut packet) = self.spare.pop() {
+            let out = packet.payload_mut(blksize);
+            let len = match self.encoder.as_mut() {
+                Some(enc) => {
+                    let mut len = enc.drain_overflow_into(out);
+                    if len < blksize {
+                        let n = self.source.read(&mut self.raw);
+                        len += enc.encode_into(&self.raw[..n], &mut out[len..]);
+                    }
+                    len
+                }
+                None => self.source.read(out),
+            };
+            packet.set_len(len);
+            packet.set_block(self.block_num);
+            self.block_num = self.block_num.wrapping_add(1);
+            self.window.push_back(packet);
+        }
+        for packet in self.window.make_contiguous() {
+            black_box(packet.as_bytes());
+        }
+        self.spare.extend(self.window.drain(..));
+    }
+}
+
+/// The receiving side of a WRQ: parse each DATA datagram in place, decode
+/// and write it, and build the ACK.
+struct WrqPath {
+    datagrams: Vec<Vec<u8>>,
+    decoder: Option<NetasciiDecoder>,
+    decoded: Vec<u8>,
+}
+
+impl WrqPath {
+    fn new(image: &[u8], blksize: usize, netascii: bool) -> Self {
+        let datagrams = image
+            .chunks(blksize)
+            .zip(1u16..)
+            .map(|(chunk, bn)| {
+                Packet::DATA {
+                    block_num: bn,
+                    data: chunk.to_vec(),
+                }
+                .to_bytes()
+            })
+            .collect();
+        Self {
+            datagrams,
+            decoder: netascii.then(NetasciiDecoder::new),
+            decoded: Vec::with_capacity(blksize + 1),
+        }
+    }
+
+    fn blocks(&mut self, count: usize) {
+        let mut sink = std::io::sink();
+        for datagram in self.datagrams.iter().cycle().take(count) {
+            let PacketRef::DATA { block_num, data } = PacketRef::parse(datagram).unwrap() else {
+                unreachable!("only DATA is queued");
+            };
+            let to_write = match self.decoder.as_mut() {
+                Some(dec) => {
+                    self.decoded.clear();
+                    dec.decode_into(data, &mut self.decoded);
+                    &self.decoded[..]
+                }
+                None => data,
+            };
+            sink.write_all(to_write).unwrap();
+            black_box(ack_bytes(block_num));
+        }
+    }
+}
+
+/// The owned round trip the hot path used to take: a `Vec` for the
+/// payload, another for the packet and a third when it is parsed back.
+fn owned_block(payload: &[u8], block_num: u16) {
+    let bytes = Packet::DATA {
+        block_num,
+        data: payload.to_vec(),
+    }
+    .to_bytes();
+    black_box(Packet::from_bytes(&bytes).unwrap());
+    black_box(Packet::ACK { block_num }.to_bytes());
+}
+
+// ---------------------------------------------------------------------------
+// Allocation check
+// ---------------------------------------------------------------------------
+
+fn check_allocations(image: &[u8]) {
+    const WINDOWS: usize = 256;
+    println!("steady-state allocations per block:");
+    for blksize in [512, 1428, 8192] {
+        for netascii in [false, true] {
+            let mode = if netascii { "netascii" } else { "octet" };
+
+            let mut rrq = RrqPath::new(image, blksize, netascii);
+            // Warm up: the netascii overflow buffer grows to its high
+            // water mark over the first blocks.
+            for _ in 0..WINDOWS {
+                rrq.window();
+            }
+            let rrq_allocs = allocations(|| (0..WINDOWS).for_each(|_| rrq.window()));
+
+            let mut wrq = WrqPath::new(image, blksize, netascii);
+            wrq.blocks(WINDOWS);
+            let wrq_allocs = allocations(|| wrq.blocks(WINDOWS * WINDOWSIZE));
+
+            let blocks = (WINDOWS * WINDOWSIZE) as f64;
+            println!(
+                "  blksize {blksize:>5} {mode:<8}  rrq {:.2}  wrq {:.2}",
+                rrq_allocs as f64 / blocks,
+                wrq_allocs as f64 / blocks,
+            );
+            assert_eq!(rrq_allocs, 0, "RRQ allocated ({blksize}, {mode})");
+            assert_eq!(wrq_allocs, 0, "WRQ allocated ({blksize}, {mode})");
+        }
+    }
+    let owned = allocations(|| owned_block(&image[..512], 1));
+    println!("  owned Packet round trip: {owned} per block\n");
+}
+
+// ---------------------------------------------------------------------------
+// Timings
+// ---------------------------------------------------------------------------
+
+fn data_path(c: &mut Criterion) {
+    let image = image();
+    for blksize in [512, 1428, 8192] {
+        let mut group = c.benchmark_group(format!("blksize_{blksize}"));
+        group.throughput(Throughput::Bytes((blksize * WINDOWSIZE) as u64));
+        for netascii in [false, true] {
+            let mode = if netascii { "netascii" } else { "octet" };
+            let mut rrq = RrqPath::new(&image, blksize, netascii);
+            group.bench_function(format!("rrq_window/{mode}"), |b| b.iter(|| rrq.window()));
+            let mut wrq = WrqPath::new(&image, blksize, netascii);
+            group.bench_function(format!("wrq_window/{mode}"), |b| {
+                b.iter(|| wrq.blocks(WINDOWSIZE))
+            });
+        }
+        group.bench_function("owned_window", |b| {
+            b.iter(|| {
+                for (chunk, bn) in image.chunks(blksize).take(WINDOWSIZE).zip(1u16..) {
+                    owned_block(chunk, bn);
+                }
+            })
+        });
+        group.finish();
+    }
+}
+
+criterion_group!(benches, data_path);
+
+fn main() {
+    check_allocations(&image());
+    benches();
+    Criterion::default().configure_from_args().final_summary();
+}
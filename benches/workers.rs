@@ -0,0 +1,101 @@
+//! Throughput of many concurrent RRQs as listener workers are added.
+//!
+//! Starts the release binary once per worker count, fires a burst of
+//! concurrent downloads at it, as during a boot storm, and reports
+//! transfers and bytes per second.
+//!
+//! ```text
+//! cargo bench --bench workers
+//! cargo bench --bench workers -- --clients 800 --workers 1,2,4,8 --single-port
+//! ```
+
+mod common;
+
+use std::net::SocketAddr;
+use std::time::Instant;
+
+use common::{Server, download, wait_ready};
+
+const PORT: u16 = 16_979;
+const BLKSIZE: usize = 1428;
+const IMAGE_SIZE: usize = 256 * 1024;
+
+struct Options {
+    clients: usize,
+    workers: Vec<usize>,
+    single_port: bool,
+}
+
+fn parse_args() -> Options {
+    let mut options = Options {
+        clients: 400,
+        workers: vec![1, 2, 4, 8],
+        single_port: false,
+    };
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--clients" => options.clients = args.next().and_then(|v| v.parse().ok()).unwrap(),
+            "--workers" => {
+                options.workers = args
+                    .next()
+                    .unwrap()
+                    .split(',')
+                    .map(|n| n.parse().unwrap())
+                    .collect()
+            }
+            "--single-port" => options.single_port = true,
+            // `cargo bench` passes `--bench`.
+            _ => {}
+        }
+    }
+    options
+}
+
+#[tokio::main]
+async fn main() {
+    let options = parse_args();
+    let dir = tempfile::tempdir().unwrap();
+    std::fs::write(dir.path().join("boot.img"), vec![0x5a; IMAGE_SIZE]).unwrap();
+    let server: SocketAddr = ([127, 0, 0, 1], PORT).into();
+
+    println!(
+        "{} concurrent RRQs of {} KiB, blksize {BLKSIZE}{}",
+        options.clients,
+        IMAGE_SIZE / 1024,
+        if options.single_port {
+            ", single-port"
+        } else {
+            ""
+        }
+    );
+    println!("workers   elapsed   transfers/s       MiB/s   failed");
+    for &workers in &options.workers {
+        let workers_arg = workers.to_string();
+        let mut args = vec!["--workers", &workers_arg];
+        if options.single_port {
+            args.push("--single-port");
+        }
+        let _server = Server::start(PORT, dir.path(), &args);
+        wait_ready(server, "boot.img").await;
+
+        let started = Instant::now();
+        let clients: Vec<_> = (0..options.clients)
+            .map(|_| tokio::spawn(download(server, "boot.img", BLKSIZE, 1)))
+            .collect();
+        let mut bytes = 0;
+        let mut failed = 0;
+        for client in clients {
+            match client.await.unwrap() {
+                Ok(n) if n == IMAGE_SIZE => bytes += n,
+                _ => failed += 1,
+            }
+        }
+        let elapsed = started.elapsed().as_secs_f64();
+        println!(
+            "{workers:>7} {elapsed:>8.2}s {:>13.0} {:>11.1} {failed:>8}",
+            (options.clients - failed) as f64 / elapsed,
+            bytes as f64 / elapsed / (1024.0 * 1024.0),
+        );
+    }
+}
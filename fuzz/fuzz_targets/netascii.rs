@@ -0,0 +1,121 @@
+//! Netascii round trips across block boundaries.
+//!
+//! Input layout: a little-endian `u16` picking the blksize (8..=1468), a
+//! count `k` and `k` bytes of chunk sizes, then the payload. The payload is
+//! encoded into DATA blocks the way an RRQ builds them and decoded back,
+//! both block by block and re-split at the chunk sizes. It is also decoded
+//! as if a client had sent it, split the same way, and compared with a
+//! one-pass reference decoder.
+//!
+//! ```text
+//! cargo +nightly fuzz run netascii fuzz/corpus/netascii fuzz/seeds/netascii
+//! ```
+
+#![no_main]
+
+#[allow(dead_code)]
+#[path = "../../src/tftp_protocol.rs"]
+mod tftp_protocol;
+
+use libfuzzer_sys::fuzz_target;
+
+use tftp_protocol::{NetasciiDecoder, NetasciiEncoder};
+
+fuzz_target!(|input: &[u8]| {
+    let Some((blksize, cuts, data)) = split_input(input) else {
+        return;
+    };
+
+    // Encode as an RRQ does: full blocks, then one short block at the end.
+    let blocks = encode_blocks(data, blksize);
+    let (last, full) = blocks.split_last().expect("at least one block");
+    assert!(full.iter().all(|b| b.len() == blksize), "short block before the end");
+    assert!(last.len() < blksize, "no short block at the end");
+
+    let mut decoder = NetasciiDecoder::new();
+    let mut decoded = Vec::new();
+    for block in &blocks {
+        decoder.decode_into(block, &mut decoded);
+    }
+    decoder.finish(&mut decoded);
+    assert_eq!(decoded, data, "block-wise round trip");
+
+    let stream = blocks.concat();
+    assert_eq!(decode_split(&stream, cuts), data, "re-split round trip");
+
+    // Arbitrary netascii from a client.
+    assert_eq!(decode_split(data, cuts), reference_decode(data));
+});
+
+fn split_input(input: &[u8]) -> Option<(usize, &[u8], &[u8])> {
+    let [lo, hi, k, rest @ ..] = input else {
+        return None;
+    };
+    let blksize = 8 + usize::from(u16::from_le_bytes([*lo, *hi])) % 1461;
+    let k = usize::from(*k).min(rest.len());
+    let (cuts, data) = rest.split_at(k);
+    Some((blksize, cuts, data))
+}
+
+/// The DATA payloads of an RRQ for `data`, built like `read_next_block`:
+/// overflow from the previous block first, then a full read of the file.
+fn encode_blocks(data: &[u8], blksize: usize) -> Vec<Vec<u8>> {
+    let mut encoder = NetasciiEncoder::new();
+    let mut reads = data.chunks(blksize);
+    let mut blocks = Vec::new();
+    loop {
+        let mut out = vec![0; blksize];
+        let mut len = encoder.drain_overflow_into(&mut out);
+        if len < blksize
+            && let Some(raw) = reads.next()
+        {
+            len += encoder.encode_into(raw, &mut out[len..]);
+        }
+        out.truncate(len);
+        blocks.push(out);
+        if len < blksize && !encoder.has_overflow() {
+            return blocks;
+        }
+    }
+}
+
+/// Decode `stream` fed in chunks of the sizes in `cuts`, cycled.
+fn decode_split(stream: &[u8], cuts: &[u8]) -> Vec<u8> {
+    let mut decoder = NetasciiDecoder::new();
+    let mut out = Vec::new();
+    let mut rest = stream;
+    for &cut in cuts.iter().cycle() {
+        if rest.is_empty() {
+            break;
+        }
+        let (chunk, tail) = rest.split_at((usize::from(cut) + 1).min(rest.len()));
+        decoder.decode_into(chunk, &mut out);
+        rest = tail;
+    }
+    if cuts.is_empty() {
+        decoder.decode_into(rest, &mut out);
+    }
+    decoder.finish(&mut out);
+    out
+}
+
+/// Netascii decoding of a whole transfer at once: CR LF is LF, CR NUL is
+/// CR, and any other CR, including one at the very end, is kept.
+fn reference_decode(stream: &[u8]) -> Vec<u8> {
+    let mut out = Vec::new();
+    let mut bytes = stream.iter().copied().peekable();
+    while let Some(b) = bytes.next() {
+        match (b, bytes.peek()) {
+            (b'\r', Some(b'\n')) => {
+                bytes.next();
+                out.push(b'\n');
+            }
+            (b'\r', Some(0)) => {
+                bytes.next();
+                out.push(b'\r');
+            }
+            _ => out.push(b),
+        }
+    }
+    out
+}
@@ -0,0 +1,213 @@
+//! Differential fuzzing of the packet parsers.
+//!
+//! `PacketRef::parse` must accept exactly the datagrams `Packet::from_bytes`
+//! does, and both must agree with `reference`, the allocating parser the
+//! server used before the borrowed one, kept here as an oracle. The oracle
+//! collected options into a map, so the ordered lists are compared after
+//! collapsing duplicates the same way (last wins).
+//!
+//! ```text
+//! cargo +nightly fuzz run packet_parsers fuzz/corpus/packet_parsers fuzz/seeds/packets
+//! ```
+
+#![no_main]
+
+#[allow(dead_code)]
+#[path = "../../src/tftp_protocol.rs"]
+mod tftp_protocol;
+
+use std::collections::BTreeMap;
+
+use libfuzzer_sys::fuzz_target;
+
+use tftp_protocol::{Options, Packet, PacketRef};
+
+fuzz_target!(|data: &[u8]| {
+    let borrowed = PacketRef::parse(data);
+    let owned = Packet::from_bytes(data);
+    let expected = reference::parse(data);
+    assert_eq!(borrowed.is_ok(), owned.is_ok(), "{borrowed:?} vs {owned:?}");
+    assert_eq!(owned.is_ok(), expected.is_ok(), "{owned:?} vs {expected:?}");
+    let (Ok(borrowed), Ok(owned), Ok(expected)) = (borrowed, owned, expected) else {
+        return;
+    };
+    assert_eq!(collapsed(owned.clone()), expected);
+    assert_eq!(Packet::from(borrowed), owned);
+
+    match borrowed {
+        PacketRef::RRQ { options, .. }
+        | PacketRef::WRQ { options, .. }
+        | PacketRef::OACK { options } => {
+            // Every option is kept, in order, duplicates included.
+            let list = match &owned {
+                Packet::RRQ { options, .. } | Packet::WRQ { options, .. } => options,
+                Packet::OACK { options } => options,
+                _ => unreachable!(),
+            };
+            assert!(
+                options
+                    .iter()
+                    .map(|(name, _)| name.to_ascii_lowercase())
+                    .eq(list.iter().map(|(name, _)| name.to_string()))
+            );
+        }
+        PacketRef::DATA { data: payload, .. } => {
+            // Borrowed from the datagram, not copied.
+            assert!(data.as_ptr_range().contains(&payload.as_ptr()) || payload.is_empty());
+        }
+        PacketRef::ACK { .. } | PacketRef::ERROR { .. } => {}
+    }
+});
+
+/// `packet` with its options collapsed as the oracle's map did: the last of
+/// duplicates wins and order is lost (sorted by name here).
+fn collapsed(packet: Packet) -> Packet {
+    let last_wins = |options: Options| -> Options {
+        options
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect::<BTreeMap<_, _>>()
+            .into_iter()
+            .collect()
+    };
+    match packet {
+        Packet::RRQ {
+            filename,
+            mode,
+            options,
+        } => Packet::RRQ {
+            filename,
+            mode,
+            options: last_wins(options),
+        },
+        Packet::WRQ {
+            filename,
+            mode,
+            options,
+        } => Packet::WRQ {
+            filename,
+            mode,
+            options: last_wins(options),
+        },
+        Packet::OACK { options } => Packet::OACK {
+            options: last_wins(options),
+        },
+        other => other,
+    }
+}
+
+/// The previous, allocating parser, unchanged except that its option map
+/// is handed over as a sorted `Options`.
+mod reference {
+    use std::collections::BTreeMap;
+
+    use anyhow::{Result, anyhow};
+
+    use super::Packet;
+
+    pub fn parse(buf: &[u8]) -> Result<Packet> {
+        if buf.len() < 2 {
+            return Err(anyhow!("packet too short"));
+        }
+        let opcode = u16::from_be_bytes([buf[0], buf[1]]);
+        match opcode {
+            1 => parse_request(buf, true),
+            2 => parse_request(buf, false),
+            3 => parse_data(buf),
+            4 => parse_ack(buf),
+            5 => parse_error(buf),
+            6 => parse_oack(buf),
+            _ => Err(anyhow!("unknown opcode {opcode}")),
+        }
+    }
+
+    fn parse_request(buf: &[u8], is_rrq: bool) -> Result<Packet> {
+        let payload = &buf[2..];
+        let fields: Vec<&[u8]> = payload.split(|&b| b == 0).collect();
+
+        if fields.len() < 2 {
+            return Err(anyhow!("missing filename or mode"));
+        }
+
+        let filename = String::from_utf8(fields[0].to_vec())?;
+        let mode = String::from_utf8(fields[1].to_vec())?.to_ascii_lowercase();
+
+        if filename.is_empty() {
+            return Err(anyhow!("empty filename"));
+        }
+
+        let mut options = BTreeMap::new();
+        let mut i = 2;
+        while i + 1 < fields.len() {
+            let key = String::from_utf8(fields[i].to_vec())?.to_ascii_lowercase();
+            let val = String::from_utf8(fields[i + 1].to_vec())?;
+            if !key.is_empty() {
+                options.insert(key, val);
+            }
+            i += 2;
+        }
+
+        if is_rrq {
+            Ok(Packet::RRQ {
+                filename,
+                mode,
+                options: options.into_iter().collect(),
+            })
+        } else {
+            Ok(Packet::WRQ {
+                filename,
+                mode,
+                options: options.into_iter().collect(),
+            })
+        }
+    }
+
+    fn parse_data(buf: &[u8]) -> Result<Packet> {
+        if buf.len() < 4 {
+            return Err(anyhow!("DATA packet too short"));
+        }
+        let block_num = u16::from_be_bytes([buf[2], buf[3]]);
+        let data = buf[4..].to_vec();
+        Ok(Packet::DATA { block_num, data })
+    }
+
+    fn parse_ack(buf: &[u8]) -> Result<Packet> {
+        if buf.len() < 4 {
+            return Err(anyhow!("ACK packet too short"));
+        }
+        let block_num = u16::from_be_bytes([buf[2], buf[3]]);
+        Ok(Packet::ACK { block_num })
+    }
+
+    fn parse_error(buf: &[u8]) -> Result<Packet> {
+        if buf.len() < 5 {
+            return Err(anyhow!("ERROR packet too short"));
+        }
+        let code = u16::from_be_bytes([buf[2], buf[3]]);
+        let msg_bytes = &buf[4..];
+        let end = msg_bytes
+            .iter()
+            .position(|&b| b == 0)
+            .unwrap_or(msg_bytes.len());
+        let msg = String::from_utf8_lossy(&msg_bytes[..end]).to_string();
+        Ok(Packet::ERROR { code, msg })
+    }
+
+    fn parse_oack(buf: &[u8]) -> Result<Packet> {
+        let payload = &buf[2..];
+        let fields: Vec<&[u8]> = payload.split(|&b| b == 0).collect();
+        let mut options = BTreeMap::new();
+        let mut i = 0;
+        while i + 1 < fields.len() {
+            let key = String::from_utf8(fields[i].to_vec())?.to_ascii_lowercase();
+            let val = String::from_utf8(fields[i + 1].to_vec())?;
+            if !key.is_empty() {
+                options.insert(key, val);
+            }
+            i += 2;
+        }
+        Ok(Packet::OACK {
+            options: options.into_iter().collect(),
+        })
+    }
+}
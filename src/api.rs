@@ -0,0 +1,416 @@
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use axum::Router;
+use axum::extract::{Path, Query, Request, State};
+use axum::http::{StatusCode, header};
+use axum::middleware::{self, Next};
+use axum::response::{IntoResponse, Json, Response};
+use axum::routing::{delete, get};
+use serde::{Deserialize, Serialize};
+
+use crate::boot_profiles::{self, BootProfile};
+use crate::cache::CacheStats;
+use crate::events::EventBus;
+use crate::limits::DirQuota;
+use crate::server::{
+    MAX_RETRIES_LIMIT, OptionPolicy, ServerConfig, ServerEvent, TransferInfo, TransferKind,
+};
+use crate::state::{LogEntry, SharedState, TransferRecord};
+use crate::tftp_protocol::MAX_BLKSIZE;
+use crate::versions::{MAX_KEEP_VERSIONS, Versioning};
+
+/// Default number of entries returned by the list endpoints.
+const DEFAULT_LIMIT: usize = 100;
+
+struct ApiState {
+    shared: Arc<SharedState>,
+    tx: EventBus,
+    token: String,
+    proxy_dhcp: bool,
+}
+
+/// Build the control API router, to be nested under `/api`.
+///
+/// | Method   | Path                    | Description                         |
+/// |----------|-------------------------|-------------------------------------|
+/// | `GET`    | `/transfers`            | Active transfers                    |
+/// | `GET`    | `/transfers/recent`     | Finished transfers, newest first    |
+/// | `DELETE` | `/transfers/{id}`       | Cancel a running transfer           |
+/// | `GET`    | `/config`               | Current runtime config              |
+/// | `PATCH`  | `/config`               | Update selected runtime settings    |
+/// | `GET`    | `/logs`                 | Log tail (`?lines=N`)               |
+/// | `GET`    | `/metrics`              | Active transfers and cache counters |
+pub fn router(shared: Arc<SharedState>, tx: EventBus, token: String, proxy_dhcp: bool) -> Router {
+    let state = Arc::new(ApiState {
+        shared,
+        tx,
+        token,
+        proxy_dhcp,
+    });
+    Router::new()
+        .route("/transfers", get(list_transfers))
+        .route("/transfers/recent", get(recent_transfers))
+        .route("/transfers/{id}", delete(cancel_transfer))
+        .route("/config", get(get_config).patch(update_config))
+        .route("/logs", get(log_tail))
+        .route("/metrics", get(metrics))
+        .route_layer(middleware::from_fn_with_state(
+            Arc::clone(&state),
+            require_token,
+        ))
+        .with_state(state)
+}
+
+/// Reject requests without a matching `Authorization: Bearer <token>`.
+async fn require_token(State(api): State<Arc<ApiState>>, request: Request, next: Next) -> Response {
+    let authorized = request
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "))
+        .is_some_and(|t| constant_time_eq(t.trim().as_bytes(), api.token.as_bytes()));
+    if !authorized {
+        return (
+            StatusCode::UNAUTHORIZED,
+            [(header::WWW_AUTHENTICATE, "Bearer")],
+            "Unauthorized",
+        )
+            .into_response();
+    }
+    next.run(request).await
+}
+
+/// Compare secrets without leaking the length of the matching prefix.
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+// ---------------------------------------------------------------------------
+// Transfers
+// ---------------------------------------------------------------------------
+
+/// JSON view of a running transfer.
+#[derive(Debug, Serialize)]
+pub struct TransferView {
+    pub id: u64,
+    pub peer: SocketAddr,
+    pub filename: String,
+    pub kind: TransferKind,
+    pub total_bytes: u64,
+    pub transferred: u64,
+    pub size_known: bool,
+    pub elapsed_ms: u64,
+    pub bytes_per_sec: u64,
+}
+
+impl From<&TransferInfo> for TransferView {
+    fn from(tf: &TransferInfo) -> Self {
+        let elapsed = tf.started.elapsed();
+        Self {
+            id: tf.id,
+            peer: tf.peer,
+            filename: tf.filename.clone(),
+            kind: tf.kind,
+            total_bytes: tf.total_bytes,
+            transferred: tf.transferred,
+            size_known: tf.size_known,
+            elapsed_ms: elapsed.as_millis() as u64,
+            bytes_per_sec: (tf.transferred as f64 / elapsed.as_secs_f64().max(0.001)) as u64,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct LimitQuery {
+    limit: Option<usize>,
+}
+
+async fn list_transfers(State(api): State<Arc<ApiState>>) -> Json<Vec<TransferView>> {
+    Json(
+        api.shared
+            .active_transfers()
+            .iter()
+            .map(TransferView::from)
+            .collect(),
+    )
+}
+
+async fn recent_transfers(
+    State(api): State<Arc<ApiState>>,
+    Query(query): Query<LimitQuery>,
+) -> Json<Vec<TransferRecord>> {
+    Json(
+        api.shared
+            .recent_transfers(query.limit.unwrap_or(DEFAULT_LIMIT)),
+    )
+}
+
+async fn cancel_transfer(State(api): State<Arc<ApiState>>, Path(id): Path<u64>) -> Response {
+    if !api.shared.cancel_transfer(id) {
+        return (StatusCode::NOT_FOUND, "No such transfer").into_response();
+    }
+    api.tx.send(ServerEvent::Log(format!(
+        "Transfer #{id} cancelled via API"
+    )));
+    StatusCode::ACCEPTED.into_response()
+}
+
+// ---------------------------------------------------------------------------
+// Runtime config
+// ---------------------------------------------------------------------------
+
+/// Partial config update. Omitted fields keep their current value.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ConfigPatch {
+    timeout_ms: Option<u64>,
+    max_block_size: Option<usize>,
+    max_window_size: Option<u16>,
+    allow_overwrite: Option<bool>,
+    max_retries: Option<u32>,
+    enable_read: Option<bool>,
+    enable_write: Option<bool>,
+    checksum_crc32: Option<bool>,
+    checksum_md5: Option<bool>,
+    sha256_sidecar: Option<bool>,
+    versioning: Option<Versioning>,
+    keep_versions: Option<usize>,
+    max_version_age_secs: Option<u64>,
+    max_upload_size: Option<u64>,
+    dir_quotas: Option<Vec<DirQuota>>,
+    client_quota: Option<u64>,
+    min_free_space: Option<u64>,
+    enable_offset: Option<bool>,
+    strict_rollover: Option<bool>,
+    batched_io: Option<bool>,
+    cache_size: Option<u64>,
+    option_policy: Option<OptionPolicy>,
+    boot_profiles: Option<Vec<BootProfile>>,
+}
+
+impl ConfigPatch {
+    fn validate(&self) -> Result<(), String> {
+        if self.timeout_ms == Some(0) {
+            return Err("timeout_ms must be greater than 0".into());
+        }
+        if let Some(size) = self.max_block_size
+            && size != 0
+            && !(8..=MAX_BLKSIZE).contains(&size)
+        {
+            return Err(format!("max_block_size must be 0 or 8..={MAX_BLKSIZE}"));
+        }
+        if self.max_window_size == Some(0) {
+            return Err("max_window_size must be 1..=65535".into());
+        }
+        if self.max_retries.is_some_and(|n| n > MAX_RETRIES_LIMIT) {
+            return Err(format!("max_retries must be at most {MAX_RETRIES_LIMIT}"));
+        }
+        if self.keep_versions.is_some_and(|n| n > MAX_KEEP_VERSIONS) {
+            return Err(format!("keep_versions must be at most {MAX_KEEP_VERSIONS}"));
+        }
+        Ok(())
+    }
+
+    fn apply(&self, config: &mut ServerConfig) {
+        if let Some(v) = self.timeout_ms {
+            config.timeout_ms = v;
+        }
+        if let Some(v) = self.max_block_size {
+            config.max_block_size = v;
+        }
+        if let Some(v) = self.max_window_size {
+            config.max_window_size = v;
+        }
+        if let Some(v) = self.allow_overwrite {
+            config.allow_overwrite = v;
+        }
+        if let Some(v) = self.max_retries {
+            config.max_retries = v;
+        }
+        if let Some(v) = self.enable_read {
+            config.enable_read = v;
+        }
+        if let Some(v) = self.enable_write {
+            config.enable_write = v;
+        }
+        if let Some(v) = self.checksum_crc32 {
+            config.checksum_crc32 = v;
+        }
+        if let Some(v) = self.checksum_md5 {
+            config.checksum_md5 = v;
+        }
+        if let Some(v) = self.sha256_sidecar {
+            config.sha256_sidecar = v;
+        }
+        if let Some(v) = self.versioning {
+            config.versioning = v;
+        }
+        if let Some(v) = self.keep_versions {
+            config.keep_versions = v;
+        }
+        if let Some(v) = self.max_version_age_secs {
+            config.max_version_age_secs = v;
+        }
+        if let Some(v) = self.max_upload_size {
+            config.max_upload_size = v;
+        }
+        if let Some(ref v) = self.dir_quotas {
+            config.dir_quotas = v.clone();
+        }
+        if let Some(v) = self.client_quota {
+            config.client_quota = v;
+        }
+        if let Some(v) = self.min_free_space {
+            config.min_free_space = v;
+        }
+        if let Some(v) = self.enable_offset {
+            config.enable_offset = v;
+        }
+        if let Some(v) = self.strict_rollover {
+            config.strict_rollover = v;
+        }
+        if let Some(v) = self.batched_io {
+            config.batched_io = v;
+        }
+        if let Some(v) = self.cache_size {
+            config.cache_size = v;
+        }
+        if let Some(v) = self.option_policy {
+            config.option_policy = v;
+        }
+        if let Some(ref v) = self.boot_profiles {
+            config.boot_profiles = v.clone();
+        }
+    }
+}
+
+async fn get_config(State(api): State<Arc<ApiState>>) -> Json<ServerConfig> {
+    Json(api.shared.config())
+}
+
+async fn update_config(
+    State(api): State<Arc<ApiState>>,
+    Json(patch): Json<ConfigPatch>,
+) -> Response {
+    if let Err(e) = patch.validate() {
+        return (StatusCode::UNPROCESSABLE_ENTITY, e).into_response();
+    }
+    let config = api.shared.update_config(|c| patch.apply(c));
+    api.tx.send(ServerEvent::Log(format!(
+        "Runtime config updated via API: read={} write={} overwrite={} timeout={}ms retries={} blksize<={} windowsize<={}",
+        config.enable_read,
+        config.enable_write,
+        config.allow_overwrite,
+        config.timeout_ms,
+        config.max_retries,
+        config.max_block_size,
+        config.max_window_size,
+    )));
+    if patch.boot_profiles.is_some()
+        && !api.proxy_dhcp
+        && let Some(warning) = boot_profiles::proxy_dhcp_warning(&config.boot_profiles)
+    {
+        api.tx.send(ServerEvent::Log(warning));
+    }
+    Json(config).into_response()
+}
+
+// ---------------------------------------------------------------------------
+// Logs
+// ---------------------------------------------------------------------------
+
+#[derive(Debug, Deserialize)]
+struct LinesQuery {
+    lines: Option<usize>,
+}
+
+async fn log_tail(
+    State(api): State<Arc<ApiState>>,
+    Query(query): Query<LinesQuery>,
+) -> Json<Vec<LogEntry>> {
+    Json(api.shared.log_tail(query.lines.unwrap_or(DEFAULT_LIMIT)))
+}
+
+// ---------------------------------------------------------------------------
+// Metrics
+// ---------------------------------------------------------------------------
+
+/// Point-in-time server counters.
+#[derive(Debug, Serialize)]
+struct Metrics {
+    active_transfers: usize,
+    /// `null` while the read cache is off.
+    read_cache: Option<CacheStats>,
+    read_cache_hit_rate: Option<f64>,
+}
+
+async fn metrics(State(api): State<Arc<ApiState>>) -> Json<Metrics> {
+    let read_cache = api.shared.cache_stats();
+    Json(Metrics {
+        active_transfers: api.shared.active_transfers().len(),
+        read_cache,
+        read_cache_hit_rate: read_cache.and_then(|c| c.hit_rate()),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn config_patch_applies_only_given_fields() {
+        let patch: ConfigPatch =
+            serde_json::from_str(r#"{"enable_write": false, "max_retries": 3}"#).unwrap();
+        patch.validate().unwrap();
+        let mut config = ServerConfig::default();
+        patch.apply(&mut config);
+        assert!(!config.enable_write);
+        assert!(config.enable_read);
+        assert_eq!(config.max_retries, 3);
+    }
+
+    #[test]
+    fn config_patch_rejects_bad_values() {
+        assert!(serde_json::from_str::<ConfigPatch>(r#"{"bogus": 1}"#).is_err());
+        let patch: ConfigPatch = serde_json::from_str(r#"{"max_block_size": 4}"#).unwrap();
+        assert!(patch.validate().is_err());
+        let patch: ConfigPatch = serde_json::from_str(r#"{"timeout_ms": 0}"#).unwrap();
+        assert!(patch.validate().is_err());
+    }
+
+    #[test]
+    fn config_patch_rejects_zero_window() {
+        assert!(serde_json::from_str::<ConfigPatch>(r#"{"max_window_size": 65536}"#).is_err());
+        let patch: ConfigPatch = serde_json::from_str(r#"{"max_window_size": 0}"#).unwrap();
+        assert!(patch.validate().is_err());
+        let patch: ConfigPatch = serde_json::from_str(r#"{"max_window_size": 65535}"#).unwrap();
+        assert!(patch.validate().is_ok());
+    }
+
+    #[test]
+    fn config_patch_rejects_excessive_retries() {
+        let patch: ConfigPatch = serde_json::from_str(r#"{"max_retries": 1001}"#).unwrap();
+        assert!(patch.validate().is_err());
+        let patch: ConfigPatch = serde_json::from_str(r#"{"max_retries": 1000}"#).unwrap();
+        assert!(patch.validate().is_ok());
+    }
+
+    #[test]
+    fn config_patch_rejects_excessive_keep_versions() {
+        let patch: ConfigPatch = serde_json::from_str(r#"{"keep_versions": 10001}"#).unwrap();
+        assert!(patch.validate().is_err());
+        let patch: ConfigPatch = serde_json::from_str(r#"{"keep_versions": 0}"#).unwrap();
+        assert!(patch.validate().is_ok());
+    }
+
+    #[test]
+    fn token_comparison() {
+        assert!(constant_time_eq(b"secret", b"secret"));
+        assert!(!constant_time_eq(b"secret", b"secreT"));
+        assert!(!constant_time_eq(b"secret", b"secret2"));
+    }
+}
@@ -0,0 +1,370 @@
+//! Boot profiles: serve a different file for the same requested name
+//! depending on the client, e.g. one `bootfile` name in the DHCP config
+//! that gives BIOS, UEFI and iPXE clients each their own loader.
+
+use std::fmt;
+use std::net::IpAddr;
+use std::str::FromStr;
+
+use serde::{Deserialize, Serialize};
+
+use crate::proxy_dhcp::ClientArch;
+
+/// Which clients a profile applies to.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ClientMatch {
+    /// Every client.
+    Any,
+    /// One hardware address, normalized to `aa:bb:cc:dd:ee:ff`.
+    Mac(String),
+    /// An address or a CIDR block.
+    Net { addr: IpAddr, prefix: u8 },
+    /// Clients running iPXE, as reported to the ProxyDHCP responder.
+    Ipxe,
+    /// Clients of one architecture, as reported to the ProxyDHCP responder.
+    Arch(ClientArch),
+}
+
+impl ClientMatch {
+    /// How specific the match is; the most specific matching profile
+    /// wins. Longer prefixes beat shorter ones.
+    fn rank(&self) -> (u8, u8) {
+        match self {
+            Self::Mac(_) => (4, 0),
+            Self::Net { prefix, .. } => (3, *prefix),
+            Self::Ipxe => (2, 0),
+            Self::Arch(_) => (1, 0),
+            Self::Any => (0, 0),
+        }
+    }
+
+    fn matches(&self, client: &ClientId) -> bool {
+        match self {
+            Self::Any => true,
+            Self::Mac(mac) => client.mac.as_ref() == Some(mac),
+            Self::Net { addr, prefix } => in_network(client.ip, *addr, *prefix),
+            Self::Ipxe => client.ipxe,
+            Self::Arch(arch) => client.arch == Some(*arch),
+        }
+    }
+
+    /// Whether matching needs the client's MAC, which costs a neighbor
+    /// table lookup.
+    fn needs_mac(&self) -> bool {
+        matches!(self, Self::Mac(_) | Self::Ipxe | Self::Arch(_))
+    }
+
+    /// Whether matching needs what only the ProxyDHCP responder learns.
+    fn needs_proxy_dhcp(&self) -> bool {
+        matches!(self, Self::Ipxe | Self::Arch(_))
+    }
+}
+
+/// Maps a requested file name to another file for matching clients.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct BootProfile {
+    /// The name clients request.
+    pub name: String,
+    pub client: ClientMatch,
+    /// The file served instead, relative to the served directory.
+    pub file: String,
+}
+
+impl FromStr for BootProfile {
+    type Err = String;
+
+    /// Parses `NAME[@CLIENT]=FILE`, where CLIENT is a MAC address, an IP
+    /// address or CIDR block, or one of `bios`, `efi64`, `arm64`, `ipxe`,
+    /// e.g. `bootfile@efi64=efi/ipxe.efi` or `bootfile@10.1.0.0/16=lab.efi`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (key, file) = s
+            .split_once('=')
+            .ok_or_else(|| format!("expected NAME[@CLIENT]=FILE, got {s:?}"))?;
+        let (name, client) = match key.rsplit_once('@') {
+            Some((name, client)) => (name, parse_client(client.trim())?),
+            None => (key, ClientMatch::Any),
+        };
+        let (name, file) = (name.trim(), file.trim());
+        if name.is_empty() || file.is_empty() {
+            return Err(format!("expected NAME[@CLIENT]=FILE, got {s:?}"));
+        }
+        Ok(Self {
+            name: name.to_string(),
+            client,
+            file: file.to_string(),
+        })
+    }
+}
+
+impl TryFrom<String> for BootProfile {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl From<BootProfile> for String {
+    fn from(profile: BootProfile) -> Self {
+        profile.to_string()
+    }
+}
+
+impl fmt::Display for BootProfile {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.name)?;
+        match &self.client {
+            ClientMatch::Any => {}
+            ClientMatch::Mac(mac) => write!(f, "@{mac}")?,
+            ClientMatch::Net { addr, prefix } if *prefix == max_prefix(*addr) => {
+                write!(f, "@{addr}")?
+            }
+            ClientMatch::Net { addr, prefix } => write!(f, "@{addr}/{prefix}")?,
+            ClientMatch::Ipxe => f.write_str("@ipxe")?,
+            ClientMatch::Arch(ClientArch::Bios) => f.write_str("@bios")?,
+            ClientMatch::Arch(ClientArch::Efi64) => f.write_str("@efi64")?,
+            ClientMatch::Arch(ClientArch::Arm64) => f.write_str("@arm64")?,
+        }
+        write!(f, "={}", self.file)
+    }
+}
+
+fn parse_client(s: &str) -> Result<ClientMatch, String> {
+    match s.to_ascii_lowercase().as_str() {
+        "bios" => return Ok(ClientMatch::Arch(ClientArch::Bios)),
+        "efi64" => return Ok(ClientMatch::Arch(ClientArch::Efi64)),
+        "arm64" => return Ok(ClientMatch::Arch(ClientArch::Arm64)),
+        "ipxe" => return Ok(ClientMatch::Ipxe),
+        _ => {}
+    }
+    if let Some(mac) = parse_mac(s) {
+        return Ok(ClientMatch::Mac(mac));
+    }
+    let (addr, prefix) = match s.split_once('/') {
+        Some((addr, prefix)) => (addr, Some(prefix)),
+        None => (s, None),
+    };
+    let addr: IpAddr = addr.parse().map_err(|_| {
+        format!("unknown client {s:?}, expected a MAC, IP, CIDR, bios, efi64, arm64 or ipxe")
+    })?;
+    let prefix = match prefix {
+        Some(p) => p
+            .parse()
+            .ok()
+            .filter(|&p| p <= max_prefix(addr))
+            .ok_or_else(|| format!("invalid prefix length in {s:?}"))?,
+        None => max_prefix(addr),
+    };
+    Ok(ClientMatch::Net { addr, prefix })
+}
+
+/// Normalize a MAC written with `:` or `-` separators, or `None`.
+pub fn parse_mac(s: &str) -> Option<String> {
+    let octets: Vec<&str> = s.split([':', '-']).collect();
+    if octets.len() != 6
+        || !octets
+            .iter()
+            .all(|o| o.len() == 2 && o.chars().all(|c| c.is_ascii_hexdigit()))
+    {
+        return None;
+    }
+    Some(octets.join(":").to_ascii_lowercase())
+}
+
+fn max_prefix(addr: IpAddr) -> u8 {
+    if addr.is_ipv4() { 32 } else { 128 }
+}
+
+fn in_network(ip: IpAddr, net: IpAddr, prefix: u8) -> bool {
+    match (ip, net) {
+        (IpAddr::V4(ip), IpAddr::V4(net)) => {
+            let mask = u32::MAX.checked_shl(32 - prefix as u32).unwrap_or(0);
+            u32::from(ip) & mask == u32::from(net) & mask
+        }
+        (IpAddr::V6(ip), IpAddr::V6(net)) => {
+            let mask = u128::MAX.checked_shl(128 - prefix as u32).unwrap_or(0);
+            u128::from(ip) & mask == u128::from(net) & mask
+        }
+        _ => false,
+    }
+}
+
+// ---------------------------------------------------------------------------
+// Selection
+// ---------------------------------------------------------------------------
+
+/// What is known about the client making a request.
+#[derive(Debug, Clone)]
+pub struct ClientId {
+    pub ip: IpAddr,
+    pub mac: Option<String>,
+    pub arch: Option<ClientArch>,
+    pub ipxe: bool,
+}
+
+/// Whether any profile for `name` needs the client's MAC to decide.
+pub fn needs_mac(profiles: &[BootProfile], name: &str) -> bool {
+    profiles
+        .iter()
+        .any(|p| p.name == name && p.client.needs_mac())
+}
+
+/// A warning naming the profiles that match on architecture or iPXE, which
+/// never match without the ProxyDHCP responder; `None` if there are none.
+pub fn proxy_dhcp_warning(profiles: &[BootProfile]) -> Option<String> {
+    let specs: Vec<String> = profiles
+        .iter()
+        .filter(|p| p.client.needs_proxy_dhcp())
+        .map(BootProfile::to_string)
+        .collect();
+    (!specs.is_empty()).then(|| {
+        format!(
+            "Warning: boot profiles {} never match without --proxy-dhcp, \
+             which is what learns client architectures and iPXE",
+            specs.join(", ")
+        )
+    })
+}
+
+/// The most specific profile for `name` matching `client`. Among equally
+/// specific ones the first given wins.
+pub fn select<'a>(
+    profiles: &'a [BootProfile],
+    name: &str,
+    client: &ClientId,
+) -> Option<&'a BootProfile> {
+    profiles
+        .iter()
+        .filter(|p| p.name == name && p.client.matches(client))
+        .rev()
+        .max_by_key(|p| p.client.rank())
+}
+
+/// Hardware address of `ip` from the kernel's neighbor (ARP) table.
+/// Clients on another subnet, and platforms without `/proc/net/arp`,
+/// have none.
+pub fn neighbor_mac(ip: IpAddr) -> Option<String> {
+    let table = std::fs::read_to_string("/proc/net/arp").ok()?;
+    arp_lookup(&table, ip)
+}
+
+/// Find `ip` in `/proc/net/arp` contents. Incomplete entries (flags 0)
+/// are skipped.
+fn arp_lookup(table: &str, ip: IpAddr) -> Option<String> {
+    table.lines().skip(1).find_map(|line| {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        match fields.as_slice() {
+            [addr, _, flags, mac, ..] if addr.parse() == Ok(ip) && *flags != "0x0" => {
+                parse_mac(mac)
+            }
+            _ => None,
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn profiles(specs: &[&str]) -> Vec<BootProfile> {
+        specs.iter().map(|s| s.parse().unwrap()).collect()
+    }
+
+    fn client(ip: &str, mac: Option<&str>, arch: Option<ClientArch>, ipxe: bool) -> ClientId {
+        ClientId {
+            ip: ip.parse().unwrap(),
+            mac: mac.map(str::to_string),
+            arch,
+            ipxe,
+        }
+    }
+
+    #[test]
+    fn profiles_parse_and_print_as_given() {
+        for spec in [
+            "bootfile=pxelinux.0",
+            "bootfile@efi64=efi/ipxe.efi",
+            "bootfile@ipxe=boot.ipxe",
+            "bootfile@52:54:00:12:34:56=special.efi",
+            "bootfile@10.1.0.0/16=lab.efi",
+            "bootfile@10.1.2.3=one.efi",
+            "bootfile@fd00::/8=v6.efi",
+        ] {
+            assert_eq!(spec.parse::<BootProfile>().unwrap().to_string(), spec);
+        }
+        let p: BootProfile = "bootfile@52-54-00-AB-CD-EF=x".parse().unwrap();
+        assert_eq!(p.client, ClientMatch::Mac("52:54:00:ab:cd:ef".into()));
+        assert!("bootfile".parse::<BootProfile>().is_err());
+        assert!("=x".parse::<BootProfile>().is_err());
+        assert!("bootfile@mips=x".parse::<BootProfile>().is_err());
+        assert!("bootfile@10.0.0.0/33=x".parse::<BootProfile>().is_err());
+
+        let json = serde_json::to_string(&p).unwrap();
+        assert_eq!(json, r#""bootfile@52:54:00:ab:cd:ef=x""#);
+        assert_eq!(serde_json::from_str::<BootProfile>(&json).unwrap(), p);
+    }
+
+    #[test]
+    fn most_specific_profile_wins() {
+        let table = profiles(&[
+            "bootfile=pxelinux.0",
+            "bootfile@efi64=efi/ipxe.efi",
+            "bootfile@ipxe=boot.ipxe",
+            "bootfile@10.0.0.0/8=wide.efi",
+            "bootfile@10.1.0.0/16=lab.efi",
+            "bootfile@52:54:00:12:34:56=special.efi",
+            "other@efi64=other.efi",
+        ]);
+        let file = |c: &ClientId| select(&table, "bootfile", c).map(|p| p.file.as_str());
+
+        assert_eq!(
+            file(&client("192.168.1.5", None, None, false)),
+            Some("pxelinux.0")
+        );
+        assert_eq!(
+            file(&client("192.168.1.5", None, Some(ClientArch::Efi64), false)),
+            Some("efi/ipxe.efi")
+        );
+        assert_eq!(
+            file(&client("192.168.1.5", None, Some(ClientArch::Efi64), true)),
+            Some("boot.ipxe")
+        );
+        assert_eq!(file(&client("10.1.2.3", None, None, true)), Some("lab.efi"));
+        assert_eq!(
+            file(&client("10.2.0.1", None, None, false)),
+            Some("wide.efi")
+        );
+        assert_eq!(
+            file(&client("10.1.2.3", Some("52:54:00:12:34:56"), None, true)),
+            Some("special.efi")
+        );
+        assert!(select(&table, "pxelinux.0", &client("10.1.2.3", None, None, false)).is_none());
+
+        assert!(needs_mac(&table, "bootfile"));
+        assert!(!needs_mac(&profiles(&["a=b", "a@10.0.0.0/8=c"]), "a"));
+
+        let warning = proxy_dhcp_warning(&table).unwrap();
+        assert!(warning.contains("bootfile@efi64=efi/ipxe.efi, bootfile@ipxe=boot.ipxe,"));
+        assert!(!warning.contains("lab.efi"));
+        assert_eq!(
+            proxy_dhcp_warning(&profiles(&["a=b", "a@10.0.0.0/8=c"])),
+            None
+        );
+    }
+
+    #[test]
+    fn arp_table_lookup() {
+        let table = "\
+IP address       HW type     Flags       HW address            Mask     Device
+10.0.0.7         0x1         0x2         52:54:00:12:34:56     *        eth0
+10.0.0.8         0x1         0x0         00:00:00:00:00:00     *        eth0
+";
+        assert_eq!(
+            arp_lookup(table, "10.0.0.7".parse().unwrap()).as_deref(),
+            Some("52:54:00:12:34:56")
+        );
+        assert_eq!(arp_lookup(table, "10.0.0.8".parse().unwrap()), None);
+        assert_eq!(arp_lookup(table, "10.0.0.9".parse().unwrap()), None);
+    }
+}
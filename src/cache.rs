@@ -0,0 +1,180 @@
+use std::collections::HashMap;
+use std::fs::Metadata;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+use serde::Serialize;
+use tokio::sync::OnceCell;
+
+/// In-memory LRU cache of whole files served by RRQ, so a boot storm
+/// reads each hot file from disk once instead of once per client.
+///
+/// Entries are keyed by path and dropped when the file's mtime or size
+/// changes. The byte budget is passed on every lookup so runtime config
+/// changes take effect immediately.
+#[derive(Debug, Default)]
+pub struct ReadCache {
+    entries: Mutex<HashMap<PathBuf, Arc<Entry>>>,
+    /// Bumped on every lookup; entries remember when they were last used.
+    clock: AtomicU64,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+#[derive(Debug)]
+struct Entry {
+    modified: Option<SystemTime>,
+    len: u64,
+    last_used: AtomicU64,
+    /// Filled by the first transfer to miss; later ones wait for it.
+    data: OnceCell<Arc<[u8]>>,
+}
+
+impl Entry {
+    fn matches(&self, metadata: &Metadata) -> bool {
+        self.modified == metadata.modified().ok() && self.len == metadata.len()
+    }
+}
+
+/// Counters for the TUI and `/api/metrics`.
+#[derive(Debug, Clone, Copy, Default, Serialize)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub entries: usize,
+    pub bytes: u64,
+    pub budget: u64,
+}
+
+impl CacheStats {
+    /// Fraction of cacheable reads served from memory, if there were any.
+    pub fn hit_rate(&self) -> Option<f64> {
+        let total = self.hits + self.misses;
+        (total > 0).then(|| self.hits as f64 / total as f64)
+    }
+}
+
+impl ReadCache {
+    /// Contents of `path`, from memory or loaded into it. `None` if the
+    /// cache is off (`budget` 0), the file alone exceeds the budget or it
+    /// could not be loaded; the caller then reads the file itself.
+    pub async fn get(&self, path: &Path, metadata: &Metadata, budget: u64) -> Option<Arc<[u8]>> {
+        if budget == 0 || metadata.len() > budget {
+            return None;
+        }
+        let now = self.clock.fetch_add(1, Ordering::Relaxed);
+        let entry = {
+            let mut entries = self.entries.lock().unwrap();
+            match entries.get(path) {
+                Some(entry) if entry.matches(metadata) => {
+                    self.hits.fetch_add(1, Ordering::Relaxed);
+                    Arc::clone(entry)
+                }
+                _ => {
+                    self.misses.fetch_add(1, Ordering::Relaxed);
+                    entries.remove(path);
+                    evict(&mut entries, budget - metadata.len());
+                    let entry = Arc::new(Entry {
+                        modified: metadata.modified().ok(),
+                        len: metadata.len(),
+                        last_used: AtomicU64::new(now),
+                        data: OnceCell::new(),
+                    });
+                    entries.insert(path.to_path_buf(), Arc::clone(&entry));
+                    entry
+                }
+            }
+        };
+        entry.last_used.store(now, Ordering::Relaxed);
+
+        let loaded = entry
+            .data
+            .get_or_try_init(|| async {
+                let data = tokio::fs::read(path).await?;
+                // Changed between stat and read: let the caller read it.
+                if data.len() as u64 != entry.len {
+                    return Err(io::Error::other("file changed while being cached"));
+                }
+                Ok::<_, io::Error>(Arc::from(data))
+            })
+            .await;
+        match loaded {
+            Ok(data) => Some(Arc::clone(data)),
+            Err(_) => {
+                let mut entries = self.entries.lock().unwrap();
+                if entries.get(path).is_some_and(|e| Arc::ptr_eq(e, &entry)) {
+                    entries.remove(path);
+                }
+                None
+            }
+        }
+    }
+
+    pub fn stats(&self, budget: u64) -> CacheStats {
+        let entries = self.entries.lock().unwrap();
+        CacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            entries: entries.len(),
+            bytes: entries.values().map(|e| e.len).sum(),
+            budget,
+        }
+    }
+}
+
+/// Drop least recently used entries until at most `limit` bytes remain.
+fn evict(entries: &mut HashMap<PathBuf, Arc<Entry>>, limit: u64) {
+    let mut total: u64 = entries.values().map(|e| e.len).sum();
+    while total > limit {
+        let Some(oldest) = entries
+            .iter()
+            .min_by_key(|(_, e)| e.last_used.load(Ordering::Relaxed))
+            .map(|(path, _)| path.clone())
+        else {
+            break;
+        };
+        if let Some(entry) = entries.remove(&oldest) {
+            total -= entry.len;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn hits_evictions_and_invalidation() {
+        let dir = tempfile::tempdir().unwrap();
+        let (a, b) = (dir.path().join("pxelinux.0"), dir.path().join("vmlinuz"));
+        std::fs::write(&a, vec![1u8; 600]).unwrap();
+        std::fs::write(&b, vec![2u8; 600]).unwrap();
+        let cache = ReadCache::default();
+        let meta = |p: &Path| std::fs::metadata(p).unwrap();
+
+        let data = cache.get(&a, &meta(&a), 1000).await.unwrap();
+        assert_eq!(data.len(), 600);
+        cache.get(&a, &meta(&a), 1000).await.unwrap();
+        let stats = cache.stats(1000);
+        assert_eq!((stats.hits, stats.misses, stats.bytes), (1, 1, 600));
+        assert_eq!(stats.hit_rate(), Some(0.5));
+
+        // Only one 600-byte file fits: loading b evicts a.
+        cache.get(&b, &meta(&b), 1000).await.unwrap();
+        cache.get(&a, &meta(&a), 1000).await.unwrap();
+        let stats = cache.stats(1000);
+        assert_eq!((stats.misses, stats.entries), (3, 1));
+
+        // A rewrite with a new size is picked up.
+        std::fs::write(&a, vec![3u8; 700]).unwrap();
+        let data = cache.get(&a, &meta(&a), 1000).await.unwrap();
+        assert_eq!(data[..], [3u8; 700][..]);
+
+        // Off, or too big for the budget: the caller reads the file.
+        assert!(cache.get(&a, &meta(&a), 0).await.is_none());
+        assert!(cache.get(&a, &meta(&a), 500).await.is_none());
+    }
+}
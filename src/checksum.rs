@@ -0,0 +1,161 @@
+use std::fmt;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use md5::Md5;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+
+use crate::server::ServerConfig;
+
+/// Digests of a file's contents. SHA-256 is always computed; CRC32 and
+/// MD5 only when enabled in the config.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Checksums {
+    pub sha256: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub crc32: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub md5: Option<String>,
+}
+
+impl fmt::Display for Checksums {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "sha256 {}", self.sha256)?;
+        if let Some(ref crc32) = self.crc32 {
+            write!(f, ", crc32 {crc32}")?;
+        }
+        if let Some(ref md5) = self.md5 {
+            write!(f, ", md5 {md5}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Incremental hasher fed with file contents as they are streamed.
+///
+/// Always hash the bytes as stored on disk, i.e. before netascii encoding
+/// on reads and after decoding on writes, so the result matches
+/// `sha256sum` on the server.
+pub struct Checksummer {
+    sha256: Sha256,
+    crc32: Option<crc32fast::Hasher>,
+    md5: Option<Md5>,
+}
+
+impl Checksummer {
+    pub fn new(config: &ServerConfig) -> Self {
+        Self {
+            sha256: Sha256::new(),
+            crc32: config.checksum_crc32.then(crc32fast::Hasher::new),
+            md5: config.checksum_md5.then(Md5::new),
+        }
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        self.sha256.update(data);
+        if let Some(ref mut crc32) = self.crc32 {
+            crc32.update(data);
+        }
+        if let Some(ref mut md5) = self.md5 {
+            md5.update(data);
+        }
+    }
+
+    pub fn finish(self) -> Checksums {
+        Checksums {
+            sha256: hex(&self.sha256.finalize()),
+            crc32: self.crc32.map(|c| format!("{:08x}", c.finalize())),
+            md5: self.md5.map(|m| hex(&m.finalize())),
+        }
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+// ---------------------------------------------------------------------------
+// Sidecar files
+// ---------------------------------------------------------------------------
+
+/// `<file>.sha256`, next to the file it describes.
+pub fn sidecar_path(path: &Path) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(".sha256");
+    PathBuf::from(name)
+}
+
+/// Write a `sha256sum`-compatible sidecar for `path`.
+pub async fn write_sidecar(path: &Path, sha256: &str) -> io::Result<()> {
+    let name = path
+        .file_name()
+        .map(|n| n.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    tokio::fs::write(sidecar_path(path), format!("{sha256}  {name}\n")).await
+}
+
+/// The digest from `path`'s sidecar, if there is one that is not older
+/// than the file itself.
+pub fn read_sidecar(path: &Path) -> Option<String> {
+    let sidecar = sidecar_path(path);
+    let file_mtime = std::fs::metadata(path).and_then(|m| m.modified()).ok()?;
+    let sidecar_mtime = std::fs::metadata(&sidecar)
+        .and_then(|m| m.modified())
+        .ok()?;
+    if sidecar_mtime < file_mtime {
+        return None;
+    }
+    let contents = std::fs::read_to_string(&sidecar).ok()?;
+    let digest = contents.split_whitespace().next()?;
+    (digest.len() == 64 && digest.bytes().all(|b| b.is_ascii_hexdigit()))
+        .then(|| digest.to_ascii_lowercase())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn known_digests() {
+        let config = ServerConfig {
+            checksum_crc32: true,
+            checksum_md5: true,
+            ..ServerConfig::default()
+        };
+        let mut hasher = Checksummer::new(&config);
+        // Split input must hash the same as one update.
+        hasher.update(b"hello ");
+        hasher.update(b"world");
+        let sums = hasher.finish();
+        assert_eq!(
+            sums.sha256,
+            "b94d27b9934d3e08a52e52d7da7dabfac484efe37a5380ee9088f7ace2efcde9"
+        );
+        assert_eq!(sums.crc32.as_deref(), Some("0d4a1185"));
+        assert_eq!(
+            sums.md5.as_deref(),
+            Some("5eb63bbbe01eeed093cb22bb8f5acdc3")
+        );
+
+        let sums = Checksummer::new(&ServerConfig::default()).finish();
+        assert_eq!(
+            sums.to_string(),
+            "sha256 e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+        );
+    }
+
+    #[tokio::test]
+    async fn sidecar_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("image.bin");
+        std::fs::write(&path, b"hello world").unwrap();
+        assert_eq!(read_sidecar(&path), None);
+
+        let sha256 = "b94d27b9934d3e08a52e52d7da7dabfac484efe37a5380ee9088f7ace2efcde9";
+        write_sidecar(&path, sha256).await.unwrap();
+        let contents = std::fs::read_to_string(sidecar_path(&path)).unwrap();
+        assert_eq!(contents, format!("{sha256}  image.bin\n"));
+        assert_eq!(read_sidecar(&path).as_deref(), Some(sha256));
+    }
+}
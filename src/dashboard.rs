@@ -0,0 +1,299 @@
+use std::convert::Infallible;
+use std::sync::Arc;
+use std::time::Duration;
+
+use axum::Router;
+use axum::extract::{Query, RawQuery, Request, State};
+use axum::http::{StatusCode, header};
+use axum::middleware::{self, Next};
+use axum::response::sse::{Event, KeepAlive, Sse};
+use axum::response::{Html, IntoResponse, Redirect, Response};
+use axum::routing::get;
+use futures_util::{Stream, StreamExt, stream};
+use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast::error::RecvError;
+
+use crate::api::{TransferView, constant_time_eq};
+use crate::checksum::Checksums;
+use crate::events::EventBus;
+use crate::server::ServerEvent;
+use crate::state::{LogEntry, SharedState, TransferRecord};
+use crate::ui::get_interface_ips;
+
+/// Number of finished transfers and log lines sent in the initial snapshot.
+const SNAPSHOT_HISTORY: usize = 50;
+const SNAPSHOT_LOG_LINES: usize = 200;
+
+/// The dashboard page. Self-contained so the binary has no asset directory.
+const DASHBOARD_HTML: &str = include_str!("dashboard.html");
+
+struct DashboardState {
+    shared: Arc<SharedState>,
+    events: EventBus,
+    tftp_port: u16,
+    http_port: u16,
+    token: Option<String>,
+}
+
+/// URL prefix of the dashboard. The leading underscore keeps it out of the
+/// way of served files, which take precedence for every other path.
+pub const PREFIX: &str = "/_dashboard";
+
+/// Build the dashboard router, to be merged into the HTTP server.
+///
+/// `GET /_dashboard/` serves the page; `GET /_dashboard/events` is a
+/// server-sent event stream
+/// that starts with a `snapshot` event and then mirrors every
+/// `ServerEvent`. When `token` is set both routes require it, either as
+/// `Authorization: Bearer <token>` or as `?token=` (browsers cannot set
+/// headers on an `EventSource`).
+pub fn router(
+    shared: Arc<SharedState>,
+    events: EventBus,
+    tftp_port: u16,
+    http_port: u16,
+    token: Option<String>,
+) -> Router {
+    let state = Arc::new(DashboardState {
+        shared,
+        events,
+        tftp_port,
+        http_port,
+        token,
+    });
+    Router::new()
+        .route(PREFIX, get(add_trailing_slash))
+        .route(&format!("{PREFIX}/"), get(page))
+        .route(&format!("{PREFIX}/events"), get(event_stream))
+        .route_layer(middleware::from_fn_with_state(
+            Arc::clone(&state),
+            require_token,
+        ))
+        .with_state(state)
+}
+
+#[derive(Debug, Deserialize)]
+struct TokenQuery {
+    token: Option<String>,
+}
+
+async fn require_token(
+    State(dash): State<Arc<DashboardState>>,
+    Query(query): Query<TokenQuery>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let Some(expected) = dash.token.as_deref() else {
+        return next.run(request).await;
+    };
+    let bearer = request
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "))
+        .map(str::trim);
+    let authorized = bearer
+        .or(query.token.as_deref())
+        .is_some_and(|t| constant_time_eq(t.as_bytes(), expected.as_bytes()));
+    if !authorized {
+        return (
+            StatusCode::UNAUTHORIZED,
+            [(header::WWW_AUTHENTICATE, "Bearer")],
+            "Unauthorized",
+        )
+            .into_response();
+    }
+    next.run(request).await
+}
+
+/// The page loads `events` relative to its own URL, so it must be served
+/// from the directory form. Keeps the query so `?token=` survives.
+async fn add_trailing_slash(RawQuery(query): RawQuery) -> Redirect {
+    match query {
+        Some(q) => Redirect::permanent(&format!("{PREFIX}/?{q}")),
+        None => Redirect::permanent(&format!("{PREFIX}/")),
+    }
+}
+
+async fn page() -> Html<&'static str> {
+    Html(DASHBOARD_HTML)
+}
+
+// ---------------------------------------------------------------------------
+// Event stream
+// ---------------------------------------------------------------------------
+
+/// Everything the page needs to render from scratch. Sent on connect and
+/// again whenever the subscriber lagged and missed events.
+#[derive(Debug, Serialize)]
+struct Snapshot {
+    tftp_port: u16,
+    http_port: u16,
+    interfaces: Vec<String>,
+    active: Vec<TransferView>,
+    recent: Vec<TransferRecord>,
+    logs: Vec<LogEntry>,
+}
+
+/// JSON payload of a live event. The SSE event name carries the kind.
+#[derive(Debug, Serialize)]
+#[serde(untagged)]
+enum LivePayload {
+    Log {
+        message: String,
+    },
+    Started(TransferView),
+    Progress {
+        id: u64,
+        transferred: u64,
+        total_bytes: u64,
+    },
+    Finished {
+        id: u64,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        error: Option<String>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        checksums: Option<Checksums>,
+    },
+}
+
+fn live_event(ev: &ServerEvent) -> (&'static str, LivePayload) {
+    match ev {
+        ServerEvent::Log(message) => (
+            "log",
+            LivePayload::Log {
+                message: message.clone(),
+            },
+        ),
+        ServerEvent::TransferStarted(info) => ("started", LivePayload::Started(info.into())),
+        ServerEvent::TransferProgress {
+            id,
+            transferred,
+            total_bytes,
+        } => (
+            "progress",
+            LivePayload::Progress {
+                id: *id,
+                transferred: *transferred,
+                total_bytes: *total_bytes,
+            },
+        ),
+        ServerEvent::TransferComplete { id, checksums } => (
+            "complete",
+            LivePayload::Finished {
+                id: *id,
+                error: None,
+                checksums: checksums.clone(),
+            },
+        ),
+        ServerEvent::TransferFailed { id, error } => (
+            "failed",
+            LivePayload::Finished {
+                id: *id,
+                error: Some(error.clone()),
+                checksums: None,
+            },
+        ),
+        ServerEvent::PxeBoot(boot) => (
+            "log",
+            LivePayload::Log {
+                message: boot.to_string(),
+            },
+        ),
+    }
+}
+
+impl DashboardState {
+    fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            tftp_port: self.tftp_port,
+            http_port: self.http_port,
+            interfaces: get_interface_ips(),
+            active: self
+                .shared
+                .active_transfers()
+                .iter()
+                .map(TransferView::from)
+                .collect(),
+            recent: self.shared.recent_transfers(SNAPSHOT_HISTORY),
+            logs: self.shared.log_tail(SNAPSHOT_LOG_LINES),
+        }
+    }
+}
+
+fn json_event(name: &str, payload: &impl Serialize) -> Event {
+    Event::default()
+        .event(name)
+        .json_data(payload)
+        .unwrap_or_else(|_| Event::default().event(name))
+}
+
+async fn event_stream(
+    State(dash): State<Arc<DashboardState>>,
+) -> Sse<impl Stream<Item = Result<Event, Infallible>>> {
+    // Subscribe before taking the snapshot so nothing falls in between.
+    // An event applied in that window may show up twice; the page treats
+    // events idempotently.
+    let rx = dash.events.subscribe();
+    let first = json_event("snapshot", &dash.snapshot());
+
+    let live = stream::unfold((dash, rx), |(dash, mut rx)| async move {
+        let event = match rx.recv().await {
+            Ok(ev) => {
+                let (name, payload) = live_event(&ev);
+                json_event(name, &payload)
+            }
+            Err(RecvError::Lagged(_)) => json_event("snapshot", &dash.snapshot()),
+            Err(RecvError::Closed) => return None,
+        };
+        Some((Ok(event), (dash, rx)))
+    });
+
+    Sse::new(stream::once(async { Ok(first) }).chain(live))
+        .keep_alive(KeepAlive::new().interval(Duration::from_secs(15)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::{TransferInfo, TransferKind};
+    use std::path::PathBuf;
+    use std::time::Instant;
+
+    #[test]
+    fn live_events_serialize_with_kind_names() {
+        let info = TransferInfo {
+            id: 3,
+            peer: "10.0.0.5:4000".parse().unwrap(),
+            filename: "boot.img".into(),
+            path: PathBuf::from("/srv/tftp").join("boot.img"),
+            kind: TransferKind::Upload,
+            total_bytes: 0,
+            transferred: 0,
+            started: Instant::now(),
+            size_known: false,
+        };
+        let (name, payload) = live_event(&ServerEvent::TransferStarted(info));
+        assert_eq!(name, "started");
+        let json = serde_json::to_value(&payload).unwrap();
+        assert_eq!(json["filename"], "boot.img");
+        assert_eq!(json["kind"], "upload");
+
+        let (name, payload) = live_event(&ServerEvent::TransferFailed {
+            id: 3,
+            error: "timeout".into(),
+        });
+        assert_eq!(name, "failed");
+        let json = serde_json::to_value(&payload).unwrap();
+        assert_eq!(json, serde_json::json!({"id": 3, "error": "timeout"}));
+
+        let (_, payload) = live_event(&ServerEvent::TransferComplete {
+            id: 3,
+            checksums: None,
+        });
+        assert_eq!(
+            serde_json::to_value(&payload).unwrap(),
+            serde_json::json!({"id": 3})
+        );
+    }
+}
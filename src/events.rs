@@ -0,0 +1,268 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use tokio::sync::broadcast;
+
+use crate::server::{ServerEvent, TransferKind};
+use crate::state::SharedState;
+
+/// Events buffered per subscriber. A subscriber that falls further behind
+/// than this gets `RecvError::Lagged` and loses the oldest events instead
+/// of growing memory without bound.
+const BUS_CAPACITY: usize = 4096;
+
+/// Minimum spacing of progress events for one transfer. Intermediate
+/// updates are folded into the next one that goes out.
+pub const PROGRESS_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Latest progress of a transfer and when it was last published.
+struct ProgressSlot {
+    last_sent: Instant,
+    pending: Option<(u64, u64)>,
+}
+
+struct Inner {
+    tx: broadcast::Sender<ServerEvent>,
+    state: Arc<SharedState>,
+    progress: Mutex<HashMap<u64, ProgressSlot>>,
+}
+
+/// Fan-out bus carrying `ServerEvent`s from the servers to every consumer
+/// (TUI, log file, dashboard, hooks).
+///
+/// Every event is folded into `SharedState` before it is broadcast, so a
+/// subscriber that lagged can resynchronise from the state and a new
+/// subscriber can take a snapshot without missing anything. Progress
+/// events are coalesced per transfer to at most one per
+/// `PROGRESS_INTERVAL`; the latest value is always published before the
+/// transfer's completion or failure.
+#[derive(Clone)]
+pub struct EventBus {
+    inner: Arc<Inner>,
+}
+
+impl EventBus {
+    pub fn new(state: Arc<SharedState>) -> Self {
+        Self {
+            inner: Arc::new(Inner {
+                tx: broadcast::channel(BUS_CAPACITY).0,
+                state,
+                progress: Mutex::new(HashMap::new()),
+            }),
+        }
+    }
+
+    /// Receive every event published from now on.
+    pub fn subscribe(&self) -> broadcast::Receiver<ServerEvent> {
+        self.inner.tx.subscribe()
+    }
+
+    /// Publish an event. Never blocks; events nobody is subscribed to are
+    /// dropped after updating the shared state.
+    pub fn send(&self, ev: ServerEvent) {
+        match ev {
+            ServerEvent::TransferProgress {
+                id,
+                transferred,
+                total_bytes,
+            } => {
+                let mut progress = self.inner.progress.lock().unwrap();
+                match progress.get_mut(&id) {
+                    Some(slot) if slot.last_sent.elapsed() < PROGRESS_INTERVAL => {
+                        slot.pending = Some((transferred, total_bytes));
+                    }
+                    _ => {
+                        progress.insert(
+                            id,
+                            ProgressSlot {
+                                last_sent: Instant::now(),
+                                pending: None,
+                            },
+                        );
+                        self.publish(ev);
+                    }
+                }
+            }
+            ServerEvent::TransferComplete { id, .. } | ServerEvent::TransferFailed { id, .. } => {
+                let slot = self.inner.progress.lock().unwrap().remove(&id);
+                if let Some((transferred, total_bytes)) = slot.and_then(|s| s.pending) {
+                    self.publish(ServerEvent::TransferProgress {
+                        id,
+                        transferred,
+                        total_bytes,
+                    });
+                }
+                self.publish(ev);
+            }
+            _ => self.publish(ev),
+        }
+    }
+
+    /// Publish progress that has been held back for at least
+    /// `PROGRESS_INTERVAL`, so a transfer that stalls right after a
+    /// suppressed update still shows its latest position.
+    pub fn flush_progress(&self) {
+        let mut progress = self.inner.progress.lock().unwrap();
+        for (&id, slot) in progress.iter_mut() {
+            if slot.last_sent.elapsed() >= PROGRESS_INTERVAL
+                && let Some((transferred, total_bytes)) = slot.pending.take()
+            {
+                slot.last_sent = Instant::now();
+                self.publish(ServerEvent::TransferProgress {
+                    id,
+                    transferred,
+                    total_bytes,
+                });
+            }
+        }
+    }
+
+    fn publish(&self, ev: ServerEvent) {
+        self.inner.state.apply(&ev);
+        // Err only means there are no subscribers right now.
+        let _ = self.inner.tx.send(ev);
+    }
+}
+
+/// Human-readable log line for an event, as shown in the TUI and written
+/// to the log file. Progress updates have none.
+pub fn describe(ev: &ServerEvent) -> Option<String> {
+    match ev {
+        ServerEvent::Log(msg) => Some(msg.clone()),
+        ServerEvent::TransferStarted(info) => Some(format!(
+            "Transfer #{} started: {} {} ({})",
+            info.id,
+            match info.kind {
+                TransferKind::Download => "DL",
+                TransferKind::Upload => "UL",
+            },
+            info.filename,
+            info.peer,
+        )),
+        ServerEvent::TransferProgress { .. } => None,
+        ServerEvent::TransferComplete { id, .. } => Some(format!("Transfer #{id} complete")),
+        ServerEvent::TransferFailed { id, error } => {
+            Some(format!("Transfer #{id} failed: {error}"))
+        }
+        ServerEvent::PxeBoot(boot) => Some(boot.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::{ServerConfig, TransferInfo};
+    use std::path::PathBuf;
+    use tokio::sync::broadcast::error::TryRecvError;
+
+    fn bus() -> (EventBus, Arc<SharedState>) {
+        let state = Arc::new(SharedState::new(ServerConfig::default()));
+        (EventBus::new(Arc::clone(&state)), state)
+    }
+
+    fn started(id: u64) -> ServerEvent {
+        ServerEvent::TransferStarted(TransferInfo {
+            id,
+            peer: "127.0.0.1:1234".parse().unwrap(),
+            filename: format!("file{id}.bin"),
+            path: PathBuf::from("/srv/tftp").join(format!("file{id}.bin")),
+            kind: TransferKind::Download,
+            total_bytes: 1000,
+            transferred: 0,
+            started: Instant::now(),
+            size_known: true,
+        })
+    }
+
+    fn progress(id: u64, transferred: u64) -> ServerEvent {
+        ServerEvent::TransferProgress {
+            id,
+            transferred,
+            total_bytes: 1000,
+        }
+    }
+
+    #[test]
+    fn every_subscriber_sees_every_event() {
+        let (bus, _) = bus();
+        let mut a = bus.subscribe();
+        let mut b = bus.subscribe();
+        bus.send(ServerEvent::Log("hello".into()));
+        for rx in [&mut a, &mut b] {
+            assert!(matches!(rx.try_recv(), Ok(ServerEvent::Log(m)) if m == "hello"));
+            assert!(matches!(rx.try_recv(), Err(TryRecvError::Empty)));
+        }
+    }
+
+    #[test]
+    fn progress_is_coalesced_and_flushed_before_completion() {
+        let (bus, state) = bus();
+        let mut rx = bus.subscribe();
+        bus.send(started(1));
+        for n in 1..=64 {
+            bus.send(progress(1, n * 10));
+        }
+        bus.send(ServerEvent::TransferComplete {
+            id: 1,
+            checksums: None,
+        });
+
+        let received: Vec<_> = std::iter::from_fn(|| rx.try_recv().ok()).collect();
+        // Started, the first progress, the last held-back progress, Complete.
+        assert_eq!(received.len(), 4);
+        assert!(matches!(
+            received[2],
+            ServerEvent::TransferProgress {
+                transferred: 640,
+                ..
+            }
+        ));
+        assert!(matches!(
+            received[3],
+            ServerEvent::TransferComplete { id: 1, .. }
+        ));
+        assert_eq!(state.recent_transfers(1)[0].bytes, 640);
+    }
+
+    #[test]
+    fn flush_publishes_stale_progress() {
+        let (bus, state) = bus();
+        bus.send(started(2));
+        bus.send(progress(2, 10));
+        bus.send(progress(2, 20));
+        assert_eq!(state.active_transfers()[0].transferred, 10);
+
+        std::thread::sleep(PROGRESS_INTERVAL);
+        bus.flush_progress();
+        assert_eq!(state.active_transfers()[0].transferred, 20);
+    }
+
+    #[test]
+    fn slow_subscriber_lags_instead_of_buffering() {
+        let (bus, _) = bus();
+        let mut rx = bus.subscribe();
+        for i in 0..BUS_CAPACITY + 10 {
+            bus.send(ServerEvent::Log(format!("line {i}")));
+        }
+        assert!(matches!(rx.try_recv(), Err(TryRecvError::Lagged(10))));
+        assert!(matches!(rx.try_recv(), Ok(ServerEvent::Log(m)) if m == "line 10"));
+    }
+
+    #[test]
+    fn describe_formats_transfer_events() {
+        assert_eq!(
+            describe(&started(5)).unwrap(),
+            "Transfer #5 started: DL file5.bin (127.0.0.1:1234)"
+        );
+        assert!(describe(&progress(5, 1)).is_none());
+        assert_eq!(
+            describe(&ServerEvent::TransferFailed {
+                id: 5,
+                error: "timeout".into()
+            })
+            .unwrap(),
+            "Transfer #5 failed: timeout"
+        );
+    }
+}
@@ -0,0 +1,582 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::path::PathBuf;
+use std::process::Stdio;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+
+use serde::Serialize;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::process::Command;
+use tokio::sync::Semaphore;
+use tokio::sync::broadcast::{self, error::RecvError};
+
+use crate::checksum::Checksums;
+use crate::events::EventBus;
+use crate::server::{ServerEvent, TransferInfo, TransferKind};
+
+/// Hook runs allowed to wait for a free slot, per slot, before new ones
+/// are dropped.
+const QUEUE_PER_SLOT: usize = 16;
+
+/// Largest webhook response head we read to find the status line.
+const MAX_RESPONSE_HEAD: usize = 1024;
+
+/// Transfer lifecycle point a hook fires on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum HookEvent {
+    Start,
+    Complete,
+    Fail,
+}
+
+impl HookEvent {
+    fn as_str(self) -> &'static str {
+        match self {
+            HookEvent::Start => "start",
+            HookEvent::Complete => "complete",
+            HookEvent::Fail => "fail",
+        }
+    }
+}
+
+/// What a hook does when it fires.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HookAction {
+    /// Run through the platform shell with transfer details in `TFTP_*`
+    /// environment variables.
+    Command(String),
+    /// POST the transfer details as JSON.
+    Webhook(WebhookUrl),
+}
+
+/// A parsed `http://` URL. TLS is not supported; use a command hook with
+/// `curl` for `https://` endpoints.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WebhookUrl {
+    host: String,
+    port: u16,
+    path: String,
+}
+
+impl FromStr for WebhookUrl {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let rest = s
+            .strip_prefix("http://")
+            .ok_or_else(|| format!("unsupported URL {s:?}: only http:// is supported"))?;
+        let (authority, path) = match rest.find('/') {
+            Some(i) => (&rest[..i], &rest[i..]),
+            None => (rest, "/"),
+        };
+        let (host, port) = if let Some(v6) = authority.strip_prefix('[') {
+            let (host, after) = v6
+                .split_once(']')
+                .ok_or_else(|| format!("invalid host in {s:?}"))?;
+            (host, after.strip_prefix(':'))
+        } else {
+            match authority.rsplit_once(':') {
+                Some((host, port)) => (host, Some(port)),
+                None => (authority, None),
+            }
+        };
+        if host.is_empty() {
+            return Err(format!("missing host in {s:?}"));
+        }
+        let port = match port {
+            Some(p) => p.parse().map_err(|_| format!("invalid port in {s:?}"))?,
+            None => 80,
+        };
+        Ok(Self {
+            host: host.to_string(),
+            port,
+            path: path.to_string(),
+        })
+    }
+}
+
+impl WebhookUrl {
+    fn host_header(&self) -> String {
+        let host = if self.host.contains(':') {
+            format!("[{}]", self.host)
+        } else {
+            self.host.clone()
+        };
+        if self.port == 80 {
+            host
+        } else {
+            format!("{host}:{}", self.port)
+        }
+    }
+}
+
+/// A hook from the command line: `EVENT=ACTION`.
+///
+/// `EVENT` is `start`, `complete` or `fail`, optionally prefixed with
+/// `upload-` or `download-` to fire for one direction only. `ACTION` is
+/// an `http://` URL to POST JSON to, or any other string to run as a
+/// shell command.
+#[derive(Debug, Clone)]
+pub struct HookSpec {
+    event: HookEvent,
+    kind: Option<TransferKind>,
+    action: HookAction,
+    raw: String,
+}
+
+impl FromStr for HookSpec {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (trigger, action) = s
+            .split_once('=')
+            .ok_or_else(|| format!("expected EVENT=ACTION, got {s:?}"))?;
+        let (kind, event) = match trigger.split_once('-') {
+            Some(("upload", event)) => (Some(TransferKind::Upload), event),
+            Some(("download", event)) => (Some(TransferKind::Download), event),
+            _ => (None, trigger),
+        };
+        let event = match event {
+            "start" => HookEvent::Start,
+            "complete" => HookEvent::Complete,
+            "fail" => HookEvent::Fail,
+            _ => {
+                return Err(format!(
+                    "unknown hook event {trigger:?} (expected [upload-|download-]start|complete|fail)"
+                ));
+            }
+        };
+        let action = action.trim();
+        if action.is_empty() {
+            return Err(format!("empty hook action in {s:?}"));
+        }
+        let action = if action.contains("://") {
+            HookAction::Webhook(action.parse()?)
+        } else {
+            HookAction::Command(action.to_string())
+        };
+        Ok(Self {
+            event,
+            kind,
+            action,
+            raw: s.to_string(),
+        })
+    }
+}
+
+impl fmt::Display for HookSpec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.raw)
+    }
+}
+
+impl HookSpec {
+    fn matches(&self, event: HookEvent, kind: TransferKind) -> bool {
+        self.event == event && self.kind.is_none_or(|k| k == kind)
+    }
+}
+
+/// Limits applied to every hook run.
+#[derive(Debug, Clone, Copy)]
+pub struct HookOptions {
+    pub timeout: Duration,
+    /// Hooks allowed to run at the same time.
+    pub concurrency: usize,
+}
+
+/// Transfer details handed to a hook, as JSON or `TFTP_*` variables.
+#[derive(Debug, Serialize)]
+struct HookPayload {
+    event: HookEvent,
+    id: u64,
+    kind: TransferKind,
+    filename: String,
+    path: PathBuf,
+    peer: String,
+    bytes: u64,
+    duration_ms: u64,
+    #[serde(flatten, skip_serializing_if = "Option::is_none")]
+    checksums: Option<Checksums>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+impl HookPayload {
+    fn new(
+        event: HookEvent,
+        info: &TransferInfo,
+        error: Option<String>,
+        checksums: Option<Checksums>,
+    ) -> Self {
+        Self {
+            event,
+            id: info.id,
+            kind: info.kind,
+            filename: info.filename.clone(),
+            path: info.path.clone(),
+            peer: info.peer.to_string(),
+            bytes: info.transferred,
+            duration_ms: info.started.elapsed().as_millis() as u64,
+            checksums,
+            error,
+        }
+    }
+
+    fn env(&self) -> Vec<(&'static str, String)> {
+        let mut env = vec![
+            ("TFTP_EVENT", self.event.as_str().to_string()),
+            ("TFTP_ID", self.id.to_string()),
+            (
+                "TFTP_KIND",
+                match self.kind {
+                    TransferKind::Download => "download",
+                    TransferKind::Upload => "upload",
+                }
+                .to_string(),
+            ),
+            ("TFTP_FILENAME", self.filename.clone()),
+            ("TFTP_PATH", self.path.display().to_string()),
+            ("TFTP_PEER", self.peer.clone()),
+            ("TFTP_BYTES", self.bytes.to_string()),
+            ("TFTP_DURATION_MS", self.duration_ms.to_string()),
+        ];
+        if let Some(ref sums) = self.checksums {
+            env.push(("TFTP_SHA256", sums.sha256.clone()));
+            if let Some(ref crc32) = sums.crc32 {
+                env.push(("TFTP_CRC32", crc32.clone()));
+            }
+            if let Some(ref md5) = sums.md5 {
+                env.push(("TFTP_MD5", md5.clone()));
+            }
+        }
+        if let Some(ref error) = self.error {
+            env.push(("TFTP_ERROR", error.clone()));
+        }
+        env
+    }
+}
+
+/// Runs hooks as a subscriber of the event bus.
+struct HookRunner {
+    hooks: Arc<Vec<HookSpec>>,
+    options: HookOptions,
+    bus: EventBus,
+    slots: Arc<Semaphore>,
+    queued: Arc<AtomicUsize>,
+}
+
+/// Fire `hooks` for the transfer events arriving on `rx` until the bus
+/// closes. `rx` should be subscribed before any server starts.
+pub async fn run(
+    hooks: Vec<HookSpec>,
+    options: HookOptions,
+    bus: EventBus,
+    mut rx: broadcast::Receiver<ServerEvent>,
+) {
+    let concurrency = options.concurrency.max(1);
+    let runner = HookRunner {
+        hooks: Arc::new(hooks),
+        options,
+        bus,
+        slots: Arc::new(Semaphore::new(concurrency)),
+        queued: Arc::new(AtomicUsize::new(0)),
+    };
+    // Transfers in flight, so completion hooks know the file and byte
+    // count.
+    let mut active: HashMap<u64, TransferInfo> = HashMap::new();
+
+    loop {
+        let ev = match rx.recv().await {
+            Ok(ev) => ev,
+            Err(RecvError::Lagged(n)) => {
+                runner.bus.send(ServerEvent::Log(format!(
+                    "Hooks fell behind, skipped {n} events"
+                )));
+                continue;
+            }
+            Err(RecvError::Closed) => return,
+        };
+        match ev {
+            ServerEvent::TransferStarted(info) => {
+                runner.fire(HookPayload::new(HookEvent::Start, &info, None, None));
+                active.insert(info.id, info);
+            }
+            ServerEvent::TransferProgress {
+                id, transferred, ..
+            } => {
+                if let Some(info) = active.get_mut(&id) {
+                    info.transferred = transferred;
+                }
+            }
+            ServerEvent::TransferComplete { id, checksums } => {
+                if let Some(info) = active.remove(&id) {
+                    runner.fire(HookPayload::new(
+                        HookEvent::Complete,
+                        &info,
+                        None,
+                        checksums,
+                    ));
+                }
+            }
+            ServerEvent::TransferFailed { id, error } => {
+                if let Some(info) = active.remove(&id) {
+                    runner.fire(HookPayload::new(HookEvent::Fail, &info, Some(error), None));
+                }
+            }
+            ServerEvent::Log(_) | ServerEvent::PxeBoot(_) => {}
+        }
+    }
+}
+
+impl HookRunner {
+    fn fire(&self, payload: HookPayload) {
+        let matching: Vec<&HookSpec> = self
+            .hooks
+            .iter()
+            .filter(|h| h.matches(payload.event, payload.kind))
+            .collect();
+        if matching.is_empty() {
+            return;
+        }
+
+        let payload = Arc::new(payload);
+        let max_queued = self.options.concurrency.max(1) * QUEUE_PER_SLOT;
+        for hook in matching {
+            if self.queued.fetch_add(1, Ordering::SeqCst) >= max_queued {
+                self.queued.fetch_sub(1, Ordering::SeqCst);
+                self.bus.send(ServerEvent::Log(format!(
+                    "Hook queue full, skipped `{hook}` for transfer #{}",
+                    payload.id
+                )));
+                continue;
+            }
+            let hook = hook.clone();
+            let timeout = self.options.timeout;
+            let bus = self.bus.clone();
+            let slots = Arc::clone(&self.slots);
+            let queued = Arc::clone(&self.queued);
+            let payload = Arc::clone(&payload);
+            tokio::spawn(async move {
+                let _permit = slots.acquire_owned().await;
+                queued.fetch_sub(1, Ordering::SeqCst);
+                let result =
+                    match tokio::time::timeout(timeout, execute(&hook.action, &payload)).await {
+                        Ok(result) => result,
+                        Err(_) => Err(format!("timed out after {}s", timeout.as_secs_f64())),
+                    };
+                if let Err(e) = result {
+                    bus.send(ServerEvent::Log(format!(
+                        "Hook `{hook}` for transfer #{} failed: {e}",
+                        payload.id
+                    )));
+                }
+            });
+        }
+    }
+}
+
+async fn execute(action: &HookAction, payload: &HookPayload) -> Result<(), String> {
+    match action {
+        HookAction::Command(cmd) => run_command(cmd, payload).await,
+        HookAction::Webhook(url) => {
+            let body = serde_json::to_vec(payload).map_err(|e| e.to_string())?;
+            post_json(url, &body).await
+        }
+    }
+}
+
+fn shell_command(cmd: &str) -> Command {
+    #[cfg(windows)]
+    {
+        let mut command = Command::new("cmd");
+        command.arg("/C").arg(cmd);
+        command
+    }
+    #[cfg(not(windows))]
+    {
+        let mut command = Command::new("sh");
+        command.arg("-c").arg(cmd);
+        command
+    }
+}
+
+async fn run_command(cmd: &str, payload: &HookPayload) -> Result<(), String> {
+    let output = shell_command(cmd)
+        .envs(payload.env())
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        // Killed if the timeout drops the future.
+        .kill_on_drop(true)
+        .output()
+        .await
+        .map_err(|e| format!("cannot run command: {e}"))?;
+    if output.status.success() {
+        return Ok(());
+    }
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    match stderr.lines().find(|l| !l.trim().is_empty()) {
+        Some(line) => Err(format!("{} ({})", output.status, line.trim())),
+        None => Err(output.status.to_string()),
+    }
+}
+
+/// Minimal HTTP/1.1 POST; succeeds on any 2xx status.
+async fn post_json(url: &WebhookUrl, body: &[u8]) -> Result<(), String> {
+    let mut stream = TcpStream::connect((url.host.as_str(), url.port))
+        .await
+        .map_err(|e| format!("cannot connect to {}: {e}", url.host_header()))?;
+    let head = format!(
+        "POST {} HTTP/1.1\r\nHost: {}\r\nUser-Agent: tftp-rs/{}\r\n\
+         Content-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        url.path,
+        url.host_header(),
+        env!("CARGO_PKG_VERSION"),
+        body.len(),
+    );
+    stream
+        .write_all(head.as_bytes())
+        .await
+        .map_err(|e| e.to_string())?;
+    stream.write_all(body).await.map_err(|e| e.to_string())?;
+
+    let mut response = Vec::with_capacity(256);
+    let mut buf = [0u8; 256];
+    while !response.windows(2).any(|w| w == b"\r\n") && response.len() < MAX_RESPONSE_HEAD {
+        let n = stream.read(&mut buf).await.map_err(|e| e.to_string())?;
+        if n == 0 {
+            break;
+        }
+        response.extend_from_slice(&buf[..n]);
+    }
+    let status_line = String::from_utf8_lossy(&response);
+    let status_line = status_line.lines().next().unwrap_or_default();
+    match status_line.split_whitespace().nth(1) {
+        Some(code) if code.starts_with('2') => Ok(()),
+        Some(_) => Err(format!("webhook answered {status_line:?}")),
+        None => Err("no HTTP response from webhook".into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Instant;
+    use tokio::net::TcpListener;
+
+    fn info() -> TransferInfo {
+        TransferInfo {
+            id: 9,
+            peer: "10.1.2.3:40000".parse().unwrap(),
+            filename: "switch1.cfg".into(),
+            path: PathBuf::from("/srv/tftp/switch1.cfg"),
+            kind: TransferKind::Upload,
+            total_bytes: 0,
+            transferred: 1234,
+            started: Instant::now(),
+            size_known: false,
+        }
+    }
+
+    #[test]
+    fn parses_hook_specs() {
+        let hook: HookSpec = "upload-complete=git commit -am \"config\"".parse().unwrap();
+        assert_eq!(hook.event, HookEvent::Complete);
+        assert_eq!(hook.kind, Some(TransferKind::Upload));
+        assert_eq!(
+            hook.action,
+            HookAction::Command("git commit -am \"config\"".into())
+        );
+        assert!(hook.matches(HookEvent::Complete, TransferKind::Upload));
+        assert!(!hook.matches(HookEvent::Complete, TransferKind::Download));
+
+        let hook: HookSpec = "fail=http://[::1]:8080/hooks/tftp".parse().unwrap();
+        assert_eq!(hook.kind, None);
+        let HookAction::Webhook(url) = hook.action else {
+            panic!("expected webhook");
+        };
+        assert_eq!(url.host, "::1");
+        assert_eq!(url.port, 8080);
+        assert_eq!(url.path, "/hooks/tftp");
+        assert_eq!(url.host_header(), "[::1]:8080");
+
+        assert!("complete".parse::<HookSpec>().is_err());
+        assert!("finish=true".parse::<HookSpec>().is_err());
+        assert!("start=".parse::<HookSpec>().is_err());
+        assert!("start=https://example.com/".parse::<HookSpec>().is_err());
+    }
+
+    #[test]
+    fn payload_env_includes_optional_fields() {
+        let payload = HookPayload::new(
+            HookEvent::Complete,
+            &info(),
+            None,
+            Some(Checksums {
+                sha256: "ab".repeat(32),
+                crc32: Some("0d4a1185".into()),
+                md5: None,
+            }),
+        );
+        let env: HashMap<_, _> = payload.env().into_iter().collect();
+        assert_eq!(env["TFTP_EVENT"], "complete");
+        assert_eq!(env["TFTP_KIND"], "upload");
+        assert_eq!(env["TFTP_BYTES"], "1234");
+        assert_eq!(env["TFTP_PEER"], "10.1.2.3:40000");
+        assert_eq!(env["TFTP_SHA256"].len(), 64);
+        assert_eq!(env["TFTP_CRC32"], "0d4a1185");
+        assert!(!env.contains_key("TFTP_MD5"));
+        assert!(!env.contains_key("TFTP_ERROR"));
+    }
+
+    #[tokio::test]
+    async fn webhook_posts_json() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url: WebhookUrl = format!("http://{}/hook", listener.local_addr().unwrap())
+            .parse()
+            .unwrap();
+        let server = tokio::spawn(async move {
+            let (mut sock, _) = listener.accept().await.unwrap();
+            let mut request = Vec::new();
+            let mut buf = [0u8; 1024];
+            while !String::from_utf8_lossy(&request).ends_with('}') {
+                let n = sock.read(&mut buf).await.unwrap();
+                request.extend_from_slice(&buf[..n]);
+            }
+            sock.write_all(b"HTTP/1.1 204 No Content\r\n\r\n")
+                .await
+                .unwrap();
+            String::from_utf8(request).unwrap()
+        });
+
+        let payload = HookPayload::new(HookEvent::Fail, &info(), Some("timeout".into()), None);
+        let body = serde_json::to_vec(&payload).unwrap();
+        post_json(&url, &body).await.unwrap();
+
+        let request = server.await.unwrap();
+        assert!(request.starts_with("POST /hook HTTP/1.1\r\n"));
+        let json: serde_json::Value =
+            serde_json::from_str(request.split("\r\n\r\n").nth(1).unwrap()).unwrap();
+        assert_eq!(json["event"], "fail");
+        assert_eq!(json["filename"], "switch1.cfg");
+        assert_eq!(json["error"], "timeout");
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn command_gets_env_and_reports_failure() {
+        let payload = HookPayload::new(HookEvent::Start, &info(), None, None);
+        run_command("test \"$TFTP_FILENAME\" = switch1.cfg", &payload)
+            .await
+            .unwrap();
+        let err = run_command("echo boom >&2; exit 3", &payload)
+            .await
+            .unwrap_err();
+        assert!(err.contains("boom"), "{err}");
+    }
+}
@@ -1,42 +1,114 @@
+use std::cell::Cell;
+use std::collections::HashMap;
 use std::net::SocketAddr;
 use std::path::{Path, PathBuf};
 use std::sync::Arc;
 
+use std::io::SeekFrom;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::{Instant, SystemTime};
+
 use anyhow::Result;
 use axum::Router;
-use axum::body::Body;
-use axum::extract::{ConnectInfo, Request, State};
-use axum::http::StatusCode;
-use axum::response::{Html, IntoResponse, Response};
-use tokio::sync::{mpsc, watch};
+use axum::body::{Body, Bytes};
+use axum::extract::{ConnectInfo, DefaultBodyLimit, FromRequest, Multipart, Query, Request, State};
+use axum::http::{HeaderMap, HeaderValue, Method, StatusCode, header};
+use axum::response::{Html, IntoResponse, Json, Redirect, Response};
+use futures_util::{Stream, StreamExt, stream};
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+use tokio::sync::watch;
 use tokio_util::io::ReaderStream;
+use tokio_util::sync::CancellationToken;
 
-use crate::server::{ServerEvent, sanitize_path};
+use crate::checksum::{self, Checksummer, Checksums};
+use crate::events::EventBus;
+use crate::limits::{Limit, UploadBudget};
+use crate::server::{
+    ServerEvent, TransferInfo, TransferKind, log_installed, next_transfer_id, part_path_for,
+    sanitize_path,
+};
+use crate::state::SharedState;
+use crate::{api, dashboard, versions};
 
 struct HttpState {
     dir: PathBuf,
-    tx: mpsc::UnboundedSender<ServerEvent>,
+    tx: EventBus,
+    shared: Arc<SharedState>,
+}
+
+/// Settings for the HTTP server that are fixed for its lifetime.
+pub struct HttpOptions {
+    pub port: u16,
+    /// TFTP port, shown on the dashboard.
+    pub tftp_port: u16,
+    /// Enables the control API under `/api` when set.
+    pub api_token: Option<String>,
+    /// Serve the live dashboard under `/_dashboard/`.
+    pub dashboard: bool,
+    /// Whether the ProxyDHCP responder runs, which some boot profiles
+    /// need; the API warns about those when it does not.
+    pub proxy_dhcp: bool,
 }
 
+/// Run the HTTP file server, plus the control API and dashboard when
+/// enabled in `options`.
 pub async fn run(
-    port: u16,
+    options: HttpOptions,
     dir: PathBuf,
-    tx: mpsc::UnboundedSender<ServerEvent>,
+    tx: EventBus,
+    shared: Arc<SharedState>,
     mut shutdown: watch::Receiver<bool>,
 ) -> Result<()> {
+    let HttpOptions {
+        port,
+        tftp_port,
+        api_token,
+        dashboard,
+        proxy_dhcp,
+    } = options;
     let state = Arc::new(HttpState {
         dir,
         tx: tx.clone(),
+        shared: Arc::clone(&shared),
     });
 
-    let app = Router::new()
+    let mut app = Router::new()
         .fallback(serve_path)
-        .with_state(state)
-        .into_make_service_with_connect_info::<SocketAddr>();
+        // Uploads are streamed to disk, so the default 2 MB body cap only
+        // gets in the way of firmware images.
+        .layer(DefaultBodyLimit::disable())
+        .with_state(state);
+    if dashboard {
+        app = app.merge(dashboard::router(
+            Arc::clone(&shared),
+            tx.clone(),
+            tftp_port,
+            port,
+            api_token.clone(),
+        ));
+    }
+    let api_enabled = api_token.is_some();
+    if let Some(token) = api_token {
+        app = app.nest("/api", api::router(shared, tx.clone(), token, proxy_dhcp));
+    }
+    let app = app.into_make_service_with_connect_info::<SocketAddr>();
 
     let addr = format!("0.0.0.0:{port}");
     let listener = tokio::net::TcpListener::bind(&addr).await?;
-    tx.send(ServerEvent::Log(format!("HTTP server listening on {addr}")))?;
+    tx.send(ServerEvent::Log(format!("HTTP server listening on {addr}")));
+    if api_enabled {
+        tx.send(ServerEvent::Log(format!(
+            "Control API enabled at {addr}/api"
+        )));
+    }
+    if dashboard {
+        tx.send(ServerEvent::Log(format!(
+            "Dashboard available at http://{addr}{}/",
+            dashboard::PREFIX
+        )));
+    }
 
     axum::serve(listener, app)
         .with_graceful_shutdown(async move {
@@ -54,21 +126,50 @@ async fn serve_path(
 ) -> Response {
     let uri_path = percent_decode(request.uri().path());
     let stripped = uri_path.trim_start_matches('/');
+    let method = request.method().clone();
+
+    state.tx.send(ServerEvent::Log(format!(
+        "{addr}: HTTP {method} /{stripped}"
+    )));
+
+    match method {
+        Method::GET | Method::HEAD => {}
+        Method::PUT => return handle_put(&state, addr, stripped, request).await,
+        Method::POST => {
+            // Redirect to the path as sent; the decoded one may hold `#`,
+            // `?` or bytes that are not valid in a Location header.
+            let raw_path = request.uri().path().to_string();
+            return handle_post(&state, addr, &uri_path, &raw_path, request).await;
+        }
+        _ => {
+            return (
+                StatusCode::METHOD_NOT_ALLOWED,
+                [(header::ALLOW, "GET, HEAD, PUT, POST")],
+                "Method not allowed",
+            )
+                .into_response();
+        }
+    }
+
+    let query = match Query::<ListingQuery>::try_from_uri(request.uri()) {
+        Ok(Query(q)) => q,
+        Err(e) => return e.into_response(),
+    };
+    // `Accept` only switches directory pages to JSON; files are served as
+    // themselves unless the URL asks for `?format=json`.
+    let is_dir =
+        || stripped.is_empty() || sanitize_path(&state.dir, stripped).is_ok_and(|p| p.is_dir());
+    if query.format.as_deref() == Some("json") || (prefers_json(request.headers()) && is_dir()) {
+        return json_metadata(&state.dir, &state.shared, stripped, &query).await;
+    }
 
-    let _ = state
-        .tx
-        .send(ServerEvent::Log(format!("{addr}: HTTP GET /{stripped}")));
+    let listing = HtmlListing {
+        allow_upload: state.shared.config().enable_write,
+    };
 
     // Root directory listing.
     if stripped.is_empty() {
-        return match render_directory(&state.dir, "/") {
-            Ok(html) => Html(html).into_response(),
-            Err(_) => (
-                StatusCode::INTERNAL_SERVER_ERROR,
-                "Failed to read directory",
-            )
-                .into_response(),
-        };
+        return directory_page(&state, state.dir.clone(), "/".into(), listing).await;
     }
 
     // Try to resolve the path using the same sanitization as TFTP.
@@ -80,60 +181,1086 @@ async fn serve_path(
     };
 
     if resolved.is_dir() {
-        match render_directory(&resolved, &uri_path) {
-            Ok(html) => Html(html).into_response(),
-            Err(_) => (
-                StatusCode::INTERNAL_SERVER_ERROR,
-                "Failed to read directory",
+        directory_page(&state, resolved, uri_path.clone(), listing).await
+    } else if resolved.is_file() {
+        let is_head = method == Method::HEAD;
+        serve_file(
+            &state,
+            addr,
+            &resolved,
+            stripped,
+            request.headers(),
+            is_head,
+        )
+        .await
+    } else {
+        (StatusCode::NOT_FOUND, "Not found").into_response()
+    }
+}
+
+// ---------------------------------------------------------------------------
+// Downloads
+// ---------------------------------------------------------------------------
+
+/// Upper bound on the number of ranges honoured in one request. Anything
+/// beyond this is answered with the full file rather than a huge multipart
+/// body.
+const MAX_RANGES: usize = 32;
+
+/// Boxed body stream so full, single-range and multi-range responses can
+/// share the same progress tracking wrapper.
+type BodyStream = Pin<Box<dyn Stream<Item = std::io::Result<Bytes>> + Send>>;
+
+/// Stream a file, honouring conditional requests (RFC 7232) and
+/// `Range` / `If-Range` (RFC 7233). HEAD gets the same headers without
+/// opening the file.
+async fn serve_file(
+    state: &HttpState,
+    addr: SocketAddr,
+    path: &Path,
+    rel_path: &str,
+    headers: &HeaderMap,
+    is_head: bool,
+) -> Response {
+    let metadata = match tokio::fs::metadata(path).await {
+        Ok(m) => m,
+        Err(_) => {
+            return (StatusCode::INTERNAL_SERVER_ERROR, "Failed to read file").into_response();
+        }
+    };
+    let file_size = metadata.len();
+    let validators = Validators::new(&metadata);
+    let ct = content_type_for(path);
+    let filename = path
+        .file_name()
+        .map(|n| n.to_string_lossy().to_string())
+        .unwrap_or_default();
+
+    let mut response_headers = HeaderMap::new();
+    response_headers.insert(header::ACCEPT_RANGES, HeaderValue::from_static("bytes"));
+    validators.insert_into(&mut response_headers);
+
+    if not_modified(headers, &validators) {
+        return (StatusCode::NOT_MODIFIED, response_headers).into_response();
+    }
+
+    let mut ranges = None;
+    if let Some(range) = headers.get(header::RANGE).and_then(|v| v.to_str().ok())
+        && if_range_matches(headers, &validators)
+    {
+        match parse_range(range, file_size) {
+            RangeSpec::Full => {}
+            RangeSpec::Partial(r) => ranges = Some(r),
+            RangeSpec::Unsatisfiable => {
+                if let Ok(v) = HeaderValue::from_str(&format!("bytes */{file_size}")) {
+                    response_headers.insert(header::CONTENT_RANGE, v);
+                }
+                return (
+                    StatusCode::RANGE_NOT_SATISFIABLE,
+                    response_headers,
+                    "Range not satisfiable",
+                )
+                    .into_response();
+            }
+        }
+    }
+
+    if let Ok(v) = HeaderValue::from_str(&format!("inline; filename=\"{filename}\"")) {
+        response_headers.insert(header::CONTENT_DISPOSITION, v);
+    }
+
+    let mut layout = None;
+    let (status, content_length, label) = match ranges.as_deref() {
+        None => {
+            response_headers.insert(header::CONTENT_TYPE, HeaderValue::from_static(ct));
+            (StatusCode::OK, file_size, rel_path.to_string())
+        }
+        Some([range]) => {
+            response_headers.insert(header::CONTENT_TYPE, HeaderValue::from_static(ct));
+            if let Ok(v) = HeaderValue::from_str(&range.content_range(file_size)) {
+                response_headers.insert(header::CONTENT_RANGE, v);
+            }
+            let label = format!("{rel_path} ({})", range.content_range(file_size));
+            (StatusCode::PARTIAL_CONTENT, range.len(), label)
+        }
+        Some(ranges) => {
+            let boundary = multipart_boundary();
+            if let Ok(v) =
+                HeaderValue::from_str(&format!("multipart/byteranges; boundary={boundary}"))
+            {
+                response_headers.insert(header::CONTENT_TYPE, v);
+            }
+            let l = MultipartLayout::new(ranges, ct, &boundary, file_size);
+            let length = l.length;
+            layout = Some(l);
+            let label = format!("{rel_path} ({} ranges)", ranges.len());
+            (StatusCode::PARTIAL_CONTENT, length, label)
+        }
+    };
+    response_headers.insert(header::CONTENT_LENGTH, HeaderValue::from(content_length));
+
+    if is_head {
+        return (status, response_headers, Body::empty()).into_response();
+    }
+
+    // Stream the file instead of loading it all into memory.
+    let stream = match (ranges.as_deref(), layout) {
+        (_, Some(layout)) => layout.into_stream(path).await,
+        (Some([range]), None) => open_segment(path, *range).await,
+        _ => {
+            open_segment(
+                path,
+                ByteRange {
+                    start: 0,
+                    end: file_size,
+                },
             )
-                .into_response(),
+            .await
         }
-    } else if resolved.is_file() {
-        let ct = content_type_for(&resolved);
-        let filename = resolved
-            .file_name()
-            .map(|n| n.to_string_lossy().to_string())
-            .unwrap_or_default();
-
-        // Get file size for Content-Length header.
-        let file_size = match tokio::fs::metadata(&resolved).await {
-            Ok(m) => m.len(),
-            Err(_) => {
-                return (StatusCode::INTERNAL_SERVER_ERROR, "Failed to read file").into_response();
+    };
+    let stream = match stream {
+        Ok(s) => s,
+        Err(_) => {
+            return (StatusCode::INTERNAL_SERVER_ERROR, "Failed to read file").into_response();
+        }
+    };
+
+    let mut tracked = TrackedBody::start(state, addr, label, path, content_length, stream);
+    if ranges.is_none() {
+        tracked = tracked.with_checksums(Checksummer::new(&state.shared.config()));
+    }
+    let body = Body::from_stream(tracked);
+    (status, response_headers, body).into_response()
+}
+
+/// Open `path` and return a stream over the bytes covered by `range`.
+async fn open_segment(path: &Path, range: ByteRange) -> std::io::Result<BodyStream> {
+    let mut file = tokio::fs::File::open(path).await?;
+    if range.start > 0 {
+        file.seek(SeekFrom::Start(range.start)).await?;
+    }
+    Ok(Box::pin(ReaderStream::new(file.take(range.len()))))
+}
+
+/// Part headers and exact length of a `multipart/byteranges` body, computed
+/// up front so `Content-Length` is known even for HEAD.
+struct MultipartLayout {
+    parts: Vec<(Bytes, ByteRange)>,
+    trailer: Bytes,
+    length: u64,
+}
+
+impl MultipartLayout {
+    fn new(ranges: &[ByteRange], content_type: &str, boundary: &str, file_size: u64) -> Self {
+        let mut parts = Vec::with_capacity(ranges.len());
+        let mut length = 0u64;
+        for range in ranges {
+            let part_header = format!(
+                "\r\n--{boundary}\r\nContent-Type: {content_type}\r\nContent-Range: {}\r\n\r\n",
+                range.content_range(file_size)
+            );
+            length += part_header.len() as u64 + range.len();
+            parts.push((Bytes::from(part_header), *range));
+        }
+        let trailer = format!("\r\n--{boundary}--\r\n");
+        length += trailer.len() as u64;
+        Self {
+            parts,
+            trailer: Bytes::from(trailer),
+            length,
+        }
+    }
+
+    async fn into_stream(self, path: &Path) -> std::io::Result<BodyStream> {
+        let mut streams: Vec<BodyStream> = Vec::with_capacity(self.parts.len() * 2 + 1);
+        for (part_header, range) in self.parts {
+            streams.push(Box::pin(stream::once(std::future::ready(Ok(part_header)))));
+            streams.push(open_segment(path, range).await?);
+        }
+        streams.push(Box::pin(stream::once(std::future::ready(Ok(self.trailer)))));
+        Ok(Box::pin(stream::iter(streams).flatten()))
+    }
+}
+
+fn multipart_boundary() -> String {
+    let nanos = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos();
+    format!(
+        "tftp-rs-{:x}",
+        nanos as u64 ^ next_transfer_id().rotate_left(32)
+    )
+}
+
+/// Wraps a download body so that it shows up as a transfer in the TUI,
+/// with progress updates as chunks are written to the socket.
+struct TrackedBody {
+    inner: BodyStream,
+    id: u64,
+    tx: EventBus,
+    cancel: CancellationToken,
+    transferred: u64,
+    total_bytes: u64,
+    finished: bool,
+    /// Set when the body is the whole file, so the digest describes it.
+    hasher: Option<Checksummer>,
+}
+
+impl TrackedBody {
+    fn start(
+        state: &HttpState,
+        addr: SocketAddr,
+        filename: String,
+        path: &Path,
+        total_bytes: u64,
+        inner: BodyStream,
+    ) -> Self {
+        let id = next_transfer_id();
+        let cancel = state.shared.register_transfer(id);
+        state.tx.send(ServerEvent::TransferStarted(TransferInfo {
+            id,
+            peer: addr,
+            filename,
+            path: path.to_path_buf(),
+            kind: TransferKind::Download,
+            total_bytes,
+            transferred: 0,
+            started: Instant::now(),
+            size_known: true,
+        }));
+        Self {
+            inner,
+            id,
+            tx: state.tx.clone(),
+            cancel,
+            transferred: 0,
+            total_bytes,
+            finished: false,
+            hasher: None,
+        }
+    }
+
+    fn with_checksums(mut self, hasher: Checksummer) -> Self {
+        self.hasher = Some(hasher);
+        self
+    }
+
+    fn complete_event(&mut self) -> ServerEvent {
+        ServerEvent::TransferComplete {
+            id: self.id,
+            checksums: self.hasher.take().map(Checksummer::finish),
+        }
+    }
+}
+
+impl Stream for TrackedBody {
+    type Item = std::io::Result<Bytes>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        if this.finished {
+            return Poll::Ready(None);
+        }
+        if this.cancel.is_cancelled() {
+            this.finished = true;
+            this.tx.send(ServerEvent::TransferFailed {
+                id: this.id,
+                error: "cancelled by operator".into(),
+            });
+            return Poll::Ready(Some(Err(std::io::Error::other("cancelled by operator"))));
+        }
+        let poll = this.inner.as_mut().poll_next(cx);
+        match &poll {
+            Poll::Ready(Some(Ok(chunk))) => {
+                this.transferred += chunk.len() as u64;
+                if let Some(ref mut hasher) = this.hasher {
+                    hasher.update(chunk);
+                }
+                this.tx.send(ServerEvent::TransferProgress {
+                    id: this.id,
+                    transferred: this.transferred,
+                    total_bytes: this.total_bytes,
+                });
+                // hyper stops polling once Content-Length bytes are out, so
+                // the final `None` may never be seen.
+                if this.transferred >= this.total_bytes {
+                    this.finished = true;
+                    let ev = this.complete_event();
+                    this.tx.send(ev);
+                }
+            }
+            Poll::Ready(Some(Err(e))) => {
+                this.finished = true;
+                this.tx.send(ServerEvent::TransferFailed {
+                    id: this.id,
+                    error: e.to_string(),
+                });
+            }
+            Poll::Ready(None) => {
+                this.finished = true;
+                let ev = this.complete_event();
+                this.tx.send(ev);
+            }
+            Poll::Pending => {}
+        }
+        poll
+    }
+}
+
+impl Drop for TrackedBody {
+    fn drop(&mut self) {
+        if self.finished {
+            return;
+        }
+        // An empty body is complete without ever being polled.
+        let ev = if self.transferred >= self.total_bytes {
+            self.complete_event()
+        } else {
+            ServerEvent::TransferFailed {
+                id: self.id,
+                error: "client disconnected".into(),
             }
         };
+        self.tx.send(ev);
+    }
+}
+
+/// A satisfiable byte range: `start` inclusive, `end` exclusive.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct ByteRange {
+    start: u64,
+    end: u64,
+}
+
+impl ByteRange {
+    fn len(&self) -> u64 {
+        self.end - self.start
+    }
+
+    fn content_range(&self, file_size: u64) -> String {
+        format!("bytes {}-{}/{file_size}", self.start, self.end - 1)
+    }
+}
+
+/// Outcome of interpreting a `Range` header against the current file size.
+#[derive(Debug, PartialEq, Eq)]
+enum RangeSpec {
+    /// No usable range: send the whole file with 200.
+    Full,
+    /// One or more satisfiable ranges, sorted and coalesced.
+    Partial(Vec<ByteRange>),
+    /// Syntactically valid, but no range overlaps the file (416).
+    Unsatisfiable,
+}
+
+/// Parse a `Range: bytes=...` header. Malformed headers are ignored as
+/// RFC 7233 requires, which makes the caller fall back to a full response.
+fn parse_range(value: &str, file_size: u64) -> RangeSpec {
+    let Some(spec) = value.trim().strip_prefix("bytes=") else {
+        return RangeSpec::Full;
+    };
+
+    let mut ranges = Vec::new();
+    let mut specs = 0usize;
+    for part in spec.split(',').map(str::trim).filter(|p| !p.is_empty()) {
+        specs += 1;
+        if specs > MAX_RANGES {
+            return RangeSpec::Full;
+        }
+        let Some((first, last)) = part.split_once('-') else {
+            return RangeSpec::Full;
+        };
+        let (first, last) = (first.trim(), last.trim());
+        let range = if first.is_empty() {
+            // Suffix range: the final N bytes.
+            let Ok(suffix) = last.parse::<u64>() else {
+                return RangeSpec::Full;
+            };
+            ByteRange {
+                start: file_size.saturating_sub(suffix),
+                end: file_size,
+            }
+        } else {
+            let Ok(start) = first.parse::<u64>() else {
+                return RangeSpec::Full;
+            };
+            let end = if last.is_empty() {
+                file_size
+            } else {
+                match last.parse::<u64>() {
+                    Ok(last) if last >= start => last.saturating_add(1).min(file_size),
+                    _ => return RangeSpec::Full,
+                }
+            };
+            ByteRange { start, end }
+        };
+        if range.start < range.end {
+            ranges.push(range);
+        }
+    }
+
+    if specs == 0 {
+        return RangeSpec::Full;
+    }
+    if ranges.is_empty() {
+        return RangeSpec::Unsatisfiable;
+    }
+
+    // Coalesce overlapping or adjacent ranges.
+    ranges.sort_by_key(|r| r.start);
+    let mut merged: Vec<ByteRange> = Vec::with_capacity(ranges.len());
+    for range in ranges {
+        match merged.last_mut() {
+            Some(prev) if range.start <= prev.end => prev.end = prev.end.max(range.end),
+            _ => merged.push(range),
+        }
+    }
+    RangeSpec::Partial(merged)
+}
+
+/// Cache validators for a file. The entity tag is derived from the size and
+/// modification time, so it changes whenever the file is replaced.
+struct Validators {
+    etag: String,
+    last_modified: Option<SystemTime>,
+}
+
+impl Validators {
+    fn new(metadata: &std::fs::Metadata) -> Self {
+        let last_modified = metadata.modified().ok();
+        let etag = match last_modified.and_then(|t| t.duration_since(SystemTime::UNIX_EPOCH).ok()) {
+            Some(mtime) => format!("\"{:x}-{:x}\"", metadata.len(), mtime.as_nanos()),
+            None => format!("\"{:x}\"", metadata.len()),
+        };
+        Self {
+            etag,
+            last_modified,
+        }
+    }
+
+    fn insert_into(&self, headers: &mut HeaderMap) {
+        if let Ok(v) = HeaderValue::from_str(&self.etag) {
+            headers.insert(header::ETAG, v);
+        }
+        if let Some(mtime) = self.last_modified
+            && let Ok(v) = HeaderValue::from_str(&httpdate::fmt_http_date(mtime))
+        {
+            headers.insert(header::LAST_MODIFIED, v);
+        }
+    }
+}
+
+/// Evaluate `If-None-Match` and `If-Modified-Since`. Returns true when the
+/// client's cached copy is still current and a 304 should be sent.
+/// `If-None-Match` takes precedence, as RFC 7232 section 6 requires.
+fn not_modified(headers: &HeaderMap, validators: &Validators) -> bool {
+    if let Some(value) = headers
+        .get(header::IF_NONE_MATCH)
+        .and_then(|v| v.to_str().ok())
+    {
+        return value
+            .split(',')
+            .map(str::trim)
+            .any(|tag| tag == "*" || weak_etag_eq(tag, &validators.etag));
+    }
+    if let Some(value) = headers
+        .get(header::IF_MODIFIED_SINCE)
+        .and_then(|v| v.to_str().ok())
+        && let Ok(since) = httpdate::parse_http_date(value.trim())
+        && let Some(mtime) = validators.last_modified
+    {
+        return unix_secs(mtime) <= unix_secs(since);
+    }
+    false
+}
+
+/// Weak comparison: entity tags match regardless of the `W/` prefix.
+fn weak_etag_eq(a: &str, b: &str) -> bool {
+    a.trim_start_matches("W/") == b.trim_start_matches("W/")
+}
+
+/// `If-Range` only lets `Range` apply when the validator still matches the
+/// file; otherwise the full file is sent. Only strong validators count.
+fn if_range_matches(headers: &HeaderMap, validators: &Validators) -> bool {
+    let Some(value) = headers.get(header::IF_RANGE).and_then(|v| v.to_str().ok()) else {
+        return true;
+    };
+    let value = value.trim();
+    if value.starts_with("W/") {
+        return false;
+    }
+    if value.starts_with('"') {
+        return value == validators.etag;
+    }
+    match (httpdate::parse_http_date(value), validators.last_modified) {
+        (Ok(date), Some(mtime)) => unix_secs(date) == unix_secs(mtime),
+        _ => false,
+    }
+}
+
+fn unix_secs(t: SystemTime) -> u64 {
+    t.duration_since(SystemTime::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+// ---------------------------------------------------------------------------
+// Uploads
+// ---------------------------------------------------------------------------
+
+/// `PUT /path/to/file` — the request body is the file content.
+async fn handle_put(
+    state: &HttpState,
+    addr: SocketAddr,
+    rel_path: &str,
+    request: Request,
+) -> Response {
+    let expected_size = request
+        .headers()
+        .get(header::CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<u64>().ok());
+    let body = request.into_body().into_data_stream();
+
+    match receive_upload(state, addr, rel_path, expected_size, body).await {
+        Ok(true) => StatusCode::NO_CONTENT.into_response(),
+        Ok(false) => StatusCode::CREATED.into_response(),
+        Err(e) => e.into_response(),
+    }
+}
+
+/// `POST /some/dir/` with `multipart/form-data` — used by the upload form on
+/// the directory listing page. Every file field is stored in that directory.
+async fn handle_post(
+    state: &HttpState,
+    addr: SocketAddr,
+    uri_path: &str,
+    raw_path: &str,
+    request: Request,
+) -> Response {
+    let dir_rel = uri_path.trim_matches('/');
+    if !dir_rel.is_empty() {
+        match sanitize_path(&state.dir, dir_rel) {
+            Ok(p) if p.is_dir() => {}
+            _ => return (StatusCode::NOT_FOUND, "Not found").into_response(),
+        }
+    }
+
+    let mut multipart = match Multipart::from_request(request, &()).await {
+        Ok(m) => m,
+        Err(e) => return e.into_response(),
+    };
+
+    let mut stored: Vec<String> = Vec::new();
+    loop {
+        let (field, name) = match multipart.next_field().await {
+            Ok(Some(f)) => match f.file_name().and_then(upload_file_name) {
+                Some(name) => (f, name),
+                None => continue,
+            },
+            Ok(None) => break,
+            Err(e) => return partial_upload(e.status(), e.body_text(), &stored),
+        };
+        let rel_path = if dir_rel.is_empty() {
+            name
+        } else {
+            format!("{dir_rel}/{name}")
+        };
+        if let Err((status, error)) = receive_upload(state, addr, &rel_path, None, field).await {
+            let error = format!("\"{rel_path}\": {error}");
+            return partial_upload(status, error, &stored);
+        }
+        stored.push(rel_path);
+    }
+
+    if stored.is_empty() {
+        return (StatusCode::BAD_REQUEST, "No file selected").into_response();
+    }
+    Redirect::to(raw_path).into_response()
+}
+
+/// A failed multi-file upload, listing the files of the same request that
+/// were stored before it failed.
+fn partial_upload(status: StatusCode, error: String, stored: &[String]) -> Response {
+    let mut body = format!("Upload failed: {error}\n");
+    if stored.is_empty() {
+        body.push_str("No files were stored.\n");
+    } else {
+        body.push_str("Stored before the failure:\n");
+        for path in stored {
+            body.push_str(&format!("  {path}\n"));
+        }
+    }
+    (status, body).into_response()
+}
+
+/// Stream an upload into the served directory using the same rules as a
+/// TFTP WRQ: path sanitization, `enable_write`, `allow_overwrite`, size
+/// limits and quotas, versioning and a `.part` file that is renamed into place only once the
+/// body is complete.
+///
+/// Returns whether an existing file was replaced.
+async fn receive_upload<S, E>(
+    state: &HttpState,
+    addr: SocketAddr,
+    rel_path: &str,
+    expected_size: Option<u64>,
+    body: S,
+) -> Result<bool, (StatusCode, String)>
+where
+    S: Stream<Item = Result<Bytes, E>>,
+    E: std::fmt::Display,
+{
+    let config = state.shared.config();
+    if !config.enable_write {
+        return Err((StatusCode::FORBIDDEN, "Write access denied".into()));
+    }
+
+    let path = sanitize_path(&state.dir, rel_path)
+        .map_err(|e| (StatusCode::BAD_REQUEST, format!("Invalid path: {e}")))?;
+    if path.is_dir() {
+        return Err((StatusCode::CONFLICT, "Path is a directory".into()));
+    }
+    let existed = path.exists();
+    if existed && !config.accepts_existing() {
+        return Err((StatusCode::CONFLICT, "File already exists".into()));
+    }
+
+    let mut budget = {
+        let shared = Arc::clone(&state.shared);
+        let (config, dir, path) = (config.clone(), state.dir.clone(), path.clone());
+        tokio::task::spawn_blocking(move || {
+            UploadBudget::compute(&shared, &config, &dir, &path, addr.ip())
+        })
+        .await
+        .unwrap_or_default()
+    };
+    if let Err(limit) = budget.admit(expected_size) {
+        state.tx.send(ServerEvent::Log(format!(
+            "{addr}: HTTP upload \"{rel_path}\" refused: {limit}"
+        )));
+        return Err((limit_status(&limit), limit.to_string()));
+    }
+
+    if let Some(parent) = path.parent() {
+        tokio::fs::create_dir_all(parent).await.map_err(|e| {
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("Cannot create directory: {e}"),
+            )
+        })?;
+    }
+
+    let part_path = part_path_for(&path);
+    let open_result = if config.accepts_existing() {
+        tokio::fs::File::create(&part_path).await
+    } else {
+        tokio::fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&part_path)
+            .await
+    };
+    let mut file = open_result.map_err(|e| {
+        if e.kind() == std::io::ErrorKind::AlreadyExists {
+            (
+                StatusCode::CONFLICT,
+                "Upload already in progress".to_string(),
+            )
+        } else {
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("Cannot create file: {e}"),
+            )
+        }
+    })?;
+
+    let id = next_transfer_id();
+    let cancel = state.shared.register_transfer(id);
+    let total_bytes = expected_size.unwrap_or(0);
+    state.tx.send(ServerEvent::TransferStarted(TransferInfo {
+        id,
+        peer: addr,
+        filename: rel_path.to_string(),
+        path: path.clone(),
+        kind: TransferKind::Upload,
+        total_bytes,
+        transferred: 0,
+        started: Instant::now(),
+        size_known: total_bytes > 0,
+    }));
+
+    let result: Result<(u64, Checksums), (StatusCode, String)> = async {
+        // The client's fault (a dropped connection or a broken body) is a
+        // 400 and an operator cancel a 503; only local I/O failures are 500s.
+        let failed = |e: String| (StatusCode::INTERNAL_SERVER_ERROR, e);
+        let mut body = std::pin::pin!(body);
+        let mut transferred: u64 = 0;
+        let mut hasher = Checksummer::new(&config);
+        loop {
+            let chunk = tokio::select! {
+                chunk = body.next() => chunk,
+                _ = cancel.cancelled() => return Err((
+                    StatusCode::SERVICE_UNAVAILABLE,
+                    "cancelled by operator".to_string(),
+                )),
+            };
+            let Some(chunk) = chunk else { break };
+            let chunk =
+                chunk.map_err(|e| (StatusCode::BAD_REQUEST, format!("upload interrupted: {e}")))?;
+            if let Err(limit) = budget.check(transferred + chunk.len() as u64) {
+                return Err((limit_status(&limit), format!("upload aborted: {limit}")));
+            }
+            file.write_all(&chunk)
+                .await
+                .map_err(|e| failed(format!("write failed: {e}")))?;
+            hasher.update(&chunk);
+            transferred += chunk.len() as u64;
+            state.tx.send(ServerEvent::TransferProgress {
+                id,
+                transferred,
+                total_bytes: total_bytes.max(transferred),
+            });
+        }
+        file.flush()
+            .await
+            .map_err(|e| failed(format!("write failed: {e}")))?;
+        drop(file);
+        let installed = {
+            let (part_path, path) = (part_path.clone(), path.clone());
+            let config = config.clone();
+            tokio::task::spawn_blocking(move || versions::install(&part_path, &path, &config))
+                .await
+                .map_err(|e| failed(format!("upload task failed: {e}")))?
+        }
+        .map_err(|e| failed(format!("failed to rename {}: {e}", part_path.display())))?;
+        budget.complete();
+        log_installed(&state.tx, addr, rel_path, &installed);
+        Ok((transferred, hasher.finish()))
+    }
+    .await;
 
-        // Stream the file instead of loading it all into memory.
-        let file = match tokio::fs::File::open(&resolved).await {
-            Ok(f) => f,
-            Err(_) => {
-                return (StatusCode::INTERNAL_SERVER_ERROR, "Failed to read file").into_response();
+    match result {
+        Ok((transferred, checksums)) => {
+            if config.sha256_sidecar
+                && let Err(e) = checksum::write_sidecar(&path, &checksums.sha256).await
+            {
+                state.tx.send(ServerEvent::Log(format!(
+                    "{addr}: cannot write checksum sidecar for \"{rel_path}\": {e}"
+                )));
             }
+            state.tx.send(ServerEvent::Log(format!(
+                "{addr}: HTTP upload \"{rel_path}\" complete ({transferred} bytes, {checksums})"
+            )));
+            state.tx.send(ServerEvent::TransferComplete {
+                id,
+                checksums: Some(checksums),
+            });
+            Ok(existed)
+        }
+        Err((status, error)) => {
+            let _ = tokio::fs::remove_file(&part_path).await;
+            state.tx.send(ServerEvent::TransferFailed {
+                id,
+                error: error.clone(),
+            });
+            state.tx.send(ServerEvent::Log(format!(
+                "{addr}: HTTP upload \"{rel_path}\" failed: {error}"
+            )));
+            Err((status, error))
+        }
+    }
+}
+
+/// `413 Payload Too Large` for the upload size limit, `507 Insufficient
+/// Storage` for quotas and free space.
+fn limit_status(limit: &Limit) -> StatusCode {
+    match limit {
+        Limit::MaxUploadSize(_) => StatusCode::PAYLOAD_TOO_LARGE,
+        _ => StatusCode::INSUFFICIENT_STORAGE,
+    }
+}
+
+/// Reduce a client-supplied multipart filename to its last path component.
+/// Some browsers send the full local path (e.g. `C:\fw\image.bin`).
+fn upload_file_name(raw: &str) -> Option<String> {
+    let name = raw.rsplit(['/', '\\']).next()?.trim();
+    if name.is_empty() || name == "." || name == ".." {
+        None
+    } else {
+        Some(name.to_string())
+    }
+}
+
+// ---------------------------------------------------------------------------
+// JSON listing / metadata
+// ---------------------------------------------------------------------------
+
+/// Recursion depth used for `?recursive` when no explicit `depth` is given.
+const DEFAULT_LISTING_DEPTH: usize = 8;
+
+/// Hard cap on listing depth, regardless of what the client asks for.
+const MAX_LISTING_DEPTH: usize = 32;
+
+/// Most entries one JSON listing returns, however deep it walks.
+const MAX_LISTING_ENTRIES: usize = 10_000;
+
+/// Query parameters understood by the JSON listing. Flags are enabled by
+/// their presence (`?recursive&versions`) unless set to `0` or `false`.
+#[derive(Debug, Default, Deserialize)]
+struct ListingQuery {
+    format: Option<String>,
+    recursive: Option<String>,
+    depth: Option<usize>,
+    versions: Option<String>,
+}
+
+impl ListingQuery {
+    fn options(&self) -> ListingOptions {
+        let depth = match (self.depth, query_flag(&self.recursive)) {
+            (Some(depth), _) => depth,
+            (None, true) => DEFAULT_LISTING_DEPTH,
+            (None, false) => 0,
         };
-        let stream = ReaderStream::new(file);
-        let body = Body::from_stream(stream);
-
-        (
-            [
-                ("content-type", ct.to_string()),
-                ("content-length", file_size.to_string()),
-                (
-                    "content-disposition",
-                    format!("inline; filename=\"{filename}\""),
-                ),
-            ],
-            body,
+        ListingOptions {
+            depth: depth.min(MAX_LISTING_DEPTH),
+            versions: query_flag(&self.versions),
+        }
+    }
+}
+
+fn query_flag(value: &Option<String>) -> bool {
+    value
+        .as_deref()
+        .is_some_and(|v| !matches!(v, "0" | "false" | "no"))
+}
+
+/// What is left of one request's entry allowance. Running out leaves the
+/// remaining entries out and marks the listing `truncated`.
+struct ListingBudget {
+    entries: Cell<usize>,
+    truncated: Cell<bool>,
+}
+
+impl ListingBudget {
+    fn new(entries: usize) -> Self {
+        Self {
+            entries: Cell::new(entries),
+            truncated: Cell::new(false),
+        }
+    }
+
+    fn take_entry(&self) -> bool {
+        let Some(left) = self.entries.get().checked_sub(1) else {
+            self.truncated.set(true);
+            return false;
+        };
+        self.entries.set(left);
+        true
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct ListingOptions {
+    /// How many directory levels below the requested one to descend into.
+    depth: usize,
+    /// Whether to list the kept versions of regular files.
+    versions: bool,
+}
+
+/// One file or directory in a JSON listing.
+#[derive(Debug, Serialize)]
+struct ListingEntry {
+    name: String,
+    /// Path relative to the served directory, `/`-separated.
+    path: String,
+    #[serde(rename = "type")]
+    kind: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    size: Option<u64>,
+    /// Modification time in seconds since the Unix epoch.
+    mtime: Option<u64>,
+    #[serde(flatten, skip_serializing_if = "Option::is_none")]
+    checksums: Option<Checksums>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    entries: Option<Vec<ListingEntry>>,
+    /// Previous versions of a file, newest first (`?versions`).
+    #[serde(skip_serializing_if = "Option::is_none")]
+    versions: Option<Vec<ListingEntry>>,
+    /// Set on the requested entry when the per-request limits left
+    /// entries or digests out.
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    truncated: bool,
+}
+
+/// Whether `Accept` names `application/json` with a non-zero quality at
+/// least as high as an explicit `text/html`. Wildcards don't count, so
+/// browsers get HTML and `Accept: application/json, */*` gets JSON.
+fn prefers_json(headers: &HeaderMap) -> bool {
+    let Some(accept) = headers.get(header::ACCEPT).and_then(|v| v.to_str().ok()) else {
+        return false;
+    };
+    let (mut json, mut html) = (None, None);
+    for range in accept.split(',') {
+        let mut params = range.split(';');
+        let media_type = params.next().unwrap_or_default().trim();
+        let q = params
+            .filter_map(|p| p.trim().strip_prefix("q="))
+            .find_map(|q| q.trim().parse::<f32>().ok())
+            .unwrap_or(1.0);
+        if media_type.eq_ignore_ascii_case("application/json") {
+            json = Some(q);
+        } else if media_type.eq_ignore_ascii_case("text/html") {
+            html = Some(q);
+        }
+    }
+    json.is_some_and(|json| json > 0.0 && json >= html.unwrap_or(0.0))
+}
+
+/// Describe a file or directory as JSON. Directories include their entries,
+/// recursing as far as the query allows.
+async fn json_metadata(
+    root: &Path,
+    shared: &Arc<SharedState>,
+    rel_path: &str,
+    query: &ListingQuery,
+) -> Response {
+    let rel_path = rel_path.trim_matches('/').to_string();
+    let path = if rel_path.is_empty() {
+        root.to_path_buf()
+    } else {
+        match sanitize_path(root, &rel_path) {
+            Ok(p) if p.exists() => p,
+            _ => return (StatusCode::NOT_FOUND, "Not found").into_response(),
+        }
+    };
+    let options = query.options();
+    let shared = Arc::clone(shared);
+
+    // Walking the tree touches the disk, keep it off the async workers.
+    let listed = tokio::task::spawn_blocking(move || {
+        let budget = ListingBudget::new(MAX_LISTING_ENTRIES);
+        let mut entry = list_entry(&shared, &path, rel_path, options, &budget, 0)?;
+        entry.truncated = budget.truncated.get();
+        std::io::Result::Ok(entry)
+    })
+    .await;
+    match listed {
+        Ok(Ok(entry)) => Json(entry).into_response(),
+        _ => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "Failed to read directory",
         )
-            .into_response()
+            .into_response(),
+    }
+}
+
+fn list_entry(
+    shared: &SharedState,
+    path: &Path,
+    rel_path: String,
+    options: ListingOptions,
+    budget: &ListingBudget,
+    depth: usize,
+) -> std::io::Result<ListingEntry> {
+    let metadata = std::fs::symlink_metadata(path)?;
+    let file_type = metadata.file_type();
+    let kind = if file_type.is_dir() {
+        "dir"
+    } else if file_type.is_file() {
+        "file"
+    } else if file_type.is_symlink() {
+        "symlink"
     } else {
-        (StatusCode::NOT_FOUND, "Not found").into_response()
+        "other"
+    };
+
+    let mut entry = ListingEntry {
+        name: rel_path.rsplit('/').next().unwrap_or_default().to_string(),
+        path: rel_path,
+        kind,
+        size: (!file_type.is_dir()).then_some(metadata.len()),
+        mtime: metadata.modified().ok().map(unix_secs),
+        checksums: None,
+        entries: None,
+        versions: None,
+        truncated: false,
+    };
+
+    if file_type.is_file() {
+        entry.checksums = file_checksums(shared, path);
+    }
+
+    if file_type.is_file() && options.versions {
+        let parent = entry.path.rsplit_once('/').map_or("", |(p, _)| p);
+        let kept = versions::list_versions(path)?
+            .into_iter()
+            .take_while(|_| budget.take_entry())
+            .filter_map(|v| {
+                let rel = if parent.is_empty() {
+                    v.name
+                } else {
+                    format!("{parent}/{}", v.name)
+                };
+                let options = ListingOptions {
+                    versions: false,
+                    ..options
+                };
+                list_entry(shared, &v.path, rel, options, budget, depth).ok()
+            })
+            .collect();
+        entry.versions = Some(kept);
+    }
+
+    if file_type.is_dir() && depth <= options.depth {
+        let mut children = Vec::new();
+        for child in sorted_entries(path)? {
+            if !budget.take_entry() {
+                break;
+            }
+            let name = child.file_name().to_string_lossy().to_string();
+            let child_rel = if entry.path.is_empty() {
+                name
+            } else {
+                format!("{}/{name}", entry.path)
+            };
+            if let Ok(e) = list_entry(shared, &child.path(), child_rel, options, budget, depth + 1)
+            {
+                children.push(e);
+            }
+        }
+        entry.entries = Some(children);
     }
+
+    Ok(entry)
 }
 
-fn render_directory(dir: &Path, display_path: &str) -> std::io::Result<String> {
-    let mut entries: Vec<_> = std::fs::read_dir(dir)?.filter_map(|e| e.ok()).collect();
+/// Digests of `path` for the listings: from a completed transfer or a
+/// fresh `.sha256` sidecar. Listings never hash files themselves, so a
+/// file that has neither is shown without a digest. Sidecars read here
+/// are cached.
+fn file_checksums(shared: &SharedState, path: &Path) -> Option<Checksums> {
+    if let Some(sums) = shared.cached_checksums(path) {
+        return Some(sums);
+    }
+    let sha256 = checksum::read_sidecar(path)?;
+    let sums = Checksums {
+        sha256,
+        crc32: None,
+        md5: None,
+    };
+    shared.cache_checksums(path, sums.clone());
+    Some(sums)
+}
 
-    // Sort: directories first, then alphabetical.
+// ---------------------------------------------------------------------------
+// Directory listing
+// ---------------------------------------------------------------------------
+
+/// Read a directory, sorted with directories first, then alphabetically.
+fn sorted_entries(dir: &Path) -> std::io::Result<Vec<std::fs::DirEntry>> {
+    let mut entries: Vec<_> = std::fs::read_dir(dir)?.filter_map(|e| e.ok()).collect();
     entries.sort_by(|a, b| {
         let a_dir = a.file_type().map(|ft| ft.is_dir()).unwrap_or(false);
         let b_dir = b.file_type().map(|ft| ft.is_dir()).unwrap_or(false);
@@ -144,6 +1271,61 @@ fn render_directory(dir: &Path, display_path: &str) -> std::io::Result<String> {
                 .cmp(&b.file_name().to_string_lossy().to_lowercase())
         })
     });
+    Ok(entries)
+}
+
+/// Options for the HTML directory listing.
+#[derive(Debug, Clone, Copy)]
+struct HtmlListing {
+    /// Show the upload form.
+    allow_upload: bool,
+}
+
+async fn directory_page(
+    state: &HttpState,
+    dir: PathBuf,
+    display_path: String,
+    listing: HtmlListing,
+) -> Response {
+    let shared = Arc::clone(&state.shared);
+    let rendered = tokio::task::spawn_blocking(move || {
+        render_directory(&shared, &dir, &display_path, listing)
+    })
+    .await;
+    match rendered {
+        Ok(Ok(html)) => Html(html).into_response(),
+        _ => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "Failed to read directory",
+        )
+            .into_response(),
+    }
+}
+
+fn render_directory(
+    shared: &SharedState,
+    dir: &Path,
+    display_path: &str,
+    listing: HtmlListing,
+) -> std::io::Result<String> {
+    let entries = sorted_entries(dir)?;
+    let digests: Vec<Option<String>> = entries
+        .iter()
+        .map(|e| {
+            let is_file = e.file_type().map(|ft| ft.is_file()).unwrap_or(false);
+            is_file
+                .then(|| file_checksums(shared, &e.path()))
+                .flatten()
+                .map(|c| c.sha256)
+        })
+        .collect();
+    let show_digests = digests.iter().any(Option::is_some);
+    let mut version_counts: HashMap<String, usize> = HashMap::new();
+    for entry in &entries {
+        if let Some(base) = versions::version_base(&entry.file_name().to_string_lossy()) {
+            *version_counts.entry(base.to_string()).or_default() += 1;
+        }
+    }
 
     let mut html = String::new();
     html.push_str("<!DOCTYPE html><html><head><meta charset=\"utf-8\">");
@@ -160,13 +1342,25 @@ fn render_directory(dir: &Path, display_path: &str) -> std::io::Result<String> {
     html.push_str("a { text-decoration: none; color: #0366d6; }");
     html.push_str("a:hover { text-decoration: underline; }");
     html.push_str(".size { color: #666; }");
+    html.push_str("form { margin-bottom: 20px; }");
     html.push_str("</style>");
     html.push_str("</head><body>");
     html.push_str("<h1>Index of ");
     html.push_str(&html_escape(display_path));
     html.push_str("</h1>");
 
-    html.push_str("<table><tr><th>Name</th><th>Size</th></tr>");
+    if listing.allow_upload {
+        html.push_str("<form method=\"post\" enctype=\"multipart/form-data\">");
+        html.push_str("<input type=\"file\" name=\"file\" multiple> ");
+        html.push_str("<button type=\"submit\">Upload</button>");
+        html.push_str("</form>");
+    }
+
+    html.push_str("<table><tr><th>Name</th><th>Size</th>");
+    if show_digests {
+        html.push_str("<th>SHA-256</th>");
+    }
+    html.push_str("</tr>");
 
     // Parent directory link.
     if display_path != "/" {
@@ -180,7 +1374,7 @@ fn render_directory(dir: &Path, display_path: &str) -> std::io::Result<String> {
         html.push_str("\">..</a></td><td></td></tr>");
     }
 
-    for entry in &entries {
+    for (entry, digest) in entries.iter().zip(&digests) {
         let name = entry.file_name().to_string_lossy().to_string();
         let is_dir = entry.file_type().map(|ft| ft.is_dir()).unwrap_or(false);
 
@@ -211,9 +1405,19 @@ fn render_directory(dir: &Path, display_path: &str) -> std::io::Result<String> {
         html.push_str(&html_escape(&href));
         html.push_str("\">");
         html.push_str(&html_escape(&display_name));
-        html.push_str("</a></td><td class=\"size\">");
+        html.push_str("</a>");
+        if !is_dir && let Some(count) = version_counts.get(&name) {
+            html.push_str(&format!(" <span class=\"size\">({count} older)</span>"));
+        }
+        html.push_str("</td><td class=\"size\">");
         html.push_str(&size_str);
-        html.push_str("</td></tr>");
+        html.push_str("</td>");
+        if show_digests {
+            html.push_str("<td class=\"size\">");
+            html.push_str(digest.as_deref().unwrap_or(""));
+            html.push_str("</td>");
+        }
+        html.push_str("</tr>");
     }
 
     html.push_str("</table></body></html>");
@@ -282,3 +1486,275 @@ fn human_bytes(b: u64) -> String {
         format!("{b} B")
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::ServerConfig;
+
+    #[test]
+    fn upload_file_name_strips_client_paths() {
+        assert_eq!(upload_file_name("image.bin").as_deref(), Some("image.bin"));
+        assert_eq!(
+            upload_file_name("C:\\fw\\image.bin").as_deref(),
+            Some("image.bin")
+        );
+        assert_eq!(
+            upload_file_name("/home/u/image.bin").as_deref(),
+            Some("image.bin")
+        );
+        assert_eq!(upload_file_name(".."), None);
+        assert_eq!(upload_file_name("dir/"), None);
+    }
+
+    #[test]
+    fn parse_range_single_and_open_ended() {
+        assert_eq!(
+            parse_range("bytes=0-99", 1000),
+            RangeSpec::Partial(vec![ByteRange { start: 0, end: 100 }])
+        );
+        assert_eq!(
+            parse_range("bytes=900-", 1000),
+            RangeSpec::Partial(vec![ByteRange {
+                start: 900,
+                end: 1000
+            }])
+        );
+        assert_eq!(
+            parse_range("bytes=-100", 1000),
+            RangeSpec::Partial(vec![ByteRange {
+                start: 900,
+                end: 1000
+            }])
+        );
+        // Last byte position past EOF is clamped.
+        assert_eq!(
+            parse_range("bytes=500-5000", 1000),
+            RangeSpec::Partial(vec![ByteRange {
+                start: 500,
+                end: 1000
+            }])
+        );
+    }
+
+    #[test]
+    fn parse_range_coalesces_multiple() {
+        assert_eq!(
+            parse_range("bytes=500-599, 0-99, 50-149", 1000),
+            RangeSpec::Partial(vec![
+                ByteRange { start: 0, end: 150 },
+                ByteRange {
+                    start: 500,
+                    end: 600
+                },
+            ])
+        );
+    }
+
+    #[test]
+    fn parse_range_unsatisfiable_and_malformed() {
+        assert_eq!(parse_range("bytes=1000-", 1000), RangeSpec::Unsatisfiable);
+        assert_eq!(parse_range("bytes=-0", 1000), RangeSpec::Unsatisfiable);
+        assert_eq!(parse_range("bytes=0-0", 0), RangeSpec::Unsatisfiable);
+        assert_eq!(parse_range("items=0-1", 1000), RangeSpec::Full);
+        assert_eq!(parse_range("bytes=5-1", 1000), RangeSpec::Full);
+        assert_eq!(parse_range("bytes=abc", 1000), RangeSpec::Full);
+    }
+
+    #[test]
+    fn conditional_requests_use_etag_and_mtime() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("pxelinux.0");
+        std::fs::write(&path, b"boot").unwrap();
+        let validators = Validators::new(&std::fs::metadata(&path).unwrap());
+        let lm = httpdate::fmt_http_date(validators.last_modified.unwrap());
+
+        let mut headers = HeaderMap::new();
+        assert!(!not_modified(&headers, &validators));
+
+        headers.insert(header::IF_MODIFIED_SINCE, lm.parse().unwrap());
+        assert!(not_modified(&headers, &validators));
+
+        // If-None-Match wins over If-Modified-Since.
+        headers.insert(header::IF_NONE_MATCH, "\"other\"".parse().unwrap());
+        assert!(!not_modified(&headers, &validators));
+        let weak = format!("\"other\", W/{}", validators.etag);
+        headers.insert(header::IF_NONE_MATCH, weak.parse().unwrap());
+        assert!(not_modified(&headers, &validators));
+
+        let mut headers = HeaderMap::new();
+        headers.insert(header::IF_RANGE, validators.etag.parse().unwrap());
+        assert!(if_range_matches(&headers, &validators));
+        let weak = format!("W/{}", validators.etag);
+        headers.insert(header::IF_RANGE, weak.parse().unwrap());
+        assert!(!if_range_matches(&headers, &validators));
+    }
+
+    fn test_state(dir: &Path) -> HttpState {
+        let config = ServerConfig {
+            enable_write: true,
+            ..ServerConfig::default()
+        };
+        let shared = Arc::new(SharedState::new(config));
+        HttpState {
+            dir: dir.to_path_buf(),
+            tx: EventBus::new(Arc::clone(&shared)),
+            shared,
+        }
+    }
+
+    #[tokio::test]
+    async fn partial_upload_lists_stored_files() {
+        let stored = ["cfg/a.bin".to_string(), "cfg/b.bin".to_string()];
+        let response = partial_upload(
+            StatusCode::CONFLICT,
+            "\"cfg/c.bin\": exists".into(),
+            &stored,
+        );
+        assert_eq!(response.status(), StatusCode::CONFLICT);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        assert_eq!(
+            std::str::from_utf8(&body).unwrap(),
+            "Upload failed: \"cfg/c.bin\": exists\nStored before the failure:\n  cfg/a.bin\n  cfg/b.bin\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn broken_upload_body_is_a_client_error() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = test_state(dir.path());
+        let addr: SocketAddr = "192.0.2.1:5000".parse().unwrap();
+        let body = futures_util::stream::iter(vec![
+            Ok(Bytes::from_static(b"partial")),
+            Err("connection reset"),
+        ]);
+
+        let (status, error) = receive_upload(&state, addr, "fw.bin", None, body)
+            .await
+            .unwrap_err();
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert!(error.contains("connection reset"), "{error}");
+        assert!(!dir.path().join("fw.bin").exists());
+        assert!(!part_path_for(&dir.path().join("fw.bin")).exists());
+    }
+
+    #[test]
+    fn prefers_json_weighs_quality_values() {
+        let accept = |value: &str| {
+            let mut headers = HeaderMap::new();
+            headers.insert(header::ACCEPT, value.parse().unwrap());
+            prefers_json(&headers)
+        };
+        assert!(!prefers_json(&HeaderMap::new()));
+        assert!(accept("application/json"));
+        assert!(accept("application/json, text/plain, */*"));
+        assert!(!accept("application/json;q=0"));
+        assert!(!accept("text/html, application/json;q=0.9"));
+        assert!(!accept(
+            "text/html,application/xhtml+xml,application/xml;q=0.9,*/*;q=0.8"
+        ));
+        assert!(!accept("*/*"));
+    }
+
+    #[test]
+    fn json_listing_respects_depth() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir_all(dir.path().join("a/b")).unwrap();
+        std::fs::write(dir.path().join("a/b/deep.bin"), b"x").unwrap();
+        std::fs::write(dir.path().join("top.bin"), b"abc").unwrap();
+        let sha256 = "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad";
+        std::fs::write(
+            dir.path().join("top.bin.sha256"),
+            format!("{sha256}  top.bin\n"),
+        )
+        .unwrap();
+
+        let shared = SharedState::new(ServerConfig::default());
+        let flat = ListingOptions {
+            depth: 0,
+            versions: false,
+        };
+        let root = list_entry(&shared, dir.path(), String::new(), flat, &unlimited(), 0).unwrap();
+        let entries = root.entries.unwrap();
+        assert_eq!(entries.len(), 3);
+        assert_eq!(entries[0].path, "a");
+        assert!(entries[0].entries.is_none());
+        assert_eq!(entries[1].size, Some(3));
+        assert_eq!(
+            entries[1].checksums.as_ref().map(|c| c.sha256.as_str()),
+            Some(sha256)
+        );
+        // The sidecar itself has no digest: listings never hash on demand.
+        assert!(entries[2].checksums.is_none());
+        // Read from the sidecar once, then served from the cache.
+        assert_eq!(
+            shared
+                .cached_checksums(&dir.path().join("top.bin"))
+                .map(|c| c.sha256),
+            Some(sha256.to_string())
+        );
+
+        let deep = ListingOptions {
+            depth: 2,
+            versions: false,
+        };
+        let root = list_entry(&shared, dir.path(), String::new(), deep, &unlimited(), 0).unwrap();
+        let a = &root.entries.as_ref().unwrap()[0];
+        let b = &a.entries.as_ref().unwrap()[0];
+        assert_eq!(b.entries.as_ref().unwrap()[0].path, "a/b/deep.bin");
+    }
+
+    fn unlimited() -> ListingBudget {
+        ListingBudget::new(usize::MAX)
+    }
+
+    #[test]
+    fn json_listing_stops_at_budget() {
+        let dir = tempfile::tempdir().unwrap();
+        for name in ["a.bin", "b.bin", "c.bin"] {
+            std::fs::write(dir.path().join(name), b"abcd").unwrap();
+        }
+
+        let shared = SharedState::new(ServerConfig::default());
+        let options = ListingOptions {
+            depth: 0,
+            versions: false,
+        };
+        let budget = ListingBudget::new(2);
+        let root = list_entry(&shared, dir.path(), String::new(), options, &budget, 0).unwrap();
+        let entries = root.entries.unwrap();
+        assert_eq!(entries.len(), 2);
+        assert!(budget.truncated.get());
+    }
+
+    #[test]
+    fn json_listing_includes_versions() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir(dir.path().join("cfg")).unwrap();
+        std::fs::write(dir.path().join("cfg/sw1.cfg"), b"new").unwrap();
+        std::fs::write(dir.path().join("cfg/sw1.cfg.~1~"), b"old").unwrap();
+
+        let shared = SharedState::new(ServerConfig::default());
+        let options = ListingOptions {
+            depth: 0,
+            versions: true,
+        };
+        let path = dir.path().join("cfg/sw1.cfg");
+        let entry = list_entry(
+            &shared,
+            &path,
+            "cfg/sw1.cfg".into(),
+            options,
+            &unlimited(),
+            0,
+        )
+        .unwrap();
+        let kept = entry.versions.unwrap();
+        assert_eq!(kept.len(), 1);
+        assert_eq!(kept[0].path, "cfg/sw1.cfg.~1~");
+        assert_eq!(kept[0].size, Some(3));
+        assert!(kept[0].versions.is_none());
+    }
+}
@@ -0,0 +1,475 @@
+use std::fmt;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+use crate::server::{ServerConfig, part_path_for, sanitize_path};
+use crate::state::SharedState;
+use crate::versions::Versioning;
+
+/// Period over which `client_quota` is enforced.
+pub const CLIENT_QUOTA_WINDOW: Duration = Duration::from_secs(24 * 3600);
+
+/// A cap on the total size of everything under a directory of the served
+/// tree. `dir` is relative to the served directory; empty for the root.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct DirQuota {
+    pub dir: String,
+    pub bytes: u64,
+}
+
+impl FromStr for DirQuota {
+    type Err = String;
+
+    /// Parses `DIR=SIZE`, e.g. `firmware=2G` or `.=500G` for the root.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (dir, size) = s
+            .rsplit_once('=')
+            .ok_or_else(|| format!("expected DIR=SIZE, got {s:?}"))?;
+        let dir = dir.trim().trim_matches('/');
+        if dir.split(['/', '\\']).any(|part| part == "..") {
+            return Err(format!(
+                "quota directory {dir:?} is outside the served directory"
+            ));
+        }
+        Ok(Self {
+            dir: if dir == "." {
+                String::new()
+            } else {
+                dir.to_string()
+            },
+            bytes: parse_size(size)?,
+        })
+    }
+}
+
+impl TryFrom<String> for DirQuota {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl From<DirQuota> for String {
+    fn from(quota: DirQuota) -> Self {
+        quota.to_string()
+    }
+}
+
+impl fmt::Display for DirQuota {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let dir = if self.dir.is_empty() { "." } else { &self.dir };
+        write!(f, "{dir}={}", self.bytes)
+    }
+}
+
+/// Parse a byte size such as `1048576`, `512K`, `100M`, `2G` or `1T`
+/// (binary multiples; a trailing `B` or `iB` is accepted).
+pub fn parse_size(s: &str) -> Result<u64, String> {
+    let s = s.trim();
+    let split = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    let (number, unit) = s.split_at(split);
+    let unit = unit.trim().to_ascii_uppercase();
+    let shift = match unit.trim_end_matches("IB").trim_end_matches('B') {
+        "" => 0,
+        "K" => 10,
+        "M" => 20,
+        "G" => 30,
+        "T" => 40,
+        _ => return Err(format!("unknown size unit in {s:?}, expected K, M, G or T")),
+    };
+    let number: u64 = number
+        .parse()
+        .map_err(|_| format!("invalid size {s:?}, expected e.g. 500M"))?;
+    number
+        .checked_mul(1 << shift)
+        .ok_or_else(|| format!("size {s:?} is too large"))
+}
+
+/// Which limit an upload ran into.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Limit {
+    MaxUploadSize(u64),
+    DirQuota(String),
+    ClientQuota(IpAddr),
+    FreeSpace,
+}
+
+impl fmt::Display for Limit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Limit::MaxUploadSize(max) => write!(f, "upload exceeds the {max}-byte size limit"),
+            Limit::DirQuota(dir) if dir.is_empty() => write!(f, "quota for / exceeded"),
+            Limit::DirQuota(dir) => write!(f, "quota for {dir}/ exceeded"),
+            Limit::ClientQuota(ip) => write!(f, "upload quota for {ip} exceeded"),
+            Limit::FreeSpace => write!(f, "not enough free disk space"),
+        }
+    }
+}
+
+/// Bytes written between two looks at the free space of the filesystem
+/// an upload goes to.
+const FREE_SPACE_RECHECK: u64 = 1 << 20;
+
+/// How many bytes one upload may write before it hits a limit.
+///
+/// Quotas are shared with the uploads running next to it: what they write
+/// (and what they finish) while this one runs comes off its allowance, and
+/// free space is looked at again as the upload goes. Dropping the budget
+/// stops counting the upload; call [`complete`](Self::complete) first if
+/// it succeeded.
+#[derive(Debug, Default)]
+pub struct UploadBudget {
+    max_upload_size: Option<u64>,
+    /// Bytes left under the client quota when the upload started.
+    client_quota: Option<(u64, Limit)>,
+    /// Bytes left under each quota directory when the upload started.
+    dir_quotas: Vec<(u64, Limit)>,
+    free_space: Option<FreeSpace>,
+    reservation: Option<Reservation>,
+}
+
+/// Free space as last looked at.
+#[derive(Debug)]
+struct FreeSpace {
+    dir: PathBuf,
+    min_free_space: u64,
+    /// Bytes that could be written before going under `min_free_space`.
+    left: u64,
+    /// What the upload had written when `left` was measured.
+    at: u64,
+}
+
+impl FreeSpace {
+    fn measure(&mut self, written: u64) {
+        if let Ok(free) = fs2::available_space(&self.dir) {
+            self.left = free.saturating_sub(self.min_free_space);
+            self.at = written;
+        }
+    }
+
+    fn remaining(&mut self, written: u64) -> u64 {
+        let since = written.saturating_sub(self.at);
+        if since >= FREE_SPACE_RECHECK || since > self.left {
+            self.measure(written);
+        }
+        self.left.saturating_sub(written.saturating_sub(self.at))
+    }
+}
+
+/// An upload counted in `SharedState` until the budget is dropped.
+struct Reservation {
+    state: Arc<SharedState>,
+    id: u64,
+    completed: bool,
+}
+
+impl fmt::Debug for Reservation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Reservation").field("id", &self.id).finish()
+    }
+}
+
+impl Drop for Reservation {
+    fn drop(&mut self) {
+        self.state.end_upload(self.id, self.completed);
+    }
+}
+
+impl UploadBudget {
+    /// Work out the budget for an upload of `path` from `client`, taking
+    /// `max_upload_size`, directory and client quotas and the free-space
+    /// threshold into account, and start counting the upload against
+    /// them. Walks quota directories, so call it off the async workers.
+    pub fn compute(
+        state: &Arc<SharedState>,
+        config: &ServerConfig,
+        root: &Path,
+        path: &Path,
+        client: IpAddr,
+    ) -> Self {
+        let mut budget = Self {
+            max_upload_size: (config.max_upload_size > 0).then_some(config.max_upload_size),
+            ..Self::default()
+        };
+
+        let quotas: Vec<(PathBuf, &DirQuota)> = config
+            .dir_quotas
+            .iter()
+            .filter_map(|quota| {
+                let dir = if quota.dir.is_empty() {
+                    root.to_path_buf()
+                } else {
+                    sanitize_path(root, &quota.dir).ok()?
+                };
+                path.starts_with(&dir).then_some((dir, quota))
+            })
+            .collect();
+
+        // Counted from here on, so that uploads starting after this one
+        // see it even while its quota directories are still being walked.
+        let dirs = quotas.iter().map(|(dir, _)| dir.clone()).collect();
+        let (id, running) = state.begin_upload(client, path, dirs);
+        budget.reservation = Some(Reservation {
+            state: Arc::clone(state),
+            id,
+            completed: false,
+        });
+
+        // A plain overwrite frees the space of the file it replaces.
+        let replaced = match config.versioning {
+            Versioning::Off => std::fs::metadata(path).map_or(0, |m| m.len()),
+            _ => 0,
+        };
+        // This upload's own `.part` file is rewritten or, when resuming,
+        // counted again through the offset the upload starts at.
+        let own_part = std::fs::metadata(part_path_for(path)).map_or(0, |m| m.len());
+
+        for ((dir, quota), running) in quotas.iter().zip(running.dirs) {
+            // Running uploads are counted as they go, not by their `.part`
+            // files found on disk.
+            let used = dir_usage(dir)
+                .saturating_sub(running)
+                .saturating_sub(replaced)
+                .saturating_sub(own_part);
+            budget.dir_quotas.push((
+                quota.bytes.saturating_sub(used),
+                Limit::DirQuota(quota.dir.clone()),
+            ));
+        }
+
+        if config.client_quota > 0 {
+            let used = state.uploaded_by(client, CLIENT_QUOTA_WINDOW);
+            budget.client_quota = Some((
+                config.client_quota.saturating_sub(used),
+                Limit::ClientQuota(client),
+            ));
+        }
+
+        if config.min_free_space > 0 {
+            let existing_dir = path.ancestors().find(|p| p.is_dir()).unwrap_or(root);
+            let mut free_space = FreeSpace {
+                dir: existing_dir.to_path_buf(),
+                min_free_space: config.min_free_space,
+                left: u64::MAX,
+                at: 0,
+            };
+            free_space.measure(0);
+            budget.free_space = Some(free_space);
+        }
+        budget
+    }
+
+    /// The tightest limit for an upload that has written `written` bytes
+    /// and the bytes it may write in total under it; `None` if unlimited.
+    fn tightest(&mut self, written: u64) -> Option<(u64, Limit)> {
+        let mut tightest: Option<(u64, Limit)> = None;
+        let mut cap = |remaining: u64, limit: &Limit| {
+            if tightest.as_ref().is_none_or(|(r, _)| remaining < *r) {
+                tightest = Some((remaining, limit.clone()));
+            }
+        };
+
+        if let Some(max) = self.max_upload_size {
+            cap(max, &Limit::MaxUploadSize(max));
+        }
+        let others = self
+            .reservation
+            .as_ref()
+            .map(|r| r.state.upload_usage(r.id, written))
+            .unwrap_or_default();
+        if let Some((left, ref limit)) = self.client_quota {
+            cap(left.saturating_sub(others.client), limit);
+        }
+        for (i, (left, limit)) in self.dir_quotas.iter().enumerate() {
+            let others = others.dirs.get(i).copied().unwrap_or(0);
+            cap(left.saturating_sub(others), limit);
+        }
+        if let Some(ref mut free_space) = self.free_space {
+            cap(written + free_space.remaining(written), &Limit::FreeSpace);
+        }
+        tightest
+    }
+
+    /// Whether an upload totalling `bytes` stays within the budget.
+    pub fn check(&mut self, bytes: u64) -> Result<(), Limit> {
+        match self.tightest(bytes) {
+            Some((remaining, limit)) if bytes > remaining => Err(limit),
+            _ => Ok(()),
+        }
+    }
+
+    /// Reject uploads that cannot write anything at all, or whose
+    /// announced size is already over budget.
+    pub fn admit(&mut self, expected_size: Option<u64>) -> Result<(), Limit> {
+        match self.tightest(0) {
+            Some((0, limit)) => Err(limit),
+            Some((remaining, limit)) if expected_size.unwrap_or(0) > remaining => Err(limit),
+            _ => Ok(()),
+        }
+    }
+
+    /// Mark the upload as finished successfully: what it wrote then counts
+    /// against the uploads still running next to it.
+    pub fn complete(&mut self) {
+        if let Some(ref mut reservation) = self.reservation {
+            reservation.completed = true;
+        }
+    }
+}
+
+/// Total size of the regular files under `dir`, not following symlinks.
+fn dir_usage(dir: &Path) -> u64 {
+    let Ok(entries) = std::fs::read_dir(dir) else {
+        return 0;
+    };
+    entries
+        .filter_map(|e| e.ok())
+        .map(|entry| match entry.metadata() {
+            Ok(m) if m.is_dir() => dir_usage(&entry.path()),
+            Ok(m) if m.is_file() => m.len(),
+            _ => 0,
+        })
+        .sum()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sizes_and_quota_specs() {
+        assert_eq!(parse_size("4096"), Ok(4096));
+        assert_eq!(parse_size("512K"), Ok(512 << 10));
+        assert_eq!(parse_size("100MB"), Ok(100 << 20));
+        assert_eq!(parse_size("2GiB"), Ok(2 << 30));
+        assert!(parse_size("2P").is_err());
+        assert!(parse_size("M").is_err());
+
+        let quota: DirQuota = "configs/=10M".parse().unwrap();
+        assert_eq!(quota.dir, "configs");
+        assert_eq!(quota.bytes, 10 << 20);
+        assert_eq!("./=1G".parse::<DirQuota>().unwrap().dir, "");
+        assert!("configs".parse::<DirQuota>().is_err());
+        assert!("../etc=1G".parse::<DirQuota>().is_err());
+
+        // The API takes the same spec as the command line.
+        let quotas: Vec<DirQuota> = serde_json::from_str(r#"[".=1G", "cfg/=10M"]"#).unwrap();
+        assert_eq!(quotas[0].dir, "");
+        assert_eq!(quotas[1].dir, "cfg");
+        assert_eq!(
+            serde_json::to_string(&quotas).unwrap(),
+            r#"[".=1073741824","cfg=10485760"]"#
+        );
+        assert!(serde_json::from_str::<DirQuota>(r#"{"dir": "cfg", "bytes": 1}"#).is_err());
+    }
+
+    #[test]
+    fn tightest_limit_wins() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir(dir.path().join("cfg")).unwrap();
+        std::fs::write(dir.path().join("cfg/a.cfg"), vec![0u8; 600]).unwrap();
+        std::fs::write(dir.path().join("cfg/b.cfg"), vec![0u8; 100]).unwrap();
+
+        let client: IpAddr = "192.0.2.7".parse().unwrap();
+        let state = Arc::new(SharedState::new(ServerConfig::default()));
+        state.record_upload(client, 1500);
+        let mut config = ServerConfig {
+            max_upload_size: 2000,
+            dir_quotas: vec!["cfg=1000".parse().unwrap()],
+            client_quota: 2000,
+            ..ServerConfig::default()
+        };
+        let new_file = dir.path().join("cfg/new.cfg");
+        let mut budget = UploadBudget::compute(&state, &config, dir.path(), &new_file, client);
+        // Directory: 1000 - 700 = 300; client: 2000 - 1500 = 500.
+        assert_eq!(budget.check(300), Ok(()));
+        assert_eq!(budget.check(301), Err(Limit::DirQuota("cfg".into())));
+
+        // Overwriting a.cfg frees its 600 bytes.
+        let existing = dir.path().join("cfg/a.cfg");
+        let mut budget = UploadBudget::compute(&state, &config, dir.path(), &existing, client);
+        assert_eq!(budget.check(501), Err(Limit::ClientQuota(client)));
+
+        // Outside the quota directory only the other limits apply.
+        config.client_quota = 0;
+        let top = dir.path().join("top.bin");
+        let mut budget = UploadBudget::compute(&state, &config, dir.path(), &top, client);
+        assert_eq!(budget.admit(Some(2000)), Ok(()));
+        assert_eq!(budget.admit(Some(2001)), Err(Limit::MaxUploadSize(2000)));
+
+        config.dir_quotas = vec!["cfg=700".parse().unwrap()];
+        let mut budget = UploadBudget::compute(&state, &config, dir.path(), &new_file, client);
+        assert_eq!(budget.admit(None), Err(Limit::DirQuota("cfg".into())));
+    }
+
+    #[test]
+    fn resumed_upload_is_not_charged_for_its_part_file() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir(dir.path().join("cfg")).unwrap();
+        std::fs::write(dir.path().join("cfg/other.cfg"), vec![0u8; 100]).unwrap();
+        // An earlier attempt left 600 bytes to resume from.
+        std::fs::write(dir.path().join("cfg/fw.bin.part"), vec![0u8; 600]).unwrap();
+
+        let client: IpAddr = "192.0.2.7".parse().unwrap();
+        let state = Arc::new(SharedState::new(ServerConfig::default()));
+        let config = ServerConfig {
+            dir_quotas: vec!["cfg=1000".parse().unwrap()],
+            ..ServerConfig::default()
+        };
+        let path = dir.path().join("cfg/fw.bin");
+        let mut budget = UploadBudget::compute(&state, &config, dir.path(), &path, client);
+        // The resumed total includes the 600 bytes already in the part file.
+        assert_eq!(budget.admit(Some(900)), Ok(()));
+        assert_eq!(budget.check(900), Ok(()));
+        assert_eq!(budget.check(901), Err(Limit::DirQuota("cfg".into())));
+    }
+
+    #[test]
+    fn running_uploads_share_quotas() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir(dir.path().join("cfg")).unwrap();
+        let (a, b): (IpAddr, IpAddr) = ("192.0.2.7".parse().unwrap(), "192.0.2.8".parse().unwrap());
+        let state = Arc::new(SharedState::new(ServerConfig::default()));
+        let config = ServerConfig {
+            dir_quotas: vec!["cfg=1000".parse().unwrap()],
+            client_quota: 800,
+            ..ServerConfig::default()
+        };
+        let path = |name: &str| dir.path().join("cfg").join(name);
+        let compute = |name: &str, client| {
+            UploadBudget::compute(&state, &config, dir.path(), &path(name), client)
+        };
+
+        let mut first = compute("1.cfg", a);
+        let mut second = compute("2.cfg", a);
+        let mut third = compute("3.cfg", b);
+        assert_eq!(first.check(600), Ok(()));
+        // Client a has 200 left of its quota, the directory 400.
+        assert_eq!(second.check(201), Err(Limit::ClientQuota(a)));
+        assert_eq!(third.check(401), Err(Limit::DirQuota("cfg".into())));
+        assert_eq!(third.check(100), Ok(()));
+
+        // A failed upload gives its share back; a completed one keeps it.
+        drop(first);
+        assert_eq!(second.check(700), Ok(()));
+        second.complete();
+        drop(second);
+        assert_eq!(third.check(300), Ok(()));
+        assert_eq!(third.check(301), Err(Limit::DirQuota("cfg".into())));
+        drop(third);
+
+        let config = ServerConfig {
+            min_free_space: u64::MAX,
+            ..ServerConfig::default()
+        };
+        let mut budget = UploadBudget::compute(&state, &config, dir.path(), &path("x"), a);
+        assert_eq!(budget.admit(None), Err(Limit::FreeSpace));
+    }
+}
@@ -1,11 +1,25 @@
+mod api;
+mod boot_profiles;
+mod cache;
+mod checksum;
+mod dashboard;
+mod events;
+mod hooks;
 mod http_server;
+mod limits;
+mod proxy_dhcp;
 mod server;
+mod state;
 mod tftp_protocol;
+mod transport;
 mod ui;
+mod versions;
 
-use std::fs::OpenOptions;
-use std::io::{self, BufWriter};
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufWriter, Write};
+use std::net::Ipv4Addr;
 use std::path::PathBuf;
+use std::sync::Arc;
 use std::time::Duration;
 
 use anyhow::Result;
@@ -17,10 +31,19 @@ use crossterm::terminal::{
 };
 use ratatui::Terminal;
 use ratatui::backend::CrosstermBackend;
-use tokio::sync::{mpsc, watch};
-
-use server::{ServerConfig, ServerEvent};
+use tokio::sync::broadcast::{self, error::RecvError, error::TryRecvError};
+use tokio::sync::watch;
+
+use boot_profiles::BootProfile;
+use events::EventBus;
+use hooks::{HookOptions, HookSpec};
+use limits::DirQuota;
+use proxy_dhcp::{BootFiles, ProxyDhcpOptions};
+use server::{OptionPolicy, ServerConfig, ServerEvent};
+use state::SharedState;
+use transport::PortRange;
 use ui::App;
+use versions::Versioning;
 
 /// A high-performance TFTP server with a TUI dashboard.
 #[derive(Parser, Debug)]
@@ -55,7 +78,8 @@ struct Cli {
     /// Maximum window size (RFC 7440) to negotiate with clients.
     /// Higher values improve throughput on high-latency links.
     /// 1 = classic stop-and-wait (disable windowing).
-    #[arg(short = 'w', long, default_value_t = 1)]
+    #[arg(short = 'w', long, default_value_t = 1,
+          value_parser = clap::value_parser!(u16).range(1..))]
     max_window_size: u16,
 
     /// Allow overwriting existing files on WRQ (upload). When disabled,
@@ -64,7 +88,8 @@ struct Cli {
     allow_overwrite: bool,
 
     /// Maximum number of retransmission attempts before giving up.
-    #[arg(long, default_value_t = 10)]
+    #[arg(long, default_value_t = 10,
+          value_parser = clap::value_parser!(u32).range(..=server::MAX_RETRIES_LIMIT as i64))]
     max_retries: u32,
 
     /// Disable read (RRQ) requests. Only uploads will be accepted.
@@ -74,6 +99,173 @@ struct Cli {
     /// Disable write (WRQ) requests. Only downloads will be accepted.
     #[arg(long)]
     disable_write: bool,
+
+    /// Extra digests to compute for every transfer, next to SHA-256.
+    /// Repeatable or comma-separated.
+    #[arg(
+        long = "checksum",
+        value_name = "ALGO",
+        value_enum,
+        value_delimiter = ','
+    )]
+    checksums: Vec<ExtraChecksum>,
+
+    /// Write a `<file>.sha256` sidecar (sha256sum format) next to every
+    /// completed upload.
+    #[arg(long)]
+    sha256_sidecar: bool,
+
+    /// Keep files replaced by an upload instead of overwriting them:
+    /// `timestamp` renames the old file to `name.~YYYYMMDD-HHMMSS~`,
+    /// `counter` to `name.~N~`. Takes precedence over --allow-overwrite.
+    #[arg(long, value_name = "MODE", value_enum, default_value_t = Versioning::Off)]
+    versions: Versioning,
+
+    /// Versions to keep per file (0 = unlimited).
+    #[arg(long, value_name = "N", default_value_t = 0,
+          value_parser = versions::parse_keep_versions)]
+    keep_versions: usize,
+
+    /// Delete versions older than this, e.g. `30d`, `12h` (s, m, h, d or
+    /// w). The newest version of a file is always kept.
+    #[arg(long, value_name = "AGE", value_parser = versions::parse_age)]
+    max_version_age: Option<u64>,
+
+    /// Largest accepted upload, e.g. `512M` or `2G`. WRQs announcing a
+    /// larger tsize are refused; others are aborted with error 3 once they
+    /// pass it.
+    #[arg(long, value_name = "SIZE", value_parser = limits::parse_size)]
+    max_upload_size: Option<u64>,
+
+    /// Cap the total size of a directory uploads may write into, as
+    /// `DIR=SIZE` relative to --dir (`.` for the whole tree). Repeatable.
+    #[arg(long = "dir-quota", value_name = "DIR=SIZE")]
+    dir_quotas: Vec<DirQuota>,
+
+    /// Bytes a single client IP may upload per 24 hours.
+    #[arg(long, value_name = "SIZE", value_parser = limits::parse_size)]
+    client_quota: Option<u64>,
+
+    /// Refuse uploads that would leave less than this much free disk
+    /// space, e.g. `1G`.
+    #[arg(long, value_name = "SIZE", value_parser = limits::parse_size)]
+    min_free_space: Option<u64>,
+
+    /// Honour the non-standard `offset` option so clients can resume
+    /// interrupted transfers: RRQs start at the byte offset and WRQs
+    /// append to the `.part` file kept from the failed attempt.
+    #[arg(long)]
+    enable_offset: bool,
+
+    /// Refuse transfers that need more than 65535 blocks unless the
+    /// client negotiated the `rollover` option.
+    #[arg(long)]
+    strict_rollover: bool,
+
+    /// Run all transfers over the listening port instead of a random
+    /// port per transfer, for firewalls, NAT and clusters that only
+    /// expose the TFTP port.
+    #[arg(long, conflicts_with = "port_range")]
+    single_port: bool,
+
+    /// Bind per-transfer sockets to ports in this range, e.g.
+    /// `50000-50100`, instead of random ephemeral ports.
+    #[arg(long, value_name = "FIRST-LAST")]
+    port_range: Option<PortRange>,
+
+    /// Listener workers receiving requests on the port in parallel via
+    /// SO_REUSEPORT. Raise for boot storms on multi-core hosts.
+    #[arg(long, value_name = "N", default_value_t = 1,
+          value_parser = clap::value_parser!(u16).range(1..))]
+    workers: u16,
+
+    /// Send RRQ windows one datagram per syscall instead of batching
+    /// them with sendmmsg/recvmmsg (Linux).
+    #[arg(long)]
+    no_batched_io: bool,
+
+    /// Keep up to this many bytes of hot files in memory for RRQ, e.g.
+    /// `512M`, so a boot storm reads them from disk once.
+    #[arg(long, value_name = "SIZE", value_parser = limits::parse_size)]
+    cache_size: Option<u64>,
+
+    /// What to do with duplicate or malformed request options: use the
+    /// first or last duplicate, or reject the request with error 8.
+    #[arg(long, value_name = "POLICY", value_enum, default_value_t = OptionPolicy::Last)]
+    option_policy: OptionPolicy,
+
+    /// Serve FILE instead of NAME to matching clients, as
+    /// `NAME[@CLIENT]=FILE`. CLIENT is a MAC, an IP or CIDR block, or
+    /// bios, efi64, arm64 or ipxe (known for clients seen by
+    /// --proxy-dhcp); without it the profile applies to every client. The
+    /// most specific match wins. Repeatable.
+    #[arg(long = "boot-profile", value_name = "NAME[@CLIENT]=FILE")]
+    boot_profiles: Vec<BootProfile>,
+
+    /// Enable the HTTP control API under /api, authenticated with
+    /// `Authorization: Bearer <TOKEN>`. Requires --http-port.
+    #[arg(long, value_name = "TOKEN", requires = "http_port")]
+    api_token: Option<String>,
+
+    /// Serve a live web dashboard at /_dashboard/ on the HTTP port. When
+    /// --api-token is set the dashboard requires it too (`?token=`).
+    #[arg(long, requires = "http_port")]
+    dashboard: bool,
+
+    /// Run a hook on transfer events, as `EVENT=ACTION`. EVENT is start,
+    /// complete or fail, optionally prefixed with upload- or download-.
+    /// ACTION is an http:// URL to POST JSON to, or a shell command that
+    /// gets TFTP_* environment variables. Repeatable.
+    #[arg(long = "hook", value_name = "EVENT=ACTION")]
+    hooks: Vec<HookSpec>,
+
+    /// Answer PXE clients' DHCP discovery on ports 67 and 4011 with this
+    /// server's address and a boot file for their architecture, next to
+    /// the network's own DHCP server. Never hands out IP addresses.
+    #[arg(long)]
+    proxy_dhcp: bool,
+
+    /// Boot file offered to legacy BIOS PXE clients.
+    #[arg(long, value_name = "FILE", default_value = "pxelinux.0")]
+    pxe_bios: String,
+
+    /// Boot file offered to UEFI x64 PXE clients.
+    #[arg(long, value_name = "FILE", default_value = "bootx64.efi")]
+    pxe_efi64: String,
+
+    /// Boot file offered to UEFI ARM64 PXE clients.
+    #[arg(long, value_name = "FILE", default_value = "bootaa64.efi")]
+    pxe_arm64: String,
+
+    /// Boot file offered to clients already running iPXE, e.g. a
+    /// `boot.ipxe` script. Without it they get their architecture's file.
+    #[arg(long, value_name = "FILE", requires = "proxy_dhcp")]
+    pxe_ipxe: Option<String>,
+
+    /// TFTP server address announced to PXE clients. Defaults to the
+    /// local address facing the client.
+    #[arg(long, value_name = "IP", requires = "proxy_dhcp")]
+    pxe_server_ip: Option<Ipv4Addr>,
+
+    /// Seconds a hook may run before it is killed.
+    #[arg(long, default_value_t = 30)]
+    hook_timeout: u64,
+
+    /// Maximum number of hooks running at the same time.
+    #[arg(long, default_value_t = 4)]
+    hook_concurrency: usize,
+
+    /// Run without the TUI and print logs to stdout. Stops on Ctrl+C or
+    /// SIGTERM.
+    #[arg(long)]
+    headless: bool,
+}
+
+/// Optional digests on top of the always-on SHA-256.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+enum ExtraChecksum {
+    Crc32,
+    Md5,
 }
 
 #[tokio::main]
@@ -90,14 +282,13 @@ async fn main() -> Result<()> {
         None => None,
     };
 
-    // Channel: server -> TUI event loop.
-    let (ev_tx, mut ev_rx) = mpsc::unbounded_channel::<ServerEvent>();
-
     // Shutdown signal: TUI -> server.
     let (shutdown_tx, shutdown_rx) = watch::channel(false);
 
-    // Clone shutdown receiver for HTTP server before TFTP server consumes it.
+    // Clone shutdown receivers for the HTTP server and ProxyDHCP before the
+    // TFTP server consumes it.
     let http_shutdown_rx = shutdown_rx.clone();
+    let pxe_shutdown_rx = shutdown_rx.clone();
 
     // Build server configuration from CLI args.
     let server_config = ServerConfig {
@@ -108,16 +299,76 @@ async fn main() -> Result<()> {
         max_retries: cli.max_retries,
         enable_read: !cli.disable_read,
         enable_write: !cli.disable_write,
+        checksum_crc32: cli.checksums.contains(&ExtraChecksum::Crc32),
+        checksum_md5: cli.checksums.contains(&ExtraChecksum::Md5),
+        sha256_sidecar: cli.sha256_sidecar,
+        versioning: cli.versions,
+        keep_versions: cli.keep_versions,
+        max_version_age_secs: cli.max_version_age.unwrap_or(0),
+        max_upload_size: cli.max_upload_size.unwrap_or(0),
+        dir_quotas: cli.dir_quotas.clone(),
+        client_quota: cli.client_quota.unwrap_or(0),
+        min_free_space: cli.min_free_space.unwrap_or(0),
+        enable_offset: cli.enable_offset,
+        strict_rollover: cli.strict_rollover,
+        single_port: cli.single_port,
+        port_range: cli.port_range,
+        workers: cli.workers.into(),
+        batched_io: !cli.no_batched_io,
+        cache_size: cli.cache_size.unwrap_or(0),
+        option_policy: cli.option_policy,
+        boot_profiles: cli.boot_profiles.clone(),
     };
+    let shared = Arc::new(SharedState::new(server_config));
+
+    // Event bus: servers -> TUI, log file, dashboard. Subscribe before
+    // anything is published so startup messages are not missed.
+    let bus = EventBus::new(Arc::clone(&shared));
+    let mut ui_rx = bus.subscribe();
+    let log_handle = log_writer
+        .map(|writer| tokio::spawn(write_log_file(bus.subscribe(), writer, shutdown_rx.clone())));
+    if !cli.hooks.is_empty() {
+        let options = HookOptions {
+            timeout: Duration::from_secs(cli.hook_timeout),
+            concurrency: cli.hook_concurrency,
+        };
+        tokio::spawn(hooks::run(
+            cli.hooks.clone(),
+            options,
+            bus.clone(),
+            bus.subscribe(),
+        ));
+    }
+    {
+        let bus = bus.clone();
+        tokio::spawn(async move {
+            let mut tick = tokio::time::interval(events::PROGRESS_INTERVAL);
+            loop {
+                tick.tick().await;
+                bus.flush_progress();
+            }
+        });
+    }
+
+    bus.send(ServerEvent::Log(if cli.headless {
+        "Starting tftp-rs (headless)...".into()
+    } else {
+        "Starting tftp-rs...".into()
+    }));
+    if !cli.proxy_dhcp
+        && let Some(warning) = boot_profiles::proxy_dhcp_warning(&cli.boot_profiles)
+    {
+        bus.send(ServerEvent::Log(warning));
+    }
 
     // Spawn the TFTP server in the background.
     let server_handle = {
         let dir = dir.clone();
-        let tx = ev_tx.clone();
-        let cfg = server_config.clone();
+        let tx = bus.clone();
+        let shared = Arc::clone(&shared);
         tokio::spawn(async move {
-            if let Err(e) = server::run(cli.port, dir, tx.clone(), shutdown_rx, cfg).await {
-                let _ = tx.send(ServerEvent::Log(format!("Server fatal: {e}")));
+            if let Err(e) = server::run(cli.port, dir, tx.clone(), shutdown_rx, shared).await {
+                tx.send(ServerEvent::Log(format!("Server fatal: {e}")));
             }
         })
     };
@@ -125,28 +376,74 @@ async fn main() -> Result<()> {
     // Optionally spawn the HTTP file server.
     if let Some(http_port) = cli.http_port {
         let dir = dir.clone();
-        let tx = ev_tx.clone();
+        let tx = bus.clone();
+        let shared = Arc::clone(&shared);
+        let options = http_server::HttpOptions {
+            port: http_port,
+            tftp_port: cli.port,
+            api_token: cli.api_token.clone(),
+            dashboard: cli.dashboard,
+            proxy_dhcp: cli.proxy_dhcp,
+        };
         tokio::spawn(async move {
-            if let Err(e) = http_server::run(http_port, dir, tx.clone(), http_shutdown_rx).await {
-                let _ = tx.send(ServerEvent::Log(format!("HTTP server fatal: {e}")));
+            if let Err(e) =
+                http_server::run(options, dir, tx.clone(), shared, http_shutdown_rx).await
+            {
+                tx.send(ServerEvent::Log(format!("HTTP server fatal: {e}")));
             }
         });
     }
 
+    // Optionally spawn the ProxyDHCP responder for PXE clients.
+    if cli.proxy_dhcp {
+        let tx = bus.clone();
+        let options = ProxyDhcpOptions {
+            boot_files: BootFiles {
+                bios: cli.pxe_bios.clone(),
+                efi64: cli.pxe_efi64.clone(),
+                arm64: cli.pxe_arm64.clone(),
+                ipxe: cli.pxe_ipxe.clone(),
+            },
+            server_ip: cli.pxe_server_ip,
+            tftp_port: cli.port,
+        };
+        tokio::spawn(async move {
+            if let Err(e) = proxy_dhcp::run(options, tx.clone(), pxe_shutdown_rx).await {
+                tx.send(ServerEvent::Log(format!("ProxyDHCP fatal: {e}")));
+            }
+        });
+    }
+
+    if cli.headless {
+        let mut app = App::new(cli.port, cli.http_port, dir);
+        app.online = true;
+        let result = run_headless(&mut app, &mut ui_rx, &shared).await;
+        bus.send(ServerEvent::Log("Shutting down...".into()));
+        drain_events(&mut app, &mut ui_rx, &shared);
+        print_new_logs(&mut app);
+
+        let _ = shutdown_tx.send(true);
+        let _ = tokio::time::timeout(Duration::from_millis(200), server_handle).await;
+        finish_log_file(log_handle).await;
+        return result;
+    }
+
     // ---------- TUI setup ----------
     enable_raw_mode()?;
     io::stdout().execute(EnterAlternateScreen)?;
     let backend = CrosstermBackend::new(io::stdout());
     let mut terminal = Terminal::new(backend)?;
 
-    let mut app = App::new(cli.port, cli.http_port, dir, log_writer);
+    let mut app = App::new(cli.port, cli.http_port, dir);
     app.online = true;
-    app.push_log("Starting tftp-rs...".into());
+    if cli.proxy_dhcp {
+        app.pxe_boots = Some(Vec::new());
+    }
 
-    let result = run_tui(&mut terminal, &mut app, &mut ev_rx).await;
+    let result = run_tui(&mut terminal, &mut app, &mut ui_rx, &shared).await;
 
     // Log shutdown before cleanup.
-    app.push_log("Shutting down...".into());
+    bus.send(ServerEvent::Log("Shutting down...".into()));
 
     // ---------- Cleanup ----------
     let _ = shutdown_tx.send(true);
@@ -155,6 +452,7 @@ async fn main() -> Result<()> {
 
     // Give the server a moment to shut down cleanly.
     let _ = tokio::time::timeout(Duration::from_millis(200), server_handle).await;
+    finish_log_file(log_handle).await;
 
     result
 }
@@ -163,19 +461,19 @@ async fn main() -> Result<()> {
 async fn run_tui(
     terminal: &mut Terminal<CrosstermBackend<io::Stdout>>,
     app: &mut App,
-    ev_rx: &mut mpsc::UnboundedReceiver<ServerEvent>,
+    ev_rx: &mut broadcast::Receiver<ServerEvent>,
+    shared: &SharedState,
 ) -> Result<()> {
     loop {
         // Draw.
         terminal.draw(|f| ui::draw(f, app))?;
 
         // Poll for server events (drain all pending).
-        while let Ok(ev) = ev_rx.try_recv() {
-            handle_server_event(app, ev);
-        }
+        drain_events(app, ev_rx, shared);
 
         // Periodically refresh interface IPs.
         app.refresh_interfaces_if_needed();
+        app.cache = shared.cache_stats();
 
         // Poll for terminal / keyboard events with a short timeout so we
         // keep refreshing the screen.
@@ -217,22 +515,119 @@ async fn run_tui(
     }
 }
 
+/// Headless event loop: print log lines to stdout until Ctrl+C / SIGTERM.
+async fn run_headless(
+    app: &mut App,
+    ev_rx: &mut broadcast::Receiver<ServerEvent>,
+    shared: &SharedState,
+) -> Result<()> {
+    let terminate = shutdown_signal();
+    tokio::pin!(terminate);
+    loop {
+        print_new_logs(app);
+        tokio::select! {
+            ev = ev_rx.recv() => match ev {
+                Ok(ev) => handle_server_event(app, ev),
+                Err(RecvError::Lagged(n)) => resync(app, shared, n),
+                Err(RecvError::Closed) => return Ok(()),
+            },
+            _ = &mut terminate => return Ok(()),
+        }
+    }
+}
+
+/// Apply every event already waiting on the bus.
+fn drain_events(app: &mut App, ev_rx: &mut broadcast::Receiver<ServerEvent>, shared: &SharedState) {
+    loop {
+        match ev_rx.try_recv() {
+            Ok(ev) => handle_server_event(app, ev),
+            Err(TryRecvError::Lagged(n)) => resync(app, shared, n),
+            Err(TryRecvError::Empty | TryRecvError::Closed) => return,
+        }
+    }
+}
+
+/// The UI fell behind and `dropped` events were discarded. Rebuild the
+/// transfer list from the shared state; the missed log lines are gone.
+fn resync(app: &mut App, shared: &SharedState, dropped: u64) {
+    app.transfers = shared.active_transfers();
+    app.push_log(format!("UI fell behind, skipped {dropped} events"));
+}
+
+/// Print and discard the log lines accumulated by `app`, so the headless
+/// process does not keep its whole history in memory.
+fn print_new_logs(app: &mut App) {
+    for line in app.logs.drain(..) {
+        println!("{line}");
+    }
+}
+
+/// Resolves on Ctrl+C, or on SIGTERM where supported.
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{SignalKind, signal};
+        if let Ok(mut term) = signal(SignalKind::terminate()) {
+            tokio::select! {
+                _ = tokio::signal::ctrl_c() => {}
+                _ = term.recv() => {}
+            }
+            return;
+        }
+    }
+    let _ = tokio::signal::ctrl_c().await;
+}
+
+/// Append the log line of every event to the log file until shutdown.
+async fn write_log_file(
+    mut ev_rx: broadcast::Receiver<ServerEvent>,
+    mut writer: BufWriter<File>,
+    mut shutdown: watch::Receiver<bool>,
+) {
+    let mut write = |msg: &str| {
+        let _ = writeln!(writer, "{} {msg}", ui::timestamp_now());
+        let _ = writer.flush();
+    };
+    loop {
+        tokio::select! {
+            ev = ev_rx.recv() => match ev {
+                Ok(ev) => {
+                    if let Some(msg) = events::describe(&ev) {
+                        write(&msg);
+                    }
+                }
+                Err(RecvError::Lagged(n)) => {
+                    write(&format!("Log writer fell behind, skipped {n} events"));
+                }
+                Err(RecvError::Closed) => return,
+            },
+            _ = shutdown.changed() => {
+                // Keep whatever was published before shutdown.
+                while let Ok(ev) = ev_rx.try_recv() {
+                    if let Some(msg) = events::describe(&ev) {
+                        write(&msg);
+                    }
+                }
+                return;
+            }
+        }
+    }
+}
+
+async fn finish_log_file(handle: Option<tokio::task::JoinHandle<()>>) {
+    if let Some(handle) = handle {
+        let _ = tokio::time::timeout(Duration::from_millis(200), handle).await;
+    }
+}
+
 fn handle_server_event(app: &mut App, ev: ServerEvent) {
+    if let Some(msg) = events::describe(&ev) {
+        app.push_log(msg);
+    }
     match ev {
-        ServerEvent::Log(msg) => app.push_log(msg),
-        ServerEvent::TransferStarted(info) => {
-            app.push_log(format!(
-                "Transfer #{} started: {} {} ({})",
-                info.id,
-                match info.kind {
-                    server::TransferKind::Download => "DL",
-                    server::TransferKind::Upload => "UL",
-                },
-                info.filename,
-                info.peer,
-            ));
-            app.transfers.push(info);
-        }
+        ServerEvent::Log(_) => {}
+        ServerEvent::PxeBoot(boot) => app.record_pxe_boot(boot),
+        ServerEvent::TransferStarted(info) => app.transfers.push(info),
         ServerEvent::TransferProgress {
             id,
             transferred,
@@ -243,13 +638,8 @@ fn handle_server_event(app: &mut App, ev: ServerEvent) {
                 tf.total_bytes = total_bytes;
             }
         }
-        ServerEvent::TransferComplete(id) => {
-            app.transfers.retain(|t| t.id != id);
-            app.push_log(format!("Transfer #{id} complete"));
-        }
-        ServerEvent::TransferFailed { id, error } => {
+        ServerEvent::TransferComplete { id, .. } | ServerEvent::TransferFailed { id, .. } => {
             app.transfers.retain(|t| t.id != id);
-            app.push_log(format!("Transfer #{id} failed: {error}"));
         }
     }
 }
@@ -0,0 +1,710 @@
+//! ProxyDHCP responder for PXE boot (PXE 2.1 spec, RFC 4578).
+//!
+//! Runs next to the network's real DHCP server and only tells PXE clients
+//! where to boot from: the TFTP server address and a boot file chosen by
+//! the client's architecture. It never hands out addresses; `yiaddr` in
+//! every reply is zero and clients take their lease from the real server.
+//!
+//! Two ports are served. DHCPDISCOVERs broadcast to port 67 get a
+//! DHCPOFFER; DHCPREQUESTs some firmware then sends to port 4011 get a
+//! DHCPACK with the same boot information.
+
+use std::fmt;
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::sync::Arc;
+
+use anyhow::{Result, anyhow};
+use tokio::net::UdpSocket;
+use tokio::sync::watch;
+
+use crate::events::EventBus;
+use crate::server::ServerEvent;
+
+/// DHCP server port, where PXE clients broadcast DHCPDISCOVER.
+const DHCP_PORT: u16 = 67;
+/// DHCP client port, where offers to clients without an address go.
+const DHCP_CLIENT_PORT: u16 = 68;
+/// PXE boot server port, where clients send DHCPREQUEST after the offer.
+const PXE_PORT: u16 = 4011;
+
+/// Fixed BOOTP header before the magic cookie.
+const HEADER_LEN: usize = 236;
+const MAGIC_COOKIE: [u8; 4] = [99, 130, 83, 99];
+/// Largest datagram accepted; DHCP messages fit comfortably in this.
+const MAX_MESSAGE: usize = 1500;
+
+const BOOTREQUEST: u8 = 1;
+const BOOTREPLY: u8 = 2;
+
+const DHCPDISCOVER: u8 = 1;
+const DHCPOFFER: u8 = 2;
+const DHCPREQUEST: u8 = 3;
+const DHCPACK: u8 = 5;
+const DHCPINFORM: u8 = 8;
+
+const OPT_PAD: u8 = 0;
+const OPT_VENDOR_SPECIFIC: u8 = 43;
+const OPT_MESSAGE_TYPE: u8 = 53;
+const OPT_SERVER_ID: u8 = 54;
+const OPT_VENDOR_CLASS: u8 = 60;
+const OPT_TFTP_SERVER: u8 = 66;
+const OPT_BOOTFILE: u8 = 67;
+const OPT_USER_CLASS: u8 = 77;
+const OPT_CLIENT_ARCH: u8 = 93;
+const OPT_CLIENT_UUID: u8 = 97;
+const OPT_END: u8 = 255;
+
+/// PXE vendor sub-option 6 (PXE_DISCOVERY_CONTROL) with bit 3 set: boot
+/// the file named in this offer without a menu or boot server discovery.
+const PXE_BOOT_FILE_DIRECTLY: [u8; 4] = [6, 1, 8, OPT_END];
+
+// ---------------------------------------------------------------------------
+// Configuration
+// ---------------------------------------------------------------------------
+
+/// Boot file handed to each kind of client, relative to the TFTP root.
+#[derive(Debug, Clone)]
+pub struct BootFiles {
+    pub bios: String,
+    pub efi64: String,
+    pub arm64: String,
+    /// For clients already running iPXE (user class `iPXE`), typically a
+    /// boot script. `None` gives them the file for their architecture.
+    pub ipxe: Option<String>,
+}
+
+impl BootFiles {
+    /// The file for `arch`, or the iPXE one for iPXE clients when set.
+    pub fn select(&self, arch: ClientArch, ipxe: bool) -> &str {
+        match (&self.ipxe, arch) {
+            (Some(file), _) if ipxe => file,
+            (_, ClientArch::Bios) => &self.bios,
+            (_, ClientArch::Efi64) => &self.efi64,
+            (_, ClientArch::Arm64) => &self.arm64,
+        }
+    }
+}
+
+/// Settings for the ProxyDHCP responder.
+#[derive(Debug, Clone)]
+pub struct ProxyDhcpOptions {
+    pub boot_files: BootFiles,
+    /// Address advertised as the TFTP server. `None` uses the local
+    /// address facing the client.
+    pub server_ip: Option<Ipv4Addr>,
+    /// Port the TFTP server listens on. PXE firmware always uses 69.
+    pub tftp_port: u16,
+}
+
+// ---------------------------------------------------------------------------
+// Boot events
+// ---------------------------------------------------------------------------
+
+/// Client system architecture (option 93) that has a boot file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ClientArch {
+    Bios,
+    Efi64,
+    Arm64,
+}
+
+impl ClientArch {
+    /// Map an RFC 4578 / IANA architecture type. HTTP boot and
+    /// architectures without a configured file give `None`.
+    pub fn from_code(code: u16) -> Option<Self> {
+        match code {
+            0 => Some(Self::Bios),
+            7 | 9 => Some(Self::Efi64),
+            11 => Some(Self::Arm64),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for ClientArch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Bios => "BIOS",
+            Self::Efi64 => "UEFI x64",
+            Self::Arm64 => "ARM64",
+        })
+    }
+}
+
+/// Which step of the PXE boot a reply answered.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PxeStage {
+    /// DHCPOFFER to a DHCPDISCOVER on port 67.
+    Offer,
+    /// DHCPACK to a DHCPREQUEST on port 4011.
+    Ack,
+}
+
+/// A PXE client was told where to boot from.
+#[derive(Debug, Clone)]
+pub struct PxeBoot {
+    /// Client hardware address, `aa:bb:cc:dd:ee:ff`.
+    pub mac: String,
+    /// The client's address, once it has one (port 4011 requests).
+    pub client_ip: Option<Ipv4Addr>,
+    pub arch: ClientArch,
+    pub ipxe: bool,
+    pub boot_file: String,
+    pub server_ip: Ipv4Addr,
+    pub stage: PxeStage,
+}
+
+impl fmt::Display for PxeBoot {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let stage = match self.stage {
+            PxeStage::Offer => "offer",
+            PxeStage::Ack => "ack",
+        };
+        write!(f, "PXE {stage} to {}", self.mac)?;
+        if let Some(ip) = self.client_ip {
+            write!(f, " at {ip}")?;
+        }
+        write!(f, " ({}", self.arch)?;
+        if self.ipxe {
+            f.write_str(", iPXE")?;
+        }
+        write!(f, "): {} from {}", self.boot_file, self.server_ip)
+    }
+}
+
+// ---------------------------------------------------------------------------
+// Message parsing and building
+// ---------------------------------------------------------------------------
+
+/// The parts of a client's DHCP message a reply needs.
+#[derive(Debug)]
+struct Request<'a> {
+    htype: u8,
+    hlen: u8,
+    xid: [u8; 4],
+    flags: [u8; 2],
+    ciaddr: Ipv4Addr,
+    giaddr: Ipv4Addr,
+    chaddr: [u8; 16],
+    options: Vec<(u8, &'a [u8])>,
+}
+
+impl<'a> Request<'a> {
+    /// Parse a BOOTREQUEST. Anything else, truncated options included,
+    /// gives `None`: port 67 sees all of the network's DHCP traffic and
+    /// none of it is ours to complain about.
+    fn parse(buf: &'a [u8]) -> Option<Self> {
+        if buf.len() < HEADER_LEN + MAGIC_COOKIE.len()
+            || buf[0] != BOOTREQUEST
+            || buf[HEADER_LEN..HEADER_LEN + 4] != MAGIC_COOKIE
+        {
+            return None;
+        }
+        let ip = |at: usize| Ipv4Addr::new(buf[at], buf[at + 1], buf[at + 2], buf[at + 3]);
+        let mut options = Vec::new();
+        let mut rest = &buf[HEADER_LEN + 4..];
+        while let Some((&tag, tail)) = rest.split_first() {
+            match tag {
+                OPT_PAD => rest = tail,
+                OPT_END => break,
+                _ => {
+                    let (&len, tail) = tail.split_first()?;
+                    let value = tail.get(..len as usize)?;
+                    options.push((tag, value));
+                    rest = &tail[len as usize..];
+                }
+            }
+        }
+        Some(Self {
+            htype: buf[1],
+            hlen: buf[2],
+            xid: buf[4..8].try_into().ok()?,
+            flags: buf[10..12].try_into().ok()?,
+            ciaddr: ip(12),
+            giaddr: ip(24),
+            chaddr: buf[28..44].try_into().ok()?,
+            options,
+        })
+    }
+
+    fn option(&self, tag: u8) -> Option<&'a [u8]> {
+        self.options
+            .iter()
+            .find(|(t, _)| *t == tag)
+            .map(|(_, v)| *v)
+    }
+
+    fn message_type(&self) -> Option<u8> {
+        self.option(OPT_MESSAGE_TYPE)?.first().copied()
+    }
+
+    /// PXE firmware and iPXE identify themselves with vendor class
+    /// `PXEClient...`.
+    fn is_pxe(&self) -> bool {
+        self.option(OPT_VENDOR_CLASS)
+            .is_some_and(|class| class.starts_with(b"PXEClient"))
+    }
+
+    /// iPXE sends user class `iPXE` so it can be handed something other
+    /// than itself.
+    fn is_ipxe(&self) -> bool {
+        self.option(OPT_USER_CLASS)
+            .is_some_and(|class| class.windows(4).any(|w| w == b"iPXE"))
+    }
+
+    /// Architecture type from option 93, falling back to the
+    /// `PXEClient:Arch:NNNNN` vendor class.
+    fn arch_code(&self) -> Option<u16> {
+        if let Some(&[hi, lo, ..]) = self.option(OPT_CLIENT_ARCH) {
+            return Some(u16::from_be_bytes([hi, lo]));
+        }
+        let class = std::str::from_utf8(self.option(OPT_VENDOR_CLASS)?).ok()?;
+        let (_, arch) = class.split_once("Arch:")?;
+        arch.get(..5)?.parse().ok()
+    }
+
+    fn mac(&self) -> String {
+        let len = (self.hlen as usize).min(self.chaddr.len());
+        self.chaddr[..len]
+            .iter()
+            .map(|b| format!("{b:02x}"))
+            .collect::<Vec<_>>()
+            .join(":")
+    }
+}
+
+/// Build the reply to `req`: no address, just the boot server and file.
+fn build_reply(req: &Request, message_type: u8, server_ip: Ipv4Addr, boot_file: &str) -> Vec<u8> {
+    let mut buf = vec![0u8; HEADER_LEN];
+    buf[0] = BOOTREPLY;
+    buf[1] = req.htype;
+    buf[2] = req.hlen;
+    buf[4..8].copy_from_slice(&req.xid);
+    buf[10..12].copy_from_slice(&req.flags);
+    buf[12..16].copy_from_slice(&req.ciaddr.octets());
+    // yiaddr (16..20) stays zero: the real DHCP server assigns addresses.
+    buf[20..24].copy_from_slice(&server_ip.octets());
+    buf[24..28].copy_from_slice(&req.giaddr.octets());
+    buf[28..44].copy_from_slice(&req.chaddr);
+    // `file` holds at most 127 bytes and a NUL; option 67 always has it all.
+    let file = boot_file.as_bytes();
+    let n = file.len().min(127);
+    buf[108..108 + n].copy_from_slice(&file[..n]);
+    buf.extend_from_slice(&MAGIC_COOKIE);
+
+    let mut option = |tag: u8, value: &[u8]| {
+        for chunk in value.chunks(255) {
+            buf.push(tag);
+            buf.push(chunk.len() as u8);
+            buf.extend_from_slice(chunk);
+        }
+    };
+    option(OPT_MESSAGE_TYPE, &[message_type]);
+    option(OPT_SERVER_ID, &server_ip.octets());
+    option(OPT_VENDOR_CLASS, b"PXEClient");
+    if let Some(uuid) = req.option(OPT_CLIENT_UUID) {
+        option(OPT_CLIENT_UUID, uuid);
+    }
+    option(OPT_VENDOR_SPECIFIC, &PXE_BOOT_FILE_DIRECTLY);
+    option(OPT_TFTP_SERVER, server_ip.to_string().as_bytes());
+    option(OPT_BOOTFILE, file);
+    buf.push(OPT_END);
+    buf
+}
+
+/// How the responder treats one datagram.
+#[derive(Debug)]
+enum Answer {
+    /// Not a PXE message this port answers.
+    Ignore,
+    /// A PXE client the responder has no boot file for; logged.
+    Unsupported(String),
+    Reply {
+        message_type: u8,
+        arch: ClientArch,
+        ipxe: bool,
+        boot_file: String,
+    },
+}
+
+/// Decide how to answer `req` received on `port`.
+fn answer(req: &Request, port: u16, files: &BootFiles) -> Answer {
+    let message_type = match (port, req.message_type()) {
+        (DHCP_PORT, Some(DHCPDISCOVER)) => DHCPOFFER,
+        (PXE_PORT, Some(DHCPREQUEST | DHCPINFORM)) => DHCPACK,
+        _ => return Answer::Ignore,
+    };
+    if !req.is_pxe() {
+        return Answer::Ignore;
+    }
+    let code = req.arch_code().unwrap_or(0);
+    let Some(arch) = ClientArch::from_code(code) else {
+        return Answer::Unsupported(format!(
+            "PXE client {} has architecture {code}, which has no boot file; not answered",
+            req.mac()
+        ));
+    };
+    let ipxe = req.is_ipxe();
+    Answer::Reply {
+        message_type,
+        arch,
+        ipxe,
+        boot_file: files.select(arch, ipxe).to_string(),
+    }
+}
+
+/// Where the reply to `req` from `peer` on `port` goes. Offers go back
+/// through the relay if there is one, else to the client's address if it
+/// has one and can take unicast, else to everyone on port 68.
+fn reply_destination(req: &Request, port: u16, peer: SocketAddr) -> SocketAddr {
+    if port == PXE_PORT {
+        return peer;
+    }
+    let broadcast = req.flags[0] & 0x80 != 0;
+    let dest = if !req.giaddr.is_unspecified() {
+        SocketAddrV4::new(req.giaddr, DHCP_PORT)
+    } else if !req.ciaddr.is_unspecified() && !broadcast {
+        SocketAddrV4::new(req.ciaddr, DHCP_CLIENT_PORT)
+    } else {
+        SocketAddrV4::new(Ipv4Addr::BROADCAST, DHCP_CLIENT_PORT)
+    };
+    dest.into()
+}
+
+/// The local address the kernel would send to `dest` from, or the first
+/// IPv4 interface address when `dest` is a broadcast.
+fn local_ip_towards(dest: SocketAddr) -> Option<Ipv4Addr> {
+    let routed = match dest {
+        SocketAddr::V4(v4) if !v4.ip().is_broadcast() => {
+            let sock = std::net::UdpSocket::bind("0.0.0.0:0").ok()?;
+            sock.connect(v4).ok()?;
+            match sock.local_addr().ok()?.ip() {
+                std::net::IpAddr::V4(ip) => Some(ip),
+                std::net::IpAddr::V6(_) => None,
+            }
+        }
+        _ => None,
+    };
+    routed.or_else(|| {
+        if_addrs::get_if_addrs()
+            .ok()?
+            .into_iter()
+            .filter(|iface| !iface.is_loopback())
+            .find_map(|iface| match iface.ip() {
+                std::net::IpAddr::V4(ip) => Some(ip),
+                std::net::IpAddr::V6(_) => None,
+            })
+    })
+}
+
+// ---------------------------------------------------------------------------
+// Responder
+// ---------------------------------------------------------------------------
+
+/// Run the ProxyDHCP responder until `shutdown` changes. Port 67 is often
+/// taken by a DHCP server on the same host; the responder then carries on
+/// with port 4011 alone.
+pub async fn run(
+    options: ProxyDhcpOptions,
+    tx: EventBus,
+    shutdown: watch::Receiver<bool>,
+) -> Result<()> {
+    let mut sockets = Vec::new();
+    for port in [DHCP_PORT, PXE_PORT] {
+        match bind(port) {
+            Ok(sock) => sockets.push((port, sock)),
+            Err(e) => tx.send(ServerEvent::Log(format!(
+                "ProxyDHCP: cannot listen on port {port}: {e}"
+            ))),
+        }
+    }
+    if sockets.is_empty() {
+        return Err(anyhow!("ProxyDHCP has no port to listen on"));
+    }
+    let files = &options.boot_files;
+    tx.send(ServerEvent::Log(format!(
+        "ProxyDHCP listening on port{} {} (BIOS: {}, UEFI x64: {}, ARM64: {}{})",
+        if sockets.len() > 1 { "s" } else { "" },
+        sockets
+            .iter()
+            .map(|(port, _)| port.to_string())
+            .collect::<Vec<_>>()
+            .join(" and "),
+        files.bios,
+        files.efi64,
+        files.arm64,
+        files
+            .ipxe
+            .as_ref()
+            .map(|f| format!(", iPXE: {f}"))
+            .unwrap_or_default(),
+    )));
+    if options.tftp_port != 69 {
+        tx.send(ServerEvent::Log(format!(
+            "ProxyDHCP: PXE firmware fetches boot files from port 69, but TFTP listens on {}",
+            options.tftp_port
+        )));
+    }
+
+    let options = Arc::new(options);
+    let mut tasks = tokio::task::JoinSet::new();
+    for (port, sock) in sockets {
+        tasks.spawn(serve(
+            port,
+            sock,
+            Arc::clone(&options),
+            tx.clone(),
+            shutdown.clone(),
+        ));
+    }
+    while let Some(joined) = tasks.join_next().await {
+        if let Err(e) = joined.map_err(anyhow::Error::from).and_then(|r| r) {
+            tasks.abort_all();
+            return Err(e);
+        }
+    }
+    Ok(())
+}
+
+/// Bind `0.0.0.0:port` with broadcast enabled for offers to port 68.
+fn bind(port: u16) -> Result<UdpSocket> {
+    let addr = SocketAddr::from((Ipv4Addr::UNSPECIFIED, port));
+    let raw = socket2::Socket::new(
+        socket2::Domain::IPV4,
+        socket2::Type::DGRAM,
+        Some(socket2::Protocol::UDP),
+    )?;
+    raw.set_reuse_address(true)?;
+    raw.set_broadcast(true)?;
+    raw.bind(&addr.into())?;
+    raw.set_nonblocking(true)?;
+    Ok(UdpSocket::from_std(raw.into())?)
+}
+
+/// Answer PXE clients on one port.
+async fn serve(
+    port: u16,
+    sock: UdpSocket,
+    options: Arc<ProxyDhcpOptions>,
+    tx: EventBus,
+    mut shutdown: watch::Receiver<bool>,
+) -> Result<()> {
+    let mut buf = vec![0u8; MAX_MESSAGE];
+    loop {
+        tokio::select! {
+            result = sock.recv_from(&mut buf) => {
+                let (n, peer) = result?;
+                let Some(req) = Request::parse(&buf[..n]) else {
+                    continue;
+                };
+                let (message_type, arch, ipxe, boot_file) =
+                    match answer(&req, port, &options.boot_files) {
+                        Answer::Ignore => continue,
+                        Answer::Unsupported(msg) => {
+                            tx.send(ServerEvent::Log(msg));
+                            continue;
+                        }
+                        Answer::Reply {
+                            message_type,
+                            arch,
+                            ipxe,
+                            boot_file,
+                        } => (message_type, arch, ipxe, boot_file),
+                    };
+                let dest = reply_destination(&req, port, peer);
+                let Some(server_ip) = options.server_ip.or_else(|| local_ip_towards(dest)) else {
+                    tx.send(ServerEvent::Log(format!(
+                        "ProxyDHCP: no local IPv4 address to offer {}; set --pxe-server-ip",
+                        req.mac()
+                    )));
+                    continue;
+                };
+                let reply = build_reply(&req, message_type, server_ip, &boot_file);
+                if let Err(e) = sock.send_to(&reply, dest).await {
+                    tx.send(ServerEvent::Log(format!(
+                        "ProxyDHCP: reply to {} via {dest} failed: {e}",
+                        req.mac()
+                    )));
+                    continue;
+                }
+                tx.send(ServerEvent::PxeBoot(PxeBoot {
+                    mac: req.mac(),
+                    client_ip: Some(req.ciaddr).filter(|ip| !ip.is_unspecified()),
+                    arch,
+                    ipxe,
+                    boot_file,
+                    server_ip,
+                    stage: if message_type == DHCPOFFER {
+                        PxeStage::Offer
+                    } else {
+                        PxeStage::Ack
+                    },
+                }));
+            }
+            _ = shutdown.changed() => return Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn files() -> BootFiles {
+        BootFiles {
+            bios: "pxelinux.0".into(),
+            efi64: "bootx64.efi".into(),
+            arm64: "bootaa64.efi".into(),
+            ipxe: Some("boot.ipxe".into()),
+        }
+    }
+
+    /// A client message with the given type and extra options.
+    fn message(message_type: u8, options: &[(u8, &[u8])]) -> Vec<u8> {
+        let mut buf = vec![0u8; HEADER_LEN];
+        buf[0] = BOOTREQUEST;
+        buf[1] = 1;
+        buf[2] = 6;
+        buf[4..8].copy_from_slice(&[0xde, 0xad, 0xbe, 0xef]);
+        buf[10] = 0x80;
+        buf[28..34].copy_from_slice(&[0x52, 0x54, 0x00, 0x12, 0x34, 0x56]);
+        buf.extend_from_slice(&MAGIC_COOKIE);
+        buf.extend_from_slice(&[OPT_MESSAGE_TYPE, 1, message_type]);
+        for (tag, value) in options {
+            buf.push(*tag);
+            buf.push(value.len() as u8);
+            buf.extend_from_slice(value);
+        }
+        buf.push(OPT_END);
+        buf
+    }
+
+    fn boot_file(answer: Answer) -> String {
+        match answer {
+            Answer::Reply { boot_file, .. } => boot_file,
+            other => panic!("expected a reply, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn boot_file_follows_architecture_and_user_class() {
+        let files = files();
+        let discover = |options: &[(u8, &[u8])]| {
+            let buf = message(DHCPDISCOVER, options);
+            answer(&Request::parse(&buf).unwrap(), DHCP_PORT, &files)
+        };
+        let pxe = (OPT_VENDOR_CLASS, &b"PXEClient:Arch:00000:UNDI:002001"[..]);
+        assert_eq!(boot_file(discover(&[pxe])), "pxelinux.0");
+        assert_eq!(
+            boot_file(discover(&[pxe, (OPT_CLIENT_ARCH, &[0, 7])])),
+            "bootx64.efi"
+        );
+        assert_eq!(
+            boot_file(discover(&[(
+                OPT_VENDOR_CLASS,
+                b"PXEClient:Arch:00011:UNDI:003016"
+            )])),
+            "bootaa64.efi"
+        );
+        assert_eq!(
+            boot_file(discover(&[pxe, (OPT_USER_CLASS, b"iPXE")])),
+            "boot.ipxe"
+        );
+        let no_ipxe = BootFiles {
+            ipxe: None,
+            ..files.clone()
+        };
+        let buf = message(DHCPDISCOVER, &[pxe, (OPT_USER_CLASS, b"iPXE")]);
+        assert_eq!(
+            boot_file(answer(&Request::parse(&buf).unwrap(), DHCP_PORT, &no_ipxe)),
+            "pxelinux.0"
+        );
+        assert!(matches!(
+            discover(&[pxe, (OPT_CLIENT_ARCH, &[0, 16])]),
+            Answer::Unsupported(_)
+        ));
+    }
+
+    #[test]
+    fn only_pxe_discovers_and_boot_server_requests_are_answered() {
+        let files = files();
+        let pxe = (OPT_VENDOR_CLASS, &b"PXEClient"[..]);
+        let answer_on = |port, message_type, options: &[(u8, &[u8])]| {
+            let buf = message(message_type, options);
+            answer(&Request::parse(&buf).unwrap(), port, &files)
+        };
+        assert!(matches!(
+            answer_on(DHCP_PORT, DHCPDISCOVER, &[(OPT_VENDOR_CLASS, b"MSFT 5.0")]),
+            Answer::Ignore
+        ));
+        assert!(matches!(
+            answer_on(DHCP_PORT, DHCPREQUEST, &[pxe]),
+            Answer::Ignore
+        ));
+        assert!(matches!(
+            answer_on(PXE_PORT, DHCPDISCOVER, &[pxe]),
+            Answer::Ignore
+        ));
+        assert!(matches!(
+            answer_on(DHCP_PORT, DHCPDISCOVER, &[pxe]),
+            Answer::Reply {
+                message_type: DHCPOFFER,
+                ..
+            }
+        ));
+        assert!(matches!(
+            answer_on(PXE_PORT, DHCPREQUEST, &[pxe]),
+            Answer::Reply {
+                message_type: DHCPACK,
+                ..
+            }
+        ));
+        assert!(Request::parse(&[0; 100]).is_none());
+        let mut truncated = message(DHCPDISCOVER, &[pxe]);
+        truncated.truncate(truncated.len() - 4);
+        assert!(Request::parse(&truncated).is_none());
+    }
+
+    #[test]
+    fn reply_carries_boot_server_but_no_address() {
+        let uuid = [0u8; 17];
+        let buf = message(
+            DHCPDISCOVER,
+            &[(OPT_VENDOR_CLASS, b"PXEClient"), (OPT_CLIENT_UUID, &uuid)],
+        );
+        let req = Request::parse(&buf).unwrap();
+        let server = Ipv4Addr::new(192, 168, 1, 10);
+        let reply = build_reply(&req, DHCPOFFER, server, "bootx64.efi");
+
+        assert_eq!(reply[0], BOOTREPLY);
+        assert_eq!(reply[4..8], [0xde, 0xad, 0xbe, 0xef]);
+        assert_eq!(reply[16..20], [0, 0, 0, 0], "yiaddr must stay zero");
+        assert_eq!(reply[20..24], server.octets());
+        assert_eq!(reply[28..34], [0x52, 0x54, 0x00, 0x12, 0x34, 0x56]);
+        assert!(reply[108..].starts_with(b"bootx64.efi\0"));
+
+        // The reply's options read back like a request's would.
+        let mut parsed = reply.clone();
+        parsed[0] = BOOTREQUEST;
+        let parsed = Request::parse(&parsed).unwrap();
+        assert_eq!(parsed.message_type(), Some(DHCPOFFER));
+        assert_eq!(parsed.option(OPT_SERVER_ID), Some(&server.octets()[..]));
+        assert_eq!(parsed.option(OPT_BOOTFILE), Some(&b"bootx64.efi"[..]));
+        assert_eq!(parsed.option(OPT_TFTP_SERVER), Some(&b"192.168.1.10"[..]));
+        assert_eq!(parsed.option(OPT_CLIENT_UUID), Some(&uuid[..]));
+        assert_eq!(
+            parsed.option(OPT_VENDOR_SPECIFIC),
+            Some(&PXE_BOOT_FILE_DIRECTLY[..])
+        );
+        assert_eq!(req.mac(), "52:54:00:12:34:56");
+
+        // Broadcast flag set and no relay: everyone on port 68.
+        let peer: SocketAddr = "0.0.0.0:68".parse().unwrap();
+        assert_eq!(
+            reply_destination(&req, DHCP_PORT, peer),
+            "255.255.255.255:68".parse::<SocketAddr>().unwrap()
+        );
+        let peer: SocketAddr = "192.168.1.50:4011".parse().unwrap();
+        assert_eq!(reply_destination(&req, PXE_PORT, peer), peer);
+    }
+}
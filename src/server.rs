@@ -1,19 +1,28 @@
-use std::collections::{HashMap, HashSet};
-use std::net::SocketAddr;
+use std::collections::{HashSet, VecDeque};
+use std::net::{IpAddr, SocketAddr};
 use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::{Arc, OnceLock};
 use std::time::Instant;
 
 use anyhow::{Result, anyhow};
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt, BufReader, BufWriter};
 use tokio::net::UdpSocket;
-use tokio::sync::mpsc;
 use tokio::time::{Duration, timeout};
 
+use crate::boot_profiles::{self, BootProfile, ClientId};
+use crate::checksum::{self, Checksummer, Checksums};
+use crate::events::EventBus;
+use crate::limits::{DirQuota, Limit, UploadBudget};
+use crate::proxy_dhcp::PxeBoot;
+use crate::state::{PART_MAX_AGE, SharedState};
 use crate::tftp_protocol::{
-    BLOCK_SIZE, DEFAULT_WINDOWSIZE, MAX_BLKSIZE, MAX_TIMEOUT, MIN_TIMEOUT, NetasciiDecoder,
-    NetasciiEncoder, Packet,
+    BLOCK_SIZE, DEFAULT_WINDOWSIZE, DataBuf, MAX_BLKSIZE, MAX_TIMEOUT, MIN_TIMEOUT,
+    NetasciiDecoder, NetasciiEncoder, Options, Packet, PacketRef, TftpError, ack_bytes,
 };
+use crate::transport::{self, Demux, Endpoint, PortRange, TransferSocket};
+use crate::versions::{self, Installed, Versioning};
 
 /// Maximum UDP datagram size we ever expect (4-byte header + max blksize).
 const MAX_PACKET: usize = 4 + MAX_BLKSIZE;
@@ -24,16 +33,37 @@ const DEFAULT_TIMEOUT_MS: u64 = 500;
 /// Maximum retransmission attempts before giving up.
 const MAX_RETRIES: u32 = 10;
 
+/// Highest `max_retries` accepted from the CLI or the API. Beyond this a
+/// vanished peer holds its transfer slot for hours.
+pub const MAX_RETRIES_LIMIT: u32 = 1000;
+
+/// Read-ahead and write-behind buffer for transfer files, so the blocking
+/// pool is entered once per this many bytes rather than once per block.
+const FILE_BUFFER_SIZE: usize = 256 * 1024;
+
+/// Socket buffer size for the listener in single-port mode, where it
+/// carries the traffic of every transfer.
+const LISTENER_BUFFER_SIZE: usize = 4 * 1024 * 1024;
+
 /// The largest TFTP blksize the OS will allow in a single UDP send.
 /// Detected once at startup by probing the kernel.
 static MAX_SENDABLE_BLKSIZE: OnceLock<usize> = OnceLock::new();
 
+/// Transfer ID counter shared by the TFTP and HTTP servers so that every
+/// transfer shown in the TUI has a unique ID.
+static NEXT_TRANSFER_ID: AtomicU64 = AtomicU64::new(1);
+
+/// Allocate the next unique transfer ID.
+pub(crate) fn next_transfer_id() -> u64 {
+    NEXT_TRANSFER_ID.fetch_add(1, Ordering::Relaxed)
+}
+
 // ---------------------------------------------------------------------------
 // Server configuration
 // ---------------------------------------------------------------------------
 
 /// Runtime-configurable server settings.
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize)]
 pub struct ServerConfig {
     /// Reply timeout in milliseconds (default 500).
     /// Can be overridden by client via RFC 2349 timeout option.
@@ -52,6 +82,61 @@ pub struct ServerConfig {
     pub enable_read: bool,
     /// Whether to enable write (WRQ) requests.
     pub enable_write: bool,
+    /// Compute a CRC32 next to the SHA-256 of every transfer.
+    pub checksum_crc32: bool,
+    /// Compute an MD5 next to the SHA-256 of every transfer.
+    pub checksum_md5: bool,
+    /// Write a `<file>.sha256` sidecar next to every completed upload.
+    pub sha256_sidecar: bool,
+    /// Keep files replaced by an upload as versions. Takes precedence over
+    /// `allow_overwrite`, since nothing is lost.
+    pub versioning: Versioning,
+    /// Versions kept per file, newest first. 0 = no limit.
+    pub keep_versions: usize,
+    /// Delete versions last modified more than this many seconds ago.
+    /// 0 = no limit.
+    pub max_version_age_secs: u64,
+    /// Largest accepted upload in bytes. 0 = no limit.
+    pub max_upload_size: u64,
+    /// Caps on the total size of directories uploads may write into.
+    pub dir_quotas: Vec<DirQuota>,
+    /// Bytes a single client IP may upload per `CLIENT_QUOTA_WINDOW`.
+    /// 0 = no limit.
+    pub client_quota: u64,
+    /// Free disk space, in bytes, that uploads must leave untouched.
+    /// 0 = no check.
+    pub min_free_space: u64,
+    /// Honour the non-standard `offset` option, which resumes an RRQ at
+    /// a byte offset and a WRQ from its preserved `.part` file.
+    pub enable_offset: bool,
+    /// Refuse transfers whose block numbers would roll over past 65535
+    /// unless the client negotiated the `rollover` option.
+    pub strict_rollover: bool,
+    /// Run every transfer over the listening socket instead of a socket
+    /// of its own, demultiplexing by peer address.
+    pub single_port: bool,
+    /// Local ports for per-transfer sockets. `None` = OS-assigned.
+    pub port_range: Option<PortRange>,
+    /// Listener workers sharing the port through `SO_REUSEPORT`.
+    pub workers: usize,
+    /// Send each RRQ window with one `sendmmsg` and receive ACK bursts
+    /// with `recvmmsg` (Linux only).
+    pub batched_io: bool,
+    /// Byte budget of the in-memory cache of files served by RRQ.
+    /// 0 = off.
+    pub cache_size: u64,
+    /// What to do with duplicate and malformed request options.
+    pub option_policy: OptionPolicy,
+    /// Per-client mappings of requested names to the files served for
+    /// them, applied to RRQs before path resolution.
+    pub boot_profiles: Vec<BootProfile>,
+}
+
+impl ServerConfig {
+    /// Whether an upload may target a file that already exists.
+    pub fn accepts_existing(&self) -> bool {
+        self.allow_overwrite || self.versioning != Versioning::Off
+    }
 }
 
 impl Default for ServerConfig {
@@ -64,6 +149,25 @@ impl Default for ServerConfig {
             max_retries: MAX_RETRIES,
             enable_read: true,
             enable_write: true,
+            checksum_crc32: false,
+            checksum_md5: false,
+            sha256_sidecar: false,
+            versioning: Versioning::Off,
+            keep_versions: 0,
+            max_version_age_secs: 0,
+            max_upload_size: 0,
+            dir_quotas: Vec::new(),
+            client_quota: 0,
+            min_free_space: 0,
+            enable_offset: false,
+            strict_rollover: false,
+            single_port: false,
+            port_range: None,
+            workers: 1,
+            batched_io: true,
+            cache_size: 0,
+            option_policy: OptionPolicy::Last,
+            boot_profiles: Vec::new(),
         }
     }
 }
@@ -74,8 +178,10 @@ struct TransferContext {
     id: u64,
     peer: SocketAddr,
     dir: Arc<PathBuf>,
-    tx: mpsc::UnboundedSender<ServerEvent>,
+    tx: EventBus,
     config: Arc<ServerConfig>,
+    state: Arc<SharedState>,
+    endpoint: Endpoint,
 }
 
 /// Probe the kernel for the largest UDP datagram it will accept in a
@@ -153,7 +259,7 @@ const ENOBUFS_MAX_RETRIES: u32 = 50;
 
 /// Send a datagram, retrying transparently on transient ENOBUFS (os error 55)
 /// errors that macOS produces when the outgoing buffer is momentarily full.
-async fn send_resilient(sock: &UdpSocket, buf: &[u8]) -> Result<()> {
+async fn send_resilient(sock: &TransferSocket, buf: &[u8]) -> Result<()> {
     for attempt in 0..ENOBUFS_MAX_RETRIES {
         match sock.send(buf).await {
             Ok(_) => return Ok(()),
@@ -171,40 +277,16 @@ async fn send_resilient(sock: &UdpSocket, buf: &[u8]) -> Result<()> {
     ))
 }
 
-/// Create an ephemeral UDP socket with send/receive buffers sized for the
-/// negotiated block size.  The OS default buffer (~9 KB on macOS) is too
-/// small for blksize values above ~8 KB and causes "No buffer space
-/// available" (ENOBUFS / os error 55).
-async fn bind_transfer_socket(peer: SocketAddr, blksize: usize) -> Result<UdpSocket> {
-    // Build the socket via socket2 so we can set buffer sizes before
-    // handing it to tokio.
-    let domain = if peer.is_ipv6() {
-        socket2::Domain::IPV6
-    } else {
-        socket2::Domain::IPV4
-    };
-    let raw = socket2::Socket::new(domain, socket2::Type::DGRAM, Some(socket2::Protocol::UDP))?;
-
-    // Need room for a comfortable number of packets in flight.
-    let buf_size = ((4 + blksize) * 16).max(256 * 1024);
-    let _ = raw.set_send_buffer_size(buf_size);
-    let _ = raw.set_recv_buffer_size(buf_size);
-
-    // Bind to an OS-assigned port.
-    let bind_addr: SocketAddr = if peer.is_ipv6() {
-        "[::]:0".parse().unwrap()
-    } else {
-        "0.0.0.0:0".parse().unwrap()
-    };
-    raw.bind(&bind_addr.into())?;
-    raw.set_nonblocking(true)?;
-
-    // Convert: socket2 -> std -> tokio.
-    let std_sock: std::net::UdpSocket = raw.into();
-    let sock = UdpSocket::from_std(std_sock)?;
-    sock.connect(peer).await?;
-
-    Ok(sock)
+/// Send a window of DATA packets: batched into `sendmmsg` calls where
+/// available, otherwise one `send_resilient` per packet.
+async fn send_window(sock: &TransferSocket, packets: &[DataBuf]) -> Result<()> {
+    if sock.is_batched() {
+        return Ok(sock.send_batch(packets).await?);
+    }
+    for packet in packets {
+        send_resilient(sock, packet.as_bytes()).await?;
+    }
+    Ok(())
 }
 
 // ---------------------------------------------------------------------------
@@ -212,7 +294,8 @@ async fn bind_transfer_socket(peer: SocketAddr, blksize: usize) -> Result<UdpSoc
 // ---------------------------------------------------------------------------
 
 /// Direction of a transfer.
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
 pub enum TransferKind {
     Download, // RRQ  (client is downloading from us)
     Upload,   // WRQ  (client is uploading to us)
@@ -224,6 +307,8 @@ pub struct TransferInfo {
     pub id: u64,
     pub peer: SocketAddr,
     pub filename: String,
+    /// Resolved path of the file inside the served directory.
+    pub path: PathBuf,
     pub kind: TransferKind,
     /// Total file size in bytes (known for downloads only).
     pub total_bytes: u64,
@@ -235,7 +320,7 @@ pub struct TransferInfo {
     pub size_known: bool,
 }
 
-/// Events emitted by the server for the TUI.
+/// Events emitted by the servers, published on the `EventBus`.
 #[derive(Debug, Clone)]
 pub enum ServerEvent {
     Log(String),
@@ -245,17 +330,38 @@ pub enum ServerEvent {
         transferred: u64,
         total_bytes: u64,
     },
-    TransferComplete(u64),
+    TransferComplete {
+        id: u64,
+        /// Digests of the file contents, when the whole file was streamed.
+        checksums: Option<Checksums>,
+    },
     TransferFailed {
         id: u64,
         error: String,
     },
+    /// The ProxyDHCP responder told a PXE client where to boot from.
+    PxeBoot(PxeBoot),
 }
 
 // ---------------------------------------------------------------------------
 // Option negotiation helpers
 // ---------------------------------------------------------------------------
 
+/// How requests with duplicate or malformed options are handled.
+/// Malformed means a value without a name, a name without a value, or a
+/// value a known option cannot take (e.g. `blksize=4`).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum OptionPolicy {
+    /// Use the first of duplicate options; ignore malformed ones.
+    First,
+    /// Use the last of duplicate options; ignore malformed ones.
+    #[default]
+    Last,
+    /// Refuse the request with ERROR 8 (RFC 2347).
+    Reject,
+}
+
 /// Negotiated transfer parameters.
 #[derive(Debug, Clone)]
 struct NegotiatedOptions {
@@ -265,17 +371,28 @@ struct NegotiatedOptions {
     timeout_ms: u64,
     /// Window size (number of DATA packets sent before waiting for ACK).
     windowsize: u16,
-    /// Options to send in OACK.
-    oack: HashMap<String, String>,
+    /// Requested resume offset (non-standard), if the extension is enabled.
+    offset: Option<u64>,
+    /// Block number following 65535 (`rollover` option, 0 or 1), if the
+    /// client asked for one.
+    rollover: Option<u16>,
+    /// The `tsize` the client sent: 0 for an RRQ, the upload size for a WRQ.
+    tsize: Option<u64>,
+    /// Options to send in OACK, in the order the client sent them.
+    oack: Options,
 }
 
 /// Negotiate all RFC options from client request.
-/// `config` provides the server-side limits.
+/// `config` provides the server-side limits and the `OptionPolicy`; under
+/// `Reject`, the error is the message for ERROR 8.
 fn negotiate_options(
-    client_options: &HashMap<String, String>,
+    client_options: &Options,
     config: &ServerConfig,
-) -> NegotiatedOptions {
-    let mut acked = HashMap::new();
+) -> Result<NegotiatedOptions, String> {
+    let client_options = &resolve_options(client_options, config.option_policy)?;
+    // First known option with a value it cannot take.
+    let mut invalid = None;
+    let mut acked = Options::new();
     let mut blksize = BLOCK_SIZE;
     let mut timeout_ms = config.timeout_ms;
     let mut windowsize = DEFAULT_WINDOWSIZE;
@@ -288,28 +405,25 @@ fn negotiate_options(
     };
 
     // RFC 2348: blksize option.
-    if let Some(val) = client_options.get("blksize")
-        && let Ok(requested) = val.parse::<usize>()
-        && (8..=MAX_BLKSIZE).contains(&requested)
-    {
+    if let Some(requested) = option_value(client_options, "blksize", &mut invalid, |v| {
+        (8..=MAX_BLKSIZE).contains(v)
+    }) {
         blksize = requested.min(effective_max_blksize);
         acked.insert("blksize".to_string(), blksize.to_string());
     }
 
     // RFC 2349: timeout option (in seconds).
-    if let Some(val) = client_options.get("timeout")
-        && let Ok(requested) = val.parse::<u8>()
-        && (MIN_TIMEOUT..=MAX_TIMEOUT).contains(&requested)
-    {
+    if let Some(requested) = option_value(client_options, "timeout", &mut invalid, |v: &u8| {
+        (MIN_TIMEOUT..=MAX_TIMEOUT).contains(v)
+    }) {
         timeout_ms = (requested as u64) * 1000;
         acked.insert("timeout".to_string(), requested.to_string());
     }
 
     // RFC 7440: windowsize option.
-    if let Some(val) = client_options.get("windowsize")
-        && let Ok(requested) = val.parse::<u16>()
-        && requested >= 1
-    {
+    if let Some(requested) = option_value(client_options, "windowsize", &mut invalid, |v: &u16| {
+        *v >= 1
+    }) {
         let max_win = if config.max_window_size > 0 {
             config.max_window_size
         } else {
@@ -322,15 +436,142 @@ fn negotiate_options(
     }
 
     // tsize option: signal that we should report tsize; the caller fills in the value.
-    if client_options.contains_key("tsize") {
+    let tsize = option_value(client_options, "tsize", &mut invalid, |_: &u64| true);
+    if tsize.is_some() {
         acked.insert("tsize".to_string(), "0".to_string());
     }
 
-    NegotiatedOptions {
+    // Non-standard offset extension: the caller validates the value and
+    // echoes the offset it will actually use. Unknown unless enabled.
+    let offset = config
+        .enable_offset
+        .then(|| option_value(client_options, "offset", &mut invalid, |_: &u64| true))
+        .flatten();
+    if let Some(offset) = offset {
+        acked.insert("offset".to_string(), offset.to_string());
+    }
+
+    // De-facto rollover option: where block numbers continue after 65535.
+    let rollover = option_value(client_options, "rollover", &mut invalid, |v: &u16| *v <= 1);
+    if let Some(rollover) = rollover {
+        acked.insert("rollover".to_string(), rollover.to_string());
+    }
+
+    if config.option_policy == OptionPolicy::Reject
+        && let Some(name) = invalid
+    {
+        let value = client_options.get(name).unwrap_or_default();
+        return Err(format!("invalid value \"{value}\" for option \"{name}\""));
+    }
+
+    // Echo options in the order the client sent them.
+    let oack = client_options
+        .iter()
+        .filter_map(|(name, _)| Some((name.to_string(), acked.get(name)?.to_string())))
+        .collect();
+
+    Ok(NegotiatedOptions {
         blksize,
         timeout_ms,
         windowsize,
-        oack: acked,
+        offset,
+        rollover,
+        tsize,
+        oack,
+    })
+}
+
+/// Collapse duplicate options per `policy`. Under `Reject`, duplicates and
+/// malformed fields are an error instead.
+fn resolve_options(options: &Options, policy: OptionPolicy) -> Result<Options, String> {
+    if policy == OptionPolicy::Reject
+        && let Some(problem) = options.malformed()
+    {
+        return Err(problem.to_string());
+    }
+    let mut resolved = Options::new();
+    for (name, value) in options.iter() {
+        if resolved.contains_key(name) {
+            match policy {
+                OptionPolicy::First => continue,
+                OptionPolicy::Last => {}
+                OptionPolicy::Reject => return Err(format!("duplicate option \"{name}\"")),
+            }
+        }
+        resolved.insert(name.to_string(), value.to_string());
+    }
+    Ok(resolved)
+}
+
+/// Value of the known option `name` if the client sent one `valid` accepts.
+/// A value that does not parse or is not valid is recorded in `invalid`.
+fn option_value<T: std::str::FromStr>(
+    options: &Options,
+    name: &'static str,
+    invalid: &mut Option<&'static str>,
+    valid: impl Fn(&T) -> bool,
+) -> Option<T> {
+    let value = options.get(name)?;
+    let parsed = value.parse().ok().filter(|v| valid(v));
+    if parsed.is_none() {
+        invalid.get_or_insert(name);
+    }
+    parsed
+}
+
+/// Block numbering of one transfer: where numbers continue after 65535,
+/// and whether the transfer may get that far at all.
+#[derive(Debug, Clone, Copy)]
+struct BlockNumbers {
+    /// Block following 65535: 0 unless the client negotiated `rollover=1`.
+    rollover: u16,
+    /// False under `strict_rollover` when the client did not negotiate
+    /// `rollover`.
+    may_wrap: bool,
+}
+
+impl BlockNumbers {
+    fn new(negotiated: Option<u16>, config: &ServerConfig) -> Self {
+        Self {
+            rollover: negotiated.unwrap_or(0),
+            may_wrap: negotiated.is_some() || !config.strict_rollover,
+        }
+    }
+
+    /// The block number following `bn`.
+    fn after(self, bn: u16) -> u16 {
+        if bn == u16::MAX {
+            self.rollover
+        } else {
+            bn + 1
+        }
+    }
+
+    /// Refuse up front a transfer of `bytes` in `blksize` blocks that
+    /// would have to roll over without the client's agreement.
+    fn check_size(self, bytes: u64, blksize: usize) -> Result<(), String> {
+        // The last block is always short, possibly empty.
+        let blocks = bytes / blksize as u64 + 1;
+        if self.may_wrap || blocks <= u64::from(u16::MAX) {
+            return Ok(());
+        }
+        Err(format!(
+            "{bytes} bytes need {blocks} blocks of {blksize} bytes, more than 65535 without \
+             rollover; negotiate rollover or a larger blksize"
+        ))
+    }
+
+    /// Advance past `bn`, aborting the transfer (and telling the client)
+    /// when that would roll over without agreement. Catches transfers
+    /// whose size was not known up front.
+    async fn next(self, bn: u16, sock: &TransferSocket) -> Result<u16> {
+        if bn == u16::MAX && !self.may_wrap {
+            let msg =
+                "block numbers would roll over past 65535; negotiate rollover or a larger blksize";
+            sock.send_error(&TftpError::NotDefined(msg.into())).await;
+            return Err(anyhow!(msg));
+        }
+        Ok(self.after(bn))
     }
 }
 
@@ -339,16 +580,40 @@ fn negotiate_options(
 // ---------------------------------------------------------------------------
 
 /// Run the TFTP server. Returns when `shutdown` is dropped.
+///
+/// The config is re-read from `state` for every request, so runtime
+/// changes made through the control API apply to the next transfer.
 pub async fn run(
     port: u16,
     dir: PathBuf,
-    tx: mpsc::UnboundedSender<ServerEvent>,
-    mut shutdown: tokio::sync::watch::Receiver<bool>,
-    config: ServerConfig,
+    tx: EventBus,
+    shutdown: tokio::sync::watch::Receiver<bool>,
+    state: Arc<SharedState>,
 ) -> Result<()> {
+    let config = state.config();
     let addr: SocketAddr = format!("0.0.0.0:{port}").parse()?;
-    let sock = UdpSocket::bind(addr).await?;
-    tx.send(ServerEvent::Log(format!("Listening on {addr}")))?;
+    let listeners = bind_listeners(addr, config.workers.max(1))?;
+    if listeners.len() > 1 {
+        tx.send(ServerEvent::Log(format!(
+            "Listening on {addr} ({} workers)",
+            listeners.len()
+        )));
+    } else {
+        tx.send(ServerEvent::Log(format!("Listening on {addr}")));
+    }
+    if config.single_port {
+        // Every transfer's traffic queues on the listening sockets.
+        for sock in &listeners {
+            let raw = socket2::SockRef::from(sock);
+            let _ = raw.set_send_buffer_size(LISTENER_BUFFER_SIZE);
+            let _ = raw.set_recv_buffer_size(LISTENER_BUFFER_SIZE);
+        }
+        tx.send(ServerEvent::Log(format!(
+            "Single-port mode: all transfers use {addr}"
+        )));
+    } else if let Some(range) = config.port_range {
+        tx.send(ServerEvent::Log(format!("Transfer ports: {range}")));
+    }
 
     let detected_blksize = max_blksize();
     let effective_max_blksize = if config.max_block_size > 0 {
@@ -358,35 +623,120 @@ pub async fn run(
     };
     tx.send(ServerEvent::Log(format!(
         "Max negotiable blksize: {effective_max_blksize}"
-    )))?;
+    )));
     if config.max_window_size > 1 {
         tx.send(ServerEvent::Log(format!(
             "Max window size: {}",
             config.max_window_size
-        )))?;
+        )));
     }
     tx.send(ServerEvent::Log(format!(
         "Default timeout: {}ms",
         config.timeout_ms
-    )))?;
+    )));
+
+    let dispatch = Arc::new(Dispatch {
+        dir: Arc::new(dir),
+        tx: tx.clone(),
+        state,
+        reqs_in_progress: Arc::new(tokio::sync::Mutex::new(HashSet::new())),
+        demux: Arc::new(Demux::default()),
+    });
+    let mut workers = tokio::task::JoinSet::new();
+    for sock in listeners {
+        workers.spawn(listen(
+            Arc::new(sock),
+            Arc::clone(&dispatch),
+            shutdown.clone(),
+        ));
+    }
+    // The first worker to fail takes the others down with it.
+    let mut result = Ok(());
+    while let Some(joined) = workers.join_next().await {
+        if let Err(e) = joined.map_err(anyhow::Error::from).and_then(|r| r) {
+            workers.abort_all();
+            if result.is_ok() {
+                result = Err(e);
+            }
+        }
+    }
+    if result.is_ok() {
+        tx.send(ServerEvent::Log("Shutting down".into()));
+    }
+    result
+}
 
-    let dir = Arc::new(dir);
-    let config = Arc::new(config);
-    let mut buf = vec![0u8; MAX_PACKET];
-    let mut next_id: u64 = 1;
+/// Bind `workers` sockets to `addr`. With more than one, `SO_REUSEPORT`
+/// lets the kernel spread incoming requests across them by peer address,
+/// so every datagram from one peer reaches the same worker.
+fn bind_listeners(addr: SocketAddr, workers: usize) -> Result<Vec<UdpSocket>> {
+    let bind = |reuse_port: bool| -> Result<UdpSocket> {
+        let raw = socket2::Socket::new(
+            socket2::Domain::for_address(addr),
+            socket2::Type::DGRAM,
+            Some(socket2::Protocol::UDP),
+        )?;
+        #[cfg(unix)]
+        raw.set_reuse_port(reuse_port)?;
+        #[cfg(not(unix))]
+        if reuse_port {
+            return Err(anyhow!(
+                "--workers needs SO_REUSEPORT, which this platform lacks"
+            ));
+        }
+        raw.bind(&addr.into())?;
+        raw.set_nonblocking(true)?;
+        Ok(UdpSocket::from_std(raw.into())?)
+    };
+    (0..workers).map(|_| bind(workers > 1)).collect()
+}
 
-    // Track in-progress transfers to reject duplicate requests from the same peer.
-    let reqs_in_progress: Arc<tokio::sync::Mutex<HashSet<SocketAddr>>> =
-        Arc::new(tokio::sync::Mutex::new(HashSet::new()));
+/// State shared by the listener workers.
+struct Dispatch {
+    dir: Arc<PathBuf>,
+    tx: EventBus,
+    state: Arc<SharedState>,
+    /// Peers with a transfer in progress, so duplicate requests are
+    /// rejected whichever worker receives them.
+    reqs_in_progress: Arc<tokio::sync::Mutex<HashSet<SocketAddr>>>,
+    /// Routes datagrams to transfers sharing the listening sockets.
+    demux: Arc<Demux>,
+}
+
+/// One listener worker: receive requests on `sock` and spawn their
+/// transfers until `shutdown` fires.
+async fn listen(
+    sock: Arc<UdpSocket>,
+    dispatch: Arc<Dispatch>,
+    mut shutdown: tokio::sync::watch::Receiver<bool>,
+) -> Result<()> {
+    let Dispatch {
+        dir,
+        tx,
+        state,
+        reqs_in_progress,
+        demux,
+    } = dispatch.as_ref();
+    let mut buf = vec![0u8; MAX_PACKET];
 
     loop {
         tokio::select! {
             result = sock.recv_from(&mut buf) => {
                 let (n, peer) = result?;
+                if demux.deliver(peer, &buf[..n]) {
+                    continue;
+                }
+                let config = Arc::new(state.config());
+                let listener = config.single_port.then_some(sock.as_ref());
                 let pkt = match Packet::from_bytes(&buf[..n]) {
                     Ok(p) => p,
                     Err(e) => {
-                        let _ = tx.send(ServerEvent::Log(format!("{peer}: bad packet: {e}")));
+                        tx.send(ServerEvent::Log(format!("{peer}: bad packet: {e}")));
+                        // Never answer an ERROR, even a garbled one.
+                        if !buf[..n].starts_with(&[0, 5]) {
+                            let err = TftpError::IllegalOperation(e.to_string());
+                            transport::send_error(listener, peer, &err).await;
+                        }
                         continue;
                     }
                 };
@@ -394,12 +744,9 @@ pub async fn run(
                 match pkt {
                     Packet::RRQ { filename, mode, options } => {
                         if !config.enable_read {
-                            let _ = tx.send(ServerEvent::Log(format!("{peer}: RRQ rejected (reads disabled)")));
-                            // Send error on a temporary socket.
-                            if let Ok(tmp) = UdpSocket::bind("0.0.0.0:0").await {
-                                let err = Packet::ERROR { code: 2, msg: "Read access denied".into() };
-                                let _ = tmp.send_to(&err.to_bytes(), peer).await;
-                            }
+                            tx.send(ServerEvent::Log(format!("{peer}: RRQ rejected (reads disabled)")));
+                            let err = TftpError::AccessViolation("Read access denied".into());
+                            transport::send_error(listener, peer, &err).await;
                             continue;
                         }
 
@@ -407,33 +754,39 @@ pub async fn run(
                         {
                             let mut in_progress = reqs_in_progress.lock().await;
                             if !in_progress.insert(peer) {
-                                let _ = tx.send(ServerEvent::Log(format!("{peer}: duplicate RRQ ignored (transfer in progress)")));
+                                tx.send(ServerEvent::Log(format!("{peer}: duplicate RRQ ignored (transfer in progress)")));
                                 continue;
                             }
                         }
 
-                        let id = next_id;
-                        next_id += 1;
+                        let id = next_transfer_id();
                         let tx2 = tx.clone();
-                        let dir2 = Arc::clone(&dir);
+                        let dir2 = Arc::clone(dir);
                         let cfg = Arc::clone(&config);
-                        let rip = Arc::clone(&reqs_in_progress);
+                        let st = Arc::clone(state);
+                        let rip = Arc::clone(reqs_in_progress);
+                        let dm = Arc::clone(demux);
+                        let endpoint = Endpoint {
+                            listener: Arc::clone(&sock),
+                            peer,
+                            inbox: config.single_port.then(|| demux.register(peer)),
+                            cancel: state.register_transfer(id),
+                        };
                         tokio::spawn(async move {
-                            let result = handle_rrq(TransferContext { id, peer, dir: dir2, tx: tx2.clone(), config: cfg }, &filename, &mode, &options).await;
+                            let result = handle_rrq(TransferContext { id, peer, dir: dir2, tx: tx2.clone(), config: cfg, state: st, endpoint }, &filename, &mode, &options).await;
+                            dm.unregister(peer);
                             rip.lock().await.remove(&peer);
                             if let Err(e) = result {
-                                let _ = tx2.send(ServerEvent::TransferFailed { id, error: e.to_string() });
-                                let _ = tx2.send(ServerEvent::Log(format!("{peer}: RRQ error: {e}")));
+                                tx2.send(ServerEvent::TransferFailed { id, error: e.to_string() });
+                                tx2.send(ServerEvent::Log(format!("{peer}: RRQ error: {e}")));
                             }
                         });
                     }
                     Packet::WRQ { filename, mode, options } => {
                         if !config.enable_write {
-                            let _ = tx.send(ServerEvent::Log(format!("{peer}: WRQ rejected (writes disabled)")));
-                            if let Ok(tmp) = UdpSocket::bind("0.0.0.0:0").await {
-                                let err = Packet::ERROR { code: 2, msg: "Write access denied".into() };
-                                let _ = tmp.send_to(&err.to_bytes(), peer).await;
-                            }
+                            tx.send(ServerEvent::Log(format!("{peer}: WRQ rejected (writes disabled)")));
+                            let err = TftpError::AccessViolation("Write access denied".into());
+                            transport::send_error(listener, peer, &err).await;
                             continue;
                         }
 
@@ -441,43 +794,51 @@ pub async fn run(
                         {
                             let mut in_progress = reqs_in_progress.lock().await;
                             if !in_progress.insert(peer) {
-                                let _ = tx.send(ServerEvent::Log(format!("{peer}: duplicate WRQ ignored (transfer in progress)")));
+                                tx.send(ServerEvent::Log(format!("{peer}: duplicate WRQ ignored (transfer in progress)")));
                                 continue;
                             }
                         }
 
-                        let id = next_id;
-                        next_id += 1;
+                        let id = next_transfer_id();
                         let tx2 = tx.clone();
-                        let dir2 = Arc::clone(&dir);
+                        let dir2 = Arc::clone(dir);
                         let cfg = Arc::clone(&config);
-                        let rip = Arc::clone(&reqs_in_progress);
+                        let st = Arc::clone(state);
+                        let rip = Arc::clone(reqs_in_progress);
+                        let dm = Arc::clone(demux);
+                        let endpoint = Endpoint {
+                            listener: Arc::clone(&sock),
+                            peer,
+                            inbox: config.single_port.then(|| demux.register(peer)),
+                            cancel: state.register_transfer(id),
+                        };
                         tokio::spawn(async move {
-                            let result = handle_wrq(TransferContext { id, peer, dir: dir2.clone(), tx: tx2.clone(), config: cfg }, &filename, &mode, &options).await;
+                            let result = handle_wrq(TransferContext { id, peer, dir: dir2, tx: tx2.clone(), config: cfg, state: st, endpoint }, &filename, &mode, &options).await;
+                            dm.unregister(peer);
                             rip.lock().await.remove(&peer);
                             if let Err(e) = result {
-                                // Clean up the incomplete .part file.
-                                if let Ok(final_path) = sanitize_path(&dir2, &filename) {
-                                    let mut part = final_path.into_os_string();
-                                    part.push(".part");
-                                    let _ = tokio::fs::remove_file(PathBuf::from(part)).await;
-                                }
-                                let _ = tx2.send(ServerEvent::TransferFailed { id, error: e.to_string() });
-                                let _ = tx2.send(ServerEvent::Log(format!("{peer}: WRQ error: {e}")));
+                                tx2.send(ServerEvent::TransferFailed { id, error: e.to_string() });
+                                tx2.send(ServerEvent::Log(format!("{peer}: WRQ error: {e}")));
                             }
                         });
                     }
                     other => {
-                        let _ = tx.send(ServerEvent::Log(format!(
+                        tx.send(ServerEvent::Log(format!(
                             "{peer}: unexpected packet on listener: {other:?}"
                         )));
+                        // In single-port mode the listening port is every
+                        // transfer's TID, so DATA or ACK from a peer without
+                        // a transfer belongs to none (RFC 1350 section 4).
+                        if let Some(listener) = listener
+                            && matches!(other, Packet::DATA { .. } | Packet::ACK { .. })
+                        {
+                            let err = TftpError::UnknownTransferId;
+                            transport::send_error(Some(listener), peer, &err).await;
+                        }
                     }
                 }
             }
-            _ = shutdown.changed() => {
-                tx.send(ServerEvent::Log("Shutting down".into()))?;
-                break;
-            }
+            _ = shutdown.changed() => break,
         }
     }
     Ok(())
@@ -491,7 +852,7 @@ async fn handle_rrq(
     ctx: TransferContext,
     filename: &str,
     mode: &str,
-    options: &HashMap<String, String>,
+    options: &Options,
 ) -> Result<()> {
     let TransferContext {
         id,
@@ -499,27 +860,41 @@ async fn handle_rrq(
         dir,
         tx,
         config,
+        state,
+        endpoint,
     } = ctx;
     let dir = dir.as_path();
     let config = config.as_ref();
-    let path = sanitize_path(dir, filename)?;
-    let metadata = tokio::fs::metadata(&path)
-        .await
-        .map_err(|e| match e.kind() {
-            std::io::ErrorKind::NotFound => {
-                anyhow!("file not found: {}", path.display())
-            }
-            std::io::ErrorKind::PermissionDenied => {
-                anyhow!("permission denied: {}", path.display())
-            }
-            _ => anyhow!("cannot read {}: {e}", path.display()),
-        })?;
+    let profile = match_boot_profile(config, &state, filename, peer.ip());
+    let filename = profile.as_ref().map_or(filename, |(p, _)| p.file.as_str());
+    let path = resolve_path(&endpoint, dir, filename).await?;
+    let metadata = match tokio::fs::metadata(&path).await {
+        Ok(metadata) => metadata,
+        Err(e) => {
+            endpoint.reject(&TftpError::from(&e)).await;
+            return Err(match e.kind() {
+                std::io::ErrorKind::NotFound => anyhow!("file not found: {}", path.display()),
+                std::io::ErrorKind::PermissionDenied => {
+                    anyhow!("permission denied: {}", path.display())
+                }
+                _ => anyhow!("cannot read {}: {e}", path.display()),
+            });
+        }
+    };
     let total_bytes = metadata.len();
 
     let is_netascii = mode == "netascii";
 
     // Negotiate options (blksize, timeout, windowsize, tsize).
-    let negotiated = negotiate_options(options, config);
+    let negotiated = match negotiate_options(options, config) {
+        Ok(negotiated) => negotiated,
+        Err(msg) => {
+            endpoint
+                .reject(&TftpError::OptionRefused(msg.clone()))
+                .await;
+            return Err(anyhow!("option negotiation failed: {msg}"));
+        }
+    };
     let blksize = negotiated.blksize;
     let timeout_dur = Duration::from_millis(negotiated.timeout_ms);
     let windowsize = negotiated.windowsize;
@@ -530,6 +905,35 @@ async fn handle_rrq(
         oack_options.insert("tsize".to_string(), total_bytes.to_string());
     }
 
+    // Resume at a byte offset (non-standard extension). Offsets count file
+    // bytes, which do not map onto netascii's encoded stream, so the option
+    // is declined there.
+    let offset = match negotiated.offset {
+        Some(_) if is_netascii => {
+            oack_options.remove("offset");
+            0
+        }
+        Some(offset) if offset > total_bytes => {
+            let msg = format!("offset {offset} is beyond the end of the file");
+            endpoint.reject(&TftpError::OptionRefused(msg)).await;
+            return Err(anyhow!(
+                "offset {offset} is beyond the end of {} ({total_bytes} bytes)",
+                path.display()
+            ));
+        }
+        Some(offset) => offset,
+        None => 0,
+    };
+
+    // Refuse now, not at block 65535, a transfer that needs a rollover the
+    // client did not agree to. netascii only grows the stream, so the file
+    // size is a lower bound there; `BlockNumbers::next` catches the rest.
+    let blocks = BlockNumbers::new(negotiated.rollover, config);
+    if let Err(msg) = blocks.check_size(total_bytes - offset, blksize) {
+        endpoint.reject(&TftpError::NotDefined(msg.clone())).await;
+        return Err(anyhow!("RRQ refused: {msg}"));
+    }
+
     let mut detail_parts = Vec::new();
     if blksize != BLOCK_SIZE {
         detail_parts.push(format!("blksize={blksize}"));
@@ -543,6 +947,18 @@ async fn handle_rrq(
     if is_netascii {
         detail_parts.push("netascii".to_string());
     }
+    if let Some(rollover) = negotiated.rollover {
+        detail_parts.push(format!("rollover={rollover}"));
+    }
+    if oack_options.contains_key("offset") {
+        detail_parts.push(format!("offset={offset} (non-standard extension)"));
+    }
+    if let Some((profile, mac)) = &profile {
+        detail_parts.push(match mac {
+            Some(mac) => format!("boot profile {profile} for {mac}"),
+            None => format!("boot profile {profile}"),
+        });
+    }
     let detail_str = if detail_parts.is_empty() {
         String::new()
     } else {
@@ -551,20 +967,21 @@ async fn handle_rrq(
 
     tx.send(ServerEvent::Log(format!(
         "{peer}: RRQ \"{filename}\" ({total_bytes} bytes){detail_str}"
-    )))?;
+    )));
     tx.send(ServerEvent::TransferStarted(TransferInfo {
         id,
         peer,
         filename: filename.to_string(),
+        path: path.clone(),
         kind: TransferKind::Download,
         total_bytes,
-        transferred: 0,
+        transferred: offset,
         started: Instant::now(),
         size_known: true,
-    }))?;
+    }));
 
     // Bind an ephemeral socket for this transfer with appropriately sized buffers.
-    let sock = bind_transfer_socket(peer, blksize).await?;
+    let mut sock = endpoint.open(blksize, config).await?;
     let mut recv_buf = vec![0u8; MAX_PACKET];
     let max_retries = config.max_retries;
 
@@ -580,12 +997,9 @@ async fn handle_rrq(
             send_resilient(&sock, &oack_bytes).await?;
             match timeout(timeout_dur, sock.recv(&mut recv_buf)).await {
                 Ok(Ok(n)) => {
-                    let ack = Packet::from_bytes(&recv_buf[..n])?;
-                    match ack {
-                        Packet::ACK { block_num: 0 } => break,
-                        Packet::ERROR { code, msg } => {
-                            return Err(anyhow!("client error {code}: {msg}"));
-                        }
+                    match parse_reply(&sock, &recv_buf[..n]).await? {
+                        PacketRef::ACK { block_num: 0 } => break,
+                        PacketRef::ERROR { code, msg } => return Err(client_error(code, msg)),
                         _ => { /* retry */ }
                     }
                 }
@@ -600,86 +1014,107 @@ async fn handle_rrq(
         }
     }
 
-    // Stream the file.
-    let mut file = tokio::fs::File::open(&path)
+    // Stream the file, from the shared read cache if it is hot.
+    let mut file: Box<dyn AsyncRead + Send + Unpin> = match state
+        .read_cache()
+        .get(&path, &metadata, config.cache_size)
         .await
-        .map_err(|e| anyhow!("cannot open {}: {e}", path.display()))?;
-    let mut block_buf = vec![0u8; blksize];
+    {
+        Some(data) => Box::new(std::io::Cursor::new(data)),
+        None => match tokio::fs::File::open(&path).await {
+            Ok(file) => Box::new(BufReader::with_capacity(FILE_BUFFER_SIZE, file)),
+            Err(e) => {
+                sock.send_error(&TftpError::from(&e)).await;
+                return Err(anyhow!("cannot open {}: {e}", path.display()));
+            }
+        },
+    };
+    // Raw file bytes ahead of netascii encoding; octet reads go straight
+    // into the packet.
+    let mut raw_buf = vec![0u8; if is_netascii { blksize } else { 0 }];
     let mut block_num: u16 = 1;
-    let mut transferred: u64 = 0;
+    let mut transferred: u64 = offset;
     let mut encoder = if is_netascii {
         Some(NetasciiEncoder::new())
     } else {
         None
     };
+    let mut hasher = Checksummer::new(config);
+    or_abort(&sock, hash_prefix(&mut file, offset, &mut hasher).await).await?;
 
     // --- Windowed transfer (RFC 7440) ---
     if windowsize > 1 {
         // Read and send `windowsize` DATA blocks, then wait for ACK.
-        // The ACK may acknowledge any block in the window.
+        // The ACK may acknowledge any block in the window. Packets are
+        // built in a fixed set of buffers that move between the window
+        // and `spare` as blocks are sent and acknowledged.
+        let windowsize = windowsize as usize;
+        let mut window: VecDeque<DataBuf> = VecDeque::with_capacity(windowsize);
+        let mut spare: Vec<DataBuf> = (0..windowsize).map(|_| DataBuf::new(blksize)).collect();
         loop {
-            let mut window: Vec<(u16, Vec<u8>)> = Vec::with_capacity(windowsize as usize);
             let mut last_block = false;
 
             // Fill the window.
-            for _ in 0..windowsize {
-                let payload =
-                    read_next_block(&mut file, &mut block_buf, blksize, &mut encoder).await?;
-                let is_last = payload.len() < blksize && !has_encoder_overflow(&encoder);
-                window.push((block_num, payload));
-                if is_last {
+            while let Some(mut packet) = spare.pop() {
+                let len = or_abort(
+                    &sock,
+                    read_next_block(
+                        &mut file,
+                        &mut raw_buf,
+                        &mut packet,
+                        blksize,
+                        &mut encoder,
+                        &mut hasher,
+                    )
+                    .await,
+                )
+                .await?;
+                packet.set_block(block_num);
+                window.push_back(packet);
+                if len < blksize && !has_encoder_overflow(&encoder) {
                     last_block = true;
                     break;
                 }
-                block_num = block_num.wrapping_add(1);
+                block_num = blocks.next(block_num, &sock).await?;
             }
 
-            let window_end = window.last().map(|(bn, _)| *bn).unwrap_or(block_num);
+            let window_end = window.back().map_or(block_num, DataBuf::block);
 
             // Send all blocks in the window.
             let mut retries = 0u32;
             loop {
-                for (bn, payload) in &window {
-                    let mut pkt_bytes = Vec::with_capacity(4 + payload.len());
-                    pkt_bytes.extend_from_slice(&3u16.to_be_bytes());
-                    pkt_bytes.extend_from_slice(&bn.to_be_bytes());
-                    pkt_bytes.extend_from_slice(payload);
-                    send_resilient(&sock, &pkt_bytes).await?;
-                }
+                send_window(&sock, window.make_contiguous()).await?;
 
                 // Wait for ACK for any block in the window.
-                match timeout(timeout_dur, sock.recv(&mut recv_buf)).await {
+                match timeout(timeout_dur, sock.recv_ack(&mut recv_buf)).await {
                     Ok(Ok(n)) => {
-                        let ack = Packet::from_bytes(&recv_buf[..n])?;
-                        match ack {
-                            Packet::ACK { block_num: bn } => {
+                        match parse_reply(&sock, &recv_buf[..n]).await? {
+                            PacketRef::ACK { block_num: bn } => {
                                 // Check if this ACK is for the end of our window.
                                 if bn == window_end {
                                     // Full window acknowledged.
-                                    let total_payload: u64 =
-                                        window.iter().map(|(_, p)| p.len() as u64).sum();
-                                    transferred += total_payload;
+                                    for packet in window.drain(..) {
+                                        transferred += packet.payload().len() as u64;
+                                        spare.push(packet);
+                                    }
                                     break;
                                 } else if is_in_window(bn, &window) {
-                                    // Partial ACK — remove acknowledged blocks and resend rest.
-                                    let mut acked_bytes: u64 = 0;
-                                    while !window.is_empty() && window[0].0 != bn.wrapping_add(1) {
-                                        let (_, p) = window.remove(0);
-                                        acked_bytes += p.len() as u64;
-                                        if window.is_empty() {
+                                    // Partial ACK — release acknowledged blocks and resend rest.
+                                    while let Some(packet) = window.pop_front() {
+                                        if packet.block() == blocks.after(bn) {
+                                            window.push_front(packet);
                                             break;
                                         }
+                                        transferred += packet.payload().len() as u64;
+                                        spare.push(packet);
                                     }
-                                    transferred += acked_bytes;
                                     // Continue loop to resend remaining blocks.
                                     retries = 0;
                                     continue;
                                 }
                                 // ACK for wrong block, retry.
                             }
-                            Packet::ERROR { code, msg } => {
-                                return Err(anyhow!("client error {code}: {msg}"));
-                            }
+                            PacketRef::ERROR { code, msg } => return Err(client_error(code, msg)),
                             _ => {}
                         }
                     }
@@ -699,35 +1134,41 @@ async fn handle_rrq(
                 id,
                 transferred,
                 total_bytes,
-            })?;
+            });
 
+            // Filling the window already advanced `block_num` past it.
             if last_block {
                 break;
             }
-            block_num = block_num.wrapping_add(1);
         }
     } else {
         // --- Classic single-block transfer ---
+        let mut packet = DataBuf::new(blksize);
         loop {
-            let payload = read_next_block(&mut file, &mut block_buf, blksize, &mut encoder).await?;
-            let is_last = payload.len() < blksize && !has_encoder_overflow(&encoder);
-
-            let mut pkt_bytes = Vec::with_capacity(4 + payload.len());
-            pkt_bytes.extend_from_slice(&3u16.to_be_bytes()); // OPCODE_DATA
-            pkt_bytes.extend_from_slice(&block_num.to_be_bytes());
-            pkt_bytes.extend_from_slice(&payload);
+            let len = or_abort(
+                &sock,
+                read_next_block(
+                    &mut file,
+                    &mut raw_buf,
+                    &mut packet,
+                    blksize,
+                    &mut encoder,
+                    &mut hasher,
+                )
+                .await,
+            )
+            .await?;
+            let is_last = len < blksize && !has_encoder_overflow(&encoder);
+            packet.set_block(block_num);
 
             let mut retries = 0u32;
             loop {
-                send_resilient(&sock, &pkt_bytes).await?;
+                send_resilient(&sock, packet.as_bytes()).await?;
                 match timeout(timeout_dur, sock.recv(&mut recv_buf)).await {
                     Ok(Ok(n)) => {
-                        let ack = Packet::from_bytes(&recv_buf[..n])?;
-                        match ack {
-                            Packet::ACK { block_num: bn } if bn == block_num => break,
-                            Packet::ERROR { code, msg } => {
-                                return Err(anyhow!("client error {code}: {msg}"));
-                            }
+                        match parse_reply(&sock, &recv_buf[..n]).await? {
+                            PacketRef::ACK { block_num: bn } if bn == block_num => break,
+                            PacketRef::ERROR { code, msg } => return Err(client_error(code, msg)),
                             _ => { /* duplicate / wrong block – resend */ }
                         }
                     }
@@ -741,63 +1182,89 @@ async fn handle_rrq(
                 }
             }
 
-            transferred += payload.len() as u64;
+            transferred += len as u64;
             tx.send(ServerEvent::TransferProgress {
                 id,
                 transferred,
                 total_bytes,
-            })?;
+            });
 
             if is_last {
                 break;
             }
-            block_num = block_num.wrapping_add(1);
+            block_num = blocks.next(block_num, &sock).await?;
         }
     }
 
-    tx.send(ServerEvent::TransferComplete(id))?;
+    let checksums = hasher.finish();
+    let resumed = if offset > 0 {
+        format!(" from offset {offset}")
+    } else {
+        String::new()
+    };
     tx.send(ServerEvent::Log(format!(
-        "{peer}: RRQ \"{filename}\" complete ({transferred} bytes transferred)"
-    )))?;
+        "{peer}: RRQ \"{filename}\" complete ({} bytes transferred{resumed}, {checksums})",
+        transferred - offset
+    )));
+    tx.send(ServerEvent::TransferComplete {
+        id,
+        checksums: Some(checksums),
+    });
     Ok(())
 }
 
-/// Read the next block from a file, applying netascii encoding if needed.
+/// Read the next block from a file into `packet`'s payload, applying
+/// netascii encoding if needed, and return its length. The raw file bytes
+/// are fed to `hasher` before encoding; `raw` holds them while encoding.
 async fn read_next_block(
-    file: &mut tokio::fs::File,
-    buf: &mut [u8],
+    file: &mut (impl AsyncRead + Unpin),
+    raw: &mut [u8],
+    packet: &mut DataBuf,
     blksize: usize,
     encoder: &mut Option<NetasciiEncoder>,
-) -> Result<Vec<u8>> {
-    if let Some(enc) = encoder {
-        // If there's overflow from a previous encode, drain it first.
-        if enc.has_overflow() {
-            let data = enc.drain_overflow(blksize);
-            if data.len() >= blksize {
-                return Ok(data);
-            }
-            // Not enough overflow to fill a block — read more from file.
-            let bytes_read = file.read(buf).await?;
-            if bytes_read == 0 {
-                return Ok(data);
+    hasher: &mut Checksummer,
+) -> Result<usize> {
+    let out = packet.payload_mut(blksize);
+    let len = if let Some(enc) = encoder {
+        // Overflow from a previous encode goes first; read more from the
+        // file only if it does not fill the block.
+        let mut len = enc.drain_overflow_into(out);
+        if len < blksize {
+            let bytes_read = read_full(file, raw).await?;
+            if bytes_read > 0 {
+                hasher.update(&raw[..bytes_read]);
+                len += enc.encode_into(&raw[..bytes_read], &mut out[len..]);
             }
-            let raw = &buf[..bytes_read];
-            let mut encoded = enc.encode(raw, blksize - data.len());
-            let mut combined = data;
-            combined.append(&mut encoded);
-            return Ok(combined);
         }
+        len
+    } else {
+        let bytes_read = read_full(file, out).await?;
+        hasher.update(&out[..bytes_read]);
+        bytes_read
+    };
+    packet.set_len(len);
+    Ok(len)
+}
 
-        let bytes_read = file.read(buf).await?;
-        if bytes_read == 0 {
-            return Ok(Vec::new());
+/// Read until `buf` is full or the file ends. A short block marks the end
+/// of a transfer, so a read cut short by a buffer boundary must not end it.
+async fn read_full(file: &mut (impl AsyncRead + Unpin), buf: &mut [u8]) -> Result<usize> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match file.read(&mut buf[filled..]).await? {
+            0 => break,
+            n => filled += n,
         }
-        let raw = &buf[..bytes_read];
-        Ok(enc.encode(raw, blksize))
-    } else {
-        let bytes_read = file.read(buf).await?;
-        Ok(buf[..bytes_read].to_vec())
     }
+    Ok(filled)
+}
+
+/// The error a transfer ends with when the client sends ERROR, with the
+/// typed error as its source. The client is not answered.
+fn client_error(code: u16, msg: &[u8]) -> anyhow::Error {
+    let msg = String::from_utf8_lossy(msg);
+    anyhow::Error::new(TftpError::from_code(code, &msg))
+        .context(format!("client error {code}: {msg}"))
 }
 
 /// Check if an encoder has pending overflow data.
@@ -806,8 +1273,8 @@ fn has_encoder_overflow(encoder: &Option<NetasciiEncoder>) -> bool {
 }
 
 /// Check if a block number is within the current window.
-fn is_in_window(bn: u16, window: &[(u16, Vec<u8>)]) -> bool {
-    window.iter().any(|(b, _)| *b == bn)
+fn is_in_window(bn: u16, window: &VecDeque<DataBuf>) -> bool {
+    window.iter().any(|packet| packet.block() == bn)
 }
 
 // ---------------------------------------------------------------------------
@@ -818,7 +1285,7 @@ async fn handle_wrq(
     ctx: TransferContext,
     filename: &str,
     mode: &str,
-    options: &HashMap<String, String>,
+    options: &Options,
 ) -> Result<()> {
     let TransferContext {
         id,
@@ -826,36 +1293,102 @@ async fn handle_wrq(
         dir,
         tx,
         config,
+        state,
+        endpoint,
     } = ctx;
     let dir = dir.as_path();
     let config = config.as_ref();
-    let path = sanitize_path(dir, filename)?;
+    let path = resolve_path(&endpoint, dir, filename).await?;
 
     // Overwrite protection.
-    if !config.allow_overwrite && path.exists() {
-        // Send error to client on a temporary socket.
-        if let Ok(tmp) = UdpSocket::bind("0.0.0.0:0").await {
-            let err = Packet::ERROR {
-                code: 6,
-                msg: "File already exists".into(),
-            };
-            let _ = tmp.send_to(&err.to_bytes(), peer).await;
-        }
+    if !config.accepts_existing() && path.exists() {
+        endpoint.reject(&TftpError::FileExists).await;
         return Err(anyhow!("file already exists: {}", path.display()));
     }
 
+    // Negotiate options (blksize, timeout, windowsize).
+    let negotiated = match negotiate_options(options, config) {
+        Ok(negotiated) => negotiated,
+        Err(msg) => {
+            endpoint
+                .reject(&TftpError::OptionRefused(msg.clone()))
+                .await;
+            return Err(anyhow!("option negotiation failed: {msg}"));
+        }
+    };
+
+    // Expected size from the tsize option, if the client sent one.
+    let expected_size = negotiated.tsize.unwrap_or(0);
+
+    // Size limit, quotas and free space. A tsize over budget is refused
+    // before any data is sent; otherwise the budget is enforced per block.
+    let mut budget = {
+        let (config, dir, path) = (config.clone(), dir.to_path_buf(), path.clone());
+        let state = Arc::clone(&state);
+        tokio::task::spawn_blocking(move || {
+            UploadBudget::compute(&state, &config, &dir, &path, peer.ip())
+        })
+        .await?
+    };
+    if let Err(limit) = budget.admit((expected_size > 0).then_some(expected_size)) {
+        endpoint
+            .reject(&TftpError::DiskFull(limit.to_string()))
+            .await;
+        return Err(anyhow!("WRQ refused: {limit}"));
+    }
+
     let is_netascii = mode == "netascii";
 
-    // Negotiate options (blksize, timeout, windowsize).
-    let negotiated = negotiate_options(options, config);
     let blksize = negotiated.blksize;
     let timeout_dur = Duration::from_millis(negotiated.timeout_ms);
     let windowsize = negotiated.windowsize;
     let mut oack_options = negotiated.oack;
 
     // RFC 2349: For WRQ, echo back the client's tsize value.
-    if let Some(tsize_val) = options.get("tsize") {
-        oack_options.insert("tsize".to_string(), tsize_val.clone());
+    if let Some(tsize) = negotiated.tsize {
+        oack_options.insert("tsize".to_string(), tsize.to_string());
+    }
+
+    // Write to a temporary ".part" file so that incomplete uploads are
+    // never mistaken for valid files.  On success we rename to the real
+    // path; on failure the .part file is cleaned up.
+    let part_path = part_path_for(&path);
+
+    // Kept .part files nobody came back for expire as uploads come in.
+    for stale in state.expire_parts(PART_MAX_AGE) {
+        tx.send(ServerEvent::Log(format!(
+            "Removed stale partial upload {}",
+            stale.display()
+        )));
+    }
+
+    // Resume from the .part file an earlier attempt left behind
+    // (non-standard extension). The OACK carries the offset actually used,
+    // which is lower than requested if less data was preserved. Declined
+    // for netascii, whose wire bytes do not map onto file offsets.
+    let offset = match negotiated.offset {
+        Some(_) if is_netascii => {
+            oack_options.remove("offset");
+            None
+        }
+        Some(requested) => {
+            let preserved = tokio::fs::metadata(&part_path).await.map_or(0, |m| m.len());
+            let offset = requested.min(preserved);
+            oack_options.insert("offset".to_string(), offset.to_string());
+            Some(offset)
+        }
+        None => None,
+    };
+    let resume_at = offset.unwrap_or(0);
+
+    // With a known size, refuse now a transfer that needs a rollover the
+    // client did not agree to.
+    let blocks = BlockNumbers::new(negotiated.rollover, config);
+    if expected_size > 0
+        && let Err(msg) = blocks.check_size(expected_size.saturating_sub(resume_at), blksize)
+    {
+        endpoint.reject(&TftpError::NotDefined(msg.clone())).await;
+        return Err(anyhow!("WRQ refused: {msg}"));
     }
 
     let mut detail_parts = Vec::new();
@@ -871,6 +1404,12 @@ async fn handle_wrq(
     if is_netascii {
         detail_parts.push("netascii".to_string());
     }
+    if let Some(rollover) = negotiated.rollover {
+        detail_parts.push(format!("rollover={rollover}"));
+    }
+    if let Some(offset) = offset {
+        detail_parts.push(format!("offset={offset} (non-standard extension)"));
+    }
     let detail_str = if detail_parts.is_empty() {
         String::new()
     } else {
@@ -879,26 +1418,21 @@ async fn handle_wrq(
 
     tx.send(ServerEvent::Log(format!(
         "{peer}: WRQ \"{filename}\"{detail_str}"
-    )))?;
-
-    // Try to determine expected size from tsize option.
-    let expected_size = options
-        .get("tsize")
-        .and_then(|v| v.parse::<u64>().ok())
-        .unwrap_or(0);
+    )));
 
     tx.send(ServerEvent::TransferStarted(TransferInfo {
         id,
         peer,
         filename: filename.to_string(),
+        path: path.clone(),
         kind: TransferKind::Upload,
         total_bytes: expected_size,
-        transferred: 0,
+        transferred: resume_at,
         started: Instant::now(),
         size_known: expected_size > 0,
-    }))?;
+    }));
 
-    let sock = bind_transfer_socket(peer, blksize).await?;
+    let mut sock = endpoint.open(blksize, config).await?;
     let mut recv_buf = vec![0u8; MAX_PACKET];
     let max_retries = config.max_retries;
 
@@ -910,53 +1444,80 @@ async fn handle_wrq(
         send_resilient(&sock, &oack_pkt.to_bytes()).await?;
     } else {
         // Send ACK 0 to acknowledge the WRQ.
-        let ack0 = Packet::ACK { block_num: 0 };
-        send_resilient(&sock, &ack0.to_bytes()).await?;
+        send_resilient(&sock, &ack_bytes(0)).await?;
     }
 
     // Ensure parent directories exist for subdirectory uploads.
     if let Some(parent) = path.parent() {
-        tokio::fs::create_dir_all(parent).await?;
+        or_abort(&sock, tokio::fs::create_dir_all(parent).await).await?;
     }
 
-    // Write to a temporary ".part" file so that incomplete uploads are
-    // never mistaken for valid files.  On success we rename to the real
-    // path; on failure the .part file is cleaned up.
-    let part_path = {
-        let mut p = path.as_os_str().to_owned();
-        p.push(".part");
-        PathBuf::from(p)
-    };
-
-    let mut file = if config.allow_overwrite {
-        tokio::fs::File::create(&part_path).await?
+    let file = if offset.is_some() {
+        // Keep what earlier attempts wrote, up to the offset.
+        let opened = tokio::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&part_path)
+            .await;
+        match opened {
+            Ok(file) => file.set_len(resume_at).await.map(|_| file),
+            Err(e) => Err(e),
+        }
+    } else if config.accepts_existing() {
+        tokio::fs::File::create(&part_path).await
     } else {
         tokio::fs::OpenOptions::new()
             .write(true)
             .create_new(true)
             .open(&part_path)
             .await
-            .map_err(|e| {
-                if e.kind() == std::io::ErrorKind::AlreadyExists {
-                    anyhow!("file already exists: {}", path.display())
-                } else {
-                    anyhow!("cannot create {}: {e}", path.display())
-                }
-            })?
+    };
+    let mut file = match file {
+        Ok(file) => file,
+        Err(e) => {
+            sock.send_error(&TftpError::from(&e)).await;
+            return Err(if e.kind() == std::io::ErrorKind::AlreadyExists {
+                anyhow!("file already exists: {}", path.display())
+            } else {
+                anyhow!("cannot create {}: {e}", part_path.display())
+            });
+        }
+    };
+
+    if offset.is_some() {
+        state.claim_part(&part_path);
+    }
+    let mut part = PartGuard {
+        path: part_path.clone(),
+        resumable: offset.is_some().then(|| Arc::clone(&state)),
+        installed: false,
     };
 
-    let mut transferred: u64 = 0;
+    let mut transferred: u64 = resume_at;
     let mut expected_block: u16 = 1;
+    // Last block ACKed, re-sent for duplicates and on timeouts.
+    let mut acked: u16 = 0;
     let mut decoder = if is_netascii {
         Some(NetasciiDecoder::new())
     } else {
         None
     };
+    let mut hasher = Checksummer::new(config);
+    or_abort(&sock, hash_prefix(&mut file, resume_at, &mut hasher).await).await?;
+    let mut file = BufWriter::with_capacity(FILE_BUFFER_SIZE, file);
+    // Netascii output of one block; never longer than the block plus a
+    // carried-over CR.
+    let mut decoded = Vec::with_capacity(if is_netascii { blksize + 1 } else { 0 });
 
     if windowsize > 1 {
         // --- Windowed WRQ ---
+        // Blocks are only accepted in order, so each one is written out
+        // straight from the receive buffer as it arrives.
         loop {
-            let mut window_data: Vec<(u16, Vec<u8>)> = Vec::new();
+            let mut received = 0usize;
+            let mut last_received = acked;
             let mut last_block = false;
             let mut retries = 0u32;
 
@@ -964,37 +1525,52 @@ async fn handle_wrq(
             loop {
                 match timeout(timeout_dur, sock.recv(&mut recv_buf)).await {
                     Ok(Ok(n)) => {
-                        let pkt = Packet::from_bytes(&recv_buf[..n])?;
-                        match pkt {
-                            Packet::DATA { block_num, data } if block_num == expected_block => {
+                        match parse_reply(&sock, &recv_buf[..n]).await? {
+                            PacketRef::DATA { block_num, data } if block_num == expected_block => {
                                 let is_last = data.len() < blksize;
-                                window_data.push((block_num, data));
-                                expected_block = expected_block.wrapping_add(1);
+                                let to_write = match decoder.as_mut() {
+                                    Some(dec) => {
+                                        decoded.clear();
+                                        dec.decode_into(data, &mut decoded);
+                                        if is_last {
+                                            dec.finish(&mut decoded);
+                                        }
+                                        &decoded[..]
+                                    }
+                                    None => data,
+                                };
+                                let total = transferred + to_write.len() as u64;
+                                if let Err(limit) = budget.check(total) {
+                                    return Err(abort_upload(&sock, limit).await);
+                                }
+                                or_abort(&sock, file.write_all(to_write).await).await?;
+                                hasher.update(to_write);
+                                transferred += to_write.len() as u64;
+                                received += 1;
+                                last_received = block_num;
+
                                 if is_last {
                                     last_block = true;
                                     break;
                                 }
-                                if window_data.len() >= windowsize as usize {
+                                expected_block = blocks.next(expected_block, &sock).await?;
+                                if received >= windowsize as usize {
                                     break;
                                 }
                             }
-                            Packet::DATA { block_num, .. }
-                                if block_num == expected_block.wrapping_sub(1)
-                                    && window_data.is_empty() =>
+                            PacketRef::DATA { block_num, .. }
+                                if block_num == acked && received == 0 =>
                             {
                                 // Duplicate of previous block — re-ACK.
-                                let ack = Packet::ACK { block_num };
-                                send_resilient(&sock, &ack.to_bytes()).await?;
-                            }
-                            Packet::ERROR { code, msg } => {
-                                return Err(anyhow!("client error {code}: {msg}"));
+                                send_resilient(&sock, &ack_bytes(block_num)).await?;
                             }
+                            PacketRef::ERROR { code, msg } => return Err(client_error(code, msg)),
                             _ => { /* ignore unexpected */ }
                         }
                     }
                     Ok(Err(e)) => return Err(e.into()),
                     Err(_) => {
-                        if !window_data.is_empty() {
+                        if received > 0 {
                             // Got partial window, process what we have.
                             break;
                         }
@@ -1006,31 +1582,21 @@ async fn handle_wrq(
                             ));
                         }
                         // Re-send previous ACK.
-                        let prev = Packet::ACK {
-                            block_num: expected_block.wrapping_sub(1),
-                        };
-                        send_resilient(&sock, &prev.to_bytes()).await?;
+                        send_resilient(&sock, &ack_bytes(acked)).await?;
                     }
                 }
             }
 
-            // Write all received data to disk.
-            for (_, data) in &window_data {
-                let to_write = if let Some(ref mut dec) = decoder {
-                    dec.decode(data)
-                } else {
-                    data.clone()
-                };
-                file.write_all(&to_write).await?;
-                transferred += to_write.len() as u64;
+            // Everything must be on disk before the final ACK, so a
+            // failure can still be reported.
+            if last_block {
+                or_abort(&sock, file.flush().await).await?;
             }
 
             // ACK the last block we received.
-            if let Some((last_bn, _)) = window_data.last() {
-                let ack = Packet::ACK {
-                    block_num: *last_bn,
-                };
-                send_resilient(&sock, &ack.to_bytes()).await?;
+            if received > 0 {
+                acked = last_received;
+                send_resilient(&sock, &ack_bytes(acked)).await?;
             }
 
             let report_total = if expected_size > 0 {
@@ -1042,7 +1608,7 @@ async fn handle_wrq(
                 id,
                 transferred,
                 total_bytes: report_total,
-            })?;
+            });
 
             if last_block {
                 break;
@@ -1052,27 +1618,19 @@ async fn handle_wrq(
         // --- Classic single-block WRQ ---
         loop {
             let mut retries = 0u32;
-            let data_payload;
 
-            loop {
+            let len = loop {
                 match timeout(timeout_dur, sock.recv(&mut recv_buf)).await {
                     Ok(Ok(n)) => {
-                        let pkt = Packet::from_bytes(&recv_buf[..n])?;
-                        match pkt {
-                            Packet::DATA { block_num, data } if block_num == expected_block => {
-                                data_payload = data;
-                                break;
+                        match parse_reply(&sock, &recv_buf[..n]).await? {
+                            PacketRef::DATA { block_num, .. } if block_num == expected_block => {
+                                break n;
                             }
                             // Duplicate of previous block – re-ACK it.
-                            Packet::DATA { block_num, .. }
-                                if block_num == expected_block.wrapping_sub(1) =>
-                            {
-                                let ack = Packet::ACK { block_num };
-                                send_resilient(&sock, &ack.to_bytes()).await?;
-                            }
-                            Packet::ERROR { code, msg } => {
-                                return Err(anyhow!("client error {code}: {msg}"));
+                            PacketRef::DATA { block_num, .. } if block_num == acked => {
+                                send_resilient(&sock, &ack_bytes(block_num)).await?;
                             }
+                            PacketRef::ERROR { code, msg } => return Err(client_error(code, msg)),
                             _ => { /* ignore unexpected */ }
                         }
                     }
@@ -1083,32 +1641,41 @@ async fn handle_wrq(
                             return Err(anyhow!("timeout waiting for DATA block {expected_block}"));
                         }
                         // Re-send previous ACK.
-                        let prev = Packet::ACK {
-                            block_num: expected_block.wrapping_sub(1),
-                        };
-                        send_resilient(&sock, &prev.to_bytes()).await?;
+                        send_resilient(&sock, &ack_bytes(acked)).await?;
                     }
                 }
-            }
-
-            let is_last = data_payload.len() < blksize;
+            };
+            let data = &recv_buf[4..len];
+            let is_last = data.len() < blksize;
 
             // Apply netascii decoding if needed.
-            let to_write = if let Some(ref mut dec) = decoder {
-                dec.decode(&data_payload)
-            } else {
-                data_payload
+            let to_write = match decoder.as_mut() {
+                Some(dec) => {
+                    decoded.clear();
+                    dec.decode_into(data, &mut decoded);
+                    if is_last {
+                        dec.finish(&mut decoded);
+                    }
+                    &decoded[..]
+                }
+                None => data,
             };
 
-            // Write directly to disk.
-            file.write_all(&to_write).await?;
+            // Write to disk, within the budget.
+            let total = transferred + to_write.len() as u64;
+            if let Err(limit) = budget.check(total) {
+                return Err(abort_upload(&sock, limit).await);
+            }
+            or_abort(&sock, file.write_all(to_write).await).await?;
+            hasher.update(to_write);
             transferred += to_write.len() as u64;
 
-            // ACK this block.
-            let ack = Packet::ACK {
-                block_num: expected_block,
-            };
-            send_resilient(&sock, &ack.to_bytes()).await?;
+            // ACK this block, the final one only once it is on disk.
+            if is_last {
+                or_abort(&sock, file.flush().await).await?;
+            }
+            acked = expected_block;
+            send_resilient(&sock, &ack_bytes(acked)).await?;
 
             let report_total = if expected_size > 0 {
                 expected_size
@@ -1119,31 +1686,56 @@ async fn handle_wrq(
                 id,
                 transferred,
                 total_bytes: report_total,
-            })?;
+            });
 
             if is_last {
                 break;
             }
-            expected_block = expected_block.wrapping_add(1);
+            expected_block = blocks.next(expected_block, &sock).await?;
         }
     }
 
-    file.flush().await?;
+    // Flushed before the final ACK.
     drop(file);
 
-    // Atomically promote the completed .part file to its final name.
-    tokio::fs::rename(&part_path, &path).await.map_err(|e| {
+    // Atomically promote the completed .part file to its final name,
+    // keeping the file it replaces when versioning is enabled.
+    let installed = {
+        let (part_path, path) = (part_path.clone(), path.clone());
+        let config = config.clone();
+        tokio::task::spawn_blocking(move || versions::install(&part_path, &path, &config)).await?
+    }
+    .map_err(|e| {
         anyhow!(
             "failed to rename {} -> {}: {e}",
             part_path.display(),
             path.display()
         )
     })?;
+    part.installed = true;
+    budget.complete();
+    log_installed(&tx, peer, filename, &installed);
 
-    tx.send(ServerEvent::TransferComplete(id))?;
+    let checksums = hasher.finish();
+    if config.sha256_sidecar
+        && let Err(e) = checksum::write_sidecar(&path, &checksums.sha256).await
+    {
+        tx.send(ServerEvent::Log(format!(
+            "{peer}: cannot write checksum sidecar for \"{filename}\": {e}"
+        )));
+    }
+    let resumed = if resume_at > 0 {
+        format!(", resumed at {resume_at}")
+    } else {
+        String::new()
+    };
     tx.send(ServerEvent::Log(format!(
-        "{peer}: WRQ \"{filename}\" complete ({transferred} bytes)"
-    )))?;
+        "{peer}: WRQ \"{filename}\" complete ({transferred} bytes{resumed}, {checksums})"
+    )));
+    tx.send(ServerEvent::TransferComplete {
+        id,
+        checksums: Some(checksums),
+    });
     Ok(())
 }
 
@@ -1151,6 +1743,158 @@ async fn handle_wrq(
 // Helpers
 // ---------------------------------------------------------------------------
 
+/// Feed the first `len` bytes of `file` to `hasher`, leaving the file
+/// positioned at `len`, so a transfer resumed at an offset still reports
+/// digests of the whole file.
+async fn hash_prefix(
+    file: &mut (impl AsyncRead + Unpin),
+    len: u64,
+    hasher: &mut Checksummer,
+) -> Result<()> {
+    let mut buf = vec![0u8; 64 * 1024];
+    let mut remaining = len;
+    while remaining > 0 {
+        let chunk = remaining.min(buf.len() as u64) as usize;
+        let n = file.read(&mut buf[..chunk]).await?;
+        if n == 0 {
+            return Err(anyhow!("file is shorter than offset {len}"));
+        }
+        hasher.update(&buf[..n]);
+        remaining -= n as u64;
+    }
+    Ok(())
+}
+
+/// Removes an upload's `.part` file when dropped, whether the transfer
+/// failed or its task was cancelled. If the client negotiated `offset`
+/// the file is kept for a retry until `PART_MAX_AGE` instead.
+struct PartGuard {
+    path: PathBuf,
+    /// Set when the upload may be resumed.
+    resumable: Option<Arc<SharedState>>,
+    /// The file was renamed into place.
+    installed: bool,
+}
+
+impl Drop for PartGuard {
+    fn drop(&mut self) {
+        if self.installed {
+            return;
+        }
+        match &self.resumable {
+            Some(state) => state.keep_part(self.path.clone()),
+            None => {
+                let _ = std::fs::remove_file(&self.path);
+            }
+        }
+    }
+}
+
+/// Tell the client an upload went over its budget (error 3, "disk full
+/// or allocation exceeded") and build the transfer's error.
+async fn abort_upload(sock: &TransferSocket, limit: Limit) -> anyhow::Error {
+    sock.send_error(&TftpError::DiskFull(limit.to_string()))
+        .await;
+    anyhow!("upload aborted: {limit}")
+}
+
+/// Pass `result` through, first telling the client why the transfer ends
+/// if it failed: the matching code for an I/O error, otherwise 0.
+async fn or_abort<T, E: Into<anyhow::Error>>(
+    sock: &TransferSocket,
+    result: std::result::Result<T, E>,
+) -> Result<T> {
+    let e = match result {
+        Ok(value) => return Ok(value),
+        Err(e) => e.into(),
+    };
+    let err = match e.downcast_ref::<std::io::Error>() {
+        Some(io) => TftpError::from(io),
+        None => TftpError::NotDefined(e.to_string()),
+    };
+    sock.send_error(&err).await;
+    Err(e)
+}
+
+/// Parse a datagram from the client, answering one that is not a TFTP
+/// packet with error 4.
+async fn parse_reply<'a>(sock: &TransferSocket, datagram: &'a [u8]) -> Result<PacketRef<'a>> {
+    let parsed = PacketRef::parse(datagram);
+    if let Err(e) = &parsed {
+        sock.send_error(&TftpError::IllegalOperation(e.to_string()))
+            .await;
+    }
+    parsed
+}
+
+/// The boot profile mapping `filename` for the client at `ip`, with the
+/// client's MAC when matching needed it. The MAC comes from the ProxyDHCP
+/// responder or the neighbor table, the architecture from the responder.
+fn match_boot_profile<'a>(
+    config: &'a ServerConfig,
+    state: &SharedState,
+    filename: &str,
+    ip: IpAddr,
+) -> Option<(&'a BootProfile, Option<String>)> {
+    let profiles = &config.boot_profiles;
+    let mut client = ClientId {
+        ip,
+        mac: None,
+        arch: None,
+        ipxe: false,
+    };
+    if boot_profiles::needs_mac(profiles, filename) {
+        client.mac = state
+            .pxe_mac(ip)
+            .or_else(|| boot_profiles::neighbor_mac(ip));
+        if let Some((arch, ipxe)) = client.mac.as_deref().and_then(|mac| state.pxe_client(mac)) {
+            client.arch = Some(arch);
+            client.ipxe = ipxe;
+        }
+    }
+    boot_profiles::select(profiles, filename, &client).map(|p| (p, client.mac))
+}
+
+/// `sanitize_path` for a request, refusing it with error 2 when the name
+/// points outside the served directory or is not a usable path.
+async fn resolve_path(endpoint: &Endpoint, dir: &Path, filename: &str) -> Result<PathBuf> {
+    let resolved = sanitize_path(dir, filename);
+    if let Err(e) = &resolved {
+        endpoint
+            .reject(&TftpError::AccessViolation(e.to_string()))
+            .await;
+    }
+    resolved
+}
+
+/// Log the versions kept and pruned when an upload replaced a file.
+pub(crate) fn log_installed(
+    tx: &EventBus,
+    peer: SocketAddr,
+    filename: &str,
+    installed: &Installed,
+) {
+    if let Some(ref archived) = installed.archived {
+        let name = archived.file_name().unwrap_or_default().to_string_lossy();
+        tx.send(ServerEvent::Log(format!(
+            "{peer}: previous \"{filename}\" kept as {name}"
+        )));
+    }
+    if !installed.pruned.is_empty() {
+        tx.send(ServerEvent::Log(format!(
+            "Pruned {} old version(s) of \"{filename}\"",
+            installed.pruned.len()
+        )));
+    }
+}
+
+/// Temporary path an upload is written to before being renamed into place.
+pub(crate) fn part_path_for(path: &Path) -> PathBuf {
+    let mut p = path.as_os_str().to_owned();
+    p.push(".part");
+    PathBuf::from(p)
+}
+
 /// Ensure the requested filename stays inside the served directory.
 /// Supports subdirectory paths (e.g. `ios/config/router.cfg`) while
 /// rejecting any traversal attempt (`..`) or absolute paths.
@@ -1272,4 +2016,260 @@ mod tests {
         assert!(sanitize_path(dir.path(), ".").is_err());
         assert!(sanitize_path(dir.path(), "..").is_err());
     }
+
+    #[test]
+    fn offset_is_only_negotiated_when_enabled() {
+        let options = Options::from([("offset", "4096")]);
+        let negotiated = negotiate_options(&options, &ServerConfig::default()).unwrap();
+        assert_eq!(negotiated.offset, None);
+        assert!(negotiated.oack.is_empty());
+
+        let config = ServerConfig {
+            enable_offset: true,
+            ..ServerConfig::default()
+        };
+        let negotiated = negotiate_options(&options, &config).unwrap();
+        assert_eq!(negotiated.offset, Some(4096));
+        assert_eq!(negotiated.oack.get("offset"), Some("4096"));
+    }
+
+    #[test]
+    fn rollover_option_sets_the_block_after_65535() {
+        let config = ServerConfig::default();
+        let options = Options::from([("rollover", "1")]);
+        let negotiated = negotiate_options(&options, &config).unwrap();
+        assert_eq!(negotiated.rollover, Some(1));
+        assert_eq!(negotiated.oack.get("rollover"), Some("1"));
+        let blocks = BlockNumbers::new(negotiated.rollover, &config);
+        assert_eq!(blocks.after(65534), 65535);
+        assert_eq!(blocks.after(65535), 1);
+
+        let options = Options::from([("rollover", "2")]);
+        let negotiated = negotiate_options(&options, &config).unwrap();
+        assert_eq!(negotiated.rollover, None);
+        assert!(negotiated.oack.is_empty());
+        assert_eq!(BlockNumbers::new(None, &config).after(65535), 0);
+    }
+
+    #[test]
+    fn oack_follows_the_client_option_order() {
+        let options = Options::from([
+            ("windowsize", "4"),
+            ("x-vendor", "1"),
+            ("tsize", "0"),
+            ("blksize", "1428"),
+        ]);
+        let config = ServerConfig {
+            max_window_size: 16,
+            ..ServerConfig::default()
+        };
+        let negotiated = negotiate_options(&options, &config).unwrap();
+        let names: Vec<_> = negotiated.oack.iter().map(|(name, _)| name).collect();
+        assert_eq!(names, ["windowsize", "tsize", "blksize"]);
+    }
+
+    #[test]
+    fn option_policy_resolves_duplicates_and_malformed_options() {
+        let config = |option_policy| ServerConfig {
+            option_policy,
+            ..ServerConfig::default()
+        };
+        let options = Options::from([("blksize", "1024"), ("tsize", "0"), ("blksize", "2048")]);
+
+        let first = negotiate_options(&options, &config(OptionPolicy::First)).unwrap();
+        assert_eq!(first.blksize, 1024);
+        let last = negotiate_options(&options, &config(OptionPolicy::Last)).unwrap();
+        assert_eq!(last.blksize, 2048);
+        // The duplicate keeps the position of its first occurrence.
+        let names: Vec<_> = last.oack.iter().map(|(name, _)| name).collect();
+        assert_eq!(names, ["blksize", "tsize"]);
+
+        let reject = config(OptionPolicy::Reject);
+        let err = negotiate_options(&options, &reject).unwrap_err();
+        assert_eq!(err, "duplicate option \"blksize\"");
+
+        let invalid = Options::from([("blksize", "4")]);
+        assert_eq!(
+            negotiate_options(&invalid, &config(OptionPolicy::Last))
+                .unwrap()
+                .blksize,
+            512
+        );
+        let err = negotiate_options(&invalid, &reject).unwrap_err();
+        assert_eq!(err, "invalid value \"4\" for option \"blksize\"");
+
+        let Packet::RRQ { options, .. } =
+            Packet::from_bytes(b"\x00\x01boot\x00octet\x00\x001024\x00tsize\x000\x00").unwrap()
+        else {
+            unreachable!("RRQ");
+        };
+        let last = negotiate_options(&options, &config(OptionPolicy::Last)).unwrap();
+        assert_eq!(last.tsize, Some(0));
+        let err = negotiate_options(&options, &reject).unwrap_err();
+        assert_eq!(err, "option value without a name");
+    }
+
+    #[test]
+    fn strict_rollover_refuses_files_that_would_wrap() {
+        let config = ServerConfig {
+            strict_rollover: true,
+            ..ServerConfig::default()
+        };
+        // 65535 blocks of 512 bytes; the last one must be short.
+        let largest = 65535 * 512 - 1;
+        let blocks = BlockNumbers::new(None, &config);
+        assert!(blocks.check_size(largest, 512).is_ok());
+        assert!(blocks.check_size(largest + 1, 512).is_err());
+        assert!(blocks.check_size(largest + 1, 1024).is_ok());
+
+        assert!(
+            BlockNumbers::new(Some(0), &config)
+                .check_size(u64::MAX, 512)
+                .is_ok()
+        );
+        let lenient = ServerConfig::default();
+        assert!(
+            BlockNumbers::new(None, &lenient)
+                .check_size(u64::MAX, 512)
+                .is_ok()
+        );
+    }
+
+    #[tokio::test]
+    async fn resumed_transfers_hash_the_whole_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("fw.bin");
+        std::fs::write(&path, b"hello world").unwrap();
+
+        let config = ServerConfig::default();
+        let mut file = tokio::fs::File::open(&path).await.unwrap();
+        let mut hasher = Checksummer::new(&config);
+        hash_prefix(&mut file, 6, &mut hasher).await.unwrap();
+        let mut rest = Vec::new();
+        file.read_to_end(&mut rest).await.unwrap();
+        assert_eq!(rest, b"world");
+        hasher.update(&rest);
+        assert_eq!(
+            hasher.finish().sha256,
+            "b94d27b9934d3e08a52e52d7da7dabfac484efe37a5380ee9088f7ace2efcde9"
+        );
+
+        let mut file = tokio::fs::File::open(&path).await.unwrap();
+        let mut hasher = Checksummer::new(&config);
+        assert!(hash_prefix(&mut file, 12, &mut hasher).await.is_err());
+    }
+
+    /// A file that hands out its contents in the given chunks, one per
+    /// read, the way a pipe or a slow disk might.
+    struct ChunkedReader(VecDeque<Vec<u8>>);
+
+    impl AsyncRead for ChunkedReader {
+        fn poll_read(
+            mut self: std::pin::Pin<&mut Self>,
+            _cx: &mut std::task::Context<'_>,
+            buf: &mut tokio::io::ReadBuf<'_>,
+        ) -> std::task::Poll<std::io::Result<()>> {
+            if let Some(chunk) = self.0.front_mut() {
+                let n = chunk.len().min(buf.remaining());
+                buf.put_slice(&chunk[..n]);
+                chunk.drain(..n);
+                if chunk.is_empty() {
+                    self.0.pop_front();
+                }
+            }
+            std::task::Poll::Ready(Ok(()))
+        }
+    }
+
+    proptest::proptest! {
+        #[test]
+        fn netascii_blocks_are_full_until_eof(
+            data in proptest::collection::vec(
+                proptest::prop_oneof![
+                    proptest::strategy::Just(b'\r'),
+                    proptest::strategy::Just(b'\n'),
+                    proptest::prelude::any::<u8>(),
+                ],
+                0..1024,
+            ),
+            blksize in 8usize..=64,
+            chunk_lens in proptest::collection::vec(1usize..=100, 1..32),
+        ) {
+            let mut chunks = VecDeque::new();
+            let mut rest = &data[..];
+            for len in chunk_lens.iter().cycle() {
+                if rest.is_empty() {
+                    break;
+                }
+                let (chunk, tail) = rest.split_at((*len).min(rest.len()));
+                chunks.push_back(chunk.to_vec());
+                rest = tail;
+            }
+            let mut file = ChunkedReader(chunks);
+
+            let config = ServerConfig::default();
+            let mut hasher = Checksummer::new(&config);
+            let mut raw = vec![0u8; blksize];
+            let mut packet = DataBuf::new(blksize);
+            let mut encoder = Some(NetasciiEncoder::new());
+            let runtime = tokio::runtime::Builder::new_current_thread()
+                .build()
+                .unwrap();
+
+            // Every byte but `\r` and `\n` encodes to one, so the block count
+            // is known: full blocks, then one short (possibly empty) block.
+            let encoded_len = data.len() + data.iter().filter(|&&b| b == b'\r' || b == b'\n').count();
+            let mut dec = NetasciiDecoder::new();
+            let mut decoded = Vec::new();
+            let mut blocks = 0;
+            loop {
+                let len = runtime
+                    .block_on(read_next_block(
+                        &mut file,
+                        &mut raw,
+                        &mut packet,
+                        blksize,
+                        &mut encoder,
+                        &mut hasher,
+                    ))
+                    .unwrap();
+                blocks += 1;
+                proptest::prop_assert!(len <= blksize);
+                proptest::prop_assert!(blocks <= encoded_len / blksize + 1);
+                dec.decode_into(packet.payload(), &mut decoded);
+                if len < blksize && !has_encoder_overflow(&encoder) {
+                    break;
+                }
+            }
+            dec.finish(&mut decoded);
+            proptest::prop_assert_eq!(blocks, encoded_len / blksize + 1);
+            proptest::prop_assert_eq!(decoded, data);
+        }
+    }
+
+    #[test]
+    fn part_file_is_kept_only_when_resumable() {
+        let dir = tempfile::tempdir().unwrap();
+        let part = dir.path().join("fw.bin.part");
+        std::fs::write(&part, b"partial").unwrap();
+        let state = Arc::new(SharedState::new(ServerConfig::default()));
+        drop(PartGuard {
+            path: part.clone(),
+            resumable: Some(Arc::clone(&state)),
+            installed: false,
+        });
+        assert!(part.exists());
+        // Kept only until nobody resumes it in time.
+        assert!(state.expire_parts(PART_MAX_AGE).is_empty());
+        assert_eq!(state.expire_parts(Duration::ZERO), vec![part.clone()]);
+        assert!(!part.exists());
+
+        std::fs::write(&part, b"partial").unwrap();
+        drop(PartGuard {
+            path: part.clone(),
+            resumable: None,
+            installed: false,
+        });
+        assert!(!part.exists());
+    }
 }
@@ -0,0 +1,754 @@
+use std::collections::{HashMap, VecDeque};
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Mutex, RwLock};
+use std::time::{Duration, Instant, SystemTime};
+
+use serde::Serialize;
+use tokio_util::sync::CancellationToken;
+
+use crate::cache::{CacheStats, ReadCache};
+use crate::checksum::Checksums;
+use crate::limits::CLIENT_QUOTA_WINDOW;
+use crate::proxy_dhcp::{ClientArch, PxeBoot};
+use crate::server::{ServerConfig, ServerEvent, TransferInfo, TransferKind};
+
+/// Number of finished transfers kept for the "recent transfers" view.
+const HISTORY_CAPACITY: usize = 200;
+
+/// Number of log lines kept for the log tail.
+const LOG_CAPACITY: usize = 1000;
+
+/// Number of files whose digests are remembered for the HTTP listing.
+const DIGEST_CACHE_CAPACITY: usize = 4096;
+
+/// Number of PXE clients remembered for boot profiles. Anyone on the LAN
+/// can send DHCP requests, so the least recently seen are dropped.
+const PXE_CLIENT_CAPACITY: usize = 1024;
+
+/// How long a `.part` file kept for a resumable upload waits for a retry
+/// before it is deleted.
+pub const PART_MAX_AGE: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// A finished (completed or failed) transfer.
+#[derive(Debug, Clone, Serialize)]
+pub struct TransferRecord {
+    pub id: u64,
+    pub peer: SocketAddr,
+    pub filename: String,
+    pub kind: TransferKind,
+    /// Bytes transferred before the transfer finished.
+    pub bytes: u64,
+    pub duration_ms: u64,
+    /// Seconds since the Unix epoch when the transfer finished.
+    pub finished_at: u64,
+    pub success: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub checksums: Option<Checksums>,
+}
+
+/// Digests of a file as it was when they were computed.
+struct CachedDigest {
+    len: u64,
+    modified: SystemTime,
+    checksums: Checksums,
+}
+
+/// What the ProxyDHCP responder last learned about each PXE client, for
+/// boot profiles matching on architecture.
+#[derive(Default)]
+struct PxeClients {
+    by_mac: HashMap<String, PxeClient>,
+    /// MAC by the address a client reported on port 4011.
+    by_ip: HashMap<IpAddr, String>,
+}
+
+struct PxeClient {
+    arch: ClientArch,
+    ipxe: bool,
+    /// The address it last reported, if any.
+    ip: Option<IpAddr>,
+    seen: Instant,
+}
+
+impl PxeClients {
+    fn record(&mut self, boot: &PxeBoot) {
+        if !self.by_mac.contains_key(&boot.mac) && self.by_mac.len() >= PXE_CLIENT_CAPACITY {
+            let oldest = self
+                .by_mac
+                .iter()
+                .min_by_key(|(_, client)| client.seen)
+                .map(|(mac, _)| mac.clone());
+            if let Some(mac) = oldest {
+                let client = self.by_mac.remove(&mac);
+                self.forget_ip(&mac, client.and_then(|c| c.ip));
+            }
+        }
+
+        // Offers go out before the client has an address; keep the last
+        // one it reported until it reports another.
+        let previous = self.by_mac.get(&boot.mac).and_then(|c| c.ip);
+        let ip = boot.client_ip.map(IpAddr::from).or(previous);
+        if previous != ip {
+            self.forget_ip(&boot.mac, previous);
+        }
+        if let Some(ip) = ip {
+            self.by_ip.insert(ip, boot.mac.clone());
+        }
+        self.by_mac.insert(
+            boot.mac.clone(),
+            PxeClient {
+                arch: boot.arch,
+                ipxe: boot.ipxe,
+                ip,
+                seen: Instant::now(),
+            },
+        );
+    }
+
+    /// Drop `mac`'s entry for `ip`, unless another client reported the
+    /// address since.
+    fn forget_ip(&mut self, mac: &str, ip: Option<IpAddr>) {
+        if let Some(ip) = ip
+            && self.by_ip.get(&ip).is_some_and(|m| m == mac)
+        {
+            self.by_ip.remove(&ip);
+        }
+    }
+}
+
+/// An upload still being written. Quotas hold back what it has written
+/// from every other upload sharing its client or a quota directory.
+struct InFlightUpload {
+    client: IpAddr,
+    path: PathBuf,
+    written: u64,
+    /// Bytes the same client finished uploading since this one started.
+    client_completed: u64,
+    /// The quota directories limiting this upload, with the bytes
+    /// finished under each since it started.
+    dirs: Vec<(PathBuf, u64)>,
+}
+
+/// What other uploads add to an upload's client and directory usage.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct UploadUsage {
+    pub client: u64,
+    /// In the order the directories were passed to `begin_upload`.
+    pub dirs: Vec<u64>,
+}
+
+/// A single log line with its wall-clock time.
+#[derive(Debug, Clone, Serialize)]
+pub struct LogEntry {
+    /// Seconds since the Unix epoch.
+    pub time: u64,
+    pub message: String,
+}
+
+/// State shared by the TFTP server, the HTTP server and its control API.
+///
+/// Transfer and log state is folded from every event published on the
+/// `EventBus`, so it always matches what the TUI and dashboard show. The runtime
+/// config is read by the servers for every new request, so updates apply
+/// to the next transfer without a restart.
+pub struct SharedState {
+    config: RwLock<ServerConfig>,
+    active: Mutex<Vec<TransferInfo>>,
+    history: Mutex<VecDeque<TransferRecord>>,
+    logs: Mutex<VecDeque<LogEntry>>,
+    cancel_tokens: Mutex<HashMap<u64, CancellationToken>>,
+    digests: Mutex<HashMap<PathBuf, CachedDigest>>,
+    /// Completed upload sizes per client, for the client quota.
+    uploads: Mutex<HashMap<IpAddr, VecDeque<(Instant, u64)>>>,
+    /// Uploads still being written, by reservation id.
+    in_flight: Mutex<HashMap<u64, InFlightUpload>>,
+    /// `.part` files of failed resumable uploads, by when they were kept.
+    kept_parts: Mutex<HashMap<PathBuf, Instant>>,
+    read_cache: ReadCache,
+    pxe_clients: Mutex<PxeClients>,
+}
+
+impl SharedState {
+    pub fn new(config: ServerConfig) -> Self {
+        Self {
+            config: RwLock::new(config),
+            active: Mutex::new(Vec::new()),
+            history: Mutex::new(VecDeque::with_capacity(HISTORY_CAPACITY)),
+            logs: Mutex::new(VecDeque::with_capacity(LOG_CAPACITY)),
+            cancel_tokens: Mutex::new(HashMap::new()),
+            digests: Mutex::new(HashMap::new()),
+            uploads: Mutex::new(HashMap::new()),
+            in_flight: Mutex::new(HashMap::new()),
+            kept_parts: Mutex::new(HashMap::new()),
+            read_cache: ReadCache::default(),
+            pxe_clients: Mutex::new(PxeClients::default()),
+        }
+    }
+
+    /// Snapshot of the current runtime config.
+    pub fn config(&self) -> ServerConfig {
+        self.config.read().unwrap().clone()
+    }
+
+    /// Modify the runtime config in place and return the new value.
+    pub fn update_config(&self, f: impl FnOnce(&mut ServerConfig)) -> ServerConfig {
+        let mut config = self.config.write().unwrap();
+        f(&mut config);
+        config.clone()
+    }
+
+    /// Register a transfer so it can be cancelled. The token is dropped
+    /// again when the transfer completes or fails.
+    pub fn register_transfer(&self, id: u64) -> CancellationToken {
+        let token = CancellationToken::new();
+        self.cancel_tokens.lock().unwrap().insert(id, token.clone());
+        token
+    }
+
+    /// Request cancellation of a running transfer. Returns false if no
+    /// transfer with that ID is running.
+    pub fn cancel_transfer(&self, id: u64) -> bool {
+        match self.cancel_tokens.lock().unwrap().get(&id) {
+            Some(token) => {
+                token.cancel();
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn active_transfers(&self) -> Vec<TransferInfo> {
+        self.active.lock().unwrap().clone()
+    }
+
+    /// Most recent finished transfers, newest first.
+    pub fn recent_transfers(&self, limit: usize) -> Vec<TransferRecord> {
+        self.history
+            .lock()
+            .unwrap()
+            .iter()
+            .rev()
+            .take(limit)
+            .cloned()
+            .collect()
+    }
+
+    /// The last `lines` log entries, oldest first.
+    pub fn log_tail(&self, lines: usize) -> Vec<LogEntry> {
+        let logs = self.logs.lock().unwrap();
+        logs.iter()
+            .skip(logs.len().saturating_sub(lines))
+            .cloned()
+            .collect()
+    }
+
+    /// Digests of `path` from a completed transfer, if the file has not
+    /// changed since.
+    pub fn cached_checksums(&self, path: &Path) -> Option<Checksums> {
+        let metadata = std::fs::metadata(path).ok()?;
+        let digests = self.digests.lock().unwrap();
+        let cached = digests.get(path)?;
+        (cached.len == metadata.len() && Some(cached.modified) == metadata.modified().ok())
+            .then(|| cached.checksums.clone())
+    }
+
+    /// Remember the digests of `path` as it is now.
+    pub fn cache_checksums(&self, path: &Path, checksums: Checksums) {
+        let Ok(metadata) = std::fs::metadata(path) else {
+            return;
+        };
+        let Ok(modified) = metadata.modified() else {
+            return;
+        };
+        let mut digests = self.digests.lock().unwrap();
+        if digests.len() >= DIGEST_CACHE_CAPACITY && !digests.contains_key(path) {
+            // Entries are cheap to recompute; drop an arbitrary one.
+            if let Some(key) = digests.keys().next().cloned() {
+                digests.remove(&key);
+            }
+        }
+        digests.insert(
+            path.to_path_buf(),
+            CachedDigest {
+                len: metadata.len(),
+                modified,
+                checksums,
+            },
+        );
+    }
+
+    /// Count a completed upload of `bytes` against `client`. Uploads older
+    /// than the quota window are dropped for every client on the way.
+    pub fn record_upload(&self, client: IpAddr, bytes: u64) {
+        let mut uploads = self.uploads.lock().unwrap();
+        uploads.retain(|_, recent| {
+            while recent
+                .front()
+                .is_some_and(|(at, _)| at.elapsed() > CLIENT_QUOTA_WINDOW)
+            {
+                recent.pop_front();
+            }
+            !recent.is_empty()
+        });
+        uploads
+            .entry(client)
+            .or_default()
+            .push_back((Instant::now(), bytes));
+    }
+
+    /// Bytes `client` uploaded successfully within the last `window`.
+    pub fn uploaded_by(&self, client: IpAddr, window: Duration) -> u64 {
+        let mut uploads = self.uploads.lock().unwrap();
+        let Some(recent) = uploads.get_mut(&client) else {
+            return 0;
+        };
+        while recent.front().is_some_and(|(at, _)| at.elapsed() > window) {
+            recent.pop_front();
+        }
+        if recent.is_empty() {
+            uploads.remove(&client);
+            return 0;
+        }
+        recent.iter().map(|(_, bytes)| bytes).sum()
+    }
+
+    /// Start counting an upload of `path` by `client`, limited by the quota
+    /// directories `dirs`. Returns its reservation id and what other
+    /// running uploads have written so far, per client and directory.
+    pub fn begin_upload(
+        &self,
+        client: IpAddr,
+        path: &Path,
+        dirs: Vec<PathBuf>,
+    ) -> (u64, UploadUsage) {
+        let id = next_reservation_id();
+        let mut in_flight = self.in_flight.lock().unwrap();
+        let usage = UploadUsage {
+            client: written_by(&in_flight, client),
+            dirs: dirs.iter().map(|d| written_under(&in_flight, d)).collect(),
+        };
+        in_flight.insert(
+            id,
+            InFlightUpload {
+                client,
+                path: path.to_path_buf(),
+                written: 0,
+                client_completed: 0,
+                dirs: dirs.into_iter().map(|d| (d, 0)).collect(),
+            },
+        );
+        (id, usage)
+    }
+
+    /// Record that upload `id` has written `written` bytes. Returns what
+    /// other uploads have added since: their bytes still in flight plus
+    /// those finished after `id` started.
+    pub fn upload_usage(&self, id: u64, written: u64) -> UploadUsage {
+        let mut in_flight = self.in_flight.lock().unwrap();
+        let Some(upload) = in_flight.get_mut(&id) else {
+            return UploadUsage::default();
+        };
+        upload.written = written;
+        let upload = &in_flight[&id];
+        let mut usage = UploadUsage {
+            client: upload.client_completed,
+            dirs: upload.dirs.iter().map(|(_, done)| *done).collect(),
+        };
+        let others = in_flight.iter().filter(|(other, _)| **other != id);
+        for (_, other) in others {
+            if other.client == upload.client {
+                usage.client += other.written;
+            }
+            for ((dir, _), used) in upload.dirs.iter().zip(&mut usage.dirs) {
+                if other.path.starts_with(dir) {
+                    *used += other.written;
+                }
+            }
+        }
+        usage
+    }
+
+    /// Stop counting upload `id`. What a completed upload wrote stays on
+    /// disk, so it is charged to the uploads still running next to it.
+    pub fn end_upload(&self, id: u64, completed: bool) {
+        let mut in_flight = self.in_flight.lock().unwrap();
+        let Some(done) = in_flight.remove(&id) else {
+            return;
+        };
+        if !completed {
+            return;
+        }
+        for upload in in_flight.values_mut() {
+            if upload.client == done.client {
+                upload.client_completed += done.written;
+            }
+            for (dir, finished) in &mut upload.dirs {
+                if done.path.starts_with(dir) {
+                    *finished += done.written;
+                }
+            }
+        }
+    }
+
+    /// Remember the `.part` file of a failed upload that negotiated
+    /// `offset`, so it is deleted if no retry takes it back in time.
+    pub fn keep_part(&self, path: PathBuf) {
+        self.kept_parts.lock().unwrap().insert(path, Instant::now());
+    }
+
+    /// Take a kept `.part` file back for a retry, so it is not deleted
+    /// while the retry writes to it.
+    pub fn claim_part(&self, path: &Path) {
+        self.kept_parts.lock().unwrap().remove(path);
+    }
+
+    /// Delete the kept `.part` files that waited `max_age` or longer
+    /// and return their paths.
+    pub fn expire_parts(&self, max_age: Duration) -> Vec<PathBuf> {
+        let mut expired = Vec::new();
+        self.kept_parts.lock().unwrap().retain(|path, kept| {
+            if kept.elapsed() < max_age {
+                return true;
+            }
+            let _ = std::fs::remove_file(path);
+            expired.push(path.clone());
+            false
+        });
+        expired
+    }
+
+    /// File contents cached for RRQ, within the `cache_size` budget.
+    pub fn read_cache(&self) -> &ReadCache {
+        &self.read_cache
+    }
+
+    /// Read cache counters; `None` while the cache is off.
+    pub fn cache_stats(&self) -> Option<CacheStats> {
+        let budget = self.config.read().unwrap().cache_size;
+        (budget > 0).then(|| self.read_cache.stats(budget))
+    }
+
+    /// Fold a server event into the shared state.
+    pub fn apply(&self, ev: &ServerEvent) {
+        match ev {
+            ServerEvent::Log(msg) => self.push_log(msg.clone()),
+            ServerEvent::TransferStarted(info) => {
+                self.active.lock().unwrap().push(info.clone());
+            }
+            ServerEvent::TransferProgress {
+                id,
+                transferred,
+                total_bytes,
+            } => {
+                if let Some(tf) = self.active.lock().unwrap().iter_mut().find(|t| t.id == *id) {
+                    tf.transferred = *transferred;
+                    tf.total_bytes = *total_bytes;
+                }
+            }
+            ServerEvent::TransferComplete { id, checksums } => {
+                self.finish(*id, None, checksums.clone())
+            }
+            ServerEvent::TransferFailed { id, error } => {
+                self.finish(*id, Some(error.clone()), None)
+            }
+            ServerEvent::PxeBoot(boot) => {
+                self.record_pxe_client(boot);
+                self.push_log(boot.to_string());
+            }
+        }
+    }
+
+    fn finish(&self, id: u64, error: Option<String>, checksums: Option<Checksums>) {
+        self.cancel_tokens.lock().unwrap().remove(&id);
+
+        let info = {
+            let mut active = self.active.lock().unwrap();
+            match active.iter().position(|t| t.id == id) {
+                Some(pos) => active.remove(pos),
+                None => return,
+            }
+        };
+
+        if let Some(ref sums) = checksums {
+            self.cache_checksums(&info.path, sums.clone());
+        }
+        // Only the client quota reads these, so don't keep them without one.
+        if error.is_none()
+            && info.kind == TransferKind::Upload
+            && self.config.read().unwrap().client_quota > 0
+        {
+            self.record_upload(info.peer.ip(), info.transferred);
+        }
+
+        let mut history = self.history.lock().unwrap();
+        if history.len() >= HISTORY_CAPACITY {
+            history.pop_front();
+        }
+        history.push_back(TransferRecord {
+            id,
+            peer: info.peer,
+            filename: info.filename,
+            kind: info.kind,
+            bytes: info.transferred,
+            duration_ms: info.started.elapsed().as_millis() as u64,
+            finished_at: unix_now(),
+            success: error.is_none(),
+            error,
+            checksums,
+        });
+    }
+
+    fn record_pxe_client(&self, boot: &PxeBoot) {
+        self.pxe_clients.lock().unwrap().record(boot);
+    }
+
+    /// MAC of the PXE client that reported `ip` to the ProxyDHCP responder.
+    pub fn pxe_mac(&self, ip: IpAddr) -> Option<String> {
+        self.pxe_clients.lock().unwrap().by_ip.get(&ip).cloned()
+    }
+
+    /// Architecture and iPXE flag last reported by the PXE client `mac`.
+    pub fn pxe_client(&self, mac: &str) -> Option<(ClientArch, bool)> {
+        self.pxe_clients
+            .lock()
+            .unwrap()
+            .by_mac
+            .get(mac)
+            .map(|c| (c.arch, c.ipxe))
+    }
+
+    fn push_log(&self, message: String) {
+        let mut logs = self.logs.lock().unwrap();
+        if logs.len() >= LOG_CAPACITY {
+            logs.pop_front();
+        }
+        logs.push_back(LogEntry {
+            time: unix_now(),
+            message,
+        });
+    }
+}
+
+fn next_reservation_id() -> u64 {
+    static NEXT: AtomicU64 = AtomicU64::new(1);
+    NEXT.fetch_add(1, Ordering::Relaxed)
+}
+
+fn written_by(in_flight: &HashMap<u64, InFlightUpload>, client: IpAddr) -> u64 {
+    in_flight
+        .values()
+        .filter(|u| u.client == client)
+        .map(|u| u.written)
+        .sum()
+}
+
+fn written_under(in_flight: &HashMap<u64, InFlightUpload>, dir: &Path) -> u64 {
+    in_flight
+        .values()
+        .filter(|u| u.path.starts_with(dir))
+        .map(|u| u.written)
+        .sum()
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+    use std::time::Instant;
+
+    fn started(id: u64) -> ServerEvent {
+        ServerEvent::TransferStarted(TransferInfo {
+            id,
+            peer: "127.0.0.1:1234".parse().unwrap(),
+            filename: format!("file{id}.bin"),
+            path: PathBuf::from("/srv/tftp").join(format!("file{id}.bin")),
+            kind: TransferKind::Download,
+            total_bytes: 100,
+            transferred: 0,
+            started: Instant::now(),
+            size_known: true,
+        })
+    }
+
+    #[test]
+    fn events_move_transfers_into_history() {
+        let state = SharedState::new(ServerConfig::default());
+        let token = state.register_transfer(1);
+        state.apply(&started(1));
+        state.apply(&started(2));
+        state.apply(&ServerEvent::TransferProgress {
+            id: 1,
+            transferred: 60,
+            total_bytes: 100,
+        });
+        assert_eq!(state.active_transfers().len(), 2);
+
+        assert!(state.cancel_transfer(1));
+        assert!(token.is_cancelled());
+        state.apply(&ServerEvent::TransferFailed {
+            id: 1,
+            error: "cancelled".into(),
+        });
+        state.apply(&ServerEvent::TransferComplete {
+            id: 2,
+            checksums: None,
+        });
+
+        assert!(state.active_transfers().is_empty());
+        assert!(!state.cancel_transfer(1));
+        let recent = state.recent_transfers(10);
+        assert_eq!(recent.len(), 2);
+        assert_eq!(recent[0].id, 2);
+        assert!(recent[0].success);
+        assert_eq!(recent[1].bytes, 60);
+        assert_eq!(recent[1].error.as_deref(), Some("cancelled"));
+    }
+
+    #[test]
+    fn completed_uploads_count_against_the_client() {
+        let state = SharedState::new(ServerConfig {
+            client_quota: 1000,
+            ..ServerConfig::default()
+        });
+        let client: IpAddr = "127.0.0.1".parse().unwrap();
+        for (id, ok) in [(1, true), (2, false)] {
+            let ServerEvent::TransferStarted(mut info) = started(id) else {
+                unreachable!()
+            };
+            info.kind = TransferKind::Upload;
+            state.apply(&ServerEvent::TransferStarted(info));
+            state.apply(&ServerEvent::TransferProgress {
+                id,
+                transferred: 40,
+                total_bytes: 100,
+            });
+            state.apply(&if ok {
+                ServerEvent::TransferComplete {
+                    id,
+                    checksums: None,
+                }
+            } else {
+                ServerEvent::TransferFailed {
+                    id,
+                    error: "timeout".into(),
+                }
+            });
+        }
+        // Only the completed upload counts; downloads never do.
+        state.apply(&started(3));
+        state.apply(&ServerEvent::TransferComplete {
+            id: 3,
+            checksums: None,
+        });
+        assert_eq!(state.uploaded_by(client, Duration::from_secs(60)), 40);
+        assert_eq!(state.uploaded_by(client, Duration::ZERO), 0);
+
+        // Without a client quota nothing is kept.
+        state.update_config(|c| c.client_quota = 0);
+        let ServerEvent::TransferStarted(mut info) = started(4) else {
+            unreachable!()
+        };
+        info.kind = TransferKind::Upload;
+        state.apply(&ServerEvent::TransferStarted(info));
+        state.apply(&ServerEvent::TransferComplete {
+            id: 4,
+            checksums: None,
+        });
+        assert!(state.uploads.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn digest_cache_is_invalidated_by_changes() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("fw.bin");
+        std::fs::write(&path, b"v1").unwrap();
+        let state = SharedState::new(ServerConfig::default());
+        let sums = Checksums {
+            sha256: "00".repeat(32),
+            crc32: None,
+            md5: None,
+        };
+        state.cache_checksums(&path, sums.clone());
+        assert_eq!(state.cached_checksums(&path), Some(sums));
+
+        std::fs::write(&path, b"v2 longer").unwrap();
+        assert_eq!(state.cached_checksums(&path), None);
+    }
+
+    #[test]
+    fn pxe_clients_are_bounded_and_follow_moves() {
+        use crate::proxy_dhcp::PxeStage;
+        use std::net::Ipv4Addr;
+
+        let boot = |n: usize, ip: Option<[u8; 4]>| {
+            ServerEvent::PxeBoot(PxeBoot {
+                mac: format!("52:54:00:00:{:02x}:{:02x}", n >> 8, n & 0xff),
+                client_ip: ip.map(Ipv4Addr::from),
+                arch: ClientArch::Efi64,
+                ipxe: false,
+                boot_file: "bootx64.efi".into(),
+                server_ip: Ipv4Addr::LOCALHOST,
+                stage: PxeStage::Ack,
+            })
+        };
+        let state = SharedState::new(ServerConfig::default());
+        let ip = |a: [u8; 4]| IpAddr::from(a);
+
+        state.apply(&boot(0, Some([10, 0, 0, 1])));
+        state.apply(&boot(0, None));
+        assert_eq!(
+            state.pxe_mac(ip([10, 0, 0, 1])).as_deref(),
+            Some("52:54:00:00:00:00")
+        );
+        state.apply(&boot(0, Some([10, 0, 0, 2])));
+        assert_eq!(state.pxe_mac(ip([10, 0, 0, 1])), None);
+        assert!(state.pxe_mac(ip([10, 0, 0, 2])).is_some());
+
+        for n in 1..=PXE_CLIENT_CAPACITY {
+            state.apply(&boot(n, None));
+        }
+        let clients = state.pxe_clients.lock().unwrap();
+        assert_eq!(clients.by_mac.len(), PXE_CLIENT_CAPACITY);
+        assert!(!clients.by_mac.contains_key("52:54:00:00:00:00"));
+        assert!(clients.by_ip.is_empty());
+    }
+
+    #[test]
+    fn log_tail_is_bounded() {
+        let state = SharedState::new(ServerConfig::default());
+        for i in 0..LOG_CAPACITY + 5 {
+            state.apply(&ServerEvent::Log(format!("line {i}")));
+        }
+        let tail = state.log_tail(2);
+        assert_eq!(tail.len(), 2);
+        assert_eq!(tail[1].message, format!("line {}", LOG_CAPACITY + 4));
+        assert_eq!(state.log_tail(usize::MAX).len(), LOG_CAPACITY);
+    }
+
+    #[test]
+    fn claimed_parts_do_not_expire() {
+        let dir = tempfile::tempdir().unwrap();
+        let (resumed, abandoned) = (dir.path().join("a.part"), dir.path().join("b.part"));
+        std::fs::write(&resumed, b"x").unwrap();
+        std::fs::write(&abandoned, b"x").unwrap();
+
+        let state = SharedState::new(ServerConfig::default());
+        state.keep_part(resumed.clone());
+        state.keep_part(abandoned.clone());
+        state.claim_part(&resumed);
+        assert_eq!(state.expire_parts(Duration::ZERO), vec![abandoned.clone()]);
+        assert!(resumed.exists());
+        assert!(!abandoned.exists());
+    }
+}
@@ -1,5 +1,3 @@
-use std::collections::HashMap;
-
 use anyhow::{Result, anyhow};
 
 /// TFTP opcodes per RFC 1350 + RFC 2347.
@@ -28,18 +26,18 @@ pub const MIN_TIMEOUT: u8 = 1;
 pub const MAX_TIMEOUT: u8 = 255;
 
 /// A fully parsed TFTP packet.
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 #[allow(clippy::upper_case_acronyms)]
 pub enum Packet {
     RRQ {
         filename: String,
         mode: String,
-        options: HashMap<String, String>,
+        options: Options,
     },
     WRQ {
         filename: String,
         mode: String,
-        options: HashMap<String, String>,
+        options: Options,
     },
     DATA {
         block_num: u16,
@@ -54,26 +52,14 @@ pub enum Packet {
     },
     /// Option Acknowledgment (RFC 2347).
     OACK {
-        options: HashMap<String, String>,
+        options: Options,
     },
 }
 
 impl Packet {
     /// Parse raw bytes into a `Packet`.
     pub fn from_bytes(buf: &[u8]) -> Result<Self> {
-        if buf.len() < 2 {
-            return Err(anyhow!("packet too short"));
-        }
-        let opcode = u16::from_be_bytes([buf[0], buf[1]]);
-        match opcode {
-            OPCODE_RRQ => parse_request(buf, true),
-            OPCODE_WRQ => parse_request(buf, false),
-            OPCODE_DATA => parse_data(buf),
-            OPCODE_ACK => parse_ack(buf),
-            OPCODE_ERROR => parse_error(buf),
-            OPCODE_OACK => parse_oack(buf),
-            _ => Err(anyhow!("unknown opcode {opcode}")),
-        }
+        PacketRef::parse(buf).map(Packet::from)
     }
 
     /// Serialize the packet to bytes for transmission.
@@ -96,12 +82,7 @@ impl Packet {
                 buf.extend_from_slice(data);
                 buf
             }
-            Packet::ACK { block_num } => {
-                let mut buf = Vec::with_capacity(4);
-                buf.extend_from_slice(&OPCODE_ACK.to_be_bytes());
-                buf.extend_from_slice(&block_num.to_be_bytes());
-                buf
-            }
+            Packet::ACK { block_num } => ack_bytes(*block_num).to_vec(),
             Packet::ERROR { code, msg } => {
                 let mut buf = Vec::with_capacity(5 + msg.len());
                 buf.extend_from_slice(&OPCODE_ERROR.to_be_bytes());
@@ -113,7 +94,7 @@ impl Packet {
             Packet::OACK { options } => {
                 let mut buf = Vec::new();
                 buf.extend_from_slice(&OPCODE_OACK.to_be_bytes());
-                for (key, val) in options {
+                for (key, val) in options.iter() {
                     buf.extend_from_slice(key.as_bytes());
                     buf.push(0);
                     buf.extend_from_slice(val.as_bytes());
@@ -135,117 +116,478 @@ impl Packet {
 }
 
 // ---------------------------------------------------------------------------
-// Internal parsing helpers
+// Errors
 // ---------------------------------------------------------------------------
 
-/// Parse RRQ / WRQ: 2‑byte opcode | filename\0 | mode\0 [| option\0 | value\0 ]*
-fn parse_request(buf: &[u8], is_rrq: bool) -> Result<Packet> {
-    let payload = &buf[2..];
-    let fields: Vec<&[u8]> = payload.split(|&b| b == 0).collect();
+/// An error reported to the peer in an ERROR packet: the codes of RFC 1350
+/// plus 8 from RFC 2347. Variants without a message send the RFC's wording.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TftpError {
+    /// 0: not defined, see the message.
+    NotDefined(String),
+    /// 1: file not found.
+    FileNotFound,
+    /// 2: access violation.
+    AccessViolation(String),
+    /// 3: disk full or allocation exceeded.
+    DiskFull(String),
+    /// 4: illegal TFTP operation.
+    IllegalOperation(String),
+    /// 5: unknown transfer ID, i.e. a datagram from the wrong port.
+    UnknownTransferId,
+    /// 6: file already exists.
+    FileExists,
+    /// 7: no such user.
+    NoSuchUser,
+    /// 8: the request's options were refused (RFC 2347).
+    OptionRefused(String),
+}
 
-    if fields.len() < 2 {
-        return Err(anyhow!("missing filename or mode"));
+impl TftpError {
+    /// The error received in an ERROR packet with `code` and `msg`.
+    pub fn from_code(code: u16, msg: &str) -> Self {
+        match code {
+            1 => Self::FileNotFound,
+            2 => Self::AccessViolation(msg.to_string()),
+            3 => Self::DiskFull(msg.to_string()),
+            4 => Self::IllegalOperation(msg.to_string()),
+            5 => Self::UnknownTransferId,
+            6 => Self::FileExists,
+            7 => Self::NoSuchUser,
+            8 => Self::OptionRefused(msg.to_string()),
+            _ => Self::NotDefined(msg.to_string()),
+        }
     }
 
-    let filename = String::from_utf8(fields[0].to_vec())?;
-    let mode = String::from_utf8(fields[1].to_vec())?.to_ascii_lowercase();
+    pub fn code(&self) -> u16 {
+        match self {
+            Self::NotDefined(_) => 0,
+            Self::FileNotFound => 1,
+            Self::AccessViolation(_) => 2,
+            Self::DiskFull(_) => 3,
+            Self::IllegalOperation(_) => 4,
+            Self::UnknownTransferId => 5,
+            Self::FileExists => 6,
+            Self::NoSuchUser => 7,
+            Self::OptionRefused(_) => 8,
+        }
+    }
 
-    if filename.is_empty() {
-        return Err(anyhow!("empty filename"));
+    /// The text carried in the ERROR packet.
+    pub fn message(&self) -> &str {
+        match self {
+            Self::NotDefined(msg)
+            | Self::AccessViolation(msg)
+            | Self::DiskFull(msg)
+            | Self::IllegalOperation(msg)
+            | Self::OptionRefused(msg) => msg,
+            Self::FileNotFound => "File not found",
+            Self::UnknownTransferId => "Unknown transfer ID",
+            Self::FileExists => "File already exists",
+            Self::NoSuchUser => "No such user",
+        }
     }
 
-    // Parse RFC 2347 options (key-value pairs after mode).
-    let mut options = HashMap::new();
-    let mut i = 2;
-    while i + 1 < fields.len() {
-        let key = String::from_utf8(fields[i].to_vec())?.to_ascii_lowercase();
-        let val = String::from_utf8(fields[i + 1].to_vec())?;
-        if !key.is_empty() {
-            options.insert(key, val);
+    /// The ERROR packet, serialized.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        Packet::ERROR {
+            code: self.code(),
+            msg: self.message().to_string(),
         }
-        i += 2;
+        .to_bytes()
     }
+}
 
-    if is_rrq {
-        Ok(Packet::RRQ {
-            filename,
-            mode,
-            options,
-        })
-    } else {
-        Ok(Packet::WRQ {
-            filename,
-            mode,
-            options,
-        })
+impl From<&std::io::Error> for TftpError {
+    fn from(e: &std::io::Error) -> Self {
+        use std::io::ErrorKind;
+        match e.kind() {
+            ErrorKind::NotFound => Self::FileNotFound,
+            ErrorKind::PermissionDenied
+            | ErrorKind::ReadOnlyFilesystem
+            | ErrorKind::IsADirectory
+            | ErrorKind::NotADirectory => Self::AccessViolation(e.kind().to_string()),
+            ErrorKind::StorageFull | ErrorKind::QuotaExceeded | ErrorKind::FileTooLarge => {
+                Self::DiskFull(e.kind().to_string())
+            }
+            ErrorKind::AlreadyExists => Self::FileExists,
+            _ => Self::NotDefined(e.to_string()),
+        }
+    }
+}
+
+impl std::fmt::Display for TftpError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "error {}: {}", self.code(), self.message())
+    }
+}
+
+impl std::error::Error for TftpError {}
+
+// ---------------------------------------------------------------------------
+// Hot path: in-place DATA packets and borrowed parsing
+// ---------------------------------------------------------------------------
+
+/// A DATA packet built in place: the 4-byte header and up to `blksize`
+/// bytes of payload share one buffer, which is reused from block to block
+/// so steady-state transfers do not allocate.
+#[derive(Debug)]
+pub struct DataBuf {
+    buf: Vec<u8>,
+}
+
+impl DataBuf {
+    pub fn new(blksize: usize) -> Self {
+        let mut buf = Vec::with_capacity(4 + blksize);
+        buf.extend_from_slice(&OPCODE_DATA.to_be_bytes());
+        buf.extend_from_slice(&[0, 0]);
+        Self { buf }
+    }
+
+    pub fn block(&self) -> u16 {
+        u16::from_be_bytes([self.buf[2], self.buf[3]])
+    }
+
+    pub fn set_block(&mut self, block_num: u16) {
+        self.buf[2..4].copy_from_slice(&block_num.to_be_bytes());
+    }
+
+    pub fn payload(&self) -> &[u8] {
+        &self.buf[4..]
+    }
+
+    /// Room for a `len`-byte payload, to be trimmed with `set_len`.
+    /// Within the capacity given to `new` this never reallocates.
+    pub fn payload_mut(&mut self, len: usize) -> &mut [u8] {
+        self.buf.resize(4 + len, 0);
+        &mut self.buf[4..]
+    }
+
+    /// Keep the first `len` bytes of the payload.
+    pub fn set_len(&mut self, len: usize) {
+        self.buf.truncate(4 + len);
+    }
+
+    /// The whole packet, ready to send.
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.buf
+    }
+}
+
+impl AsRef<[u8]> for DataBuf {
+    fn as_ref(&self) -> &[u8] {
+        self.as_bytes()
+    }
+}
+
+/// Serialize an ACK without allocating.
+pub fn ack_bytes(block_num: u16) -> [u8; 4] {
+    let [a, b] = OPCODE_ACK.to_be_bytes();
+    let [c, d] = block_num.to_be_bytes();
+    [a, b, c, d]
+}
+
+// ---------------------------------------------------------------------------
+// Borrowed parsing
+// ---------------------------------------------------------------------------
+
+/// A TFTP packet parsed in place: strings and payloads borrow from the
+/// datagram, so parsing never allocates. `Packet::from_bytes` is this plus
+/// a copy; unlike it, mode and option names keep the case they were sent
+/// in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[allow(clippy::upper_case_acronyms)]
+pub enum PacketRef<'a> {
+    RRQ {
+        filename: &'a str,
+        mode: &'a str,
+        options: OptionsRef<'a>,
+    },
+    WRQ {
+        filename: &'a str,
+        mode: &'a str,
+        options: OptionsRef<'a>,
+    },
+    DATA {
+        block_num: u16,
+        data: &'a [u8],
+    },
+    ACK {
+        block_num: u16,
+    },
+    /// `msg` is the raw message up to its NUL, not necessarily UTF-8.
+    ERROR {
+        code: u16,
+        msg: &'a [u8],
+    },
+    OACK {
+        options: OptionsRef<'a>,
+    },
+}
+
+impl<'a> PacketRef<'a> {
+    /// Parse a datagram, accepting exactly what `Packet::from_bytes` does.
+    pub fn parse(buf: &'a [u8]) -> Result<Self> {
+        let (opcode, body) = match buf {
+            [hi, lo, body @ ..] => (u16::from_be_bytes([*hi, *lo]), body),
+            _ => return Err(anyhow!("packet too short")),
+        };
+        match opcode {
+            OPCODE_RRQ | OPCODE_WRQ => {
+                let (filename, mode, options) = parse_request(body)?;
+                Ok(if opcode == OPCODE_RRQ {
+                    PacketRef::RRQ {
+                        filename,
+                        mode,
+                        options,
+                    }
+                } else {
+                    PacketRef::WRQ {
+                        filename,
+                        mode,
+                        options,
+                    }
+                })
+            }
+            // DATA: 2‑byte block# | 0‥N bytes
+            OPCODE_DATA => match body {
+                [hi, lo, data @ ..] => Ok(PacketRef::DATA {
+                    block_num: u16::from_be_bytes([*hi, *lo]),
+                    data,
+                }),
+                _ => Err(anyhow!("DATA packet too short")),
+            },
+            // ACK: 2‑byte block#
+            OPCODE_ACK => match body {
+                [hi, lo, ..] => Ok(PacketRef::ACK {
+                    block_num: u16::from_be_bytes([*hi, *lo]),
+                }),
+                _ => Err(anyhow!("ACK packet too short")),
+            },
+            // ERROR: 2‑byte code | msg\0
+            OPCODE_ERROR => match body {
+                [hi, lo, msg @ ..] if !msg.is_empty() => {
+                    // Strip trailing NUL if present.
+                    let end = msg.iter().position(|&b| b == 0).unwrap_or(msg.len());
+                    Ok(PacketRef::ERROR {
+                        code: u16::from_be_bytes([*hi, *lo]),
+                        msg: &msg[..end],
+                    })
+                }
+                _ => Err(anyhow!("ERROR packet too short")),
+            },
+            // OACK: [option\0 | value\0]*
+            OPCODE_OACK => Ok(PacketRef::OACK {
+                options: OptionsRef::parse(body)?,
+            }),
+            _ => Err(anyhow!("unknown opcode {opcode}")),
+        }
+    }
+}
+
+impl From<PacketRef<'_>> for Packet {
+    fn from(pkt: PacketRef<'_>) -> Self {
+        match pkt {
+            PacketRef::RRQ {
+                filename,
+                mode,
+                options,
+            } => Packet::RRQ {
+                filename: filename.to_string(),
+                mode: mode.to_ascii_lowercase(),
+                options: options.to_options(),
+            },
+            PacketRef::WRQ {
+                filename,
+                mode,
+                options,
+            } => Packet::WRQ {
+                filename: filename.to_string(),
+                mode: mode.to_ascii_lowercase(),
+                options: options.to_options(),
+            },
+            PacketRef::DATA { block_num, data } => Packet::DATA {
+                block_num,
+                data: data.to_vec(),
+            },
+            PacketRef::ACK { block_num } => Packet::ACK { block_num },
+            PacketRef::ERROR { code, msg } => Packet::ERROR {
+                code,
+                msg: String::from_utf8_lossy(msg).to_string(),
+            },
+            PacketRef::OACK { options } => Packet::OACK {
+                options: options.to_options(),
+            },
+        }
+    }
+}
+
+/// RFC 2347 options of a request or OACK, borrowed from the datagram.
+/// Iterating yields them in the order they were sent, with option names in
+/// their original case; pairs with an empty name are skipped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OptionsRef<'a> {
+    /// `name\0value\0...`; every complete pair is valid UTF-8.
+    fields: &'a [u8],
+}
+
+impl<'a> OptionsRef<'a> {
+    fn parse(fields: &'a [u8]) -> Result<Self> {
+        for (name, value) in pairs(fields) {
+            std::str::from_utf8(name)?;
+            std::str::from_utf8(value)?;
+        }
+        Ok(Self { fields })
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&'a str, &'a str)> + use<'a> {
+        pairs(self.fields)
+            .filter(|(name, _)| !name.is_empty())
+            .map(|(name, value)| {
+                let utf8 = |b| std::str::from_utf8(b).expect("validated by OptionsRef::parse");
+                (utf8(name), utf8(value))
+            })
+    }
+
+    /// What is wrong with the options as sent, if anything: a value
+    /// without a name, or a name without a value. Trailing NUL padding is
+    /// tolerated.
+    pub fn malformed(&self) -> Option<String> {
+        let mut split = self.fields.split(|&b| b == 0);
+        loop {
+            match (split.next()?, split.next()) {
+                (name, Some(value)) if name.is_empty() && !value.is_empty() => {
+                    return Some("option value without a name".to_string());
+                }
+                (_, Some(_)) => {}
+                (name, None) => {
+                    return (!name.is_empty()).then(|| {
+                        format!("option \"{}\" has no value", String::from_utf8_lossy(name))
+                    });
+                }
+            }
+        }
+    }
+
+    fn to_options(self) -> Options {
+        Options {
+            entries: self
+                .iter()
+                .map(|(name, value)| (name.to_ascii_lowercase(), value.to_string()))
+                .collect(),
+            malformed: self.malformed(),
+        }
+    }
+}
+
+/// RFC 2347 options in the order they were sent, names lowercased.
+/// Duplicates are kept as sent; choosing between them is up to the caller.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Options {
+    entries: Vec<(String, String)>,
+    /// What was wrong with the options as sent; the offending fields are
+    /// not in `entries`.
+    malformed: Option<String>,
+}
+
+impl Options {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Value of the first option called `name`.
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.entries
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, v)| v.as_str())
+    }
+
+    pub fn contains_key(&self, name: &str) -> bool {
+        self.get(name).is_some()
+    }
+
+    /// Set `name` to `value`: in place if present, otherwise at the end.
+    pub fn insert(&mut self, name: String, value: String) {
+        match self.entries.iter_mut().find(|(n, _)| *n == name) {
+            Some((_, v)) => *v = value,
+            None => self.entries.push((name, value)),
+        }
+    }
+
+    pub fn remove(&mut self, name: &str) {
+        self.entries.retain(|(n, _)| n != name);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.entries.iter().map(|(n, v)| (n.as_str(), v.as_str()))
+    }
+
+    /// See `OptionsRef::malformed`.
+    pub fn malformed(&self) -> Option<&str> {
+        self.malformed.as_deref()
     }
 }
 
-/// Parse DATA: 2‑byte opcode | 2‑byte block# | 0‥N bytes
-fn parse_data(buf: &[u8]) -> Result<Packet> {
-    if buf.len() < 4 {
-        return Err(anyhow!("DATA packet too short"));
+impl FromIterator<(String, String)> for Options {
+    fn from_iter<I: IntoIterator<Item = (String, String)>>(entries: I) -> Self {
+        Self {
+            entries: entries.into_iter().collect(),
+            malformed: None,
+        }
     }
-    let block_num = u16::from_be_bytes([buf[2], buf[3]]);
-    let data = buf[4..].to_vec();
-    Ok(Packet::DATA { block_num, data })
 }
 
-/// Parse ACK: 2‑byte opcode | 2‑byte block#
-fn parse_ack(buf: &[u8]) -> Result<Packet> {
-    if buf.len() < 4 {
-        return Err(anyhow!("ACK packet too short"));
+#[cfg(test)]
+impl<const N: usize> From<[(&str, &str); N]> for Options {
+    fn from(entries: [(&str, &str); N]) -> Self {
+        Self {
+            entries: entries
+                .iter()
+                .map(|(n, v)| (n.to_string(), v.to_string()))
+                .collect(),
+            malformed: None,
+        }
     }
-    let block_num = u16::from_be_bytes([buf[2], buf[3]]);
-    Ok(Packet::ACK { block_num })
 }
 
-/// Parse ERROR: 2‑byte opcode | 2‑byte code | msg\0
-fn parse_error(buf: &[u8]) -> Result<Packet> {
-    if buf.len() < 5 {
-        return Err(anyhow!("ERROR packet too short"));
-    }
-    let code = u16::from_be_bytes([buf[2], buf[3]]);
-    let msg_bytes = &buf[4..];
-    // Strip trailing NUL if present.
-    let end = msg_bytes
-        .iter()
-        .position(|&b| b == 0)
-        .unwrap_or(msg_bytes.len());
-    let msg = String::from_utf8_lossy(&msg_bytes[..end]).to_string();
-    Ok(Packet::ERROR { code, msg })
+/// NUL-separated name/value pairs; an unpaired trailing field is ignored.
+fn pairs(fields: &[u8]) -> impl Iterator<Item = (&[u8], &[u8])> {
+    let mut split = fields.split(|&b| b == 0);
+    std::iter::from_fn(move || Some((split.next()?, split.next()?)))
 }
 
-/// Parse OACK: 2‑byte opcode | [option\0 | value\0]*
-fn parse_oack(buf: &[u8]) -> Result<Packet> {
-    let payload = &buf[2..];
-    let fields: Vec<&[u8]> = payload.split(|&b| b == 0).collect();
-    let mut options = HashMap::new();
-    let mut i = 0;
-    while i + 1 < fields.len() {
-        let key = String::from_utf8(fields[i].to_vec())?.to_ascii_lowercase();
-        let val = String::from_utf8(fields[i + 1].to_vec())?;
-        if !key.is_empty() {
-            options.insert(key, val);
-        }
-        i += 2;
-    }
-    Ok(Packet::OACK { options })
+/// RRQ / WRQ body: filename\0 | mode\0 [| option\0 | value\0 ]*
+fn parse_request(body: &[u8]) -> Result<(&str, &str, OptionsRef<'_>)> {
+    let mut split = body.splitn(3, |&b| b == 0);
+    let (Some(filename), Some(mode)) = (split.next(), split.next()) else {
+        return Err(anyhow!("missing filename or mode"));
+    };
+    let filename = std::str::from_utf8(filename)?;
+    let mode = std::str::from_utf8(mode)?;
+    if filename.is_empty() {
+        return Err(anyhow!("empty filename"));
+    }
+    let options = OptionsRef::parse(split.next().unwrap_or_default())?;
+    Ok((filename, mode, options))
 }
 
-fn encode_request(
-    opcode: u16,
-    filename: &str,
-    mode: &str,
-    options: &HashMap<String, String>,
-) -> Vec<u8> {
+// ---------------------------------------------------------------------------
+// Serialization helpers
+// ---------------------------------------------------------------------------
+
+fn encode_request(opcode: u16, filename: &str, mode: &str, options: &Options) -> Vec<u8> {
     let mut buf = Vec::with_capacity(4 + filename.len() + mode.len());
     buf.extend_from_slice(&opcode.to_be_bytes());
     buf.extend_from_slice(filename.as_bytes());
     buf.push(0);
     buf.extend_from_slice(mode.as_bytes());
     buf.push(0);
-    for (key, val) in options {
+    for (key, val) in options.iter() {
         buf.extend_from_slice(key.as_bytes());
         buf.push(0);
         buf.extend_from_slice(val.as_bytes());
@@ -275,8 +617,15 @@ impl NetasciiDecoder {
 
     /// Convert a netascii-encoded chunk to octet (binary) data.
     /// Updates internal state to handle `\r` spanning block boundaries.
+    #[cfg(test)]
     pub fn decode(&mut self, input: &[u8]) -> Vec<u8> {
         let mut out = Vec::with_capacity(input.len());
+        self.decode_into(input, &mut out);
+        out
+    }
+
+    /// Like `decode`, but appends to `out` so its allocation can be reused.
+    pub fn decode_into(&mut self, input: &[u8], out: &mut Vec<u8>) {
         let mut i = 0;
 
         // Handle pending CR from previous block.
@@ -328,8 +677,14 @@ impl NetasciiDecoder {
                 i += 1;
             }
         }
+    }
 
-        out
+    /// End of the transfer: a `\r` still deferred was a bare one after
+    /// all, so keep it.
+    pub fn finish(&mut self, out: &mut Vec<u8>) {
+        if std::mem::take(&mut self.pending_cr) {
+            out.push(b'\r');
+        }
     }
 }
 
@@ -353,49 +708,50 @@ impl NetasciiEncoder {
     /// Encode octet (binary) data to netascii, respecting `blksize`.
     /// Returns the encoded block (may be smaller or equal to blksize).
     /// Any overflow is stored internally and will be prepended to the next call.
+    #[cfg(test)]
     pub fn encode(&mut self, input: &[u8], blksize: usize) -> Vec<u8> {
-        let mut out = Vec::with_capacity(blksize);
+        let mut out = vec![0; blksize];
+        let n = self.encode_into(input, &mut out);
+        out.truncate(n);
+        out
+    }
 
+    /// Like `encode`, but writes into `out`, at most `out.len()` bytes, and
+    /// returns how many were written.
+    pub fn encode_into(&mut self, input: &[u8], out: &mut [u8]) -> usize {
         // First, emit any overflow from previous block.
-        if !self.overflow.is_empty() {
-            let drain: Vec<u8> = std::mem::take(&mut self.overflow);
-            for &b in &drain {
-                if out.len() >= blksize {
-                    self.overflow.push(b);
-                } else {
-                    out.push(b);
-                }
-            }
-        }
+        let mut n = self.drain_overflow_into(out);
 
         for &b in input {
-            if out.len() >= blksize {
+            if n >= out.len() {
                 // All remaining input goes to overflow, but still needs conversion.
                 self.overflow_encode_byte(b);
-            } else {
-                match b {
-                    b'\n' => {
-                        out.push(b'\r');
-                        if out.len() >= blksize {
-                            self.overflow.push(b'\n');
-                        } else {
-                            out.push(b'\n');
-                        }
-                    }
-                    b'\r' => {
-                        out.push(b'\r');
-                        if out.len() >= blksize {
-                            self.overflow.push(0);
-                        } else {
-                            out.push(0);
-                        }
+                continue;
+            }
+            let escaped = match b {
+                b'\n' => Some(b'\n'),
+                b'\r' => Some(0),
+                _ => None,
+            };
+            match escaped {
+                Some(second) => {
+                    out[n] = b'\r';
+                    n += 1;
+                    if n >= out.len() {
+                        self.overflow.push(second);
+                    } else {
+                        out[n] = second;
+                        n += 1;
                     }
-                    _ => out.push(b),
+                }
+                None => {
+                    out[n] = b;
+                    n += 1;
                 }
             }
         }
 
-        out
+        n
     }
 
     /// Check if there is buffered overflow data remaining.
@@ -404,15 +760,21 @@ impl NetasciiEncoder {
     }
 
     /// Drain remaining overflow into a final block.
+    #[cfg(test)]
     pub fn drain_overflow(&mut self, blksize: usize) -> Vec<u8> {
-        let drain = std::mem::take(&mut self.overflow);
-        if drain.len() <= blksize {
-            drain
-        } else {
-            // Extremely unlikely but handle gracefully.
-            self.overflow = drain[blksize..].to_vec();
-            drain[..blksize].to_vec()
-        }
+        let mut out = vec![0; blksize];
+        let n = self.drain_overflow_into(&mut out);
+        out.truncate(n);
+        out
+    }
+
+    /// Move as much overflow as fits into `out`, returning how much did.
+    /// The rest stays buffered for the next block.
+    pub fn drain_overflow_into(&mut self, out: &mut [u8]) -> usize {
+        let n = self.overflow.len().min(out.len());
+        out[..n].copy_from_slice(&self.overflow[..n]);
+        self.overflow.drain(..n);
+        n
     }
 
     fn overflow_encode_byte(&mut self, b: u8) {
@@ -439,7 +801,7 @@ mod tests {
         let pkt = Packet::RRQ {
             filename: "hello.txt".into(),
             mode: "octet".into(),
-            options: HashMap::new(),
+            options: Options::new(),
         };
         let bytes = pkt.to_bytes();
         let parsed = Packet::from_bytes(&bytes).unwrap();
@@ -494,6 +856,37 @@ mod tests {
         }
     }
 
+    #[test]
+    fn tftp_errors_map_codes_and_io_kinds() {
+        use std::io::{Error, ErrorKind};
+
+        for code in 0..=8 {
+            assert_eq!(TftpError::from_code(code, "x").code(), code);
+        }
+        assert_eq!(
+            TftpError::from_code(42, "x"),
+            TftpError::NotDefined("x".into())
+        );
+
+        let cases = [
+            (ErrorKind::NotFound, 1),
+            (ErrorKind::PermissionDenied, 2),
+            (ErrorKind::IsADirectory, 2),
+            (ErrorKind::StorageFull, 3),
+            (ErrorKind::AlreadyExists, 6),
+            (ErrorKind::Interrupted, 0),
+        ];
+        for (kind, code) in cases {
+            assert_eq!(TftpError::from(&Error::from(kind)).code(), code, "{kind:?}");
+        }
+
+        let bytes = TftpError::UnknownTransferId.to_bytes();
+        assert_eq!(
+            Packet::from_bytes(&bytes).unwrap(),
+            Packet::error(5, "Unknown transfer ID")
+        );
+    }
+
     #[test]
     fn parse_rrq_with_blksize_option() {
         let mut buf = Vec::new();
@@ -513,19 +906,135 @@ mod tests {
 
     #[test]
     fn round_trip_oack() {
-        let mut options = HashMap::new();
-        options.insert("blksize".to_string(), "8192".to_string());
-        let pkt = Packet::OACK { options };
+        let options = Options::from([("tsize", "1048576"), ("blksize", "8192")]);
+        let pkt = Packet::OACK {
+            options: options.clone(),
+        };
         let bytes = pkt.to_bytes();
         let parsed = Packet::from_bytes(&bytes).unwrap();
         match parsed {
-            Packet::OACK { options } => {
-                assert_eq!(options.get("blksize").unwrap(), "8192");
-            }
+            Packet::OACK { options: parsed } => assert_eq!(parsed, options),
             _ => panic!("expected OACK"),
         }
     }
 
+    #[test]
+    fn data_buf_builds_packets_in_place() {
+        let mut pkt = DataBuf::new(512);
+        pkt.payload_mut(512)[..3].copy_from_slice(&[1, 2, 3]);
+        pkt.set_len(3);
+        pkt.set_block(42);
+        assert_eq!(pkt.block(), 42);
+        assert_eq!(pkt.payload(), [1, 2, 3]);
+        match Packet::from_bytes(pkt.as_bytes()).unwrap() {
+            Packet::DATA { block_num, data } => {
+                assert_eq!(block_num, 42);
+                assert_eq!(data, vec![1, 2, 3]);
+            }
+            _ => panic!("expected DATA"),
+        }
+        assert_eq!(
+            PacketRef::parse(pkt.as_bytes()).unwrap(),
+            PacketRef::DATA {
+                block_num: 42,
+                data: &[1, 2, 3]
+            }
+        );
+        assert_eq!(
+            ack_bytes(7).to_vec(),
+            Packet::ACK { block_num: 7 }.to_bytes()
+        );
+    }
+
+    #[test]
+    fn borrowed_parse_keeps_option_order_and_case() {
+        let mut buf = Vec::new();
+        buf.extend_from_slice(&1u16.to_be_bytes());
+        buf.extend_from_slice(b"pxelinux.0\0OCTET\0tsize\x000\0\0x\0BlkSize\x001428\0odd");
+        let PacketRef::RRQ {
+            filename,
+            mode,
+            options,
+        } = PacketRef::parse(&buf).unwrap()
+        else {
+            panic!("expected RRQ");
+        };
+        assert_eq!((filename, mode), ("pxelinux.0", "OCTET"));
+        let options: Vec<_> = options.iter().collect();
+        assert_eq!(options, [("tsize", "0"), ("BlkSize", "1428")]);
+
+        match Packet::from_bytes(&buf).unwrap() {
+            Packet::RRQ { mode, options, .. } => {
+                assert_eq!(mode, "octet");
+                let options: Vec<_> = options.iter().collect();
+                assert_eq!(options, [("tsize", "0"), ("blksize", "1428")]);
+            }
+            _ => panic!("expected RRQ"),
+        }
+    }
+
+    #[test]
+    fn options_keep_duplicates_and_report_malformed_fields() {
+        let parse = |options: &[u8]| {
+            let mut buf = b"\0\x01f\0octet\0".to_vec();
+            buf.extend_from_slice(options);
+            match Packet::from_bytes(&buf).unwrap() {
+                Packet::RRQ { options, .. } => options,
+                _ => panic!("expected RRQ"),
+            }
+        };
+
+        let options = parse(b"blksize\x001428\0BLKSIZE\x00512\0");
+        let pairs: Vec<_> = options.iter().collect();
+        assert_eq!(pairs, [("blksize", "1428"), ("blksize", "512")]);
+        assert_eq!(options.get("blksize"), Some("1428"));
+        assert_eq!(options.malformed(), None);
+
+        // NUL padding is fine; a stray value or a name without one is not.
+        assert_eq!(parse(b"tsize\x000\0\0\0\0").malformed(), None);
+        assert_eq!(
+            parse(b"\x00512\0tsize\x000\0").malformed(),
+            Some("option value without a name")
+        );
+        let options = parse(b"tsize\x000\0blksize");
+        assert_eq!(options.malformed(), Some("option \"blksize\" has no value"));
+        assert_eq!(options.get("tsize"), Some("0"));
+
+        let mut oack = Options::from([("blksize", "1428"), ("tsize", "0")]);
+        oack.insert("tsize".to_string(), "4096".to_string());
+        oack.insert("windowsize".to_string(), "8".to_string());
+        oack.remove("blksize");
+        let pairs: Vec<_> = oack.iter().collect();
+        assert_eq!(pairs, [("tsize", "4096"), ("windowsize", "8")]);
+    }
+
+    #[test]
+    fn borrowed_and_owned_parsers_reject_the_same_packets() {
+        let bad: [&[u8]; 8] = [
+            b"\0",
+            b"\0\x09",
+            b"\0\x01file",
+            b"\0\x01\0octet\0",
+            b"\0\x01\xff\0octet\0",
+            b"\0\x01f\0octet\0blksize\0\xff\0",
+            b"\0\x03\0",
+            b"\0\x05\0\x01",
+        ];
+        for buf in bad {
+            assert!(PacketRef::parse(buf).is_err(), "{buf:?}");
+            assert!(Packet::from_bytes(buf).is_err(), "{buf:?}");
+        }
+        // An unpaired trailing field is ignored, even if it is not UTF-8.
+        assert!(PacketRef::parse(b"\0\x01f\0octet\0\xff").is_ok());
+        assert_eq!(
+            PacketRef::parse(b"\0\x05\0\x01Disk full\0").unwrap(),
+            PacketRef::ERROR {
+                code: 1,
+                msg: b"Disk full"
+            }
+        );
+    }
+
     #[test]
     fn netascii_decode_crlf() {
         let mut dec = NetasciiDecoder::new();
@@ -553,6 +1062,17 @@ mod tests {
         assert_eq!(combined, b"abc\ndef");
     }
 
+    #[test]
+    fn netascii_decode_keeps_trailing_cr() {
+        let mut dec = NetasciiDecoder::new();
+        let mut out = dec.decode(b"abc\r");
+        assert_eq!(out, b"abc");
+        dec.finish(&mut out);
+        assert_eq!(out, b"abc\r");
+        dec.finish(&mut out);
+        assert_eq!(out, b"abc\r");
+    }
+
     #[test]
     fn netascii_encode_basic() {
         let mut enc = NetasciiEncoder::new();
@@ -583,4 +1103,63 @@ mod tests {
         let out2 = enc.drain_overflow(512);
         assert_eq!(out2, b"d\r\n");
     }
+
+    /// Cut `data` at the (sorted, deduplicated) `cuts`, each taken modulo
+    /// its length.
+    fn split_at_cuts<'a>(data: &'a [u8], cuts: &[usize]) -> Vec<&'a [u8]> {
+        let mut at: Vec<usize> = cuts.iter().map(|c| c % (data.len() + 1)).collect();
+        at.sort_unstable();
+        at.dedup();
+        let mut chunks = Vec::new();
+        let mut start = 0;
+        for end in at.into_iter().chain([data.len()]) {
+            chunks.push(&data[start..end]);
+            start = end;
+        }
+        chunks
+    }
+
+    /// Bytes weighted towards the ones netascii rewrites.
+    fn netascii_bytes() -> impl proptest::strategy::Strategy<Value = Vec<u8>> {
+        use proptest::prelude::*;
+        proptest::collection::vec(
+            prop_oneof![Just(b'\r'), Just(b'\n'), Just(0u8), any::<u8>()],
+            0..1024,
+        )
+    }
+
+    proptest::proptest! {
+        #[test]
+        fn netascii_round_trips_across_any_split(
+            data in netascii_bytes(),
+            blksize in 8usize..=64,
+            cuts in proptest::collection::vec(proptest::prelude::any::<usize>(), 0..16),
+            recuts in proptest::collection::vec(proptest::prelude::any::<usize>(), 0..16),
+        ) {
+            // Encode the input as it arrives, in arbitrary chunks, one block
+            // per chunk, then flush the overflow a block at a time.
+            let mut enc = NetasciiEncoder::new();
+            let mut encoded = Vec::new();
+            for chunk in split_at_cuts(&data, &cuts) {
+                let block = enc.encode(chunk, blksize);
+                proptest::prop_assert!(block.len() <= blksize);
+                encoded.extend_from_slice(&block);
+            }
+            while enc.has_overflow() {
+                let block = enc.drain_overflow(blksize);
+                proptest::prop_assert!(!block.is_empty() && block.len() <= blksize);
+                encoded.extend_from_slice(&block);
+            }
+
+            // Decode it split anywhere else, including between `\r` and
+            // what follows it.
+            let mut dec = NetasciiDecoder::new();
+            let mut decoded = Vec::new();
+            for chunk in split_at_cuts(&encoded, &recuts) {
+                dec.decode_into(chunk, &mut decoded);
+            }
+            dec.finish(&mut decoded);
+            proptest::prop_assert_eq!(decoded, data);
+        }
+    }
 }
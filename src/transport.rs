@@ -0,0 +1,717 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::io;
+use std::net::SocketAddr;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Arc, Mutex};
+
+use anyhow::{Result, anyhow};
+use serde::Serialize;
+#[cfg(target_os = "linux")]
+use tokio::io::Interest;
+use tokio::net::UdpSocket;
+use tokio::sync::mpsc;
+use tokio_util::sync::CancellationToken;
+
+use crate::server::ServerConfig;
+use crate::tftp_protocol::TftpError;
+
+/// Datagrams queued per transfer in single-port mode before further ones
+/// are dropped, as a full socket buffer would.
+const INBOX_CAPACITY: usize = 256;
+
+/// Datagrams the listener forwards to one transfer in single-port mode.
+pub type Inbox = mpsc::Receiver<Vec<u8>>;
+
+/// Why a transfer cancelled through the API ended, as told to the peer.
+const CANCELLED: &str = "cancelled by operator";
+
+// ---------------------------------------------------------------------------
+// Port range
+// ---------------------------------------------------------------------------
+
+/// Inclusive range of local ports for transfer sockets, so a firewall only
+/// has to open that range instead of every ephemeral port.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct PortRange {
+    pub first: u16,
+    pub last: u16,
+}
+
+impl FromStr for PortRange {
+    type Err = String;
+
+    /// Parses `FIRST-LAST`, e.g. `50000-50100`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (first, last) = s
+            .split_once('-')
+            .ok_or_else(|| format!("expected FIRST-LAST, got {s:?}"))?;
+        let port = |p: &str| {
+            p.trim()
+                .parse::<u16>()
+                .map_err(|_| format!("invalid port {p:?}"))
+        };
+        let (first, last) = (port(first)?, port(last)?);
+        if first == 0 || first > last {
+            return Err(format!("invalid port range {s:?}"));
+        }
+        Ok(Self { first, last })
+    }
+}
+
+impl fmt::Display for PortRange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}-{}", self.first, self.last)
+    }
+}
+
+impl PortRange {
+    fn len(self) -> u32 {
+        u32::from(self.last - self.first) + 1
+    }
+}
+
+/// Where the next search through a port range starts, so consecutive
+/// transfers do not all probe the same busy ports first.
+static NEXT_PORT: AtomicU32 = AtomicU32::new(0);
+
+// ---------------------------------------------------------------------------
+// Single-port demultiplexing
+// ---------------------------------------------------------------------------
+
+/// Routes datagrams arriving on the listening socket to the transfers
+/// sharing it, keyed by peer address.
+#[derive(Debug, Default)]
+pub struct Demux {
+    routes: Mutex<HashMap<SocketAddr, mpsc::Sender<Vec<u8>>>>,
+}
+
+impl Demux {
+    /// Start routing datagrams from `peer` to the returned inbox.
+    pub fn register(&self, peer: SocketAddr) -> Inbox {
+        let (sender, inbox) = mpsc::channel(INBOX_CAPACITY);
+        self.routes.lock().unwrap().insert(peer, sender);
+        inbox
+    }
+
+    pub fn unregister(&self, peer: SocketAddr) {
+        self.routes.lock().unwrap().remove(&peer);
+    }
+
+    /// Hand `datagram` to the transfer running with `peer`. Returns false
+    /// if there is none, i.e. the datagram is a new request.
+    pub fn deliver(&self, peer: SocketAddr, datagram: &[u8]) -> bool {
+        match self.routes.lock().unwrap().get(&peer) {
+            Some(sender) => {
+                // A full inbox drops the datagram; the peer retransmits.
+                let _ = sender.try_send(datagram.to_vec());
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+// ---------------------------------------------------------------------------
+// Transfer sockets
+// ---------------------------------------------------------------------------
+
+/// How a transfer reaches its peer: the listening socket plus, in
+/// single-port mode, the inbox the listener forwards the peer's datagrams to.
+pub struct Endpoint {
+    pub listener: Arc<UdpSocket>,
+    pub peer: SocketAddr,
+    pub inbox: Option<Inbox>,
+    /// Cancels the transfer; the peer is sent an ERROR when it fires.
+    pub cancel: CancellationToken,
+}
+
+impl Endpoint {
+    /// Answer the request with an ERROR before the transfer socket exists.
+    pub async fn reject(&self, err: &TftpError) {
+        let listener = self.inbox.as_ref().map(|_| self.listener.as_ref());
+        send_error(listener, self.peer, err).await;
+    }
+
+    /// Open the socket the transfer runs on: the shared listener in
+    /// single-port mode, otherwise a socket of its own.
+    pub async fn open(self, blksize: usize, config: &ServerConfig) -> Result<TransferSocket> {
+        if self.cancel.is_cancelled() {
+            self.reject(&TftpError::NotDefined(CANCELLED.into())).await;
+            return Err(anyhow!(CANCELLED));
+        }
+        let route = match self.inbox {
+            Some(inbox) => Route::Shared {
+                sock: self.listener,
+                peer: self.peer,
+                inbox,
+            },
+            None => Route::Dedicated {
+                sock: bind_transfer_socket(self.peer, blksize, config.port_range).await?,
+                peer: self.peer,
+            },
+        };
+        Ok(TransferSocket {
+            route,
+            batched: config.batched_io,
+            acks: AckBatch::default(),
+            cancel: self.cancel,
+        })
+    }
+}
+
+/// Send an ERROR in reply to a request: from `listener` in single-port
+/// mode, otherwise from a throwaway socket as RFC 1350 expects.
+pub async fn send_error(listener: Option<&UdpSocket>, peer: SocketAddr, err: &TftpError) {
+    let err = err.to_bytes();
+    match listener {
+        Some(sock) => {
+            let _ = sock.send_to(&err, peer).await;
+        }
+        None => {
+            if let Ok(tmp) = UdpSocket::bind("0.0.0.0:0").await {
+                let _ = tmp.send_to(&err, peer).await;
+            }
+        }
+    }
+}
+
+/// The socket a transfer sends and receives on.
+pub struct TransferSocket {
+    route: Route,
+    /// Send windows with `sendmmsg` and receive ACK bursts with
+    /// `recvmmsg`. Only takes effect on Linux.
+    batched: bool,
+    /// ACKs picked up by the last `recvmmsg` and not handed out yet.
+    acks: AckBatch,
+    cancel: CancellationToken,
+}
+
+enum Route {
+    /// A socket of its own. It is not connected, so datagrams from other
+    /// peers arrive too and can be answered with ERROR 5.
+    Dedicated { sock: UdpSocket, peer: SocketAddr },
+    /// The listening socket, shared with other transfers; this peer's
+    /// datagrams arrive through `inbox`.
+    Shared {
+        sock: Arc<UdpSocket>,
+        peer: SocketAddr,
+        inbox: Inbox,
+    },
+}
+
+impl TransferSocket {
+    pub async fn send(&self, buf: &[u8]) -> io::Result<usize> {
+        match &self.route {
+            Route::Dedicated { sock, peer } => sock.send_to(buf, *peer).await,
+            Route::Shared { sock, peer, .. } => sock.send_to(buf, *peer).await,
+        }
+    }
+
+    /// Tell the peer the transfer is over because of `err`. Best effort:
+    /// the transfer is failing anyway.
+    pub async fn send_error(&self, err: &TftpError) {
+        let _ = self.send(&err.to_bytes()).await;
+    }
+
+    /// Whether `send_batch` sends with `sendmmsg`.
+    pub fn is_batched(&self) -> bool {
+        cfg!(target_os = "linux") && self.batched
+    }
+
+    /// Send `packets` in order, handing the kernel as many per `sendmmsg`
+    /// call as it takes.
+    pub async fn send_batch(&self, packets: &[impl AsRef<[u8]>]) -> io::Result<()> {
+        #[cfg(target_os = "linux")]
+        if self.batched {
+            let (sock, peer) = match &self.route {
+                Route::Dedicated { sock, peer } => (sock, peer),
+                Route::Shared { sock, peer, .. } => (sock.as_ref(), peer),
+            };
+            let dest = socket2::SockAddr::from(*peer);
+            let mut sent = 0;
+            while sent < packets.len() {
+                sent += sock
+                    .async_io(Interest::WRITABLE, || {
+                        mmsg::send(sock, &packets[sent..], &dest)
+                    })
+                    .await?;
+            }
+            return Ok(());
+        }
+        for packet in packets {
+            self.send(packet.as_ref()).await?;
+        }
+        Ok(())
+    }
+
+    /// Receive the next datagram from the peer into `buf`, returning its
+    /// length. Datagrams from anyone else are answered with ERROR 5.
+    ///
+    /// Transfers spend their waiting here, so this is where a cancel is
+    /// noticed: the peer gets an ERROR and the call fails.
+    pub async fn recv(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let cancel = self.cancel.clone();
+        let received = tokio::select! {
+            r = self.recv_datagram(buf) => Some(r),
+            _ = cancel.cancelled() => None,
+        };
+        match received {
+            Some(r) => r,
+            None => Err(self.cancelled().await),
+        }
+    }
+
+    /// Like `recv`, for a sender waiting on ACKs: a burst of them is picked
+    /// up with one `recvmmsg` and handed out one per call. Datagrams
+    /// longer than an ERROR with a 512-byte message are truncated.
+    pub async fn recv_ack(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let cancel = self.cancel.clone();
+        let received = tokio::select! {
+            r = self.recv_ack_batch(buf) => Some(r),
+            _ = cancel.cancelled() => None,
+        };
+        match received {
+            Some(r) => r,
+            None => Err(self.cancelled().await),
+        }
+    }
+
+    async fn cancelled(&self) -> io::Error {
+        self.send_error(&TftpError::NotDefined(CANCELLED.into()))
+            .await;
+        io::Error::new(io::ErrorKind::Interrupted, CANCELLED)
+    }
+
+    async fn recv_datagram(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if let Some(n) = pop_ack(&self.route, &mut self.acks, buf) {
+            return Ok(n);
+        }
+        match &mut self.route {
+            Route::Dedicated { sock, peer } => loop {
+                let (n, from) = sock.recv_from(buf).await?;
+                if from == *peer {
+                    return Ok(n);
+                }
+                reject_stray(sock, from, &buf[..n]);
+            },
+            Route::Shared { inbox, .. } => {
+                let datagram = inbox
+                    .recv()
+                    .await
+                    .ok_or_else(|| io::Error::new(io::ErrorKind::BrokenPipe, "listener closed"))?;
+                let n = datagram.len().min(buf.len());
+                buf[..n].copy_from_slice(&datagram[..n]);
+                Ok(n)
+            }
+        }
+    }
+
+    async fn recv_ack_batch(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if let Some(n) = pop_ack(&self.route, &mut self.acks, buf) {
+            return Ok(n);
+        }
+        #[cfg(target_os = "linux")]
+        if self.batched
+            && let Route::Dedicated { sock, .. } = &self.route
+        {
+            loop {
+                let acks = &mut self.acks;
+                sock.async_io(Interest::READABLE, || mmsg::recv(sock, acks))
+                    .await?;
+                if let Some(n) = pop_ack(&self.route, acks, buf) {
+                    return Ok(n);
+                }
+            }
+        }
+        self.recv_datagram(buf).await
+    }
+}
+
+/// The next datagram from the peer picked up by `recvmmsg`, answering any
+/// strays queued ahead of it.
+fn pop_ack(route: &Route, acks: &mut AckBatch, buf: &mut [u8]) -> Option<usize> {
+    while let Some((n, from)) = acks.pop(buf) {
+        match route {
+            Route::Dedicated { sock, peer } if from != *peer => {
+                reject_stray(sock, from, &buf[..n]);
+            }
+            _ => return Some(n),
+        }
+    }
+    None
+}
+
+/// Answer a datagram that reached a transfer socket from someone other than
+/// its peer with ERROR 5, without disturbing the transfer (RFC 1350
+/// section 4). ERRORs are not answered, so two hosts cannot keep bouncing
+/// them, and a full send buffer just drops the reply.
+fn reject_stray(sock: &UdpSocket, from: SocketAddr, datagram: &[u8]) {
+    if datagram.starts_with(&[0, 5]) {
+        return;
+    }
+    let _ = sock.try_send_to(&TftpError::UnknownTransferId.to_bytes(), from);
+}
+
+/// Room for an ACK, or an ERROR with a message of up to 512 bytes.
+const ACK_SLOT: usize = 516;
+
+/// Datagrams one `recvmmsg` call picks up at most.
+const ACK_BATCH: usize = 32;
+
+/// Datagrams one `sendmmsg` call hands over at most; larger windows take
+/// several calls.
+#[cfg(target_os = "linux")]
+const SEND_BATCH: usize = 64;
+
+/// Datagrams received by one `recvmmsg` call.
+#[derive(Default)]
+struct AckBatch {
+    slots: Vec<[u8; ACK_SLOT]>,
+    lens: Vec<usize>,
+    sources: Vec<SocketAddr>,
+    next: usize,
+}
+
+impl AckBatch {
+    /// Copy the next unread datagram into `buf`, returning its length and
+    /// sender.
+    fn pop(&mut self, buf: &mut [u8]) -> Option<(usize, SocketAddr)> {
+        let i = self.next;
+        let n = (*self.lens.get(i)?).min(buf.len());
+        buf[..n].copy_from_slice(&self.slots[i][..n]);
+        self.next += 1;
+        Some((n, self.sources[i]))
+    }
+}
+
+/// Thin wrappers around `sendmmsg(2)` and `recvmmsg(2)`. Both are
+/// non-blocking; callers wait for readiness through tokio. Message headers
+/// live on the stack, so neither allocates.
+#[cfg(target_os = "linux")]
+mod mmsg {
+    use std::io;
+    use std::net::SocketAddr;
+    use std::os::fd::AsRawFd;
+
+    use super::{ACK_BATCH, ACK_SLOT, AckBatch, SEND_BATCH};
+
+    const EMPTY_IOVEC: libc::iovec = libc::iovec {
+        iov_base: std::ptr::null_mut(),
+        iov_len: 0,
+    };
+
+    fn header(iov: &mut libc::iovec) -> libc::mmsghdr {
+        // SAFETY: an all-zero msghdr is valid: no name, no control data.
+        let mut hdr: libc::msghdr = unsafe { std::mem::zeroed() };
+        hdr.msg_iov = iov;
+        hdr.msg_iovlen = 1;
+        libc::mmsghdr {
+            msg_hdr: hdr,
+            msg_len: 0,
+        }
+    }
+
+    /// Send as many of `packets`, up to `SEND_BATCH`, as the socket takes
+    /// in one call, to `dest`. Returns how many went.
+    pub fn send(
+        sock: &impl AsRawFd,
+        packets: &[impl AsRef<[u8]>],
+        dest: &socket2::SockAddr,
+    ) -> io::Result<usize> {
+        let count = packets.len().min(SEND_BATCH);
+        let mut iovecs = [EMPTY_IOVEC; SEND_BATCH];
+        for (iov, packet) in iovecs.iter_mut().zip(packets) {
+            let packet = packet.as_ref();
+            iov.iov_base = packet.as_ptr() as *mut libc::c_void;
+            iov.iov_len = packet.len();
+        }
+        let mut msgs: [libc::mmsghdr; SEND_BATCH] = std::array::from_fn(|i| header(&mut iovecs[i]));
+        for msg in &mut msgs[..count] {
+            msg.msg_hdr.msg_name = dest.as_ptr() as *mut libc::c_void;
+            msg.msg_hdr.msg_namelen = dest.len();
+        }
+        // SAFETY: the first `count` headers point into `iovecs`, `packets`
+        // and `dest`, all of which outlive the call; the kernel only reads
+        // them.
+        let sent = unsafe {
+            libc::sendmmsg(
+                sock.as_raw_fd(),
+                msgs.as_mut_ptr(),
+                count as libc::c_uint,
+                libc::MSG_DONTWAIT,
+            )
+        };
+        if sent < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(sent as usize)
+    }
+
+    /// Receive up to `ACK_BATCH` datagrams, and who sent them, into
+    /// `batch`.
+    pub fn recv(sock: &impl AsRawFd, batch: &mut AckBatch) -> io::Result<()> {
+        batch.slots.resize(ACK_BATCH, [0; ACK_SLOT]);
+        let mut iovecs = [EMPTY_IOVEC; ACK_BATCH];
+        for (iov, slot) in iovecs.iter_mut().zip(&mut batch.slots) {
+            iov.iov_base = slot.as_mut_ptr().cast();
+            iov.iov_len = ACK_SLOT;
+        }
+        // SAFETY: an all-zero sockaddr_storage is valid.
+        let mut names: [libc::sockaddr_storage; ACK_BATCH] = unsafe { std::mem::zeroed() };
+        let mut msgs: [libc::mmsghdr; ACK_BATCH] = std::array::from_fn(|i| header(&mut iovecs[i]));
+        for (msg, name) in msgs.iter_mut().zip(&mut names) {
+            msg.msg_hdr.msg_name = (name as *mut libc::sockaddr_storage).cast();
+            msg.msg_hdr.msg_namelen = size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+        }
+        // SAFETY: every header points at one slot of `batch` and one entry
+        // of `names`, which outlive the call and are only written within
+        // their sizes.
+        let received = unsafe {
+            libc::recvmmsg(
+                sock.as_raw_fd(),
+                msgs.as_mut_ptr(),
+                msgs.len() as libc::c_uint,
+                libc::MSG_DONTWAIT,
+                std::ptr::null_mut(),
+            )
+        };
+        if received < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let msgs = &msgs[..received as usize];
+        batch.lens.clear();
+        batch.lens.extend(msgs.iter().map(|m| m.msg_len as usize));
+        batch.sources.clear();
+        batch.sources.extend(
+            msgs.iter()
+                .zip(&names)
+                .map(|(m, name)| source(name, m.msg_hdr.msg_namelen)),
+        );
+        batch.next = 0;
+        Ok(())
+    }
+
+    /// The sender recorded in `name`. UDP sockets only receive from IPv4
+    /// and IPv6 peers.
+    fn source(name: &libc::sockaddr_storage, len: libc::socklen_t) -> SocketAddr {
+        let mut storage = socket2::SockAddrStorage::zeroed();
+        // SAFETY: `view_as` targets the platform's own sockaddr_storage,
+        // and the kernel set `len` for the address it wrote.
+        let addr = unsafe {
+            *storage.view_as::<libc::sockaddr_storage>() = *name;
+            socket2::SockAddr::new(storage, len)
+        };
+        addr.as_socket()
+            .unwrap_or_else(|| SocketAddr::from(([0, 0, 0, 0], 0)))
+    }
+}
+
+/// Create a UDP socket for one transfer with send/receive buffers sized
+/// for the negotiated block size, bound to a port from `ports` or to an
+/// OS-assigned one. The OS default buffer (~9 KB on macOS) is too small
+/// for blksize values above ~8 KB and causes "No buffer space available"
+/// (ENOBUFS / os error 55).
+async fn bind_transfer_socket(
+    peer: SocketAddr,
+    blksize: usize,
+    ports: Option<PortRange>,
+) -> Result<UdpSocket> {
+    // Build the socket via socket2 so we can set buffer sizes before
+    // handing it to tokio.
+    let domain = if peer.is_ipv6() {
+        socket2::Domain::IPV6
+    } else {
+        socket2::Domain::IPV4
+    };
+    let raw = socket2::Socket::new(domain, socket2::Type::DGRAM, Some(socket2::Protocol::UDP))?;
+
+    // Need room for a comfortable number of packets in flight.
+    let buf_size = ((4 + blksize) * 16).max(256 * 1024);
+    let _ = raw.set_send_buffer_size(buf_size);
+    let _ = raw.set_recv_buffer_size(buf_size);
+
+    let mut bind_addr: SocketAddr = if peer.is_ipv6() {
+        "[::]:0".parse().unwrap()
+    } else {
+        "0.0.0.0:0".parse().unwrap()
+    };
+    match ports {
+        None => raw.bind(&bind_addr.into())?,
+        Some(range) => {
+            // Take the first free port, starting after the last one handed out.
+            let start = NEXT_PORT.fetch_add(1, Ordering::Relaxed);
+            let bound = (0..range.len()).any(|i| {
+                let port = range.first as u32 + (start + i) % range.len();
+                bind_addr.set_port(port as u16);
+                raw.bind(&bind_addr.into()).is_ok()
+            });
+            if !bound {
+                return Err(anyhow!("no free port in range {range}"));
+            }
+        }
+    }
+    raw.set_nonblocking(true)?;
+
+    // Convert: socket2 -> std -> tokio.
+    let std_sock: std::net::UdpSocket = raw.into();
+    Ok(UdpSocket::from_std(std_sock)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn port_ranges() {
+        let range: PortRange = "50000-50010".parse().unwrap();
+        assert_eq!((range.first, range.last, range.len()), (50000, 50010, 11));
+        assert_eq!(range.to_string(), "50000-50010");
+        assert!("50010-50000".parse::<PortRange>().is_err());
+        assert!("0-10".parse::<PortRange>().is_err());
+        assert!("50000".parse::<PortRange>().is_err());
+    }
+
+    #[tokio::test]
+    async fn dedicated_sockets_stay_in_range() {
+        let peer: SocketAddr = "127.0.0.1:9".parse().unwrap();
+        let probe = std::net::UdpSocket::bind("0.0.0.0:0").unwrap();
+        let port = probe.local_addr().unwrap().port();
+        drop(probe);
+        let range = PortRange {
+            first: port,
+            last: port,
+        };
+        let sock = bind_transfer_socket(peer, 512, Some(range)).await.unwrap();
+        assert_eq!(sock.local_addr().unwrap().port(), port);
+        // The only port in the range is taken now.
+        assert!(bind_transfer_socket(peer, 512, Some(range)).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn shared_socket_demultiplexes_by_peer() {
+        let listener = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let peer = client.local_addr().unwrap();
+        let demux = Demux::default();
+        let endpoint = Endpoint {
+            listener: Arc::clone(&listener),
+            peer,
+            inbox: Some(demux.register(peer)),
+            cancel: CancellationToken::new(),
+        };
+        let mut sock = endpoint.open(512, &ServerConfig::default()).await.unwrap();
+
+        let other: SocketAddr = "127.0.0.1:9".parse().unwrap();
+        assert!(!demux.deliver(other, b"request"));
+        assert!(demux.deliver(peer, b"ack"));
+        let mut buf = [0u8; 16];
+        assert_eq!(sock.recv(&mut buf).await.unwrap(), 3);
+        assert_eq!(&buf[..3], b"ack");
+
+        // Replies leave from the listening port.
+        sock.send(b"data").await.unwrap();
+        let (n, from) = client.recv_from(&mut buf).await.unwrap();
+        assert_eq!(
+            (&buf[..n], from),
+            (&b"data"[..], listener.local_addr().unwrap())
+        );
+
+        demux.unregister(peer);
+        assert!(!demux.deliver(peer, b"late"));
+    }
+
+    #[tokio::test]
+    async fn strays_get_unknown_transfer_id() {
+        for batched_io in [false, true] {
+            let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            let stray = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            let endpoint = Endpoint {
+                listener: Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap()),
+                peer: client.local_addr().unwrap(),
+                inbox: None,
+                cancel: CancellationToken::new(),
+            };
+            let config = ServerConfig {
+                batched_io,
+                ..ServerConfig::default()
+            };
+            let mut sock = endpoint.open(512, &config).await.unwrap();
+            sock.send(b"data").await.unwrap();
+            let mut buf = [0u8; 600];
+            let (_, server) = client.recv_from(&mut buf).await.unwrap();
+
+            stray.send_to(b"\x00\x04\x00\x01", server).await.unwrap();
+            // A stray ERROR is not answered.
+            stray
+                .send_to(b"\x00\x05\x00\x00x\x00", server)
+                .await
+                .unwrap();
+            tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+            client.send_to(b"ack1", server).await.unwrap();
+            let n = sock.recv_ack(&mut buf).await.unwrap();
+            assert_eq!(&buf[..n], b"ack1", "batched_io={batched_io}");
+
+            let n = stray.recv(&mut buf).await.unwrap();
+            assert_eq!(&buf[..n], TftpError::UnknownTransferId.to_bytes());
+            let again = stray.try_recv(&mut buf);
+            assert!(again.is_err(), "answered a stray ERROR");
+        }
+    }
+
+    #[tokio::test]
+    async fn cancel_sends_error_to_the_peer() {
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let cancel = CancellationToken::new();
+        let endpoint = Endpoint {
+            listener: Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap()),
+            peer: client.local_addr().unwrap(),
+            inbox: None,
+            cancel: cancel.clone(),
+        };
+        let mut sock = endpoint.open(512, &ServerConfig::default()).await.unwrap();
+        let mut buf = [0u8; 600];
+        let waiting = tokio::spawn(async move { sock.recv(&mut buf).await });
+        cancel.cancel();
+
+        let err = waiting.await.unwrap().unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::Interrupted);
+        let mut buf = [0u8; 600];
+        let n = client.recv(&mut buf).await.unwrap();
+        assert_eq!(
+            &buf[..n],
+            TftpError::NotDefined(CANCELLED.into()).to_bytes()
+        );
+    }
+
+    #[tokio::test]
+    async fn batched_windows_and_ack_bursts() {
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let endpoint = Endpoint {
+            listener: Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap()),
+            peer: client.local_addr().unwrap(),
+            inbox: None,
+            cancel: CancellationToken::new(),
+        };
+        let mut sock = endpoint.open(512, &ServerConfig::default()).await.unwrap();
+        let window: Vec<Vec<u8>> = (1..=3u8).map(|i| vec![i; 100 * i as usize]).collect();
+        sock.send_batch(&window).await.unwrap();
+
+        let mut buf = [0u8; 600];
+        let mut server = None;
+        for packet in &window {
+            let (n, from) = client.recv_from(&mut buf).await.unwrap();
+            assert_eq!(&buf[..n], packet.as_slice());
+            server = Some(from);
+        }
+        for ack in [b"ack1", b"ack2", b"ack3"] {
+            client.send_to(ack, server.unwrap()).await.unwrap();
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+        for ack in [b"ack1", b"ack2", b"ack3"] {
+            let n = sock.recv_ack(&mut buf).await.unwrap();
+            assert_eq!(&buf[..n], ack);
+        }
+    }
+}
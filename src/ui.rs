@@ -1,5 +1,3 @@
-use std::fs::File;
-use std::io::{BufWriter, Write};
 use std::path::{Path, PathBuf};
 use std::time::{Instant, SystemTime};
 
@@ -9,11 +7,17 @@ use ratatui::style::{Color, Modifier, Style};
 use ratatui::text::{Line, Span};
 use ratatui::widgets::{Block, Borders, Clear, Gauge, List, ListItem, Paragraph};
 
+use crate::cache::CacheStats;
+use crate::proxy_dhcp::{PxeBoot, PxeStage};
 use crate::server::{TransferInfo, TransferKind};
+use crate::versions;
 
 /// How often to refresh the interface IP list.
 const IP_REFRESH_INTERVAL: std::time::Duration = std::time::Duration::from_secs(10);
 
+/// Number of PXE clients kept for the PXE Boot panel.
+const PXE_BOOT_CAPACITY: usize = 50;
+
 /// Which panel currently has keyboard focus for scrolling.
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum FocusedPanel {
@@ -38,17 +42,16 @@ pub struct App {
     /// true = "Yes" selected, false = "No" selected.
     pub quit_selection: bool,
     pub interface_ips: Vec<String>,
+    /// Read cache counters, refreshed every tick; `None` while it is off.
+    pub cache: Option<CacheStats>,
+    /// Latest boot step of each PXE client with its timestamp, newest
+    /// first; `None` while ProxyDHCP is off.
+    pub pxe_boots: Option<Vec<(String, PxeBoot)>>,
     last_ip_refresh: Instant,
-    log_writer: Option<BufWriter<File>>,
 }
 
 impl App {
-    pub fn new(
-        port: u16,
-        http_port: Option<u16>,
-        dir: PathBuf,
-        log_writer: Option<BufWriter<File>>,
-    ) -> Self {
+    pub fn new(port: u16, http_port: Option<u16>, dir: PathBuf) -> Self {
         let interface_ips = get_interface_ips();
         Self {
             port,
@@ -64,8 +67,9 @@ impl App {
             show_quit_dialog: false,
             quit_selection: false,
             interface_ips,
+            cache: None,
+            pxe_boots: None,
             last_ip_refresh: Instant::now(),
-            log_writer,
         }
     }
 
@@ -78,20 +82,22 @@ impl App {
 
     pub fn push_log(&mut self, msg: String) {
         let ts = timestamp_now();
-        let line = format!("{ts} {msg}");
-
-        if let Some(ref mut w) = self.log_writer {
-            let _ = writeln!(w, "{line}");
-            let _ = w.flush();
-        }
-
-        self.logs.push(line);
+        self.logs.push(format!("{ts} {msg}"));
         // Auto-scroll to bottom.
         let visible = 10u16; // approximate
         let total = self.logs.len() as u16;
         self.log_scroll = total.saturating_sub(visible);
     }
 
+    /// Move the client's entry to the top of the PXE Boot panel.
+    pub fn record_pxe_boot(&mut self, boot: PxeBoot) {
+        if let Some(boots) = &mut self.pxe_boots {
+            boots.retain(|(_, b)| b.mac != boot.mac);
+            boots.insert(0, (timestamp_now(), boot));
+            boots.truncate(PXE_BOOT_CAPACITY);
+        }
+    }
+
     pub fn scroll_up(&mut self) {
         match self.focused_panel {
             FocusedPanel::Files => {
@@ -136,7 +142,8 @@ impl App {
 // Timestamp helper
 // ---------------------------------------------------------------------------
 
-fn timestamp_now() -> String {
+/// Wall-clock `[HH:MM:SS]` (UTC) prefix for log lines.
+pub fn timestamp_now() -> String {
     let dur = SystemTime::now()
         .duration_since(SystemTime::UNIX_EPOCH)
         .unwrap_or_default();
@@ -151,7 +158,8 @@ fn timestamp_now() -> String {
 // Interface IP helper
 // ---------------------------------------------------------------------------
 
-fn get_interface_ips() -> Vec<String> {
+/// Non-loopback IPv4 addresses of this host, sorted.
+pub fn get_interface_ips() -> Vec<String> {
     let mut ips: Vec<String> = if_addrs::get_if_addrs()
         .unwrap_or_default()
         .into_iter()
@@ -172,6 +180,8 @@ struct TreeEntry {
     name: String,
     depth: usize,
     is_dir: bool,
+    /// A kept previous version, shown under the file it belongs to.
+    is_version: bool,
     is_last: bool,
     size: Option<u64>,
     /// Whether each ancestor at depth 0..depth is the last child of its parent.
@@ -198,6 +208,27 @@ fn build_tree(dir: &Path, depth: usize, ancestors_are_last: &[bool]) -> Vec<Tree
         })
     });
 
+    // Versions are listed under their file rather than as siblings.
+    let is_file = |name: &str| {
+        children.iter().any(|c| {
+            c.file_name().to_string_lossy() == name
+                && c.file_type().map(|ft| ft.is_file()).unwrap_or(false)
+        })
+    };
+    let has_versions: std::collections::HashSet<String> = children
+        .iter()
+        .filter_map(|c| {
+            let name = c.file_name().to_string_lossy().to_string();
+            versions::version_base(&name)
+                .filter(|base| is_file(base))
+                .map(str::to_string)
+        })
+        .collect();
+    children.retain(|c| {
+        let name = c.file_name().to_string_lossy().to_string();
+        versions::version_base(&name).is_none_or(|base| !has_versions.contains(base))
+    });
+
     let count = children.len();
     for (i, entry) in children.into_iter().enumerate() {
         let is_last = i + 1 == count;
@@ -213,11 +244,28 @@ fn build_tree(dir: &Path, depth: usize, ancestors_are_last: &[bool]) -> Vec<Tree
             name: name.clone(),
             depth,
             is_dir,
+            is_version: false,
             is_last,
             size,
             ancestors_are_last: ancestors_are_last.to_vec(),
         });
 
+        if has_versions.contains(&name) {
+            let kept = versions::list_versions(&dir.join(&name)).unwrap_or_default();
+            let mut child_ancestors = ancestors_are_last.to_vec();
+            child_ancestors.push(is_last);
+            let kept_count = kept.len();
+            entries.extend(kept.into_iter().enumerate().map(|(j, v)| TreeEntry {
+                name: v.name,
+                depth: depth + 1,
+                is_dir: false,
+                is_version: true,
+                is_last: j + 1 == kept_count,
+                size: Some(v.len),
+                ancestors_are_last: child_ancestors.clone(),
+            }));
+        }
+
         if is_dir {
             let mut child_ancestors = ancestors_are_last.to_vec();
             child_ancestors.push(is_last);
@@ -265,9 +313,14 @@ fn format_tree_entry(entry: &TreeEntry) -> Line<'static> {
             .size
             .map(|s| format!("  ({})", human_bytes(s)))
             .unwrap_or_default();
+        let name_style = if entry.is_version {
+            Style::default().fg(Color::DarkGray)
+        } else {
+            Style::default()
+        };
         Line::from(vec![
             Span::styled(format!(" {prefix}"), Style::default().fg(Color::DarkGray)),
-            Span::raw(entry.name.clone()),
+            Span::styled(entry.name.clone(), name_style),
             Span::styled(size_str, Style::default().fg(Color::DarkGray)),
         ])
     }
@@ -337,6 +390,21 @@ fn draw_header(f: &mut Frame, app: &App, area: Rect) {
         ));
     }
 
+    if let Some(cache) = app.cache {
+        let hit_rate = cache
+            .hit_rate()
+            .map_or("-".to_string(), |r| format!("{:.0}%", r * 100.0));
+        spans.push(Span::raw("  |  "));
+        spans.push(Span::styled(
+            "Cache: ",
+            Style::default().fg(Color::DarkGray),
+        ));
+        spans.push(Span::styled(
+            format!("{hit_rate} hits, {}", human_bytes(cache.bytes)),
+            Style::default().fg(Color::Cyan),
+        ));
+    }
+
     spans.push(Span::raw("  |  "));
     spans.push(Span::styled(
         "Directory: ",
@@ -355,7 +423,7 @@ fn draw_header(f: &mut Frame, app: &App, area: Rect) {
 }
 
 // ---------------------------------------------------------------------------
-// Middle: Shared Files (left) + Active Transfers (right)
+// Middle: Shared Files (left) + Active Transfers and PXE Boot (right)
 // ---------------------------------------------------------------------------
 
 fn draw_middle(f: &mut Frame, app: &mut App, area: Rect) {
@@ -365,7 +433,16 @@ fn draw_middle(f: &mut Frame, app: &mut App, area: Rect) {
         .split(area);
 
     draw_shared_files(f, app, cols[0]);
-    draw_transfers(f, app, cols[1]);
+    if app.pxe_boots.is_some() {
+        let rows = Layout::default()
+            .direction(Direction::Vertical)
+            .constraints([Constraint::Min(4), Constraint::Length(7)])
+            .split(cols[1]);
+        draw_transfers(f, app, rows[0]);
+        draw_pxe_boots(f, app, rows[1]);
+    } else {
+        draw_transfers(f, app, cols[1]);
+    }
 }
 
 fn draw_shared_files(f: &mut Frame, app: &mut App, area: Rect) {
@@ -531,6 +608,68 @@ fn draw_transfers(f: &mut Frame, app: &mut App, area: Rect) {
     }
 }
 
+// ---------------------------------------------------------------------------
+// PXE Boot
+// ---------------------------------------------------------------------------
+
+fn draw_pxe_boots(f: &mut Frame, app: &App, area: Rect) {
+    let block = Block::default().borders(Borders::ALL).title(" PXE Boot ");
+    let boots = app.pxe_boots.as_deref().unwrap_or_default();
+    if boots.is_empty() {
+        let para = Paragraph::new(" Waiting for PXE clients")
+            .style(Style::default().fg(Color::DarkGray))
+            .block(block);
+        f.render_widget(para, area);
+        return;
+    }
+
+    let items: Vec<ListItem> = boots
+        .iter()
+        .take(area.height.saturating_sub(2) as usize)
+        .map(|(ts, boot)| {
+            let (stage, stage_color) = match boot.stage {
+                PxeStage::Offer => ("OFFER", Color::Yellow),
+                PxeStage::Ack => ("ACK", Color::Green),
+            };
+            let arch = if boot.ipxe {
+                format!("{}, iPXE", boot.arch)
+            } else {
+                boot.arch.to_string()
+            };
+            let mut spans = vec![
+                Span::styled(format!(" {ts} "), Style::default().fg(Color::DarkGray)),
+                Span::styled(
+                    format!("[{stage}] "),
+                    Style::default()
+                        .fg(stage_color)
+                        .add_modifier(Modifier::BOLD),
+                ),
+                Span::raw(format!("{} ", boot.mac)),
+            ];
+            if let Some(ip) = boot.client_ip {
+                spans.push(Span::styled(
+                    format!("{ip} "),
+                    Style::default().fg(Color::DarkGray),
+                ));
+            }
+            spans.push(Span::styled(
+                format!("({arch}) "),
+                Style::default().fg(Color::DarkGray),
+            ));
+            spans.push(Span::styled(
+                boot.boot_file.clone(),
+                Style::default().fg(Color::Cyan),
+            ));
+            ListItem::new(Line::from(spans))
+        })
+        .collect();
+
+    let list = List::new(items)
+        .block(block)
+        .style(Style::default().fg(Color::White));
+    f.render_widget(list, area);
+}
+
 // ---------------------------------------------------------------------------
 // Logs
 // ---------------------------------------------------------------------------
@@ -0,0 +1,387 @@
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use serde::{Deserialize, Serialize};
+
+use crate::checksum;
+use crate::server::ServerConfig;
+
+/// What happens to an existing file when an upload replaces it.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum Versioning {
+    /// Replace it (or reject the upload, see `allow_overwrite`).
+    #[default]
+    Off,
+    /// Keep it as `name.~YYYYMMDD-HHMMSS~` (UTC modification time).
+    Timestamp,
+    /// Keep it as `name.~N~`, the highest N being the newest.
+    Counter,
+}
+
+/// A previous version of a file, kept next to it.
+#[derive(Debug, Clone)]
+pub struct Version {
+    pub path: PathBuf,
+    pub name: String,
+    pub modified: SystemTime,
+    pub len: u64,
+}
+
+/// Outcome of installing a finished upload.
+#[derive(Debug, Default)]
+pub struct Installed {
+    /// Where the replaced file was kept, if it was.
+    pub archived: Option<PathBuf>,
+    /// Versions removed by the retention policy.
+    pub pruned: Vec<PathBuf>,
+}
+
+/// Move a completed `.part` file to `path`. With versioning enabled, the
+/// file it replaces is kept as a version first and old versions are pruned
+/// per `keep_versions` and `max_version_age_secs`.
+///
+/// The previous contents are hard-linked to the version name before the
+/// rename, so readers always see either the old or the new file at `path`.
+pub fn install(part: &Path, path: &Path, config: &ServerConfig) -> io::Result<Installed> {
+    let mut installed = Installed::default();
+    if config.versioning != Versioning::Off && path.is_file() {
+        let version = next_version_path(path, config.versioning)?;
+        if std::fs::hard_link(path, &version).is_err() {
+            // No hard links on this filesystem: fall back to a plain move,
+            // leaving `path` briefly absent.
+            std::fs::rename(path, &version)?;
+        }
+        // Keep the sidecar with the contents it describes.
+        let sidecar = checksum::sidecar_path(path);
+        if sidecar.is_file() {
+            let _ = std::fs::rename(&sidecar, checksum::sidecar_path(&version));
+        }
+        installed.archived = Some(version);
+    }
+
+    std::fs::rename(part, path)?;
+
+    if installed.archived.is_some() {
+        installed.pruned = prune(path, config.keep_versions, config.max_version_age_secs);
+    }
+    Ok(installed)
+}
+
+/// Previous versions of `path`, newest first.
+pub fn list_versions(path: &Path) -> io::Result<Vec<Version>> {
+    let (Some(dir), Some(base)) = (path.parent(), path.file_name()) else {
+        return Ok(Vec::new());
+    };
+    let base = base.to_string_lossy();
+    let mut versions = Vec::new();
+    for entry in std::fs::read_dir(dir)? {
+        let Ok(entry) = entry else { continue };
+        let name = entry.file_name().to_string_lossy().into_owned();
+        if version_base(&name) != Some(&base) {
+            continue;
+        }
+        let Ok(metadata) = entry.metadata() else {
+            continue;
+        };
+        if metadata.is_file() {
+            versions.push(Version {
+                path: entry.path(),
+                modified: metadata.modified().unwrap_or(SystemTime::UNIX_EPOCH),
+                len: metadata.len(),
+                name,
+            });
+        }
+    }
+    // Suffixes of equal shape compare correctly by (length, text), which
+    // orders `name.~10~` after `name.~9~` and `-2` collisions after the
+    // plain timestamp.
+    versions.sort_by(|a, b| {
+        b.modified
+            .cmp(&a.modified)
+            .then_with(|| version_key(&b.name).cmp(&version_key(&a.name)))
+    });
+    Ok(versions)
+}
+
+/// The file a version belongs to: `router.cfg` for `router.cfg.~3~` or
+/// `router.cfg.~20261018-093000~`. `None` if `name` is not a version name.
+///
+/// The `~` markers keep versions apart from files that merely end in
+/// digits, such as `pxelinux.0` next to `pxelinux`.
+pub fn version_base(name: &str) -> Option<&str> {
+    let (base, suffix) = split_version(name)?;
+    (!base.is_empty() && is_version_suffix(suffix)).then_some(base)
+}
+
+/// `name.~suffix~` split into `name` and `suffix`.
+fn split_version(name: &str) -> Option<(&str, &str)> {
+    let (base, suffix) = name.strip_suffix('~')?.rsplit_once(".~")?;
+    Some((base, suffix))
+}
+
+fn is_version_suffix(suffix: &str) -> bool {
+    let digits = |s: &str, n: usize| s.len() == n && s.bytes().all(|b| b.is_ascii_digit());
+    if !suffix.is_empty() && suffix.bytes().all(|b| b.is_ascii_digit()) {
+        return true;
+    }
+    let mut parts = suffix.split('-');
+    match (parts.next(), parts.next(), parts.next(), parts.next()) {
+        (Some(date), Some(time), None, None) => digits(date, 8) && digits(time, 6),
+        (Some(date), Some(time), Some(n), None) => {
+            digits(date, 8) && digits(time, 6) && !n.is_empty() && digits(n, n.len())
+        }
+        _ => false,
+    }
+}
+
+fn version_key(name: &str) -> (usize, &str) {
+    let suffix = split_version(name).map_or("", |(_, s)| s);
+    (suffix.len(), suffix)
+}
+
+/// A free version name for the current contents of `path`.
+fn next_version_path(path: &Path, versioning: Versioning) -> io::Result<PathBuf> {
+    let with_suffix = |suffix: &str| {
+        let mut name = path.as_os_str().to_owned();
+        name.push(format!(".~{suffix}~"));
+        PathBuf::from(name)
+    };
+    match versioning {
+        Versioning::Counter => {
+            let highest = list_versions(path)?
+                .iter()
+                .filter_map(|v| split_version(&v.name)?.1.parse::<u64>().ok())
+                .max()
+                .unwrap_or(0);
+            Ok(with_suffix(&(highest + 1).to_string()))
+        }
+        _ => {
+            let modified = std::fs::metadata(path)?
+                .modified()
+                .unwrap_or_else(|_| SystemTime::now());
+            let stamp = utc_stamp(modified);
+            let mut candidate = with_suffix(&stamp);
+            let mut n = 2;
+            while candidate.exists() {
+                candidate = with_suffix(&format!("{stamp}-{n}"));
+                n += 1;
+            }
+            Ok(candidate)
+        }
+    }
+}
+
+/// Delete versions of `path` beyond the newest `keep` (0 = no limit) and
+/// those last modified more than `max_age_secs` ago (0 = no limit). The
+/// newest version is never removed for its age, so a file that sat
+/// unchanged for a long time still has its previous contents after an
+/// upload.
+fn prune(path: &Path, keep: usize, max_age_secs: u64) -> Vec<PathBuf> {
+    let Ok(versions) = list_versions(path) else {
+        return Vec::new();
+    };
+    let cutoff = (max_age_secs > 0)
+        .then(|| SystemTime::now().checked_sub(Duration::from_secs(max_age_secs)))
+        .flatten();
+    let mut pruned = Vec::new();
+    for (i, version) in versions.into_iter().enumerate() {
+        let over_count = keep > 0 && i >= keep;
+        let too_old = i > 0 && cutoff.is_some_and(|c| version.modified < c);
+        if (over_count || too_old) && std::fs::remove_file(&version.path).is_ok() {
+            let _ = std::fs::remove_file(checksum::sidecar_path(&version.path));
+            pruned.push(version.path);
+        }
+    }
+    pruned
+}
+
+/// Highest `keep_versions` accepted from the CLI or the API.
+pub const MAX_KEEP_VERSIONS: usize = 10_000;
+
+/// Parse `--keep-versions`: 0 (unlimited) up to [`MAX_KEEP_VERSIONS`].
+pub fn parse_keep_versions(s: &str) -> Result<usize, String> {
+    let n: usize = s
+        .trim()
+        .parse()
+        .map_err(|_| format!("invalid version count {s:?}"))?;
+    if n > MAX_KEEP_VERSIONS {
+        return Err(format!("at most {MAX_KEEP_VERSIONS} versions can be kept"));
+    }
+    Ok(n)
+}
+
+/// Parse a retention age such as `90`, `45m`, `12h`, `30d` or `2w` into
+/// seconds. A bare number is seconds.
+pub fn parse_age(s: &str) -> Result<u64, String> {
+    let s = s.trim();
+    let (number, unit) = match s.find(|c: char| !c.is_ascii_digit()) {
+        Some(i) => s.split_at(i),
+        None => (s, "s"),
+    };
+    let multiplier = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 3600,
+        "d" => 86_400,
+        "w" => 7 * 86_400,
+        _ => return Err(format!("unknown unit {unit:?}, expected s, m, h, d or w")),
+    };
+    let number: u64 = number
+        .parse()
+        .map_err(|_| format!("invalid age {s:?}, expected e.g. 30d"))?;
+    number
+        .checked_mul(multiplier)
+        .ok_or_else(|| format!("age {s:?} is too large"))
+}
+
+/// `YYYYMMDD-HHMMSS` in UTC.
+fn utc_stamp(t: SystemTime) -> String {
+    let secs = t
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    let (year, month, day) = civil_from_days((secs / 86_400) as i64);
+    let rem = secs % 86_400;
+    format!(
+        "{year:04}{month:02}{day:02}-{:02}{:02}{:02}",
+        rem / 3600,
+        (rem % 3600) / 60,
+        rem % 60
+    )
+}
+
+/// Gregorian date for a count of days since 1970-01-01
+/// (Howard Hinnant's `civil_from_days`).
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(versioning: Versioning, keep_versions: usize) -> ServerConfig {
+        ServerConfig {
+            versioning,
+            keep_versions,
+            ..ServerConfig::default()
+        }
+    }
+
+    fn upload(dir: &Path, name: &str, contents: &str, config: &ServerConfig) -> Installed {
+        let path = dir.join(name);
+        let part = dir.join(format!("{name}.part"));
+        std::fs::write(&part, contents).unwrap();
+        install(&part, &path, config).unwrap()
+    }
+
+    #[test]
+    fn version_names() {
+        assert_eq!(version_base("router.cfg.~3~"), Some("router.cfg"));
+        assert_eq!(
+            version_base("router.cfg.~20261018-093000~"),
+            Some("router.cfg")
+        );
+        assert_eq!(
+            version_base("router.cfg.~20261018-093000-2~"),
+            Some("router.cfg")
+        );
+        assert_eq!(version_base("router.cfg"), None);
+        assert_eq!(version_base("router.cfg.part"), None);
+        assert_eq!(version_base("router.cfg.~3~.sha256"), None);
+        assert_eq!(version_base(".~3~"), None);
+        // Names that only end in digits belong to nobody.
+        assert_eq!(version_base("pxelinux.0"), None);
+        assert_eq!(version_base("config.2024"), None);
+        assert_eq!(version_base("router.cfg.~x~"), None);
+    }
+
+    #[test]
+    fn unrelated_numbered_files_are_left_alone() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("pxelinux.0"), b"loader").unwrap();
+        std::fs::write(dir.path().join("pxelinux.7"), b"other").unwrap();
+        let config = config(Versioning::Counter, 1);
+        upload(dir.path(), "pxelinux", "v1", &config);
+        upload(dir.path(), "pxelinux", "v2", &config);
+        let installed = upload(dir.path(), "pxelinux", "v3", &config);
+
+        assert_eq!(installed.archived, Some(dir.path().join("pxelinux.~2~")));
+        assert_eq!(installed.pruned, [dir.path().join("pxelinux.~1~")]);
+        assert!(dir.path().join("pxelinux.0").exists());
+        assert!(dir.path().join("pxelinux.7").exists());
+    }
+
+    #[test]
+    fn counter_versions_with_retention() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = config(Versioning::Counter, 2);
+        assert!(
+            upload(dir.path(), "sw1.cfg", "v1", &config)
+                .archived
+                .is_none()
+        );
+        for (i, contents) in ["v2", "v3", "v4"].iter().enumerate() {
+            let installed = upload(dir.path(), "sw1.cfg", contents, &config);
+            let expected = dir.path().join(format!("sw1.cfg.~{}~", i + 1));
+            assert_eq!(installed.archived, Some(expected));
+        }
+
+        let path = dir.path().join("sw1.cfg");
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "v4");
+        let versions = list_versions(&path).unwrap();
+        let names: Vec<_> = versions.iter().map(|v| v.name.as_str()).collect();
+        // Same-second mtimes fall back to the counter for ordering.
+        assert_eq!(names, ["sw1.cfg.~3~", "sw1.cfg.~2~"]);
+        assert_eq!(std::fs::read_to_string(&versions[0].path).unwrap(), "v3");
+        assert!(!dir.path().join("sw1.cfg.~1~").exists());
+    }
+
+    #[test]
+    fn timestamp_versions_do_not_collide() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = config(Versioning::Timestamp, 0);
+        upload(dir.path(), "a.bin", "1", &config);
+        let first = upload(dir.path(), "a.bin", "2", &config).archived.unwrap();
+        let second = upload(dir.path(), "a.bin", "3", &config).archived.unwrap();
+        let first = first.file_name().unwrap().to_string_lossy().into_owned();
+        let second = second.file_name().unwrap().to_string_lossy().into_owned();
+        assert_eq!(version_base(&first), Some("a.bin"));
+        assert_eq!(version_base(&second), Some("a.bin"));
+        assert_ne!(first, second);
+        assert_eq!(list_versions(&dir.path().join("a.bin")).unwrap().len(), 2);
+    }
+
+    #[test]
+    fn versioning_off_overwrites() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = ServerConfig::default();
+        upload(dir.path(), "a.bin", "1", &config);
+        assert!(upload(dir.path(), "a.bin", "2", &config).archived.is_none());
+        assert!(list_versions(&dir.path().join("a.bin")).unwrap().is_empty());
+    }
+
+    #[test]
+    fn ages_and_dates() {
+        assert_eq!(parse_age("90"), Ok(90));
+        assert_eq!(parse_age("45m"), Ok(2700));
+        assert_eq!(parse_age("30d"), Ok(30 * 86_400));
+        assert!(parse_age("3y").is_err());
+        assert!(parse_age("d").is_err());
+
+        assert_eq!(utc_stamp(SystemTime::UNIX_EPOCH), "19700101-000000");
+        let t = SystemTime::UNIX_EPOCH + Duration::from_secs(1_709_210_096);
+        assert_eq!(utc_stamp(t), "20240229-123456");
+    }
+}